@@ -17,8 +17,16 @@ def generate_latency_configs(base_dir):
     save_json_to_file(latency_unikraft_config, f"{latency_config_output}/unikraft_config.json")
     latency_process_config = generate_latency_process_config(base_dir)
     save_json_to_file(latency_process_config, f"{latency_config_output}/process_config.json")
+    latency_process_hardened_config = generate_process_hardened_config(latency_process_config)
+    save_json_to_file(latency_process_hardened_config, f"{latency_config_output}/process_hardened_config.json")
+    latency_process_zygote_config = generate_process_zygote_config(latency_process_config)
+    save_json_to_file(latency_process_zygote_config, f"{latency_config_output}/process_zygote_config.json")
+    latency_container_config = generate_latency_container_config(base_dir)
+    save_json_to_file(latency_container_config, f"{latency_config_output}/container_config.json")
     latency_hyperlight_config = generate_latency_hyperlight_config(base_dir)
     save_json_to_file(latency_hyperlight_config, f"{latency_config_output}/hyperlight_config.json")
+    latency_wasm_config = generate_latency_wasm_config(base_dir)
+    save_json_to_file(latency_wasm_config, f"{latency_config_output}/wasm_config.json")
     latency_firecracker_snapshot_config = generate_firecracker_snapshot_config(base_dir)
     save_json_to_file(latency_firecracker_snapshot_config, f"{latency_config_output}/firecracker_snapshot_config.json")
     latency_firecracker_config = generate_firecracker_config(base_dir)
@@ -33,10 +41,20 @@ def generate_density_configs(base_dir):
     save_json_to_file(density_unikraft_config, f"{density_config_output}/unikraft_config.json")
     density_process_config = generate_density_process_config(base_dir)
     save_json_to_file(density_process_config, f"{density_config_output}/process_config.json")
+    density_process_hardened_config = generate_process_hardened_config(density_process_config)
+    save_json_to_file(density_process_hardened_config, f"{density_config_output}/process_hardened_config.json")
+    density_process_zygote_config = generate_process_zygote_config(density_process_config)
+    save_json_to_file(density_process_zygote_config, f"{density_config_output}/process_zygote_config.json")
+    density_container_config = generate_density_container_config(base_dir)
+    save_json_to_file(density_container_config, f"{density_config_output}/container_config.json")
     density_hyperlight_config = generate_density_hyperlight_config(base_dir)
     save_json_to_file(density_hyperlight_config, f"{density_config_output}/hyperlight_config.json")
+    density_wasm_config = generate_density_wasm_config(base_dir)
+    save_json_to_file(density_wasm_config, f"{density_config_output}/wasm_config.json")
     density_firecracker_config = generate_density_firecracker_config(base_dir)
     save_json_to_file(density_firecracker_config, f"{density_config_output}/firecracker_config.json")
+    density_firecracker_snapshot_config = generate_density_firecracker_snapshot_config(base_dir)
+    save_json_to_file(density_firecracker_snapshot_config, f"{density_config_output}/firecracker_snapshot_config.json")
     density_eval_config = generate_density_eval_config(base_dir)
     save_json_to_file(density_eval_config, f"{density_config_output}/eval_config.json")
 
@@ -69,6 +87,36 @@ def generate_latency_process_config(base_dir):
 
     return base_json
 
+def generate_process_hardened_config(process_config):
+    base_json = dict(process_config)
+    # Without "uid", which needs root
+    base_json["hardening"] = {
+        "rlimits": {
+            "nofile": 1024,
+            "core": 0
+        },
+        "landlock": {},
+        "seccomp": {}
+    }
+
+    return base_json
+
+def generate_process_zygote_config(process_config):
+    base_json = dict(process_config)
+    # Servers are forked from an echo server that is already initialized, without exec
+    base_json["spawn"] = "zygote"
+
+    return base_json
+
+def generate_latency_container_config(base_dir):
+    base_json = {
+        "binary_path": f"{base_dir}/bin/rust-http-echo",
+        "port": 8080,
+        "output_dir": "/tmp"
+    }
+
+    return base_json
+
 def generate_latency_hyperlight_config(base_dir):
     base_json = {
         "guest_binary": f"{base_dir}/bin/hyperlight-guest-nanvix",
@@ -79,6 +127,17 @@ def generate_latency_hyperlight_config(base_dir):
     } 
     return base_json
 
+def generate_latency_wasm_config(base_dir):
+    base_json = {
+        "host_binary": f"{base_dir}/bin/wasm-host",
+        "module": f"{base_dir}/bin/wasm-guest-echo.cwasm",
+        "listen_ip": "127.0.0.1",
+        "listen_port": 8080,
+        "output_dir": "/tmp",
+        "instantiation": "per_instance"
+    }
+    return base_json
+
 def generate_firecracker_snapshot_config(base_dir):
     base_json = {
         "firecracker_binary_dir": f"{base_dir}/scripts/firecracker/output", 
@@ -86,9 +145,16 @@ def generate_firecracker_snapshot_config(base_dir):
         "firecracker_socket_prefix": "/tmp/firecracker",
         "snapshot_file": "/tmp/snapshot_file",
         "mem_file": "/tmp/mem_file",
-        "network_setup_file": f"{base_dir}/scripts/firecracker/setup_network.sh",
-        "network_cleanup_file": f"{base_dir}/scripts/firecracker/clean_network.sh",
-        "output_dir": "/tmp"
+        "output_dir": "/tmp",
+        "manifest_file": "/tmp/snapshot_file.manifest.json",
+        "source": {
+            "firecracker_config_location": f"{base_dir}/config/latency_eval/firecracker_config.json",
+            "snapshot_type": "Full"
+        },
+        "metrics": {
+            "output_dir": "/tmp",
+            "fifo": True
+        }
     } 
 
     return base_json
@@ -98,8 +164,10 @@ def generate_firecracker_config(base_dir):
         "firecracker_binary_dir": f"{base_dir}/scripts/firecracker/output", 
         "firecracker_socket_prefix": "/tmp/firecracker",
         "config_file_template": f"{base_dir}/scripts/firecracker/output/vm_config_template.json",
-        "network_setup_file": f"{base_dir}/scripts/firecracker/setup_network.sh",
-        "network_cleanup_file": f"{base_dir}/scripts/firecracker/clean_network.sh"
+        "metrics": {
+            "output_dir": "/tmp",
+            "fifo": True
+        }
     }
 
     return base_json
@@ -111,6 +179,18 @@ def generate_latency_eval_config(base_dir):
                 "type_of_eval": "process",
                 "config_location": f"{base_dir}/config/latency_eval/process_config.json"
             },
+            {
+                "type_of_eval": "process",
+                "config_location": f"{base_dir}/config/latency_eval/process_hardened_config.json"
+            },
+            {
+                "type_of_eval": "process",
+                "config_location": f"{base_dir}/config/latency_eval/process_zygote_config.json"
+            },
+            {
+                "type_of_eval": "container",
+                "config_location": f"{base_dir}/config/latency_eval/container_config.json"
+            },
             {
                 "type_of_eval": "unikraft",
                 "config_location": f"{base_dir}/config/latency_eval/unikraft_config.json"
@@ -119,6 +199,10 @@ def generate_latency_eval_config(base_dir):
                 "type_of_eval": "hyperlight",
                 "config_location": f"{base_dir}/config/latency_eval/hyperlight_config.json"
             },
+            {
+                "type_of_eval": "wasm",
+                "config_location": f"{base_dir}/config/latency_eval/wasm_config.json"
+            },
             {
                 "type_of_eval": "firecracker-snapshot",
                 "config_location": f"{base_dir}/config/latency_eval/firecracker_snapshot_config.json"
@@ -153,6 +237,15 @@ def generate_density_process_config(base_dir):
 
     return base_json
 
+def generate_density_container_config(base_dir):
+    base_json = {
+        "binary_path": f"{base_dir}/bin/rust-http-echo",
+        "port": 8080,
+        "output_dir": "/tmp"
+    }
+
+    return base_json
+
 def generate_density_hyperlight_config(base_dir):
     base_json = {
         "guest_binary": f"{base_dir}/bin/hyperlight-guest-nanvix",
@@ -164,13 +257,35 @@ def generate_density_hyperlight_config(base_dir):
 
     return base_json
 
+def generate_density_wasm_config(base_dir):
+    base_json = generate_latency_wasm_config(base_dir)
+
+    return base_json
+
 def generate_density_firecracker_config(base_dir):
     base_json = {
         "firecracker_binary_dir": f"{base_dir}/scripts/firecracker/output", 
         "firecracker_socket_prefix": "/tmp/firecracker",
         "config_file_template": f"{base_dir}/scripts/firecracker/output/vm_config_template.json",
-        "network_setup_file": f"{base_dir}/scripts/firecracker/setup_network.sh",
-        "network_cleanup_file": f"{base_dir}/scripts/firecracker/clean_network.sh"
+        "metrics": {
+            "output_dir": "/tmp",
+            "fifo": True
+        }
+    }
+    # Deflated at boot, so it only matters with -balloon-reclaim-mib
+    base_json["balloon"] = {
+        "amount_mib": 0,
+        "deflate_on_oom": True,
+        "stats_polling_interval_s": 1
+    }
+
+    return base_json
+
+def generate_density_firecracker_snapshot_config(base_dir):
+    base_json = generate_firecracker_snapshot_config(base_dir)
+    # Each clone gets a /30 of this pool for the veth pair to its network namespace
+    base_json["clone_network"] = {
+        "address_pool": {"cidr": "10.200.0.0/16", "subnet_prefix_len": 30}
     }
 
     return base_json
@@ -186,13 +301,33 @@ def generate_density_eval_config(base_dir):
                 "type_of_eval": "firecracker",
                 "config_location": f"{base_dir}/config/density_eval/firecracker_config.json"
             },
+            {
+                "type_of_eval": "firecracker-snapshot",
+                "config_location": f"{base_dir}/config/density_eval/firecracker_snapshot_config.json"
+            },
             {
                 "type_of_eval": "process",
                 "config_location": f"{base_dir}/config/density_eval/process_config.json"
             },
+            {
+                "type_of_eval": "process",
+                "config_location": f"{base_dir}/config/density_eval/process_hardened_config.json"
+            },
+            {
+                "type_of_eval": "process",
+                "config_location": f"{base_dir}/config/density_eval/process_zygote_config.json"
+            },
+            {
+                "type_of_eval": "container",
+                "config_location": f"{base_dir}/config/density_eval/container_config.json"
+            },
             {
                 "type_of_eval": "hyperlight",
                 "config_location": f"{base_dir}/config/density_eval/hyperlight_config.json"
+            },
+            {
+                "type_of_eval": "wasm",
+                "config_location": f"{base_dir}/config/density_eval/wasm_config.json"
             }
         ]
     }
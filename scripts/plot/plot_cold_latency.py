@@ -8,6 +8,10 @@ from typing import List
 def get_system_name(name: str) -> str:
     if name == "Firecracker-Snapshot": 
         return "Firecracker\nSnapshot"
+    if name == "Process-Hardened":
+        return "Process\nHardened"
+    if name == "Process-Zygote":
+        return "Process\nZygote"
     return name
 
 def plot_cold_start_latency(csv_file_path: str, save_path_directory: str = None):
@@ -54,8 +58,8 @@ def plot_cold_start_latency(csv_file_path: str, save_path_directory: str = None)
     df = df.groupby('TYPE').mean().reset_index() 
     print(df)
 
-    # Sort the  df by TYPE by the order: Unikraft, Firecracker, Fireckare-Snapshot, Hyperlight, Process
-    order = ['Unikraft', 'Firecracker', 'Firecracker\nSnapshot', 'Hyperlight', 'Process']
+    # Sort the  df by TYPE by the order: Unikraft, Firecracker, Fireckare-Snapshot, Hyperlight, Wasm, Container, Process-Hardened, Process, Process-Zygote
+    order = ['Unikraft', 'Firecracker', 'Firecracker\nSnapshot', 'Hyperlight', 'Wasm', 'Container', 'Process\nHardened', 'Process', 'Process\nZygote']
     df['TYPE'] = pd.Categorical(df['TYPE'], categories=order, ordered=True)
     df = df.sort_values('TYPE')
     df.reset_index(drop=True, inplace=True)
@@ -107,8 +111,8 @@ def plot_warm_start_latency(csv_file_path: str, save_path_directory: str = None)
     # Create a DataFrame from the new data
     df = pd.DataFrame(new_data[1:], columns=new_data[0])
 
-    # Sort the  df by TYPE by the order: Unikraft, Firecracker, Fireckare-Snapshot, Hyperlight, Process
-    order = ['Unikraft', 'Firecracker', 'Firecracker\nSnapshot', 'Hyperlight', 'Process']
+    # Sort the  df by TYPE by the order: Unikraft, Firecracker, Fireckare-Snapshot, Hyperlight, Wasm, Container, Process-Hardened, Process, Process-Zygote
+    order = ['Unikraft', 'Firecracker', 'Firecracker\nSnapshot', 'Hyperlight', 'Wasm', 'Container', 'Process\nHardened', 'Process', 'Process\nZygote']
     df['TYPE'] = pd.Categorical(df['TYPE'], categories=order, ordered=True)
     df = df.sort_values('TYPE')
     df.reset_index(drop=True, inplace=True)
@@ -19,8 +19,8 @@ def plot_density(csv_file: str, save_path_directory: str = None):
     if 'SYSTEM' not in df.columns or 'INITIAL_MEM' not in df.columns or 'FINAL_MEM' not in df.columns or 'MAX_INSTANCES' not in df.columns:
         raise ValueError("CSV file must contain columns SYSTEM, INITIAL_MEM, FINAL_MEM, and MAX_INSTANCES")
     
-    # Sort the  df by TYPE by the order: Unikraft, Firecracker, Hyperlight, Process
-    order = ['Unikraft', 'Firecracker', 'Hyperlight', 'Process']
+    # Sort the  df by TYPE by the order: Unikraft, Firecracker, Firecracker-Snapshot, Hyperlight, Wasm, Container, Process-Hardened, Process, Process-Zygote
+    order = ['Unikraft', 'Firecracker', 'Firecracker-Snapshot', 'Hyperlight', 'Wasm', 'Container', 'Process-Hardened', 'Process', 'Process-Zygote']
     df['SYSTEM'] = pd.Categorical(df['SYSTEM'], categories=order, ordered=True)
     df = df.sort_values('SYSTEM')
     df.reset_index(drop=True, inplace=True)
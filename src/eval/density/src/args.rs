@@ -15,6 +15,10 @@ pub struct Args {
     config: String,
     // This defines the minimum memory limit that the evaluation will allow the system to go, before stopping for each sandbox
     memory_limit: u64,
+    // Number of sandboxes that are brought up concurrently
+    parallelism: usize,
+    // Balloon size each instance is inflated to once idle, to measure density with guest memory handed back
+    balloon_reclaim_mib: Option<u32>,
 }
 
 //==================================================================================================
@@ -25,10 +29,14 @@ impl Args {
     const OPT_HELP: &'static str = "-help";
     const OPT_CONFIG_JSON: &'static str = "-config";
     const OPT_MEMORY_LIMIT: &'static str = "-memory-limit";
+    const OPT_PARALLELISM: &'static str = "-parallelism";
+    const OPT_BALLOON_RECLAIM_MIB: &'static str = "-balloon-reclaim-mib";
 
     pub fn parse(args: Vec<String>) -> Result<Self> {
         let mut config_json: String = String::new();
         let mut memory_limit: u64 = 512;
+        let mut parallelism: usize = 1;
+        let mut balloon_reclaim_mib: Option<u32> = None;
 
         let mut i: usize = 1;
         while i < args.len() {
@@ -45,6 +53,18 @@ impl Args {
                     i += 1;
                     memory_limit = args[i].parse::<u64>().unwrap();
                 }
+                Self::OPT_PARALLELISM => {
+                    i += 1;
+                    parallelism = args[i].parse::<usize>()?;
+                    if parallelism < 1 {
+                        Self::usage(args[0].as_str());
+                        return Err(anyhow::anyhow!("parallelism must be at least 1"));
+                    }
+                }
+                Self::OPT_BALLOON_RECLAIM_MIB => {
+                    i += 1;
+                    balloon_reclaim_mib = Some(args[i].parse::<u32>().unwrap());
+                }
                 _ => {
                     return Err(anyhow::anyhow!("invalid argument"));
                 },
@@ -56,15 +76,19 @@ impl Args {
         Ok(Self {
             config: config_json,
             memory_limit,
+            parallelism,
+            balloon_reclaim_mib,
         })
     }
 
     pub fn usage(program_name: &str) {
         println!(
-            "Usage: {} {} <config.json> {} [memory_limit_in_mb]",
+            "Usage: {} {} <config.json> {} [memory_limit_in_mb] {} [parallelism] {} [balloon_mib]",
             program_name,
             Self::OPT_CONFIG_JSON,
             Self::OPT_MEMORY_LIMIT,
+            Self::OPT_PARALLELISM,
+            Self::OPT_BALLOON_RECLAIM_MIB,
         );
     }
 
@@ -75,4 +99,12 @@ impl Args {
     pub fn memory_limit(&self) -> u64 {
         self.memory_limit
     }
+
+    pub fn parallelism(&self) -> usize {
+        self.parallelism
+    }
+
+    pub fn balloon_reclaim_mib(&self) -> Option<u32> {
+        self.balloon_reclaim_mib
+    }
 }
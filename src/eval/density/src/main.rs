@@ -8,83 +8,113 @@ mod logging;
 use args::Args;
 use anyhow::Result;
 use sandbox_lib::{
-    sandbox::Sandbox,
-    firecracker::Firecracker,
-    firecracker_snapshot::FirecrackerSnapshot,
-    process::Process,
-    unikraft::Unikraft,
-    hyperlight::Hyperlight,
-    net_lib::wait_for_port,
+    cgroup::{self, CgroupStats},
+    firecracker_api::BalloonStats,
+    guard::{until_shutdown, SandboxGuard},
+    memory::{system_free_memory_mb, MemoryUsage},
+    registry::{self, EvalsConfig},
+    vmm_metrics::VmmMetrics,
 };
 use client_lib::{build_request, send_request, MAX_REQUEST_SIZE};
 use log::{error, debug};
-use serde::Deserialize;
 use std::collections::VecDeque;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::sync::Arc;
-use tokio::time::sleep;
-
-enum EvalType {
-    Firecracker,
-    FirecrackerSnapshot,
-    Process,
-    Unikraft,
-    Hyperlight,
+use tokio::{task::JoinSet, time::sleep};
+
+fn print_memory_usage(system_name: &str, iteration: usize, usage: &MemoryUsage) {
+    println!("{},RSS_KB,{},{}", system_name, iteration, usage.rss_kb);
+    println!("{},PSS_KB,{},{}", system_name, iteration, usage.pss_kb);
+    println!("{},USS_KB,{},{}", system_name, iteration, usage.uss_kb);
+    println!("{},SWAP_KB,{},{}", system_name, iteration, usage.swap_kb);
 }
 
-impl EvalType {
-    fn from_string(s: &str) -> Self {
-        match s {
-            "firecracker" => EvalType::Firecracker,
-            "firecracker-snapshot" => EvalType::FirecrackerSnapshot,
-            "unikraft" => EvalType::Unikraft,
-            "process" => EvalType::Process,
-            "hyperlight" => EvalType::Hyperlight,
-            _ => panic!("Invalid eval type"),
-        }
-    }
+fn print_cgroup_stats(system_name: &str, iteration: usize, stats: &CgroupStats) {
+    println!("{},CGROUP_MEMORY_KB,{},{}", system_name, iteration, stats.memory_current_bytes / 1024);
+    println!("{},CGROUP_MEMORY_PEAK_KB,{},{}", system_name, iteration, stats.memory_peak_bytes / 1024);
+    println!("{},CGROUP_OOM_KILL,{},{}", system_name, iteration, stats.oom_kill);
 }
 
-#[derive(Deserialize)]
-struct EvalConfig {
-    type_of_eval: String,
-    config_location: String,
+fn print_vmm_metrics(system_name: &str, iteration: usize, metrics: &VmmMetrics) {
+    for (name, value) in metrics.rows() {
+        println!("{},VMM_{},{},{}", system_name, name, iteration, value);
+    }
 }
 
-#[derive(Deserialize)]
-struct EvalsConfig {
-    evals: Vec<EvalConfig>,
+fn print_balloon_stats(system_name: &str, iteration: usize, stats: &BalloonStats) {
+    println!("{},BALLOON_TARGET_MIB,{},{}", system_name, iteration, stats.target_mib);
+    println!("{},BALLOON_ACTUAL_MIB,{},{}", system_name, iteration, stats.actual_mib);
+    if let Some(available_memory) = stats.available_memory {
+        println!("{},BALLOON_GUEST_AVAILABLE_KB,{},{}", system_name, iteration, available_memory / 1024);
+    }
+    if let Some(free_memory) = stats.free_memory {
+        println!("{},BALLOON_GUEST_FREE_KB,{},{}", system_name, iteration, free_memory / 1024);
+    }
 }
 
-async fn clean_sandbox(sandbox: &mut Box<dyn Sandbox>) -> Result<()> {
-    sandbox.kill().expect("Failed to kill sandbox");
-    sandbox.cleanup().expect("Failed to cleanup sandbox");
+// How long an idle guest gets to hand its memory back to the balloon
+const BALLOON_RECLAIM_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Inflates the balloon of an idle sandbox and waits for the guest to fill it, or for the timeout. The
+// guest may not manage to give all of it back, so the statistics tell how much it actually did.
+async fn reclaim_guest_memory(sandbox: &mut SandboxGuard, iteration: usize, amount_mib: u32) -> Result<()> {
+    sandbox
+        .set_balloon(amount_mib)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to inflate balloon of {}: {}", sandbox.get_name(), e))?;
+
+    let start = Instant::now();
+    let mut stats = None;
+    while start.elapsed() < BALLOON_RECLAIM_TIMEOUT {
+        stats = sandbox.balloon_stats().await?;
+        match &stats {
+            Some(current) if current.actual_mib >= amount_mib as u64 => break,
+            Some(_) => sleep(Duration::from_millis(100)).await,
+            // Nothing to wait on without statistics
+            None => break,
+        }
+    }
+    match stats {
+        Some(stats) => print_balloon_stats(&sandbox.get_name(), iteration, &stats),
+        None => debug!("{} reports no balloon statistics", sandbox.get_name()),
+    }
     Ok(())
 }
 
-fn get_free_avail_mem() -> Result<u64> {
-    let output = std::process::Command::new("sh")
-        .arg("-c")
-        .arg("cat /proc/meminfo | grep MemFree")
-        .output()?;
-
-    let output_str = String::from_utf8_lossy(&output.stdout);
-    let mem_free_kb_option =output_str.split_whitespace().nth(1);
-    let mem_free_kb_str= match mem_free_kb_option {
-        Some(mem_free_kb) => mem_free_kb,
-        None => {
-            return Err(anyhow::anyhow!("Failed to get free memory"));
-
+// Reports the memory used by all the sandboxes that are alive at the end of an eval, along with the
+// average cost of a single instance
+fn print_total_memory_usage(system_name: &str, sandboxes: &VecDeque<SandboxGuard>) {
+    let mut total = MemoryUsage::default();
+    let mut cgroup_total = CgroupStats::default();
+    let mut cgroups = 0;
+    for sandbox in sandboxes {
+        match sandbox.memory_usage() {
+            Ok(usage) => total += usage,
+            Err(e) => debug!("Failed to read memory usage of {}: {}", system_name, e),
         }
+        if let Some(stats) = sandbox.cgroup_stats() {
+            cgroup_total.memory_current_bytes += stats.memory_current_bytes;
+            cgroup_total.oom_kill += stats.oom_kill;
+            cgroups += 1;
+        }
+    }
 
-    };
-    let mem_free_kb: u64 = mem_free_kb_str.parse()?;
-    let mem_free_mb = mem_free_kb / 1024;
-
-    Ok(mem_free_mb)
+    let instances = sandboxes.len() as u64;
+    println!("{},TOTAL_PSS_KB,{},{}", system_name, instances, total.pss_kb);
+    println!("{},TOTAL_USS_KB,{},{}", system_name, instances, total.uss_kb);
+    if instances > 0 {
+        println!("{},AVG_PSS_KB,{},{}", system_name, instances, total.pss_kb / instances);
+        println!("{},AVG_USS_KB,{},{}", system_name, instances, total.uss_kb / instances);
+    }
+    if cgroups > 0 {
+        let cgroup_memory_kb = cgroup_total.memory_current_bytes / 1024;
+        println!("{},TOTAL_CGROUP_MEMORY_KB,{},{}", system_name, cgroups, cgroup_memory_kb);
+        println!("{},AVG_CGROUP_MEMORY_KB,{},{}", system_name, cgroups, cgroup_memory_kb / cgroups);
+        println!("{},TOTAL_CGROUP_OOM_KILL,{},{}", system_name, cgroups, cgroup_total.oom_kill);
+    }
 }
 
-async fn send_single_request(sandbox: &mut Box<dyn Sandbox>) -> Result<()> {
+async fn send_single_request(sandbox: &mut SandboxGuard) -> Result<()> {
     let address = format!("{}:{}", sandbox.get_target_ip(), sandbox.get_target_port());
     let request_data: Vec<u8> = vec![0u8; MAX_REQUEST_SIZE];
     let http_request: Arc<Vec<u8>> = Arc::new(build_request(request_data));
@@ -103,39 +133,22 @@ async fn send_single_request(sandbox: &mut Box<dyn Sandbox>) -> Result<()> {
 }
 
 
-async fn start_sandbox_and_wait_for_server(sandbox: &mut Box<dyn Sandbox>) -> Result<()> {
+async fn start_sandbox_and_wait_for_server(sandbox: &mut SandboxGuard) -> Result<()> {
     let system_name = sandbox.get_name();
 
-    sandbox.presetup().expect("Failed to presetup VM");
-
-    match sandbox.start() {
-        Ok(_) => {
-            if sandbox.get_name() != "Firecracker" {
-                let found = wait_for_port(&sandbox.get_target_ip(), sandbox.get_target_port());
-                if !found {
-                    return Err(anyhow::anyhow!("Failed to start VM"));
-                }
-            }
-            else {
-                // Wait for 2 s
-                sleep(Duration::from_secs(2)).await;
-            }
-        }
-        Err(e) => {
-            error!("Failed to start {} VM: {}", &system_name, e);
-            // exit the program with an error code
-            std::process::exit(1);
-        }
-    }
-
-
-    let sandbox_name = sandbox.get_name();
-    if sandbox_name != "Firecracker" || sandbox_name != "Unikraft" {
-        // Send a single request to the server
+    sandbox
+        .presetup()
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to presetup {} VM: {}", system_name, e))?;
+    sandbox
+        .start()
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to start {} VM: {}", system_name, e))?;
+    sandbox.wait_ready().await?;
+
+    if sandbox.capabilities().warmup_required {
+        // Send a single request to the server, and give it a moment to settle
         send_single_request(sandbox).await?;
-    }
-
-    if sandbox_name == "Process" || sandbox_name == "Hyperlight" {
         sleep(Duration::from_millis(20)).await;
     }
 
@@ -143,31 +156,33 @@ async fn start_sandbox_and_wait_for_server(sandbox: &mut Box<dyn Sandbox>) -> Re
 }
 
 
-async fn init_sandbox(sandbox: &mut Box<dyn Sandbox>, iteration: usize) -> Result<u64> {
-    let system_name = sandbox.get_name();
-
-    match start_sandbox_and_wait_for_server(sandbox).await {
-        Ok(_) => {}
-        Err(e) => {
-            error!("Failed to create sandbox: {}", e);
-            clean_sandbox(sandbox).await?;
-            return Err(anyhow::anyhow!("Failed to create sandbox"));
-        }
-    };
-
-    // Get free memory
-
-    let free_mem_mb = match get_free_avail_mem() {
-        Ok(free_mem_mb) => free_mem_mb,
-        Err(_e) => {
-            0
-
+async fn init_sandbox(mut sandbox: SandboxGuard, iteration: usize, balloon_reclaim_mib: Option<u32>) -> Result<SandboxGuard> {
+    let mut result = start_sandbox_and_wait_for_server(&mut sandbox).await;
+    if let (Ok(()), Some(amount_mib)) = (&result, balloon_reclaim_mib) {
+        result = reclaim_guest_memory(&mut sandbox, iteration, amount_mib).await;
+    }
+    if let Err(e) = result {
+        // Report why the sandbox failed rather than why its teardown did
+        if let Err(teardown_error) = sandbox.teardown().await {
+            error!("Failed to tear down {}: {:#}", sandbox.get_name(), teardown_error);
         }
-    };
+        return Err(e.context(format!("{} instance {}", sandbox.get_name(), iteration)));
+    }
 
-    println!("{},FREE_MEM_MB,{},{}", system_name, iteration, free_mem_mb);
+    // Memory used by this instance alone, after its balloon took back what it could
+    match sandbox.memory_usage() {
+        Ok(usage) => print_memory_usage(&sandbox.get_name(), iteration, &usage),
+        Err(e) => debug!("Failed to read memory usage of {}: {}", sandbox.get_name(), e),
+    }
+    if let Some(stats) = sandbox.cgroup_stats() {
+        print_cgroup_stats(&sandbox.get_name(), iteration, &stats);
+    }
+    // The VMM's own counters, as of readiness
+    if let Some(metrics) = sandbox.vmm_metrics() {
+        print_vmm_metrics(&sandbox.get_name(), iteration, &metrics);
+    }
 
-    Ok(free_mem_mb)
+    Ok(sandbox)
 }
 
 
@@ -180,67 +195,108 @@ fn clean_caches() {
 }
 
 #[tokio::main]
-async fn main() {
+async fn main() -> Result<()> {
     logging::initialize(false);
 
-    let args: Args = Args::parse(std::env::args().collect()).unwrap();
-    let file = std::fs::File::open(args.config()).expect("Failed to open main config file");
-    let config: EvalsConfig = serde_json::from_reader(file).expect("Failed to load main config file");
+    let args: Args = Args::parse(std::env::args().collect())?;
+    let config: EvalsConfig = EvalsConfig::load(args.config())
+        .map_err(|e| anyhow::anyhow!("Failed to load main config file: {}", e))?;
+
+    // Stopping the run drops every sandbox still alive, which tears it down
+    let result = until_shutdown(run(&args, &config)).await;
+    cgroup::remove_run_cgroups();
+    result
+}
 
-    let mut sandbox_queue: VecDeque<Box<dyn Sandbox>> = VecDeque::new();
+async fn run(args: &Args, config: &EvalsConfig) -> Result<()> {
+    let mut sandbox_queue: VecDeque<SandboxGuard> = VecDeque::new();
 
     println!("SYSTEM,OP_TYPE,ITERATION, FREE_MEMORY");
 
     for eval in &config.evals {
-        let eval_type = EvalType::from_string(&eval.type_of_eval);
+        let sandbox_config = match eval.sandbox_config() {
+            Ok(sandbox_config) => sandbox_config,
+            Err(e) => {
+                error!("Skipping eval {}: {}", eval.type_of_eval, e);
+                continue;
+            }
+        };
         clean_caches();
         let mut iteration = 0;
-        loop {
-            debug!("{},ITERATION,{}", eval.type_of_eval, iteration);
-            let mut sandbox: Box<dyn Sandbox> = match eval_type {
-                EvalType::Firecracker => {
-                    Box::new(Firecracker::new(&eval.config_location, iteration))
-                }
-                EvalType::Unikraft => {
-                    Box::new(Unikraft::new(&eval.config_location, iteration))
-                }
-                EvalType::Process => {
-                    Box::new(Process::new(&eval.config_location, iteration))
-                }
-                EvalType::FirecrackerSnapshot => {
-                    Box::new(FirecrackerSnapshot::new(&eval.config_location))
+        'eval: loop {
+            // Bring up a batch of sandboxes concurrently
+            let mut batch = JoinSet::new();
+            for _ in 0..args.parallelism() {
+                debug!("{},ITERATION,{}", eval.type_of_eval, iteration);
+                let sandbox = match registry::create_async(&eval.type_of_eval, &sandbox_config, iteration) {
+                    Ok(sandbox) => SandboxGuard::new(sandbox),
+                    Err(e) => {
+                        error!("Skipping eval {}: {}", eval.type_of_eval, e);
+                        break 'eval;
+                    }
+                };
+                // The eval keeps every instance running at once
+                if iteration == 0 && !sandbox.capabilities().multi_instance_safe {
+                    error!("Skipping eval {}: {} cannot run several instances at once", eval.type_of_eval, sandbox.get_name());
+                    break 'eval;
                 }
-                EvalType:: Hyperlight => {
-                    Box::new(Hyperlight::new(&eval.config_location, iteration))
-                }
-            };
+                batch.spawn(init_sandbox(sandbox, iteration, args.balloon_reclaim_mib()));
+                iteration += 1;
+            }
 
             // Keep creating sandboxes until it breaks
-            let mem = match init_sandbox(&mut sandbox, iteration).await {
-                Ok(mem) => mem,
-                Err(e) => {
-                    println!("{},FAILED", e);
-                    break;
+            let mut failed = false;
+            let mut system_name = eval.type_of_eval.clone();
+            while let Some(result) = batch.join_next().await {
+                match result {
+                    Ok(Ok(sandbox)) => {
+                        system_name = sandbox.get_name();
+                        sandbox_queue.push_back(sandbox);
+                    }
+                    Ok(Err(e)) => {
+                        error!("Failed to create sandbox: {:#}", e);
+                        println!("{},FAILED", system_name);
+                        failed = true;
+                    }
+                    Err(e) => {
+                        error!("Sandbox task failed: {}", e);
+                        println!("{},FAILED", system_name);
+                        failed = true;
+                    }
                 }
-            };
-            sandbox_queue .push_back(sandbox);
+            }
+            if failed {
+                break;
+            }
+
+            // Get free memory
+            let mem = system_free_memory_mb().unwrap_or(0);
+            println!("{},FREE_MEM_MB,{},{}", system_name, iteration - 1, mem);
 
             // Break if the free memory is less than the memory limit (512 MB being the default)
             if mem != 0 && mem < args.memory_limit() {
                 break;
             }
-
-            iteration += 1;
         }
 
-        // Clean all the sandboxes
-        for mut sandbox in &mut sandbox_queue {
-            clean_sandbox(&mut sandbox).await.expect("Failed to clean sandbox");
+        if let Some(sandbox) = sandbox_queue.front() {
+            print_total_memory_usage(&sandbox.get_name(), &sandbox_queue);
         }
 
-        sandbox_queue.clear();
+        // Clean all the sandboxes concurrently
+        let mut teardown = JoinSet::new();
+        for mut sandbox in sandbox_queue.drain(..) {
+            teardown.spawn(async move { (sandbox.get_name(), sandbox.teardown().await) });
+        }
+        // A sandbox that fails to go away does not stop the next evals
+        for (system_name, result) in teardown.join_all().await {
+            if let Err(e) = result {
+                error!("Failed to tear down {}: {:#}", system_name, e);
+            }
+        }
 
         // Wait for a while
         sleep(Duration::from_secs(5)).await;
     }
+    Ok(())
 }
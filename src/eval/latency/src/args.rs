@@ -16,6 +16,9 @@ pub struct Args {
     data_size: usize,
     invocations: u32,
     iterations: usize,
+    concurrency: usize,
+    regenerate_snapshots: bool,
+    pause_idle_ms: Option<u64>,
 }
 
 //==================================================================================================
@@ -28,12 +31,18 @@ impl Args {
     const OPT_DATA_SIZE : &'static str = "-data_size";
     const OPT_INVOCATIONS: &'static str = "-invocations";
     const OPT_ITERATIONS: &'static str = "-iterations";
+    const OPT_CONCURRENCY: &'static str = "-concurrency";
+    const OPT_REGENERATE_SNAPSHOTS: &'static str = "-regenerate-snapshots";
+    const OPT_PAUSE_IDLE_MS: &'static str = "-pause-idle-ms";
 
     pub fn parse(args: Vec<String>) -> Result<Self> {
         let mut config_json: String = String::new();
         let mut data_size: usize = 1024;
         let mut invocations: u32 = 1000;
         let mut iterations: usize = 10;
+        let mut concurrency: usize = 1;
+        let mut regenerate_snapshots: bool = false;
+        let mut pause_idle_ms: Option<u64> = None;
 
         let mut i: usize = 1;
         while i < args.len() {
@@ -58,6 +67,21 @@ impl Args {
                     i += 1;
                     iterations = args[i].parse::<usize>().unwrap();
                 }
+                Self::OPT_CONCURRENCY => {
+                    i += 1;
+                    concurrency = args[i].parse::<usize>()?;
+                    if concurrency < 1 {
+                        Self::usage(args[0].as_str());
+                        return Err(anyhow::anyhow!("concurrency must be at least 1"));
+                    }
+                }
+                Self::OPT_REGENERATE_SNAPSHOTS => {
+                    regenerate_snapshots = true;
+                }
+                Self::OPT_PAUSE_IDLE_MS => {
+                    i += 1;
+                    pause_idle_ms = Some(args[i].parse::<u64>().unwrap());
+                }
                 _ => {
                     return Err(anyhow::anyhow!("invalid argument"));
                 },
@@ -70,18 +94,24 @@ impl Args {
             config: config_json,
             data_size,
             invocations,
-            iterations
+            iterations,
+            concurrency,
+            regenerate_snapshots,
+            pause_idle_ms,
         })
     }
 
     pub fn usage(program_name: &str) {
         println!(
-            "Usage: {} {} <config.json> [{} <data_size> {} <invocations> {} <iterations> ]",
+            "Usage: {} {} <config.json> [{} <data_size> {} <invocations> {} <iterations> {} <concurrency> {} {} <idle_ms> ]",
             program_name,
             Self::OPT_CONFIG_JSON,
             Self::OPT_DATA_SIZE,
             Self::OPT_INVOCATIONS,
-            Self::OPT_ITERATIONS
+            Self::OPT_ITERATIONS,
+            Self::OPT_CONCURRENCY,
+            Self::OPT_REGENERATE_SNAPSHOTS,
+            Self::OPT_PAUSE_IDLE_MS
         );
     }
 
@@ -100,4 +130,16 @@ impl Args {
     pub fn iterations(&self) -> usize {
         self.iterations
     }
+
+    pub fn concurrency(&self) -> usize {
+        self.concurrency
+    }
+
+    pub fn regenerate_snapshots(&self) -> bool {
+        self.regenerate_snapshots
+    }
+
+    pub fn pause_idle_ms(&self) -> Option<u64> {
+        self.pause_idle_ms
+    }
 }
@@ -1,110 +1,98 @@
 mod args;
 
 use args::Args;
+use anyhow::{Context, Result};
 use sandbox_lib::{
-    sandbox::Sandbox,
-    firecracker::Firecracker, 
-    firecracker_snapshot::FirecrackerSnapshot,
-    process::Process,
-    unikraft::Unikraft,
-    hyperlight::Hyperlight,
-    net_lib::wait_for_port,
+    async_sandbox::AsyncSandbox,
+    cgroup::{self, CgroupStats},
+    guard::{until_shutdown, SandboxGuard},
+    registry::{self, EvalsConfig},
+    timings::{LifecycleTimings, Phase},
+    vmm_metrics::VmmMetrics,
 };
 use client_lib::{build_request, send_request, MAX_REQUEST_SIZE};
-use log::{debug, error};
-use serde::Deserialize;
+use log::debug;
 use std::time::{Duration, Instant};
 use std::sync::Arc;
-use tokio::time::sleep;
-
-enum EvalType {
-    Firecracker,
-    FirecrackerSnapshot,
-    Process,
-    Unikraft,
-    Hyperlight,
-}
+use tokio::{task::JoinSet, time::sleep};
 
-impl EvalType {
-    fn from_string(s: &str) -> Self {
-        match s {
-            "firecracker" => EvalType::Firecracker,
-            "firecracker-snapshot" => EvalType::FirecrackerSnapshot,
-            "unikraft" => EvalType::Unikraft,
-            "process" => EvalType::Process,
-            "hyperlight" => EvalType::Hyperlight,
-            _ => panic!("Invalid eval type"),
-        }
-    }
-}
 
-#[derive(Deserialize)]
-struct EvalConfig {
-    type_of_eval: String,
-    config_location: String,
-}
+async fn process_sandbox(sandbox: Box<dyn AsyncSandbox>, index: usize, data_size: usize, total_invocations: u32, pause_idle: Option<Duration>) -> Result<()> {
+    let mut sandbox = SandboxGuard::new(sandbox);
+    let system_name = sandbox.get_name();
 
-#[derive(Deserialize)]
-struct EvalsConfig {
-    evals: Vec<EvalConfig>,
-}
+    let first_response = run_sandbox(&mut sandbox, data_size, total_invocations, pause_idle).await;
 
+    // Kill and cleanup the VM, whether the run succeeded or not
+    let teardown = sandbox.teardown().await;
+    let (first_request_time, first_response_time) = first_response
+        .and_then(|times| teardown.map(|_| times))
+        .with_context(|| format!("{} instance {}", system_name, index))?;
 
-async fn process_sandbox(sandbox: &mut Box<dyn Sandbox>, data_size: usize, total_invocations: u32) {
+    // Print where the time went
+    let mut timings = sandbox.timings();
+    timings.record(Phase::FirstResponse, first_request_time, first_response_time);
+    print_timings(&system_name, &timings);
+    // The VMM's own counters, up to right before the kill
+    if let Some(metrics) = sandbox.vmm_metrics() {
+        print_vmm_metrics(&system_name, &metrics);
+    }
+    Ok(())
+}
+
+// Starts the sandbox and sends it `total_invocations` requests, then pauses and resumes it if
+// `pause_idle` is set. Returns when the first request was sent and when its response arrived.
+async fn run_sandbox(sandbox: &mut SandboxGuard, data_size: usize, total_invocations: u32, pause_idle: Option<Duration>) -> Result<(Instant, Instant)> {
     let system_name = sandbox.get_name();
 
     let presetup_time = Instant::now();
-    sandbox.presetup().expect("Failed to presetup VM");
+    sandbox
+        .presetup()
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to presetup {} VM: {}", system_name, e))?;
     let elapsed_in_micros = presetup_time.elapsed().as_micros();
     println!("{},PRESETUP,{}", &system_name, elapsed_in_micros);
 
-    // Wait for 2 s 
-    sleep(Duration::from_secs(2)).await;
+    // Give new host network devices and rules 2 s to settle before the cold start is measured
+    if sandbox.capabilities().needs_network_presetup {
+        sleep(Duration::from_secs(2)).await;
+    }
 
     let current_time = Instant::now();
 
     // Start the VM
-    match sandbox.start() {
-        Ok(_) => {
-            let found = wait_for_port(&sandbox.get_target_ip(), sandbox.get_target_port());
-            if found {
-                let elapsed_in_micros = current_time.elapsed().as_micros();
-                println!("{},SETUP_SANDBOX,{}", &system_name, elapsed_in_micros);
-            } else {
-                error!("Failed to start {} VM: Port {} is not open", &system_name,  sandbox.get_target_port());
-                sandbox.kill().expect("Failed to kill VM");
-                sandbox.cleanup().expect("Failed to cleanup VM");
-                std::process::exit(1);
-            }
-        }
-        Err(e) => {
-            eprintln!("Failed to start {} VM: {}", &system_name, e);
-            // exit the program with an error code
-            std::process::exit(1);
-        }
-    }
+    sandbox
+        .start()
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to start {} VM: {}", system_name, e))?;
+    sandbox
+        .wait_ready()
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to start {} VM: {}", system_name, e))?;
+    let elapsed_in_micros = current_time.elapsed().as_micros();
+    println!("{},SETUP_SANDBOX,{}", &system_name, elapsed_in_micros);
 
     // Build the request
     if data_size > MAX_REQUEST_SIZE {
-        panic!("Request size is too large");
+        return Err(anyhow::anyhow!("Request size is too large"));
     }
     let request_data: Vec<u8> = vec![0u8; data_size];
     let http_request: Arc<Vec<u8>> = Arc::new(build_request(request_data));
 
-    // Send the request
+    // Send the first request on its own, so that it shows up in the lifecycle timings
     let address = format!("{}:{}", sandbox.get_target_ip(), sandbox.get_target_port());
-    let latencies = match send_request(address, http_request, total_invocations).await {
-        Ok(latencies) => {
-            debug!("Requests sents successfully");
-            latencies
-        }
-        Err(e) => {
-            eprintln!("Failed to send request: {}", e);
-            sandbox.kill().expect("Failed to kill VM");
-            sandbox.cleanup().expect("Failed to cleanup VM");
-            std::process::exit(1);
-        }
-    };
+    let first_request_time = Instant::now();
+    let mut latencies = send_request(address.clone(), http_request.clone(), 1)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to send request: {}", e))?;
+    let first_response_time = Instant::now();
+
+    // Send the remaining requests
+    let remaining = send_request(address.clone(), http_request.clone(), total_invocations.saturating_sub(1))
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to send request: {}", e))?;
+    latencies.extend(remaining);
+    debug!("Requests sents successfully");
 
     println!("{},FIRST_EXECUTION,{}", &system_name, latencies[0]);
     // Print the latencies
@@ -112,48 +100,170 @@ async fn process_sandbox(sandbox: &mut Box<dyn Sandbox>, data_size: usize, total
         println!("{},EXECUTION,{}", &system_name, latency);
     }
 
-    // Kill the VM
-    sandbox.kill().expect("Failed to kill VM");
+    // Read the cgroup counters while the sandbox is still alive
+    if let Some(stats) = sandbox.cgroup_stats() {
+        print_cgroup_stats(&system_name, &stats);
+    }
 
-    // Cleanup the VM
-    sandbox.cleanup().expect("Failed to cleanup VM");
+    if let Some(idle) = pause_idle {
+        pause_and_resume(sandbox, address, http_request, idle).await?;
+    }
 
+    Ok((first_request_time, first_response_time))
+}
+
+// Pauses the idle sandbox, leaves it paused for `idle`, then resumes it and sends it one more
+// request. Reports how long pausing and resuming took, what the paused sandbox still used, and the
+// latency of the first request after resuming.
+async fn pause_and_resume(sandbox: &mut SandboxGuard, address: String, http_request: Arc<Vec<u8>>, idle: Duration) -> Result<()> {
+    let system_name = sandbox.get_name();
+
+    let pause_time = Instant::now();
+    sandbox
+        .pause()
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to pause {} VM: {}", system_name, e))?;
+    println!("{},PAUSE,{}", system_name, pause_time.elapsed().as_micros());
+
+    let cpu_before = sandbox.cpu_time()?;
+    let cgroup_before = sandbox.cgroup_stats();
+    sleep(idle).await;
+    let cpu_after = sandbox.cpu_time()?;
+    println!("{},PAUSED_CPU_USEC,{}", system_name, cpu_after.saturating_sub(cpu_before).as_micros());
+    if let (Some(before), Some(after)) = (cgroup_before, sandbox.cgroup_stats()) {
+        println!("{},PAUSED_CGROUP_CPU_USAGE_USEC,{}", system_name, after.cpu_usage_usec.saturating_sub(before.cpu_usage_usec));
+        println!("{},PAUSED_CGROUP_MEMORY_CURRENT_BYTES,{}", system_name, after.memory_current_bytes);
+    }
+    let usage = sandbox.memory_usage()?;
+    println!("{},PAUSED_RSS_KB,{}", system_name, usage.rss_kb);
+    println!("{},PAUSED_PSS_KB,{}", system_name, usage.pss_kb);
+    println!("{},PAUSED_USS_KB,{}", system_name, usage.uss_kb);
+
+    let resume_time = Instant::now();
+    sandbox
+        .resume()
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to resume {} VM: {}", system_name, e))?;
+    println!("{},RESUME,{}", system_name, resume_time.elapsed().as_micros());
+
+    let latencies = send_request(address, http_request, 1)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to send request: {}", e))?;
+    println!("{},FIRST_EXECUTION_AFTER_RESUME,{}", system_name, latencies[0]);
+    Ok(())
+}
+
+// Regenerates the snapshot the sandbox restores from if asked to, and prints which snapshot its
+// results come from. Creating a snapshot boots a whole VM, so it runs off the runtime.
+async fn prepare_snapshot(sandbox: &dyn AsyncSandbox, regenerate: bool) -> Result<()> {
+    let system_name = sandbox.get_name();
+    if let Some((manifest_file, manifest)) = sandbox.prepare_snapshot(regenerate).await? {
+        println!("{},SNAPSHOT_MANIFEST,{}", system_name, manifest_file);
+        println!("{},SNAPSHOT_SHA256,{}", system_name, manifest.snapshot.sha256);
+    }
+    Ok(())
+}
+
+fn print_cgroup_stats(system_name: &str, stats: &CgroupStats) {
+    println!("{},CGROUP_MEMORY_CURRENT_BYTES,{}", system_name, stats.memory_current_bytes);
+    println!("{},CGROUP_MEMORY_PEAK_BYTES,{}", system_name, stats.memory_peak_bytes);
+    println!("{},CGROUP_CPU_USAGE_USEC,{}", system_name, stats.cpu_usage_usec);
+    println!("{},CGROUP_CPU_USER_USEC,{}", system_name, stats.cpu_user_usec);
+    println!("{},CGROUP_CPU_SYSTEM_USEC,{}", system_name, stats.cpu_system_usec);
+    println!("{},CGROUP_CPU_THROTTLED_USEC,{}", system_name, stats.cpu_throttled_usec);
+    println!("{},CGROUP_OOM_KILL,{}", system_name, stats.oom_kill);
+}
+
+fn print_vmm_metrics(system_name: &str, metrics: &VmmMetrics) {
+    for (name, value) in metrics.rows() {
+        println!("{},VMM_{},{}", system_name, name, value);
+    }
+}
+
+fn print_timings(system_name: &str, timings: &LifecycleTimings) {
+    let origin = match timings.origin() {
+        Some(origin) => origin,
+        None => return,
+    };
+    for (phase, timing) in timings.iter() {
+        println!("{},PHASE_{},{}", system_name, phase.name(), timing.duration().as_micros());
+        println!("{},PHASE_{}_START,{}", system_name, phase.name(), timing.start.duration_since(origin).as_micros());
+    }
 }
 
 
 #[tokio::main]
-async fn main() {
-    let args: Args = Args::parse(std::env::args().collect()).unwrap();
-    let file = std::fs::File::open(args.config()).expect("Failed to open main config file");
-    let config: EvalsConfig = serde_json::from_reader(file).expect("Failed to load main config file");
+async fn main() -> Result<()> {
+    let args: Args = Args::parse(std::env::args().collect())?;
+    let config: EvalsConfig = EvalsConfig::load(args.config())
+        .map_err(|e| anyhow::anyhow!("Failed to load main config file: {}", e))?;
+
+    // Stopping the run drops every in-flight sandbox, which tears it down
+    let result = until_shutdown(run(&args, &config)).await;
+    cgroup::remove_run_cgroups();
+    result
+}
 
+async fn run(args: &Args, config: &EvalsConfig) -> Result<()> {
     println!("SYSTEM,OP_TYPE,LATENCY_MICROSECONDS");
 
     for eval in &config.evals {
-        let eval_type = EvalType::from_string(&eval.type_of_eval);
-        for iteration in 0..args.iterations() { 
-            let mut sandbox: Box<dyn Sandbox> = match eval_type {
-                EvalType::Firecracker => {
-                    Box::new(Firecracker::new(&eval.config_location, iteration))
-                }
-                EvalType::Unikraft => {
-                    Box::new(Unikraft::new(&eval.config_location, iteration))
-                }
-                EvalType::Process => {
-                    Box::new(Process::new(&eval.config_location, iteration))
-                }
-                EvalType::FirecrackerSnapshot => {
-                    Box::new(FirecrackerSnapshot::new(&eval.config_location))
-                }
-                EvalType:: Hyperlight => {
-                    Box::new(Hyperlight::new(&eval.config_location, iteration))
+        let sandbox_config = match eval.sandbox_config() {
+            Ok(sandbox_config) => sandbox_config,
+            Err(e) => {
+                eprintln!("Skipping eval {}: {}", eval.type_of_eval, e);
+                continue;
+            }
+        };
+        let mut pause_idle = args.pause_idle_ms().map(Duration::from_millis);
+        'eval: for iteration in 0..args.iterations() { 
+            // Start `concurrency` sandboxes at once, each one with its own instance index
+            let mut sandboxes = JoinSet::new();
+            for instance in 0..args.concurrency() {
+                let index = iteration * args.concurrency() + instance;
+                let sandbox = match registry::create_async(&eval.type_of_eval, &sandbox_config, index) {
+                    Ok(sandbox) => sandbox,
+                    Err(e) => {
+                        eprintln!("Skipping eval {}: {}", eval.type_of_eval, e);
+                        break;
+                    }
+                };
+                // What the eval can do only shows once it has a sandbox
+                if index == 0 {
+                    let capabilities = sandbox.capabilities();
+                    if args.concurrency() > 1 && !capabilities.multi_instance_safe {
+                        eprintln!("Skipping eval {}: {} cannot run several instances at once", eval.type_of_eval, sandbox.get_name());
+                        break 'eval;
+                    }
+                    if capabilities.supports_snapshot {
+                        if let Err(e) = prepare_snapshot(sandbox.as_ref(), args.regenerate_snapshots()).await {
+                            eprintln!("Skipping eval {}: cannot prepare snapshot: {:#}", eval.type_of_eval, e);
+                            break 'eval;
+                        }
+                    }
+                    if pause_idle.is_some() && !capabilities.supports_pause {
+                        eprintln!("{} cannot pause and resume, only measuring cold starts", sandbox.get_name());
+                        pause_idle = None;
+                    }
                 }
-            };
+                sandboxes.spawn(process_sandbox(sandbox, index, args.data_size(), args.invocations(), pause_idle));
+            }
 
-            process_sandbox(&mut sandbox, args.data_size(), args.invocations()).await;
+            if sandboxes.is_empty() {
+                break;
+            }
+            // A failed sandbox only loses its own samples
+            while let Some(result) = sandboxes.join_next().await {
+                match result {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => eprintln!("Sandbox failed: {:#}", e),
+                    Err(e) => eprintln!("Sandbox task failed: {}", e),
+                }
+            }
 
             // Sleep for a bit to allow the VM to cleanup
             sleep(Duration::from_secs(2)).await;
         }
     }
+    Ok(())
 }
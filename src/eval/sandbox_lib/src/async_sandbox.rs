@@ -0,0 +1,277 @@
+use crate::{
+    cgroup::CgroupStats,
+    cpu::tree_cpu_time,
+    error::{ignore_not_started, SandboxError},
+    firecracker_api::BalloonStats,
+    memory::{tree_memory_usage, MemoryUsage},
+    sandbox::{Capabilities, Pausable, Sandbox},
+    snapshot::SnapshotManifest,
+    timings::{LifecycleTimings, Phase},
+    vmm_metrics::VmmMetrics,
+};
+use anyhow::Result;
+use log::debug;
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+pub type SandboxFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T>> + Send + 'a>>;
+
+// Asynchronous counterpart of `Sandbox`. Every lifecycle operation returns a future, so drivers can
+// bring up many sandboxes concurrently from a single runtime, and give up on any of them by
+// dropping the future or wrapping it in `tokio::time::timeout`.
+pub trait AsyncSandbox: Send {
+    fn presetup(&mut self) -> SandboxFuture<'_, ()>;
+    fn start(&mut self) -> SandboxFuture<'_, ()>;
+    // Resolves once the sandbox's readiness probe succeeds, or fails when the probe times out
+    fn wait_ready(&mut self) -> SandboxFuture<'_, ()>;
+    fn kill(&mut self) -> SandboxFuture<'_, ()>;
+    fn cleanup(&mut self) -> SandboxFuture<'_, ()>;
+    // See `Pausable`. Fail with `SandboxError::Unsupported` for backends that cannot pause.
+    fn pause(&mut self) -> SandboxFuture<'_, ()>;
+    fn resume(&mut self) -> SandboxFuture<'_, ()>;
+    // Kills and cleans up the sandbox without going through the runtime, for callers that cannot
+    // await, like destructors. Waits for any in-flight operation to finish first.
+    fn teardown_blocking(&mut self) -> Result<()>;
+
+    // Properties of the sandbox
+    fn get_target_ip(&self) -> String;
+    fn get_target_port(&self) -> u16;
+    fn get_name(&self) -> String;
+    fn capabilities(&self) -> Capabilities;
+
+    // See `Sandbox::prepare_snapshot`
+    fn prepare_snapshot(&self, regenerate: bool) -> SandboxFuture<'_, Option<(String, SnapshotManifest)>>;
+
+    // Lifecycle phases recorded so far, both by the driver facing side and by the sandbox itself
+    fn timings(&self) -> LifecycleTimings;
+
+    fn root_pid(&self) -> Option<u32>;
+
+    // Counters of the sandbox's cgroup, if it runs in one
+    fn cgroup_stats(&self) -> Option<CgroupStats>;
+
+    // See `Sandbox::set_balloon` and `Sandbox::balloon_stats`
+    fn set_balloon(&mut self, amount_mib: u32) -> SandboxFuture<'_, ()>;
+    fn balloon_stats(&self) -> SandboxFuture<'_, Option<BalloonStats>>;
+
+    // Counters the VMM reported about itself, flushed once the sandbox is ready and before it is
+    // killed
+    fn vmm_metrics(&self) -> Option<VmmMetrics>;
+
+    // Memory used by the process tree hosting the sandbox
+    fn memory_usage(&self) -> Result<MemoryUsage> {
+        let pid = self
+            .root_pid()
+            .ok_or_else(|| anyhow::anyhow!("{} has no running process", self.get_name()))?;
+        tree_memory_usage(pid)
+    }
+
+    // CPU time used so far by the process tree hosting the sandbox
+    fn cpu_time(&self) -> Result<Duration> {
+        let pid = self
+            .root_pid()
+            .ok_or_else(|| anyhow::anyhow!("{} has no running process", self.get_name()))?;
+        tree_cpu_time(pid)
+    }
+}
+
+// Runs a synchronous `Sandbox` on tokio's blocking thread pool. Operations are serialized through a
+// mutex: if a caller drops an in-flight future, the underlying operation still runs to completion,
+// and a later `kill` or `cleanup` waits for it before tearing the sandbox down. The adapter records
+// the presetup, guest listening, kill and cleanup phases for every backend.
+pub struct BlockingSandbox {
+    inner: Arc<Mutex<Box<dyn Sandbox>>>,
+    timings: Arc<Mutex<LifecycleTimings>>,
+    started_at: Option<Instant>,
+    target_ip: String,
+    target_port: u16,
+    name: String,
+    capabilities: Capabilities,
+}
+
+impl BlockingSandbox {
+    pub fn new(sandbox: Box<dyn Sandbox>) -> Self {
+        BlockingSandbox {
+            target_ip: sandbox.get_target_ip(),
+            target_port: sandbox.get_target_port(),
+            name: sandbox.get_name(),
+            capabilities: sandbox.capabilities(),
+            inner: Arc::new(Mutex::new(sandbox)),
+            timings: Arc::new(Mutex::new(LifecycleTimings::default())),
+            started_at: None,
+        }
+    }
+
+    fn run<F>(&self, phase: Option<Phase>, op: F) -> SandboxFuture<'static, ()>
+    where
+        F: FnOnce(&mut dyn Sandbox) -> Result<()> + Send + 'static,
+    {
+        let inner = self.inner.clone();
+        let timings = self.timings.clone();
+        Box::pin(async move {
+            tokio::task::spawn_blocking(move || {
+                let mut sandbox = inner
+                    .lock()
+                    .map_err(|_| anyhow::anyhow!("sandbox lock poisoned"))?;
+                let start = Instant::now();
+                op(sandbox.as_mut())?;
+                if let (Some(phase), Ok(mut timings)) = (phase, timings.lock()) {
+                    timings.record(phase, start, Instant::now());
+                }
+                Ok(())
+            })
+            .await?
+        })
+    }
+}
+
+impl AsyncSandbox for BlockingSandbox {
+    fn presetup(&mut self) -> SandboxFuture<'_, ()> {
+        self.run(Some(Phase::Presetup), |sandbox| sandbox.presetup())
+    }
+
+    fn start(&mut self) -> SandboxFuture<'_, ()> {
+        self.started_at = Some(Instant::now());
+        self.run(None, |sandbox| sandbox.start())
+    }
+
+    fn wait_ready(&mut self) -> SandboxFuture<'_, ()> {
+        let inner = self.inner.clone();
+        let timings = self.timings.clone();
+        let started_at = self.started_at.unwrap_or_else(Instant::now);
+        Box::pin(async move {
+            tokio::task::spawn_blocking(move || {
+                // Only hold the lock while building the probe, so that the sandbox can still be
+                // killed while we wait
+                let readiness = inner
+                    .lock()
+                    .map_err(|_| anyhow::anyhow!("sandbox lock poisoned"))?
+                    .readiness()?;
+                readiness.wait()?;
+                if let Ok(mut timings) = timings.lock() {
+                    timings.record(Phase::GuestListening, started_at, Instant::now());
+                }
+                if let Ok(mut sandbox) = inner.lock()
+                    && let Err(e) = sandbox.flush_vmm_metrics()
+                {
+                    debug!("Failed to flush VMM metrics of {}: {}", sandbox.get_name(), e);
+                }
+                Ok(())
+            })
+            .await?
+        })
+    }
+
+    fn kill(&mut self) -> SandboxFuture<'_, ()> {
+        self.run(Some(Phase::Kill), |sandbox| sandbox.kill())
+    }
+
+    fn cleanup(&mut self) -> SandboxFuture<'_, ()> {
+        self.run(Some(Phase::Cleanup), |sandbox| sandbox.cleanup())
+    }
+
+    fn pause(&mut self) -> SandboxFuture<'_, ()> {
+        self.run(Some(Phase::Pause), |sandbox| pausable(sandbox)?.pause())
+    }
+
+    fn resume(&mut self) -> SandboxFuture<'_, ()> {
+        self.run(Some(Phase::Resume), |sandbox| pausable(sandbox)?.resume())
+    }
+
+    fn teardown_blocking(&mut self) -> Result<()> {
+        // A panic in a previous operation poisons the lock, but the sandbox still has to go away
+        let mut sandbox = self.inner.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let killed = sandbox.kill();
+        let cleaned = sandbox.cleanup();
+        ignore_not_started(killed).and(cleaned)
+    }
+
+    fn get_target_ip(&self) -> String {
+        self.target_ip.clone()
+    }
+
+    fn get_target_port(&self) -> u16 {
+        self.target_port
+    }
+
+    fn get_name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.capabilities.clone()
+    }
+
+    fn prepare_snapshot(&self, regenerate: bool) -> SandboxFuture<'_, Option<(String, SnapshotManifest)>> {
+        let inner = self.inner.clone();
+        Box::pin(async move {
+            tokio::task::spawn_blocking(move || {
+                inner
+                    .lock()
+                    .map_err(|_| anyhow::anyhow!("sandbox lock poisoned"))?
+                    .prepare_snapshot(regenerate)
+            })
+            .await?
+        })
+    }
+
+    fn timings(&self) -> LifecycleTimings {
+        let mut timings = self
+            .timings
+            .lock()
+            .map(|timings| timings.clone())
+            .unwrap_or_default();
+        if let Ok(sandbox) = self.inner.lock() {
+            timings.merge(&sandbox.timings());
+        }
+        timings
+    }
+
+    fn root_pid(&self) -> Option<u32> {
+        self.inner.lock().ok().and_then(|sandbox| sandbox.root_pid())
+    }
+
+    fn cgroup_stats(&self) -> Option<CgroupStats> {
+        let sandbox = self.inner.lock().ok()?;
+        match sandbox.cgroup()?.stats() {
+            Ok(stats) => Some(stats),
+            Err(e) => {
+                debug!("Failed to read cgroup of {}: {}", self.name, e);
+                None
+            }
+        }
+    }
+
+    fn set_balloon(&mut self, amount_mib: u32) -> SandboxFuture<'_, ()> {
+        self.run(None, move |sandbox| sandbox.set_balloon(amount_mib))
+    }
+
+    fn balloon_stats(&self) -> SandboxFuture<'_, Option<BalloonStats>> {
+        let inner = self.inner.clone();
+        Box::pin(async move {
+            tokio::task::spawn_blocking(move || {
+                inner
+                    .lock()
+                    .map_err(|_| anyhow::anyhow!("sandbox lock poisoned"))?
+                    .balloon_stats()
+            })
+            .await?
+        })
+    }
+
+    fn vmm_metrics(&self) -> Option<VmmMetrics> {
+        self.inner.lock().ok()?.vmm_metrics()
+    }
+}
+
+fn pausable(sandbox: &mut dyn Sandbox) -> Result<&mut dyn Pausable, SandboxError> {
+    let backend = sandbox.get_name();
+    sandbox.as_pausable().ok_or(SandboxError::Unsupported {
+        operation: "pause and resume".to_string(),
+        backend,
+    })
+}
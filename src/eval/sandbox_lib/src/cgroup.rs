@@ -0,0 +1,286 @@
+use crate::error::SandboxError;
+use log::{debug, warn};
+use serde::Deserialize;
+use std::{
+    collections::HashSet,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    os::{
+        fd::AsRawFd,
+        unix::{
+            fs::{FileExt, OpenOptionsExt},
+            process::CommandExt,
+        },
+    },
+    path::{Path, PathBuf},
+    process::Command,
+    sync::{LazyLock, Mutex},
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+const DEFAULT_PARENT: &str = "sandbox_bench";
+const CONTROLLERS: &str = "+memory +cpu +cpuset";
+// How long the freezer gets to stop or restart every process of a cgroup
+const FREEZE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Run cgroups created by this process. They are shared by every sandbox of the run, and only go
+// away in `remove_run_cgroups`, as removing one while another sandbox creates its cgroup in it
+// would leave that sandbox without controllers.
+static RUN_CGROUPS: LazyLock<Mutex<HashSet<PathBuf>>> = LazyLock::new(Default::default);
+
+// Optional `cgroup` section of a backend config. Each sandbox gets its own cgroup under
+// `<parent>/run-<driver pid>`, with the given limits in cgroup v2 syntax (e.g. `"memory_max":
+// "256M"`, `"cpu_max": "50000 100000"`, `"cpuset_cpus": "2-3"`).
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct CgroupConfig {
+    // Parent cgroup, relative to the cgroup v2 mount point
+    pub parent: Option<String>,
+    pub memory_max: Option<String>,
+    pub cpu_max: Option<String>,
+    pub cpuset_cpus: Option<String>,
+}
+
+// Counters read back from a sandbox's cgroup.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CgroupStats {
+    pub memory_current_bytes: u64,
+    pub memory_peak_bytes: u64,
+    pub cpu_usage_usec: u64,
+    pub cpu_user_usec: u64,
+    pub cpu_system_usec: u64,
+    pub cpu_nr_throttled: u64,
+    pub cpu_throttled_usec: u64,
+    pub oom: u64,
+    pub oom_kill: u64,
+}
+
+pub struct Cgroup {
+    path: PathBuf,
+}
+
+impl Cgroup {
+    // Creates the cgroup of sandbox `id` and applies the configured limits
+    pub fn create(config: &CgroupConfig, id: &str) -> Result<Self, SandboxError> {
+        if !Path::new(CGROUP_ROOT).join("cgroup.controllers").exists() {
+            return Err(SandboxError::ConfigInvalid {
+                reason: format!("cgroup limits need a cgroup v2 hierarchy mounted at {}", CGROUP_ROOT),
+            });
+        }
+        let parent = config.parent.as_deref().unwrap_or(DEFAULT_PARENT);
+        let run_dir = Path::new(CGROUP_ROOT)
+            .join(parent.trim_start_matches('/'))
+            .join(format!("run-{}", std::process::id()));
+        {
+            let mut run_cgroups = RUN_CGROUPS.lock().unwrap_or_else(|e| e.into_inner());
+            if !run_cgroups.contains(&run_dir) {
+                Self::create_parents(&run_dir)?;
+                run_cgroups.insert(run_dir.clone());
+            }
+        }
+
+        let path = run_dir.join(id);
+        fs::create_dir_all(&path).map_err(SandboxError::io(path.to_string_lossy()))?;
+        let cgroup = Cgroup { path };
+
+        if let Some(memory_max) = &config.memory_max {
+            cgroup.write("memory.max", memory_max)?;
+        }
+        if let Some(cpu_max) = &config.cpu_max {
+            cgroup.write("cpu.max", cpu_max)?;
+        }
+        if let Some(cpuset_cpus) = &config.cpuset_cpus {
+            cgroup.write("cpuset.cpus", cpuset_cpus)?;
+        }
+
+        debug!("Created cgroup {}", cgroup.path.display());
+        Ok(cgroup)
+    }
+
+    pub fn create_optional(config: Option<&CgroupConfig>, id: &str) -> Result<Option<Self>, SandboxError> {
+        config.map(|config| Self::create(config, id)).transpose()
+    }
+
+    // Creates every missing cgroup between the root and `run_dir`, delegating the controllers we
+    // set limits on to their children
+    fn create_parents(run_dir: &Path) -> Result<(), SandboxError> {
+        let mut current = PathBuf::from(CGROUP_ROOT);
+        let relative = run_dir.strip_prefix(CGROUP_ROOT).map_err(|_| SandboxError::ConfigInvalid {
+            reason: format!("{} is not a cgroup", run_dir.display()),
+        })?;
+        for component in relative.components() {
+            if let Err(e) = write_file(&current.join("cgroup.subtree_control"), CONTROLLERS) {
+                warn!(
+                    "Failed to enable controllers in {} ({}), limits may not apply",
+                    current.display(),
+                    e
+                );
+            }
+            current.push(component);
+            fs::create_dir_all(&current).map_err(SandboxError::io(current.to_string_lossy()))?;
+        }
+        if let Err(e) = write_file(&current.join("cgroup.subtree_control"), CONTROLLERS) {
+            warn!("Failed to enable controllers in {} ({})", current.display(), e);
+        }
+        Ok(())
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Makes `command` join this cgroup before it executes, so that the sandbox and everything it
+    // spawns is accounted from the very first instruction
+    pub fn attach(&self, command: &mut Command) -> Result<(), SandboxError> {
+        let procs = self.open_procs()?;
+        // SAFETY: the hook only issues a write(2) on an already opened file, without allocating,
+        // which is safe to do between fork and exec. Writing "0" moves the writing process.
+        unsafe {
+            command.pre_exec(move || (&procs).write_all(b"0"));
+        }
+        Ok(())
+    }
+
+    // For processes that join on their own: writing "0" to it moves the writing process
+    pub fn open_procs(&self) -> Result<File, SandboxError> {
+        let path = self.path.join("cgroup.procs");
+        OpenOptions::new().write(true).open(&path).map_err(SandboxError::io(path.to_string_lossy()))
+    }
+
+    // For clone3(2) with CLONE_INTO_CGROUP, which starts the child in the cgroup right away
+    pub fn open_dir(&self) -> Result<File, SandboxError> {
+        OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_DIRECTORY)
+            .open(&self.path)
+            .map_err(SandboxError::io(self.path.to_string_lossy()))
+    }
+
+    // Freezes or thaws every process in the cgroup with the cgroup v2 freezer, and waits until the
+    // kernel reports it done
+    pub fn set_frozen(&self, frozen: bool) -> Result<(), SandboxError> {
+        let events_path = self.path.join("cgroup.events");
+        let events = File::open(&events_path).map_err(SandboxError::io(events_path.to_string_lossy()))?;
+        let (value, state) = if frozen { ("1", "frozen 1") } else { ("0", "frozen 0") };
+        self.write("cgroup.freeze", value)?;
+
+        let deadline = Instant::now() + FREEZE_TIMEOUT;
+        let mut buffer = [0u8; 256];
+        loop {
+            // Reading through the polled descriptor also acknowledges the change
+            let read = events
+                .read_at(&mut buffer, 0)
+                .map_err(SandboxError::io(events_path.to_string_lossy()))?;
+            if String::from_utf8_lossy(&buffer[..read]).lines().any(|line| line == state) {
+                return Ok(());
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(SandboxError::Timeout {
+                    what: format!("{} of {}", if frozen { "freezing" } else { "thawing" }, self.path.display()),
+                    after: FREEZE_TIMEOUT,
+                });
+            }
+            // cgroup.events signals every change with POLLPRI
+            let mut poll_fd = libc::pollfd {
+                fd: events.as_raw_fd(),
+                events: libc::POLLPRI,
+                revents: 0,
+            };
+            // SAFETY: polls a single descriptor that outlives the call
+            unsafe { libc::poll(&mut poll_fd, 1, remaining.as_millis().max(1) as libc::c_int) };
+        }
+    }
+
+    pub fn stats(&self) -> Result<CgroupStats, SandboxError> {
+        let mut stats = CgroupStats {
+            memory_current_bytes: self.read_u64("memory.current")?,
+            // Only available on kernels 5.19 and later
+            memory_peak_bytes: self.read_u64("memory.peak").unwrap_or_default(),
+            ..CgroupStats::default()
+        };
+        for (key, value) in self.read_keyed("cpu.stat")? {
+            match key.as_str() {
+                "usage_usec" => stats.cpu_usage_usec = value,
+                "user_usec" => stats.cpu_user_usec = value,
+                "system_usec" => stats.cpu_system_usec = value,
+                "nr_throttled" => stats.cpu_nr_throttled = value,
+                "throttled_usec" => stats.cpu_throttled_usec = value,
+                _ => {},
+            }
+        }
+        for (key, value) in self.read_keyed("memory.events")? {
+            match key.as_str() {
+                "oom" => stats.oom = value,
+                "oom_kill" => stats.oom_kill = value,
+                _ => {},
+            }
+        }
+        Ok(stats)
+    }
+
+    // Kills whatever is left in the cgroup and removes it
+    pub fn remove(&self) -> Result<(), SandboxError> {
+        // cgroup.kill is only available on kernels 5.14 and later
+        let _ = self.write("cgroup.kill", "1");
+        let mut attempts = 0;
+        loop {
+            match fs::remove_dir(&self.path) {
+                Ok(_) => break,
+                Err(e) if attempts < 100 => {
+                    debug!("Retrying removal of {}: {}", self.path.display(), e);
+                    attempts += 1;
+                    sleep(Duration::from_millis(10));
+                },
+                Err(e) => return Err(SandboxError::Io { path: self.path.to_string_lossy().into_owned(), source: e }),
+            }
+        }
+        Ok(())
+    }
+
+    fn write(&self, file: &str, value: &str) -> Result<(), SandboxError> {
+        write_file(&self.path.join(file), value)
+    }
+
+    fn read_u64(&self, file: &str) -> Result<u64, SandboxError> {
+        let path = self.path.join(file);
+        let value = fs::read_to_string(&path).map_err(SandboxError::io(path.to_string_lossy()))?;
+        value
+            .trim()
+            .parse()
+            .map_err(|e| SandboxError::io(path.to_string_lossy())(io::Error::new(io::ErrorKind::InvalidData, e)))
+    }
+
+    fn read_keyed(&self, file: &str) -> Result<Vec<(String, u64)>, SandboxError> {
+        let path = self.path.join(file);
+        let content = fs::read_to_string(&path).map_err(SandboxError::io(path.to_string_lossy()))?;
+        Ok(content
+            .lines()
+            .filter_map(|line| {
+                let (key, value) = line.split_once(' ')?;
+                Some((key.to_string(), value.trim().parse().ok()?))
+            })
+            .collect())
+    }
+}
+
+// Removes the run cgroups of this process, once every sandbox is gone. Drivers call it on exit.
+pub fn remove_run_cgroups() {
+    let mut run_cgroups = RUN_CGROUPS.lock().unwrap_or_else(|e| e.into_inner());
+    for run_dir in run_cgroups.drain() {
+        match fs::remove_dir(&run_dir) {
+            Ok(()) => debug!("Removed cgroup {}", run_dir.display()),
+            Err(e) => warn!("Failed to remove cgroup {}: {}", run_dir.display(), e),
+        }
+    }
+}
+
+// Writes to an existing cgroup interface file. Unlike `fs::write`, never creates the file.
+fn write_file(path: &Path, value: &str) -> Result<(), SandboxError> {
+    let mut handle = OpenOptions::new()
+        .write(true)
+        .open(path)
+        .map_err(SandboxError::io(path.to_string_lossy()))?;
+    handle.write_all(value.as_bytes()).map_err(SandboxError::io(path.to_string_lossy()))
+}
@@ -0,0 +1,566 @@
+use crate::{
+    cgroup::{Cgroup, CgroupConfig},
+    error::SandboxError,
+    net_alloc::{AddressPoolConfig, Lease, NetworkAllocator},
+    netdev::{self, NetDev},
+    readiness::{ProbeKind, ProbeTarget, Readiness, ReadinessConfig},
+    registry::{SandboxConfig, SandboxEntry},
+    sandbox::{Capabilities, Sandbox},
+    timings::{LifecycleTimings, Phase},
+};
+use anyhow::Result;
+use log::debug;
+use serde::Deserialize;
+use std::{
+    ffi::{CStr, CString},
+    fs::{self, File},
+    io,
+    mem,
+    net::TcpListener,
+    os::{
+        fd::{AsRawFd, RawFd},
+        unix::process::CommandExt,
+    },
+    path::{Path, PathBuf},
+    process::{Child, Command},
+    ptr,
+    time::Instant,
+};
+use uuid::Uuid;
+
+// The echo server in a container of our own making: new user, pid, mount, net, uts and ipc
+// namespaces, and a root file system holding the server and the host directories it needs. Works
+// without a container runtime, and without root wherever unprivileged user namespaces are allowed.
+#[derive(Deserialize)]
+struct ContainerConfig {
+    binary_path: String,
+    port: u16,
+    output_dir: String,
+    // Host directories bind mounted read-only at the same place in the container, by default the
+    // ones the shared libraries of a dynamically linked server come from. Missing ones are skipped.
+    #[serde(default = "ContainerConfig::default_bind_mounts")]
+    bind_mounts: Vec<String>,
+    #[serde(default)]
+    network: ContainerNetwork,
+    readiness: Option<ReadinessConfig>,
+    cgroup: Option<CgroupConfig>,
+}
+
+impl ContainerConfig {
+    fn default_bind_mounts() -> Vec<String> {
+        ["/lib", "/lib64", "/usr/lib", "/usr/lib64"].map(String::from).to_vec()
+    }
+}
+
+// How requests reach the server, e.g. `"network": { "mode": "veth" }`
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+enum ContainerNetwork {
+    // The socket is bound to 127.0.0.1:<port + instance> on the host and handed over to the server,
+    // like a published port, while the container's own network namespace stays empty. Needs no
+    // privileges.
+    #[default]
+    PortForward,
+    // A veth pair between the host and the container's eth0, with a subnet from `address_pool`, and
+    // the server listening on `port` in the container. Needs root.
+    Veth { address_pool: Option<AddressPoolConfig> },
+}
+
+pub struct Container {
+    id: String,
+    config: ContainerConfig,
+    iteration: usize,
+    // `<output_dir>/container-<id>`, holding the root file system
+    bundle_dir: PathBuf,
+    // Port forwarding only, handed over to the server on start
+    listener: Option<TcpListener>,
+    // Veth only. The lease is held until cleanup, so that no other instance gets the same addresses.
+    lease: Option<Lease>,
+    veth: Option<String>,
+    child_process: Option<Child>,
+    timings: LifecycleTimings,
+    cgroup: Option<Cgroup>,
+}
+
+pub const ENTRY: SandboxEntry = SandboxEntry {
+    name: "container",
+    build: |config, iteration| Ok(Box::new(Container::from_config(config, iteration)?)),
+};
+
+// Where the server and its listening socket end up in the container
+const SERVER_PATH: &str = "/server";
+const LISTEN_FD: RawFd = 3;
+const CONTAINER_IFACE: &str = "eth0";
+const HOSTNAME: &CStr = c"container";
+
+impl Container {
+    pub fn new(config_path: &str, iteration: usize) -> Result<Self, SandboxError> {
+        Self::from_config(&SandboxConfig::Path(config_path.to_string()), iteration)
+    }
+
+    pub fn from_config(config: &SandboxConfig, iteration: usize) -> Result<Self, SandboxError> {
+        let mut config: ContainerConfig = config.parse()?;
+
+        let lease = match &config.network {
+            // Every instance gets its own host port
+            ContainerNetwork::PortForward => {
+                config.port += iteration as u16;
+                None
+            },
+            ContainerNetwork::Veth { address_pool } => {
+                let address_pool = address_pool.clone().unwrap_or_default();
+                Some(NetworkAllocator::shared(&address_pool)?.allocate()?)
+            },
+        };
+
+        let id = Uuid::new_v4().to_string();
+        Ok(Container {
+            bundle_dir: Path::new(&config.output_dir).join(format!("container-{}", id)),
+            id,
+            config,
+            iteration,
+            listener: None,
+            lease,
+            veth: None,
+            child_process: None,
+            timings: LifecycleTimings::default(),
+            cgroup: None,
+        })
+    }
+
+    fn log_file(&self, suffix: &str) -> String {
+        format!("{}/container{}-{}{}", self.config.output_dir, self.id, self.iteration, suffix)
+    }
+
+    fn root_dir(&self) -> PathBuf {
+        self.bundle_dir.join("rootfs")
+    }
+
+    // Creates the mount points of the root file system. Only the container sees what is mounted on
+    // them, the host keeps seeing empty directories.
+    fn prepare_root(&self) -> Result<RootSetup, SandboxError> {
+        let root = self.root_dir();
+        let create_dir = |path: &Path| fs::create_dir_all(path).map_err(SandboxError::io(path.to_string_lossy()));
+        create_dir(&root.join("proc"))?;
+
+        let server = root.join(SERVER_PATH.trim_start_matches('/'));
+        File::create(&server).map_err(SandboxError::io(server.to_string_lossy()))?;
+        let mut bind_mounts = vec![BindMount::new(Path::new(&self.config.binary_path), &server)?];
+        for source in &self.config.bind_mounts {
+            if !Path::new(source).is_dir() {
+                debug!("Not mounting {} in the container, it is not a directory on the host", source);
+                continue;
+            }
+            let target = root.join(source.trim_start_matches('/'));
+            create_dir(&target)?;
+            bind_mounts.push(BindMount::new(Path::new(source), &target)?);
+        }
+
+        Ok(RootSetup {
+            root: c_path(&root)?,
+            proc_dir: c_path(&root.join("proc"))?,
+            bind_mounts,
+        })
+    }
+
+    fn server_args(&self) -> Vec<String> {
+        match self.config.network {
+            ContainerNetwork::PortForward => vec!["-listen-fd".to_string(), LISTEN_FD.to_string()],
+            ContainerNetwork::Veth { .. } => vec!["-listen".to_string(), format!("0.0.0.0:{}", self.config.port)],
+        }
+    }
+
+    // Connects the container to the host through a veth pair, from a thread that joins its network
+    // namespace for the container side
+    fn setup_veth(&mut self, pid: u32) -> Result<(), SandboxError> {
+        let Some(lease) = &self.lease else {
+            return Ok(());
+        };
+        let subnet = lease.subnet.clone();
+        let veth = format!("veth-ctr{}", self.iteration);
+        let netdev = NetDev::new(false);
+        netdev.create_veth(&veth, CONTAINER_IFACE, pid)?;
+        self.veth = Some(veth.clone());
+        netdev.add_address(&veth, subnet.host_ip, subnet.prefix_len)?;
+        netdev.set_up(&veth)?;
+        netdev::in_network_namespace(pid, || {
+            netdev.add_address(CONTAINER_IFACE, subnet.guest_ip, subnet.prefix_len)?;
+            netdev.set_up(CONTAINER_IFACE)?;
+            netdev.set_up("lo")
+        })
+    }
+}
+
+impl Sandbox for Container {
+    fn presetup(&mut self) -> Result<()> {
+        self.cgroup = Cgroup::create_optional(self.config.cgroup.as_ref(), &self.id)?;
+        if let ContainerNetwork::PortForward = self.config.network {
+            let address = format!("127.0.0.1:{}", self.config.port);
+            let listener = TcpListener::bind(&address).map_err(|e| SandboxError::NetworkSetupFailed {
+                reason: format!("cannot listen on {}: {}", address, e),
+            })?;
+            self.listener = Some(listener);
+        }
+        Ok(())
+    }
+
+    fn cleanup(&mut self) -> Result<()> {
+        // The veth pair goes away with the container's network namespace, unless that is still
+        // around
+        if let Some(veth) = self.veth.take()
+            && let Err(e) = NetDev::new(false).delete_link(&veth)
+        {
+            debug!("Did not delete {}: {}", veth, e);
+        }
+        self.lease = None;
+        self.listener = None;
+        match fs::remove_dir_all(&self.bundle_dir) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                return Err(SandboxError::io(self.bundle_dir.to_string_lossy())(e).into());
+            },
+            _ => {},
+        }
+        if let Some(cgroup) = self.cgroup.take() {
+            cgroup.remove()?;
+        }
+        Ok(())
+    }
+
+    fn start(&mut self) -> Result<()> {
+        let log_file_out = File::create(self.log_file(".out")).map_err(SandboxError::io(self.log_file(".out")))?;
+        let log_file_err = File::create(self.log_file(".err")).map_err(SandboxError::io(self.log_file(".err")))?;
+
+        // SAFETY: getuid(2) and getgid(2) always succeed and touch no memory
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+        let setup = ChildSetup {
+            // Root in the container is us outside of it
+            uid_map: format!("0 {} 1", uid).into_bytes(),
+            gid_map: format!("0 {} 1", gid).into_bytes(),
+            root: self.prepare_root()?,
+            listener: self.listener.as_ref().map(|listener| listener.as_raw_fd()),
+        };
+        let args = self.server_args();
+        debug!("Starting Container sandbox in {} with {} {:?}", self.bundle_dir.display(), SERVER_PATH, args);
+
+        let spawn_start = Instant::now();
+        let mut command = Command::new(SERVER_PATH);
+        command.args(&args).stdout(log_file_out).stderr(log_file_err);
+        // Joined before leaving the host's namespaces, while the cgroup is still writable
+        if let Some(cgroup) = &self.cgroup {
+            cgroup.attach(&mut command)?;
+        }
+        // SAFETY: `enter` runs between fork and exec, where it only makes system calls on buffers the
+        // parent prepared, and neither allocates nor takes locks
+        unsafe {
+            command.pre_exec(move || setup.enter());
+        }
+        // Only returns once the server was executed in the container, or failed to be
+        let process = command.spawn().map_err(SandboxError::spawn(&self.config.binary_path))?;
+        self.timings.record(Phase::ProcessSpawn, spawn_start, Instant::now());
+        debug!("Started Container sandbox with PID: {}", process.id());
+
+        let pid = process.id();
+        self.child_process = Some(process);
+        // The server has its own copy now
+        self.listener = None;
+        self.setup_veth(pid)?;
+        Ok(())
+    }
+
+    fn kill(&mut self) -> Result<()> {
+        let child = self.child_process.as_mut().ok_or(SandboxError::NotStarted)?;
+        // The server is killed along with the process it was forked from, and takes every other
+        // process of its pid namespace with it
+        child.kill()?;
+        child.wait()?;
+        Ok(())
+    }
+
+    fn get_target_ip(&self) -> String {
+        match &self.lease {
+            Some(lease) => lease.subnet.guest_ip.to_string(),
+            None => "127.0.0.1".to_string(),
+        }
+    }
+
+    fn get_target_port(&self) -> u16 {
+        self.config.port
+    }
+
+    fn get_name(&self) -> String {
+        "Container".to_string()
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            // The veth pair can only be created on start, once the container's network namespace
+            // exists, and the port forward needs nothing
+            needs_network_presetup: false,
+            supports_snapshot: false,
+            supports_pause: false,
+            // A handed over socket accepts connections before the server runs, so only an answer counts
+            readiness_kind: ProbeKind::echo_http(),
+            warmup_required: true,
+            multi_instance_safe: true,
+        }
+    }
+
+    fn timings(&self) -> LifecycleTimings {
+        self.timings.clone()
+    }
+
+    fn root_pid(&self) -> Option<u32> {
+        self.child_process.as_ref().map(|child| child.id())
+    }
+
+    fn cgroup(&self) -> Option<&Cgroup> {
+        self.cgroup.as_ref()
+    }
+
+    fn readiness(&self) -> Result<Readiness> {
+        let target = ProbeTarget {
+            ip: self.get_target_ip(),
+            port: self.get_target_port(),
+            log_file: Some(self.log_file(".out")),
+            api_socket: None,
+        };
+        Readiness::from_config(self.config.readiness.as_ref(), self.capabilities().readiness_kind, &target)
+    }
+}
+
+//
+// Between fork and exec
+//
+
+struct BindMount {
+    source: CString,
+    target: CString,
+    // Flags of the source's mount a read-only remount has to keep, as the kernel does not let a
+    // user namespace drop them
+    locked_flags: libc::c_ulong,
+}
+
+impl BindMount {
+    fn new(source: &Path, target: &Path) -> Result<Self, SandboxError> {
+        let source_path = c_path(source)?;
+        // SAFETY: statvfs is plain old data, filled in by the call
+        let mut stat: libc::statvfs = unsafe { mem::zeroed() };
+        // SAFETY: `source_path` is a valid NUL terminated string and `stat` outlives the call
+        if unsafe { libc::statvfs(source_path.as_ptr(), &mut stat) } < 0 {
+            return Err(SandboxError::io(source.to_string_lossy())(io::Error::last_os_error()));
+        }
+        let flag_pairs = [
+            (libc::ST_NOSUID, libc::MS_NOSUID),
+            (libc::ST_NODEV, libc::MS_NODEV),
+            (libc::ST_NOEXEC, libc::MS_NOEXEC),
+            (libc::ST_NOATIME, libc::MS_NOATIME),
+            (libc::ST_NODIRATIME, libc::MS_NODIRATIME),
+            (libc::ST_RELATIME, libc::MS_RELATIME),
+        ];
+        let locked_flags = flag_pairs
+            .iter()
+            .filter(|(st_flag, _)| stat.f_flag & st_flag != 0)
+            .fold(0, |flags, (_, ms_flag)| flags | ms_flag);
+
+        Ok(BindMount {
+            source: source_path,
+            target: c_path(target)?,
+            locked_flags,
+        })
+    }
+}
+
+struct RootSetup {
+    root: CString,
+    proc_dir: CString,
+    bind_mounts: Vec<BindMount>,
+}
+
+// Everything the child does to end up in the container. It runs between fork and exec, where only
+// async-signal-safe calls are allowed, so everything it needs is prepared beforehand and it does
+// not allocate.
+struct ChildSetup {
+    uid_map: Vec<u8>,
+    gid_map: Vec<u8>,
+    root: RootSetup,
+    listener: Option<RawFd>,
+}
+
+impl ChildSetup {
+    fn enter(&self) -> io::Result<()> {
+        let namespaces = libc::CLONE_NEWUSER
+            | libc::CLONE_NEWNS
+            | libc::CLONE_NEWNET
+            | libc::CLONE_NEWUTS
+            | libc::CLONE_NEWIPC
+            | libc::CLONE_NEWPID;
+        // SAFETY: every call below is a plain system call on data prepared by the parent
+        unsafe {
+            check(libc::unshare(namespaces))?;
+            // Writing the gid map without being root outside needs setgroups(2) disabled first
+            write_file(c"/proc/self/setgroups", b"deny")?;
+            write_file(c"/proc/self/uid_map", &self.uid_map)?;
+            write_file(c"/proc/self/gid_map", &self.gid_map)?;
+
+            // Only children join the new pid namespace. This process stays in between until the
+            // server exits, and takes it down if killed.
+            let server = check(libc::fork())?;
+            if server > 0 {
+                wait_and_exit(server);
+            }
+            check(libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL))?;
+
+            check(libc::sethostname(HOSTNAME.as_ptr(), HOSTNAME.count_bytes()))?;
+            self.root.pivot()?;
+            if let Some(listener) = self.listener {
+                match listener {
+                    // Already in place, only has to survive exec
+                    LISTEN_FD => check(libc::fcntl(LISTEN_FD, libc::F_SETFD, 0))?,
+                    _ => check(libc::dup2(listener, LISTEN_FD))?,
+                };
+            }
+        }
+        Ok(())
+    }
+}
+
+impl RootSetup {
+    // Mounts the root file system and makes it the root of the mount namespace
+    unsafe fn pivot(&self) -> io::Result<()> {
+        let none = ptr::null();
+        // SAFETY: every path is a valid NUL terminated string prepared by the parent
+        unsafe {
+            // Nothing mounted from here on shows up on the host
+            check(libc::mount(none, c"/".as_ptr(), none, libc::MS_REC | libc::MS_PRIVATE, ptr::null()))?;
+            // pivot_root(2) wants the new root to be a mount point
+            let root = self.root.as_ptr();
+            check(libc::mount(root, root, none, libc::MS_BIND | libc::MS_REC, ptr::null()))?;
+            for bind_mount in &self.bind_mounts {
+                let target = bind_mount.target.as_ptr();
+                check(libc::mount(bind_mount.source.as_ptr(), target, none, libc::MS_BIND | libc::MS_REC, ptr::null()))?;
+                let read_only = libc::MS_REMOUNT | libc::MS_BIND | libc::MS_RDONLY | bind_mount.locked_flags;
+                check(libc::mount(none, target, none, read_only, ptr::null()))?;
+            }
+            // Not allowed when the host's /proc is partly hidden, e.g. inside another container.
+            // The server does not need it.
+            let proc_flags = libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC;
+            libc::mount(c"proc".as_ptr(), self.proc_dir.as_ptr(), c"proc".as_ptr(), proc_flags, ptr::null());
+
+            check(libc::chdir(root))?;
+            check(libc::syscall(libc::SYS_pivot_root, c".".as_ptr(), c".".as_ptr()) as libc::c_int)?;
+            // The old root is stacked on top of the new one, detaching it uncovers the new one
+            check(libc::umount2(c".".as_ptr(), libc::MNT_DETACH))?;
+            check(libc::chdir(c"/".as_ptr()))?;
+        }
+        Ok(())
+    }
+}
+
+// Waits for `pid` and exits with its status, without ever returning to the caller
+unsafe fn wait_and_exit(pid: libc::pid_t) -> ! {
+    // SAFETY: plain system calls. The descriptors above stdio are closed first: holding on to them
+    // would keep `Command::spawn` waiting for an exec that this process never does.
+    unsafe {
+        libc::syscall(libc::SYS_close_range, 3, libc::c_uint::MAX, 0);
+        let mut status = 0;
+        while libc::waitpid(pid, &mut status, 0) < 0 {
+            if io::Error::last_os_error().kind() != io::ErrorKind::Interrupted {
+                libc::_exit(1);
+            }
+        }
+        match libc::WIFEXITED(status) {
+            true => libc::_exit(libc::WEXITSTATUS(status)),
+            false => libc::_exit(128 + libc::WTERMSIG(status)),
+        }
+    }
+}
+
+unsafe fn write_file(path: &CStr, contents: &[u8]) -> io::Result<()> {
+    // SAFETY: `path` is a valid NUL terminated string and `contents` a valid buffer
+    unsafe {
+        let fd = check(libc::open(path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC))?;
+        let written = match libc::write(fd, contents.as_ptr() as *const libc::c_void, contents.len()) {
+            -1 => Err(io::Error::last_os_error()),
+            _ => Ok(()),
+        };
+        libc::close(fd);
+        written
+    }
+}
+
+fn check(result: libc::c_int) -> io::Result<libc::c_int> {
+    match result {
+        -1 => Err(io::Error::last_os_error()),
+        result => Ok(result),
+    }
+}
+
+fn c_path(path: &Path) -> Result<CString, SandboxError> {
+    CString::new(path.as_os_str().as_encoded_bytes()).map_err(|e| SandboxError::ConfigInvalid {
+        reason: format!("bad path '{}': {}", path.display(), e),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::os::unix::fs::PermissionsExt;
+
+    // Whether this process may create a user namespace, checked in a child so that this one stays
+    // where it is
+    fn user_namespaces_allowed() -> bool {
+        // SAFETY: the child only calls unshare(2) and _exit(2)
+        let pid = unsafe { libc::fork() };
+        assert!(pid >= 0, "fork failed: {}", io::Error::last_os_error());
+        if pid == 0 {
+            // SAFETY: unshare(2) and _exit(2) only affect the child
+            unsafe { libc::_exit(if libc::unshare(libc::CLONE_NEWUSER) < 0 { 1 } else { 0 }) }
+        }
+        let mut status = 0;
+        // SAFETY: `pid` is our child and `status` a valid pointer
+        assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+        libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0
+    }
+
+    fn namespace(pid: &str, kind: &str) -> PathBuf {
+        fs::read_link(format!("/proc/{}/ns/{}", pid, kind)).unwrap()
+    }
+
+    #[test]
+    fn starts_in_new_namespaces_and_cleans_up() {
+        if !user_namespaces_allowed() {
+            eprintln!("skipped, cannot create a user namespace");
+            return;
+        }
+        let dir = std::env::temp_dir().join(format!("container-test-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        // Stays around until killed, whatever arguments it gets
+        let server = dir.join("server");
+        fs::write(&server, "#!/bin/sh\nexec sleep 60\n").unwrap();
+        fs::set_permissions(&server, fs::Permissions::from_mode(0o755)).unwrap();
+        let config = SandboxConfig::Inline(json!({
+            "binary_path": server,
+            "port": 0,
+            "output_dir": dir,
+            "bind_mounts": ["/bin", "/usr/bin", "/lib", "/lib64", "/usr/lib", "/usr/lib64"],
+        }));
+
+        let mut container = Container::from_config(&config, 0).unwrap();
+        container.presetup().unwrap();
+        container.start().unwrap();
+        // The process between us and the server is in every new namespace but the pid one, which
+        // only its children join
+        let pid = container.root_pid().unwrap().to_string();
+        for kind in ["user", "mnt", "net", "uts", "ipc"] {
+            assert_ne!(namespace(&pid, kind), namespace("self", kind), "same {} namespace", kind);
+        }
+        assert_ne!(namespace(&pid, "pid_for_children"), namespace("self", "pid"));
+
+        assert!(container.bundle_dir.exists());
+        container.kill().unwrap();
+        container.cleanup().unwrap();
+        assert!(!container.bundle_dir.exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
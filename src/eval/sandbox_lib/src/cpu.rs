@@ -0,0 +1,37 @@
+use crate::memory::process_tree;
+use anyhow::Result;
+use std::{fs, time::Duration};
+
+// CPU time a process spent in user and kernel mode, summed over its threads, as reported by
+// `/proc/<pid>/stat`
+pub fn read_cpu_time(pid: u32) -> Result<Duration> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid))?;
+    // The command name may contain spaces, so parse after its closing parenthesis. utime and stime
+    // are the 14th and 15th fields.
+    let mut fields = stat
+        .rsplit_once(')')
+        .map(|(_, rest)| rest.split_whitespace().skip(11))
+        .ok_or_else(|| anyhow::anyhow!("Malformed /proc/{}/stat", pid))?;
+    let mut ticks = 0;
+    for _ in 0..2 {
+        ticks += fields
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("Malformed /proc/{}/stat", pid))?
+            .parse::<u64>()?;
+    }
+    // SAFETY: only reads a system setting
+    let ticks_per_second = unsafe { libc::sysconf(libc::_SC_CLK_TCK) }.max(1) as u64;
+    Ok(Duration::from_micros(ticks * 1_000_000 / ticks_per_second))
+}
+
+// CPU time used by the whole process tree of a sandbox.
+pub fn tree_cpu_time(root: u32) -> Result<Duration> {
+    let mut cpu_time = Duration::ZERO;
+    for pid in process_tree(root)? {
+        // Processes may exit while we scan
+        if let Ok(process_cpu_time) = read_cpu_time(pid) {
+            cpu_time += process_cpu_time;
+        }
+    }
+    Ok(cpu_time)
+}
@@ -0,0 +1,79 @@
+use std::{fmt, io, time::Duration};
+
+// Why a sandbox could not be created or driven through its lifecycle. Backends return it wrapped in
+// `anyhow::Error`, so drivers can `downcast_ref` to tell failures apart.
+#[derive(Debug)]
+pub enum SandboxError {
+    // The backend config file does not exist or cannot be read
+    ConfigMissing { path: String, source: io::Error },
+    // The backend config does not describe a valid sandbox
+    ConfigInvalid { reason: String },
+    // A file the sandbox needs (logs, VM config, ...) cannot be created, read or written
+    Io { path: String, source: io::Error },
+    // The process hosting the sandbox could not be spawned
+    SpawnFailed { program: String, source: io::Error },
+    // Setting up the sandbox's network (TAP device, nft rules, ...) failed
+    NetworkSetupFailed { reason: String },
+    // The operation needs a running sandbox, but it was never started
+    NotStarted,
+    // The VMM rejected or did not answer a request on its API socket
+    VmmApi { reason: String },
+    // Something the sandbox was waiting for did not happen in time
+    Timeout { what: String, after: Duration },
+    // The backend, or the way it is configured, cannot do what was asked
+    Unsupported { operation: String, backend: String },
+}
+
+impl SandboxError {
+    // Helpers to use with `map_err`
+    pub fn io(path: impl Into<String>) -> impl FnOnce(io::Error) -> Self {
+        let path = path.into();
+        move |source| SandboxError::Io { path, source }
+    }
+
+    pub fn spawn(program: impl Into<String>) -> impl FnOnce(io::Error) -> Self {
+        let program = program.into();
+        move |source| SandboxError::SpawnFailed { program, source }
+    }
+
+    // Whether `error` only says that there was nothing to act upon
+    pub fn is_not_started(error: &anyhow::Error) -> bool {
+        matches!(error.downcast_ref::<SandboxError>(), Some(SandboxError::NotStarted))
+    }
+}
+
+// Turns a `NotStarted` error into success, e.g. when killing a sandbox that failed before starting
+pub fn ignore_not_started(result: anyhow::Result<()>) -> anyhow::Result<()> {
+    match result {
+        Err(e) if SandboxError::is_not_started(&e) => Ok(()),
+        result => result,
+    }
+}
+
+impl fmt::Display for SandboxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SandboxError::ConfigMissing { path, source } => {
+                write!(f, "cannot read config file '{}': {}", path, source)
+            },
+            SandboxError::ConfigInvalid { reason } => write!(f, "invalid config: {}", reason),
+            SandboxError::Io { path, source } => write!(f, "cannot access '{}': {}", path, source),
+            SandboxError::SpawnFailed { program, source } => {
+                write!(f, "failed to spawn '{}': {}", program, source)
+            },
+            SandboxError::NetworkSetupFailed { reason } => {
+                write!(f, "network setup failed: {}", reason)
+            },
+            SandboxError::NotStarted => write!(f, "sandbox was never started"),
+            SandboxError::VmmApi { reason } => write!(f, "VMM API error: {}", reason),
+            SandboxError::Timeout { what, after } => {
+                write!(f, "{} timed out after {:?}", what, after)
+            },
+            SandboxError::Unsupported { operation, backend } => {
+                write!(f, "{} cannot {}", backend, operation)
+            },
+        }
+    }
+}
+
+impl std::error::Error for SandboxError {}
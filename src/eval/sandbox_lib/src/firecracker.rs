@@ -1,8 +1,22 @@
-use crate::sandbox::Sandbox;
+use crate::{
+    cgroup::{Cgroup, CgroupConfig},
+    error::SandboxError,
+    firecracker_api::{Balloon, BalloonStats, FirecrackerApi},
+    jailer::{Jail, JailerConfig},
+    readiness::{ProbeKind, ProbeTarget, Readiness, ReadinessConfig},
+    registry::{SandboxConfig, SandboxEntry},
+    net_alloc::{AddressPoolConfig, Lease, NetworkAllocator, Subnet},
+    net_lib::{run_network_script, wait_for_unix_socket},
+    netdev::{NetDev, NetDevConfig, TapNetwork},
+    sandbox::{Capabilities, Pausable, Sandbox},
+    timings::{LifecycleTimings, Phase},
+    vm_config::{VmConfigBuilder, VmOverrides},
+    vmm_metrics::{MetricsConfig, MetricsSink, VmmMetrics},
+};
 use anyhow::Result; 
-use log::debug;
+use log::{debug, warn};
 use serde::Deserialize;
-use std::{fs::File, io::{Read, Write}, process::{Child, Command}, str};
+use std::{fs::{File, OpenOptions}, path::Path, process::{Child, Command}, time::{Duration, Instant}};
 use uuid::Uuid;
 
 
@@ -10,9 +24,25 @@ use uuid::Uuid;
 struct FirecrackerConfig {
     firecracker_binary_dir: String,
     firecracker_socket_prefix: String,
+    // Base Firecracker config, see config/firecracker/vm_config_template.json
     config_file_template: String,
-    network_setup_file: String,
-    network_cleanup_file: String,
+    vm: Option<VmOverrides>,
+    // Balloon device attached at boot, e.g.
+    // `"balloon": { "amount_mib": 0, "deflate_on_oom": true, "stats_polling_interval_s": 1 }`
+    balloon: Option<Balloon>,
+    // Where Firecracker writes its own counters, which are otherwise thrown away
+    metrics: Option<MetricsConfig>,
+    // Launch through the jailer instead of running the binary directly
+    jailer: Option<JailerConfig>,
+    // Scripts that set the TAP device up and down, see scripts/firecracker. Without them, this is
+    // done in-process by `netdev`.
+    network_setup_file: Option<String>,
+    network_cleanup_file: Option<String>,
+    netdev: Option<NetDevConfig>,
+    // Defaults to /30 subnets of 172.16.0.0/16
+    address_pool: Option<AddressPoolConfig>,
+    readiness: Option<ReadinessConfig>,
+    cgroup: Option<CgroupConfig>,
 }
 
 pub struct Firecracker {
@@ -22,90 +52,177 @@ pub struct Firecracker {
     iteration: usize,
     log_location: String,
     vm_config_location: String,
+    timings: LifecycleTimings,
+    cgroup: Option<Cgroup>,
+    network: Option<TapNetwork>,
+    metrics: Option<MetricsSink>,
+    jail: Option<Jail>,
+    tap: String,
+    subnet: Subnet,
+    // Held until cleanup, so that no other instance gets the same addresses. `None` for a network
+    // given by the caller.
+    lease: Option<Lease>,
 }
 
+// Firecracker's log in the jail
+const LOG_FILE: &str = "firecracker.log";
+
+pub const ENTRY: SandboxEntry = SandboxEntry {
+    name: "firecracker",
+    build: |config, iteration| Ok(Box::new(Firecracker::from_config(config, iteration)?)),
+};
+
 impl Firecracker {
-    pub fn new(config_path: &str, iteration: usize) -> Self {
-        // Open file
-        let file = std::fs::File::open(config_path).expect("Failed to open config file");
-        let config: FirecrackerConfig = serde_json::from_reader(file)
-            .expect("Failed to load config file");
+    pub fn new(config_path: &str, iteration: usize) -> Result<Self, SandboxError> {
+        Self::from_config(&SandboxConfig::Path(config_path.to_string()), iteration)
+    }
+
+    pub fn from_config(config: &SandboxConfig, iteration: usize) -> Result<Self, SandboxError> {
+        let config: FirecrackerConfig = config.parse()?;
+
+        let address_pool = config.address_pool.clone().unwrap_or_default();
+        let lease = NetworkAllocator::shared(&address_pool)?.allocate()?;
+        let subnet = lease.subnet.clone();
+        Self::build(config, iteration, format!("tap{}", iteration), subnet, Some(lease))
+    }
+
+    // A VM on `tap` with the addresses of `subnet` rather than ones from the address pool, e.g. one
+    // that is snapshotted for clones that expect a given network
+    pub fn with_network(config: &SandboxConfig, tap: &str, subnet: Subnet) -> Result<Self, SandboxError> {
+        Self::build(config.parse()?, 0, tap.to_string(), subnet, None)
+    }
 
+    fn build(
+        config: FirecrackerConfig,
+        iteration: usize,
+        tap: String,
+        subnet: Subnet,
+        lease: Option<Lease>,
+    ) -> Result<Self, SandboxError> {
         let id = Uuid::new_v4().to_string();
-        let log_location = Self::create_log_file(&config.firecracker_binary_dir, &id).unwrap();
+        // The TAP device and its lease are set up in the host namespace, where a VM in another one
+        // would never see them
+        if config.jailer.as_ref().is_some_and(|jailer| jailer.netns.is_some()) {
+            return Err(SandboxError::Unsupported {
+                operation: "join a network namespace, as its TAP devices are created on the host".to_string(),
+                backend: "Firecracker".to_string(),
+            });
+        }
+        // Only laid out here, the chroot and the log file are created by presetup
+        let jail = match &config.jailer {
+            Some(jailer) => Some(Jail::new(jailer, &format!("{}/firecracker", config.firecracker_binary_dir), &id)?),
+            None => None,
+        };
+        let log_location = match &jail {
+            Some(jail) => jail.host_path(LOG_FILE).to_string_lossy().into_owned(),
+            None => format!("{}/firecracker_{}.log", config.firecracker_binary_dir, id),
+        };
 
-        Firecracker {
+        Ok(Firecracker {
             config,
             child_process: None,
             id,
             iteration,
             log_location,
             vm_config_location: "".to_string(),
-        }
+            timings: LifecycleTimings::default(),
+            cgroup: None,
+            network: None,
+            metrics: None,
+            jail,
+            tap,
+            subnet,
+            lease,
+        })
     }
 
+    // The guest routes through the TAP device
     pub fn get_gateway_ip(&self) -> String {
-        "172.16.0.1".to_string()
+        self.get_tap_ip()
     }
 
-    fn get_prefixes(&self, offset: usize) -> (u8, u8) {
-        if offset >= 256 * 256 {
-            panic!("Too many iterations");
+    pub fn get_tap_ip(&self) -> String {
+        self.subnet.host_ip.to_string()
+    }
+
+    pub fn get_mac_address(&self) -> String {
+        self.subnet.mac.clone()
+    }
+
+    pub fn get_socket_addr(&self) -> String {
+        if let Some(jail) = &self.jail {
+            return jail.api_socket();
         }
-        let last_prefix = offset % 256;
-        let second_prefix = last_prefix / 256;
-        (second_prefix as u8, last_prefix as u8)
+        format!("{}{}.socket", self.config.firecracker_socket_prefix, self.id)
     }
 
-    pub fn get_tap_ip(&self) -> String {
-        let ip_offset = 1 + 2*self.iteration;
-        let (second_to_last_prefix, last_prefix) = self.get_prefixes(ip_offset);
-        format!("172.16.{}.{}", second_to_last_prefix, last_prefix)
+    pub fn is_jailed(&self) -> bool {
+        self.jail.is_some()
     }
 
-    pub fn get_mac_address(&self) -> String {
-        let offset= 2 + 2 * self.iteration;
-       let (second_to_last_prefix, last_prefix) = self.get_prefixes(offset);
-        format!("06:00:AC:10:{:02x}:{:02x}",second_to_last_prefix, last_prefix)
+    // Firecracker configuration written by presetup, empty before that
+    pub fn vm_config_location(&self) -> &str {
+        &self.vm_config_location
     }
 
-    fn create_log_file(firecracker_binary_dir: &str, id: &str) -> Result<String> {
-        let log_file = format!("{}/firecracker_{}.log", firecracker_binary_dir, id);
-        let execution_log = Command::new("touch")
-            .arg(&log_file)
-            .output()
-            .expect("Failed to create log file");
-        debug!("Log file created with output: {:?}", execution_log);
-        Ok(log_file)
+    // Creates the jail if any, and the log file, which Firecracker expects to exist
+    fn create_log_file(&self) -> Result<(), SandboxError> {
+        if let Some(jail) = &self.jail {
+            jail.prepare()?;
+            jail.create_file(LOG_FILE)?;
+            return Ok(());
+        }
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.log_location)
+            .map_err(SandboxError::io(&self.log_location))?;
+        debug!("Log file {} created", self.log_location);
+        Ok(())
     }
 
-    fn create_firecracker_process_log(&self, suffix: &str) -> Result<File> {
+    fn create_firecracker_process_log(&self, suffix: &str) -> Result<File, SandboxError> {
         let log_file = format!("/tmp/firecracker_{}_{}.{}", self.id, self.iteration, suffix);
-        let log = File::create(log_file).expect("failed to open log");
-        Ok(log)
-    }
-
-    fn create_vm_config(&mut self) -> Result<()> {
-        let vm_location = format!("{}/vm_config_{}.json", self.config.firecracker_binary_dir, self.id);
-
-        // Open the template file
-        let mut template_file = std::fs::File::open(&self.config.config_file_template).expect("Failed to open template file");
-        // Read file to template
-        let mut template = String::new();
-        template_file.read_to_string(&mut template).expect("Failed to read template file");
-        // Rewrite the template file with the correct values for {{guest_ip}}, {{tap_ip}}, {{tap_id}}, {{mac_address}}, and {{firecracker_log_location}}
-        let result = template
-            .replace("{{guest_ip}}", &self.get_target_ip())
-            .replace("{{tap_ip}}", &self.get_tap_ip())
-            .replace("{{tap_id}}", &format!("tap{}", self.iteration))
-            .replace("{{mac_address}}", &self.get_mac_address())
-            .replace("{{firecracker_log_location}}", &self.log_location);
-
-        // Write the result to the vm_location
-        let mut vm_file = std::fs::File::create(&vm_location).expect("Failed to create VM config file");
-        vm_file.write_all(result.as_bytes()).expect("Failed to write to VM config file");
-        vm_file.flush().expect("Failed to flush VM config file");
-        self.vm_config_location = vm_location.clone(); 
+        File::create(&log_file).map_err(SandboxError::io(log_file))
+    }
+
+    // Path Firecracker opens `host_path` by, which differs inside a jail
+    fn vmm_path(&self, host_path: &str) -> String {
+        self.jail
+            .as_ref()
+            .and_then(|jail| jail.jailed_path(Path::new(host_path)))
+            .unwrap_or_else(|| host_path.to_string())
+    }
+
+    fn create_vm_config(&mut self) -> Result<(), SandboxError> {
+        let vm_location = match &self.jail {
+            Some(jail) => jail.host_path("vm_config.json").to_string_lossy().into_owned(),
+            None => format!("{}/vm_config_{}.json", self.config.firecracker_binary_dir, self.id),
+        };
+
+        let mut builder = VmConfigBuilder::from_template(&self.config.config_file_template)?;
+        if let Some(balloon) = &self.config.balloon {
+            builder = builder.balloon(balloon);
+        }
+        if let Some(metrics) = &self.metrics {
+            builder = builder.metrics_path(&self.vmm_path(metrics.path()));
+        }
+        let mut vm_config = builder
+            .overrides(self.config.vm.as_ref().unwrap_or(&VmOverrides::default()))
+            .network(
+                &self.tap,
+                &self.subnet.mac,
+                self.subnet.guest_ip,
+                self.subnet.prefix_len,
+                self.subnet.host_ip,
+            )
+            .log_path(&self.vmm_path(&self.log_location))
+            .build()?;
+        if let Some(jail) = &self.jail {
+            jail.move_in(&mut vm_config)?;
+        }
+        vm_config.write(&vm_location)?;
+        self.vm_config_location = vm_location;
 
         Ok(())
     }
@@ -113,70 +230,137 @@ impl Firecracker {
 
 impl Sandbox for Firecracker {
     fn presetup(&mut self) -> Result<()> {
+        self.create_log_file()?;
+        self.cgroup = Cgroup::create_optional(self.config.cgroup.as_ref(), &self.id)?;
+        if let Some(metrics) = &self.config.metrics {
+            let sink = match &self.jail {
+                Some(jail) => {
+                    let jailed = MetricsConfig {
+                        output_dir: jail.root().to_string_lossy().into_owned(),
+                        ..metrics.clone()
+                    };
+                    let sink = MetricsSink::create(&jailed, &self.id)?;
+                    jail.give_to_jailed_user(Path::new(sink.path()))?;
+                    sink
+                },
+                None => MetricsSink::create(metrics, &self.id)?,
+            };
+            self.metrics = Some(sink);
+        }
         self.create_vm_config()?;
-        // Run the command in self.config.network_setup_file
-        let tap_device = format!("tap{}", self.iteration);
-        let execution_setup= Command::new("sh")
-            .arg("-c")
-            .arg(format!("{} {} {} {}", &self.config.network_setup_file, &tap_device, &self.get_tap_ip(), &self.get_target_ip()))
-            .output()
-            .expect("Failed to execute network setup script");
-        debug!("Network setup script executed with output: {} and error: {}", str::from_utf8(&execution_setup.stdout)?, str::from_utf8(&execution_setup.stderr)?);
+        let tap_device = self.tap.clone();
+        match &self.config.network_setup_file {
+            Some(network_setup_file) => run_network_script(
+                Command::new("sh")
+                    .arg("-c")
+                    .arg(format!("{} {} {} {}", network_setup_file, tap_device, self.get_tap_ip(), self.get_target_ip())),
+            )?,
+            None => {
+                let network = NetDev::setup_guest_tap(
+                    self.config.netdev.as_ref(),
+                    &tap_device,
+                    &self.get_tap_ip(),
+                    self.subnet.prefix_len,
+                    &self.get_target_ip(),
+                )?;
+                self.network = Some(network);
+            },
+        }
         Ok(())
     }
 
     fn cleanup(&mut self) -> Result<()> {
-        // Run the command in self.config.network_setup_file
-        let execution_cleanup= Command::new(&self.config.network_cleanup_file)
-            .arg(format!("tap{}", self.iteration))
-            .arg(&self.get_target_ip())
-            .output().expect("Failed to execute network cleanup script");
-        debug!("Network cleanup script executed with output: {} and error {}", str::from_utf8(&execution_cleanup.stdout)?, str::from_utf8(&execution_cleanup.stderr)?); 
+        // Keep going on failure, so that the cgroup is still removed
+        let network = match (self.network.take(), &self.config.network_cleanup_file) {
+            (Some(network), _) => NetDev::from_config(self.config.netdev.as_ref()).cleanup_tap(&network),
+            (None, Some(network_cleanup_file)) => run_network_script(
+                Command::new(network_cleanup_file)
+                    .arg(&self.tap)
+                    .arg(self.get_target_ip()),
+            ),
+            (None, None) => Ok(()),
+        };
+        if let Err(e) = network {
+            warn!("Failed to clean up network of {}: {}", self.id, e);
+        }
+        // The TAP device is gone, its addresses can go to another instance
+        self.lease = None;
+        if let Some(metrics) = self.metrics.take()
+            && let Err(e) = metrics.close()
+        {
+            warn!("Failed to remove metrics sink of {}: {}", self.id, e);
+        }
+        if let Some(jail) = &self.jail
+            && let Err(e) = jail.remove()
+        {
+            warn!("Failed to remove jail of {}: {}", self.id, e);
+        }
+        if let Some(cgroup) = self.cgroup.take() {
+            cgroup.remove()?;
+        }
         Ok(())
     }
 
     fn start(&mut self) -> Result<()> {
-        let socket_addr = format!("{}{}.socket",&self.config.firecracker_socket_prefix, self.id);
+        let socket_addr = self.get_socket_addr();
 
         debug!("Using socket address {}", socket_addr);
 
         let output_file = self.create_firecracker_process_log("out")?;
         let error_file = self.create_firecracker_process_log("err")?;
 
-        let firecracker_args: Vec<String> = vec![
-            format!("{}/firecracker", self.config.firecracker_binary_dir.clone()),
-            "--config-file".to_string(),
-            self.vm_config_location.clone(),
-            "--api-sock".to_string(),
-            socket_addr
-        ];
+        let firecracker_args: Vec<String> = match &self.jail {
+            Some(jail) => jail.command_line(&["--config-file".to_string(), self.vmm_path(&self.vm_config_location)]),
+            None => vec![
+                format!("{}/firecracker", self.config.firecracker_binary_dir.clone()),
+                "--config-file".to_string(),
+                self.vm_config_location.clone(),
+                "--api-sock".to_string(),
+                socket_addr
+            ],
+        };
 
         // Print the command we're going to run
         debug!("Starting Firecracker VM with command: {:?}", firecracker_args);
 
         // Execute the program and send the output to /dev/null
-        let firecracker_process = Command::new(&firecracker_args[0])
+        let spawn_start = Instant::now();
+        let mut command = Command::new(&firecracker_args[0]);
+        command
             .args(&firecracker_args[1..])
             .current_dir(&self.config.firecracker_binary_dir)
             .stdout(output_file)
-            .stderr(error_file)
-            .spawn()?;
+            .stderr(error_file);
+        if let Some(cgroup) = &self.cgroup {
+            cgroup.attach(&mut command)?;
+        }
+        let firecracker_process = command.spawn().map_err(SandboxError::spawn(&firecracker_args[0]))?;
+        self.timings.record(Phase::ProcessSpawn, spawn_start, Instant::now());
 
         // Waiting for Firecracker to finish setup (in a real application, you'd likely handle this better)
         debug!("Started Firecracker VM with PID: {}", firecracker_process.id());
         self.child_process = Some(firecracker_process);
+
+        if wait_for_unix_socket(&self.get_socket_addr(), Duration::from_secs(1)) {
+            self.timings.record(Phase::VmmApiReady, spawn_start, Instant::now());
+        }
         Ok(())
     }
 
     fn kill(&mut self) -> Result<()> {
-        self.child_process.as_mut().unwrap().kill().expect("Failed to kill Firecracker VM");
+        // Last chance to get the counters, Firecracker does not write them when killed
+        if self.child_process.is_some()
+            && let Err(e) = self.flush_vmm_metrics()
+        {
+            debug!("Failed to flush VMM metrics of {}: {}", self.id, e);
+        }
+        let child = self.child_process.as_mut().ok_or(SandboxError::NotStarted)?;
+        child.kill()?;
         Ok(())
     }
 
     fn get_target_ip(&self) -> String {
-        let ip_offset= 2 + 2*self.iteration;
-        let (second_to_last_prefix, last_prefix) = self.get_prefixes(ip_offset);
-        format!("172.16.{}.{}", second_to_last_prefix, last_prefix)
+        self.subnet.guest_ip.to_string()
     }
 
     fn get_target_port(&self) -> u16 {
@@ -186,4 +370,101 @@ impl Sandbox for Firecracker {
     fn get_name(&self) -> String {
         "Firecracker".to_string()
     }
-}
\ No newline at end of file
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            needs_network_presetup: true,
+            supports_snapshot: false,
+            supports_pause: true,
+            readiness_kind: ProbeKind::echo_http(),
+            warmup_required: false,
+            multi_instance_safe: true,
+        }
+    }
+
+    fn timings(&self) -> LifecycleTimings {
+        self.timings.clone()
+    }
+
+    fn root_pid(&self) -> Option<u32> {
+        self.child_process.as_ref().map(|child| child.id())
+    }
+
+    fn cgroup(&self) -> Option<&Cgroup> {
+        self.cgroup.as_ref()
+    }
+
+    fn readiness(&self) -> Result<Readiness> {
+        let target = ProbeTarget {
+            ip: self.get_target_ip(),
+            port: self.get_target_port(),
+            log_file: Some(self.log_location.clone()),
+            api_socket: Some(self.get_socket_addr()),
+        };
+        Readiness::from_config(self.config.readiness.as_ref(), self.capabilities().readiness_kind, &target)
+    }
+
+    fn set_balloon(&mut self, amount_mib: u32) -> Result<()> {
+        if self.config.balloon.is_none() {
+            return Err(SandboxError::Unsupported {
+                operation: "resize a memory balloon without a balloon in its config".to_string(),
+                backend: self.get_name(),
+            }
+            .into());
+        }
+        if self.child_process.is_none() {
+            return Err(SandboxError::NotStarted.into());
+        }
+        FirecrackerApi::new(&self.get_socket_addr()).update_balloon(amount_mib)?;
+        Ok(())
+    }
+
+    fn balloon_stats(&self) -> Result<Option<BalloonStats>> {
+        // Firecracker only collects statistics when given a polling interval
+        match &self.config.balloon {
+            Some(balloon) if balloon.stats_polling_interval_s.unwrap_or(0) > 0 => {},
+            _ => return Ok(None),
+        }
+        if self.child_process.is_none() {
+            return Err(SandboxError::NotStarted.into());
+        }
+        Ok(Some(FirecrackerApi::new(&self.get_socket_addr()).balloon_stats()?))
+    }
+
+    fn flush_vmm_metrics(&mut self) -> Result<()> {
+        let api = FirecrackerApi::new(&self.get_socket_addr());
+        let Some(metrics) = &mut self.metrics else {
+            return Ok(());
+        };
+        api.flush_metrics()?;
+        metrics.collect()?;
+        Ok(())
+    }
+
+    fn vmm_metrics(&self) -> Option<VmmMetrics> {
+        self.metrics.as_ref().map(|metrics| metrics.metrics().clone())
+    }
+
+    fn as_pausable(&mut self) -> Option<&mut dyn Pausable> {
+        Some(self)
+    }
+}
+
+// Pauses and resumes the guest's vCPUs through the API, the VMM itself keeps running
+impl Pausable for Firecracker {
+    fn pause(&mut self) -> Result<()> {
+        if self.child_process.is_none() {
+            return Err(SandboxError::NotStarted.into());
+        }
+        FirecrackerApi::new(&self.get_socket_addr()).pause()?;
+        Ok(())
+    }
+
+    fn resume(&mut self) -> Result<()> {
+        if self.child_process.is_none() {
+            return Err(SandboxError::NotStarted.into());
+        }
+        FirecrackerApi::new(&self.get_socket_addr()).resume()?;
+        Ok(())
+    }
+}
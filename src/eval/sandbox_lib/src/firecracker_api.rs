@@ -0,0 +1,494 @@
+use crate::error::SandboxError;
+use log::debug;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    os::unix::net::UnixStream,
+    time::Duration,
+};
+
+// Blocking client for the Firecracker API served on the VMM's Unix socket. Every call opens its own
+// connection, so a client can be shared freely and outlive VMM restarts.
+pub struct FirecrackerApi {
+    socket_path: String,
+    timeout: Duration,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct BootSource {
+    pub kernel_image_path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub boot_args: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub initrd_path: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct MachineConfig {
+    pub vcpu_count: u8,
+    pub mem_size_mib: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub smt: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub track_dirty_pages: Option<bool>,
+    // "None", "2M"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub huge_pages: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Drive {
+    pub drive_id: String,
+    pub path_on_host: String,
+    pub is_root_device: bool,
+    pub is_read_only: bool,
+    // "Unsafe", "Writeback"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cache_type: Option<String>,
+    // "Sync", "Async"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub io_engine: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct NetworkInterface {
+    pub iface_id: String,
+    pub host_dev_name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub guest_mac: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum ActionType {
+    InstanceStart,
+    SendCtrlAltDel,
+    FlushMetrics,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct InstanceAction {
+    pub action_type: ActionType,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum VmState {
+    Paused,
+    Resumed,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct Vm {
+    pub state: VmState,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum SnapshotType {
+    Full,
+    Diff,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SnapshotCreateParams {
+    pub snapshot_path: String,
+    pub mem_file_path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub snapshot_type: Option<SnapshotType>,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum MemoryBackendType {
+    File,
+    Uffd,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MemoryBackend {
+    pub backend_type: MemoryBackendType,
+    pub backend_path: String,
+}
+
+// Exactly one of `mem_file_path` and `mem_backend` must be set
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct SnapshotLoadParams {
+    pub snapshot_path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mem_file_path: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mem_backend: Option<MemoryBackend>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub enable_diff_snapshots: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resume_vm: Option<bool>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Balloon {
+    pub amount_mib: u32,
+    pub deflate_on_oom: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stats_polling_interval_s: Option<u32>,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct BalloonUpdate {
+    pub amount_mib: u32,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct BalloonStatsUpdate {
+    pub stats_polling_interval_s: u32,
+}
+
+// Only the fields the guest driver reported are set
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct BalloonStats {
+    pub target_pages: u64,
+    pub actual_pages: u64,
+    pub target_mib: u64,
+    pub actual_mib: u64,
+    pub swap_in: Option<u64>,
+    pub swap_out: Option<u64>,
+    pub major_faults: Option<u64>,
+    pub minor_faults: Option<u64>,
+    pub free_memory: Option<u64>,
+    pub total_memory: Option<u64>,
+    pub available_memory: Option<u64>,
+    pub disk_caches: Option<u64>,
+    pub hugetlb_allocations: Option<u64>,
+    pub hugetlb_failures: Option<u64>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Metrics {
+    pub metrics_path: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct InstanceInfo {
+    pub id: String,
+    // "Not started", "Running", "Paused"
+    pub state: String,
+    pub vmm_version: String,
+    pub app_name: String,
+}
+
+#[derive(Deserialize)]
+struct Fault {
+    fault_message: String,
+}
+
+impl FirecrackerApi {
+    pub fn new(socket_path: &str) -> Self {
+        FirecrackerApi {
+            socket_path: socket_path.to_string(),
+            timeout: Duration::from_secs(5),
+        }
+    }
+
+    // How long to wait for the VMM to answer a single call
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn socket_path(&self) -> &str {
+        &self.socket_path
+    }
+
+    pub fn instance_info(&self) -> Result<InstanceInfo, SandboxError> {
+        self.get("/")
+    }
+
+    pub fn put_boot_source(&self, boot_source: &BootSource) -> Result<(), SandboxError> {
+        self.send("PUT", "/boot-source", boot_source)
+    }
+
+    pub fn machine_config(&self) -> Result<MachineConfig, SandboxError> {
+        self.get("/machine-config")
+    }
+
+    pub fn put_machine_config(&self, machine_config: &MachineConfig) -> Result<(), SandboxError> {
+        self.send("PUT", "/machine-config", machine_config)
+    }
+
+    pub fn put_drive(&self, drive: &Drive) -> Result<(), SandboxError> {
+        self.send("PUT", &format!("/drives/{}", drive.drive_id), drive)
+    }
+
+    pub fn put_network_interface(&self, interface: &NetworkInterface) -> Result<(), SandboxError> {
+        self.send("PUT", &format!("/network-interfaces/{}", interface.iface_id), interface)
+    }
+
+    pub fn action(&self, action_type: ActionType) -> Result<(), SandboxError> {
+        self.send("PUT", "/actions", &InstanceAction { action_type })
+    }
+
+    pub fn start_instance(&self) -> Result<(), SandboxError> {
+        self.action(ActionType::InstanceStart)
+    }
+
+    pub fn send_ctrl_alt_del(&self) -> Result<(), SandboxError> {
+        self.action(ActionType::SendCtrlAltDel)
+    }
+
+    pub fn flush_metrics(&self) -> Result<(), SandboxError> {
+        self.action(ActionType::FlushMetrics)
+    }
+
+    pub fn set_vm_state(&self, state: VmState) -> Result<(), SandboxError> {
+        self.send("PATCH", "/vm", &Vm { state })
+    }
+
+    pub fn pause(&self) -> Result<(), SandboxError> {
+        self.set_vm_state(VmState::Paused)
+    }
+
+    pub fn resume(&self) -> Result<(), SandboxError> {
+        self.set_vm_state(VmState::Resumed)
+    }
+
+    // The VM must be paused
+    pub fn create_snapshot(&self, params: &SnapshotCreateParams) -> Result<(), SandboxError> {
+        self.send("PUT", "/snapshot/create", params)
+    }
+
+    // Only valid before the VM is configured in any other way
+    pub fn load_snapshot(&self, params: &SnapshotLoadParams) -> Result<(), SandboxError> {
+        self.send("PUT", "/snapshot/load", params)
+    }
+
+    // Must be called before the VM starts
+    pub fn put_balloon(&self, balloon: &Balloon) -> Result<(), SandboxError> {
+        self.send("PUT", "/balloon", balloon)
+    }
+
+    pub fn balloon(&self) -> Result<Balloon, SandboxError> {
+        self.get("/balloon")
+    }
+
+    pub fn update_balloon(&self, amount_mib: u32) -> Result<(), SandboxError> {
+        self.send("PATCH", "/balloon", &BalloonUpdate { amount_mib })
+    }
+
+    // Needs a non zero `stats_polling_interval_s` on the balloon
+    pub fn balloon_stats(&self) -> Result<BalloonStats, SandboxError> {
+        self.get("/balloon/statistics")
+    }
+
+    pub fn update_balloon_stats(&self, stats_polling_interval_s: u32) -> Result<(), SandboxError> {
+        self.send("PATCH", "/balloon/statistics", &BalloonStatsUpdate { stats_polling_interval_s })
+    }
+
+    // Must be called before the VM starts
+    pub fn put_metrics(&self, metrics: &Metrics) -> Result<(), SandboxError> {
+        self.send("PUT", "/metrics", metrics)
+    }
+
+    fn get<T: DeserializeOwned>(&self, path: &str) -> Result<T, SandboxError> {
+        let body = self.request("GET", path, None)?;
+        serde_json::from_slice(&body).map_err(|e| self.error("GET", path, format!("invalid response: {}", e)))
+    }
+
+    fn send<T: Serialize>(&self, method: &str, path: &str, body: &T) -> Result<(), SandboxError> {
+        let body = serde_json::to_vec(body).map_err(|e| self.error(method, path, e.to_string()))?;
+        self.request(method, path, Some(&body))?;
+        Ok(())
+    }
+
+    // Sends one request and returns the body of a successful response
+    fn request(&self, method: &str, path: &str, body: Option<&[u8]>) -> Result<Vec<u8>, SandboxError> {
+        let io_error = |e: std::io::Error| self.error(method, path, e.to_string());
+        let mut stream = UnixStream::connect(&self.socket_path).map_err(io_error)?;
+        stream.set_read_timeout(Some(self.timeout)).map_err(io_error)?;
+        stream.set_write_timeout(Some(self.timeout)).map_err(io_error)?;
+
+        let mut request = format!("{} {} HTTP/1.1\r\nHost: localhost\r\nAccept: application/json\r\n", method, path);
+        if let Some(body) = body {
+            request.push_str(&format!("Content-Type: application/json\r\nContent-Length: {}\r\n", body.len()));
+        }
+        request.push_str("\r\n");
+        let mut bytes = request.into_bytes();
+        bytes.extend_from_slice(body.unwrap_or_default());
+        stream.write_all(&bytes).map_err(io_error)?;
+
+        let (status, body) = Self::read_response(&mut stream).map_err(io_error)?;
+        debug!("{} {} on {}: {}", method, path, self.socket_path, status);
+        if (200..300).contains(&status) {
+            return Ok(body);
+        }
+
+        // Firecracker explains every error with a fault message
+        let reason = match serde_json::from_slice::<Fault>(&body) {
+            Ok(fault) => format!("{}: {}", status, fault.fault_message),
+            Err(_) => format!("{}: {}", status, String::from_utf8_lossy(&body).trim()),
+        };
+        Err(self.error(method, path, reason))
+    }
+
+    // Reads the status code and the body of an HTTP/1.1 response. Firecracker always sends a
+    // Content-Length, and omits the body of 204 responses.
+    fn read_response(stream: &mut UnixStream) -> std::io::Result<(u16, Vec<u8>)> {
+        let invalid = |what: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, what.to_string());
+        let mut reader = BufReader::new(stream);
+
+        let mut status_line = String::new();
+        reader.read_line(&mut status_line)?;
+        let status = status_line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|status| status.parse::<u16>().ok())
+            .ok_or_else(|| invalid(&format!("malformed status line '{}'", status_line.trim())))?;
+
+        let mut content_length = 0;
+        loop {
+            let mut header = String::new();
+            if reader.read_line(&mut header)? == 0 {
+                return Err(invalid("connection closed before the end of the headers"));
+            }
+            let header = header.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':')
+                && name.trim().eq_ignore_ascii_case("content-length")
+            {
+                content_length = value
+                    .trim()
+                    .parse()
+                    .map_err(|_| invalid(&format!("malformed header '{}'", header)))?;
+            }
+        }
+
+        let mut body = vec![0u8; content_length];
+        reader.read_exact(&mut body)?;
+        Ok((status, body))
+    }
+
+    fn error(&self, method: &str, path: &str, reason: String) -> SandboxError {
+        SandboxError::VmmApi {
+            reason: format!("{} {} on {}: {}", method, path, self.socket_path, reason),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, os::unix::net::UnixListener, path::PathBuf, thread::JoinHandle};
+    use uuid::Uuid;
+
+    // A request as the mock VMM received it
+    #[derive(Debug)]
+    struct Received {
+        request_line: String,
+        body: String,
+    }
+
+    // Serves one canned response per connection on a socket in a fresh temporary directory, and
+    // returns the requests it got once all responses are sent
+    fn mock_vmm(responses: Vec<String>) -> (PathBuf, String, JoinHandle<Vec<Received>>) {
+        let dir = std::env::temp_dir().join(format!("firecracker-api-test-{}", Uuid::new_v4()));
+        fs::create_dir(&dir).unwrap();
+        let socket_path = dir.join("firecracker.socket").to_string_lossy().into_owned();
+        let listener = UnixListener::bind(&socket_path).unwrap();
+        let server = std::thread::spawn(move || {
+            let mut received = Vec::new();
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(&mut stream);
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let mut content_length = 0;
+                loop {
+                    let mut header = String::new();
+                    reader.read_line(&mut header).unwrap();
+                    let header = header.trim_end();
+                    if header.is_empty() {
+                        break;
+                    }
+                    if let Some(length) = header.strip_prefix("Content-Length: ") {
+                        content_length = length.parse().unwrap();
+                    }
+                }
+                let mut body = vec![0u8; content_length];
+                reader.read_exact(&mut body).unwrap();
+                stream.write_all(response.as_bytes()).unwrap();
+                received.push(Received {
+                    request_line: request_line.trim_end().to_string(),
+                    body: String::from_utf8(body).unwrap(),
+                });
+            }
+            received
+        });
+        (dir, socket_path, server)
+    }
+
+    fn response(status: &str, body: &str) -> String {
+        format!("HTTP/1.1 {}\r\nServer: Firecracker API\r\nContent-Length: {}\r\n\r\n{}", status, body.len(), body)
+    }
+
+    fn no_content() -> String {
+        "HTTP/1.1 204 \r\nServer: Firecracker API\r\n\r\n".to_string()
+    }
+
+    #[test]
+    fn sends_requests_and_accepts_no_content() {
+        let (dir, socket_path, server) = mock_vmm(vec![no_content(), no_content(), no_content()]);
+        let api = FirecrackerApi::new(&socket_path);
+        api.pause().unwrap();
+        api.update_balloon(64).unwrap();
+        api.put_metrics(&Metrics { metrics_path: "/metrics.fifo".to_string() }).unwrap();
+
+        let received = server.join().unwrap();
+        let requests: Vec<_> = received.iter().map(|r| (r.request_line.as_str(), r.body.as_str())).collect();
+        assert_eq!(
+            requests,
+            [
+                ("PATCH /vm HTTP/1.1", r#"{"state":"Paused"}"#),
+                ("PATCH /balloon HTTP/1.1", r#"{"amount_mib":64}"#),
+                ("PUT /metrics HTTP/1.1", r#"{"metrics_path":"/metrics.fifo"}"#),
+            ]
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn parses_balloon_stats() {
+        let stats = r#"{"target_pages":16384,"actual_pages":8192,"target_mib":64,"actual_mib":32,"free_memory":1048576}"#;
+        let (dir, socket_path, server) = mock_vmm(vec![response("200 OK", stats)]);
+        let stats = FirecrackerApi::new(&socket_path).balloon_stats().unwrap();
+        assert_eq!((stats.target_pages, stats.actual_pages), (16384, 8192));
+        assert_eq!((stats.target_mib, stats.actual_mib), (64, 32));
+        assert_eq!(stats.free_memory, Some(1048576));
+        assert_eq!(stats.swap_in, None);
+
+        let received = server.join().unwrap();
+        assert_eq!(received[0].request_line, "GET /balloon/statistics HTTP/1.1");
+        assert!(received[0].body.is_empty());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn faults_become_vmm_api_errors() {
+        let fault = r#"{"fault_message":"Balloon device not active"}"#;
+        let (dir, socket_path, server) = mock_vmm(vec![response("400 Bad Request", fault), response("200 OK", "{")]);
+        let api = FirecrackerApi::new(&socket_path);
+
+        match api.update_balloon_stats(1) {
+            Err(SandboxError::VmmApi { reason }) => {
+                assert!(reason.starts_with("PATCH /balloon/statistics on "), "{}", reason);
+                assert!(reason.ends_with(": 400: Balloon device not active"), "{}", reason);
+            },
+            other => panic!("expected a VMM API error, got {:?}", other.err()),
+        }
+        match api.balloon_stats() {
+            Err(SandboxError::VmmApi { reason }) => assert!(reason.contains("invalid response"), "{}", reason),
+            other => panic!("expected a VMM API error, got {:?}", other.map(|_| ())),
+        }
+
+        server.join().unwrap();
+        // Nothing listens any more
+        assert!(matches!(api.resume(), Err(SandboxError::VmmApi { .. })));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,8 +1,30 @@
-use crate::sandbox::Sandbox;
+use crate::{
+    cgroup::{Cgroup, CgroupConfig},
+    error::SandboxError,
+    firecracker_api::{FirecrackerApi, Metrics, SnapshotLoadParams},
+    readiness::{ProbeKind, ProbeTarget, Readiness, ReadinessConfig},
+    registry::{SandboxConfig, SandboxEntry},
+    net_alloc::{AddressPoolConfig, Lease, NetworkAllocator},
+    net_lib::{run_network_script, wait_for_unix_socket},
+    netdev::{self, GuestTap, NetDev, NetDevConfig, TapNetwork},
+    sandbox::{Capabilities, Pausable, Sandbox},
+    snapshot::{
+        create_snapshot, SnapshotManifest, SnapshotSource, SNAPSHOT_GUEST_IP, SNAPSHOT_PREFIX_LEN, SNAPSHOT_TAP,
+        SNAPSHOT_TAP_IP,
+    },
+    timings::{LifecycleTimings, Phase},
+    vmm_metrics::{MetricsConfig, MetricsSink, VmmMetrics},
+};
 use anyhow::Result; 
-use log::debug;
+use log::{debug, info, warn};
 use serde::Deserialize;
-use std::{io::{Read, Write}, process::{Child, Command}, str};
+use std::{
+    io,
+    os::{fd::AsRawFd, unix::process::CommandExt},
+    path::Path,
+    process::{Child, Command},
+    time::{Duration, Instant},
+};
 use uuid::Uuid;
 use std::fs::File;
 
@@ -12,115 +34,215 @@ struct FirecrackerSnapshotConfig {
     firecracker_socket_prefix: String,
     snapshot_file: String,
     mem_file: String,
-    network_setup_file: String,
-    network_cleanup_file: String,
+    // Scripts that set `tap0` up and down, done in-process by `netdev` without them
+    network_setup_file: Option<String>,
+    network_cleanup_file: Option<String>,
+    netdev: Option<NetDevConfig>,
     output_dir: String,
+    // Where Firecracker writes its own counters, which are otherwise thrown away
+    metrics: Option<MetricsConfig>,
+    readiness: Option<ReadinessConfig>,
+    cgroup: Option<CgroupConfig>,
+    // How to recreate `snapshot_file` and `mem_file`, see `prepare_snapshot`
+    source: Option<SnapshotSource>,
+    // Defaults to `<snapshot_file>.manifest.json`
+    manifest_file: Option<String>,
+    // Without it, only one clone of the snapshot can run at a time
+    clone_network: Option<CloneNetworkConfig>,
+}
+
+// Runs each clone in its own network namespace, where it finds the TAP device and addresses it was
+// snapshotted with. NAT maps the guest address to an address that is unique to the clone and
+// reachable from the host through a veth pair, e.g. `"clone_network": {}`.
+#[derive(Deserialize)]
+struct CloneNetworkConfig {
+    // Where the veth pairs take their addresses from, defaults to /30 subnets of 10.200.0.0/16
+    address_pool: Option<AddressPoolConfig>,
+    netdev: Option<NetDevConfig>,
+}
+
+impl CloneNetworkConfig {
+    fn address_pool(&self) -> AddressPoolConfig {
+        self.address_pool.clone().unwrap_or_else(|| AddressPoolConfig {
+            cidr: "10.200.0.0/16".to_string(),
+            ..AddressPoolConfig::default()
+        })
+    }
+}
+
+impl FirecrackerSnapshotConfig {
+    fn manifest_file(&self) -> String {
+        self.manifest_file.clone().unwrap_or_else(|| format!("{}.manifest.json", self.snapshot_file))
+    }
 }
 
 pub struct FirecrackerSnapshot {
     id: String,
+    iteration: usize,
     config: FirecrackerSnapshotConfig,
     child_process: Option<Child>,
+    timings: LifecycleTimings,
+    cgroup: Option<Cgroup>,
+    network: Option<TapNetwork>,
+    metrics: Option<MetricsSink>,
+    // Clone network only: the veth pair's addresses, held until cleanup so that no other clone gets
+    // them, and whether the namespace was set up
+    lease: Option<Lease>,
+    clone_namespace: bool,
 }
 
+pub const ENTRY: SandboxEntry = SandboxEntry {
+    name: "firecracker-snapshot",
+    build: |config, iteration| Ok(Box::new(FirecrackerSnapshot::from_config(config, iteration)?)),
+};
+
 impl FirecrackerSnapshot {
-    pub fn new(config_path: &str) -> Self {
-        // Open file
-        let file = std::fs::File::open(config_path).expect("Failed to open config file");
-        let config: FirecrackerSnapshotConfig = serde_json::from_reader(file)
-            .expect("Failed to load config file");
+    pub fn new(config_path: &str, iteration: usize) -> Result<Self, SandboxError> {
+        Self::from_config(&SandboxConfig::Path(config_path.to_string()), iteration)
+    }
+
+    pub fn from_config(config: &SandboxConfig, iteration: usize) -> Result<Self, SandboxError> {
+        let config: FirecrackerSnapshotConfig = config.parse()?;
+
+        let lease = match &config.clone_network {
+            Some(clone_network) => Some(NetworkAllocator::shared(&clone_network.address_pool())?.allocate()?),
+            None => None,
+        };
 
         let id = Uuid::new_v4().to_string();
 
-        FirecrackerSnapshot {
+        Ok(FirecrackerSnapshot {
             id,
+            iteration,
             config,
             child_process: None,
-        }
+            timings: LifecycleTimings::default(),
+            cgroup: None,
+            network: None,
+            metrics: None,
+            lease,
+            clone_namespace: false,
+        })
     }
 
+    // Addresses the snapshotted guest was configured with
     fn get_tap_ip(&self) -> String {
-        "172.16.0.1".to_string()
+        SNAPSHOT_TAP_IP.to_string()
     }
 
-    fn create_log_file(output_dir: &str, id: &str, iteration: u16, suffix: &str) -> Result<File> {
-        let log_file = format!("{}/firecracker_snapshot{}-{}{}", output_dir, id, iteration, suffix);
-        let log = File::create(log_file).expect("failed to open log");
-        Ok(log)
+    fn get_guest_ip(&self) -> String {
+        SNAPSHOT_GUEST_IP.to_string()
     }
 
-    fn create_snapshot_load_http_string(&self) -> Result<String> {
-        let snapshot_file = &self.config.snapshot_file;
-        let mem_file = &self.config.mem_file;
-        let content = format!(
-r#"{{
-    "snapshot_path": "{}",
-    "mem_file_path": "{}"
-}}"#,
-            snapshot_file,
-            mem_file);
-
-        let http_request_str = format!("PUT /snapshot/load HTTP/1.1\r\nHost: localhost\r\nAccept: application/json\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
-            content.len(),
-            content
-        );
-
-        Ok(http_request_str)
+    fn get_netns(&self) -> String {
+        format!("fc-clone-{}", self.iteration)
     }
 
-    fn create_resume_http_string(&self) -> Result<String> {
-        let content_str = 
-r#"{
-    "state": "Resumed"
-}"#;
-        
-        let http_request_str = format!("PATCH /vm HTTP/1.1\r\nAccept: application/json\r\nContent-Type: application/json\r\nContent-Length:{}\r\n\r\n{}",
-            content_str.len(),
-            content_str
-        )
-    ;
-
-        Ok(http_request_str)
+    fn get_host_veth(&self) -> String {
+        format!("fcveth{}", self.iteration)
     }
 
-    fn send_string_to_socket(&self, stream: &mut std::os::unix::net::UnixStream, string: &str) -> Result<()> {
-        stream.write_all(string.as_bytes()).expect("Failed to write to unix socket");
-
-        // Read the response from the server
-        let mut response = Vec::new();
-        let mut buffer = [0; 1024]; // 1 KB buffer size for reading chunks
-
-        // Read the response in chunks
-        let bytes_read = stream.read(&mut buffer)?;
-
-        response.extend_from_slice(&buffer[..bytes_read]);
-
-        // Check if bytes contain 204
-        let response_str= String::from_utf8_lossy(&response);
+    fn setup_network(&mut self) -> Result<(), SandboxError> {
+        let tap_device = SNAPSHOT_TAP;
+        match (&self.config.clone_network, &self.lease) {
+            (Some(clone_network), Some(lease)) => {
+                let tap = GuestTap {
+                    name: tap_device,
+                    tap_ip: SNAPSHOT_TAP_IP,
+                    prefix_len: SNAPSHOT_PREFIX_LEN,
+                    guest_ip: SNAPSHOT_GUEST_IP,
+                };
+                NetDev::from_config(clone_network.netdev.as_ref()).setup_clone_namespace(
+                    &self.get_netns(),
+                    &self.get_host_veth(),
+                    &lease.subnet,
+                    &tap,
+                )?;
+                self.clone_namespace = true;
+                Ok(())
+            },
+            _ => match &self.config.network_setup_file {
+                Some(network_setup_file) => run_network_script(
+                    Command::new("sh")
+                        .arg("-c")
+                        .arg(format!("{} {} {} {}", network_setup_file, tap_device, self.get_tap_ip(), self.get_guest_ip())),
+                ),
+                None => {
+                    let network = NetDev::setup_guest_tap(
+                        self.config.netdev.as_ref(),
+                        tap_device,
+                        &self.get_tap_ip(),
+                        SNAPSHOT_PREFIX_LEN,
+                        &self.get_guest_ip(),
+                    )?;
+                    self.network = Some(network);
+                    Ok(())
+                },
+            },
+        }
+    }
 
-        if  response_str.contains("204") {
-            return Ok(()); 
+    fn cleanup_network(&mut self) -> Result<(), SandboxError> {
+        if let Some(network) = self.network.take() {
+            return NetDev::from_config(self.config.netdev.as_ref()).cleanup_tap(&network);
         }
+        match (&self.config.clone_network, &self.config.network_cleanup_file) {
+            (Some(clone_network), _) => {
+                if !std::mem::take(&mut self.clone_namespace) {
+                    return Ok(());
+                }
+                NetDev::from_config(clone_network.netdev.as_ref()).cleanup_clone_namespace(&self.get_netns(), &self.get_host_veth())
+            },
+            (None, Some(network_cleanup_file)) => run_network_script(
+                Command::new(network_cleanup_file)
+                    .arg(SNAPSHOT_TAP)
+                    .arg(self.get_guest_ip()),
+            ),
+            (None, None) => Ok(()),
+        }
+    }
 
-        Err(anyhow::anyhow!("Failed to send string to socket"))
-   }
+    // Has `command` join the clone's network namespace before it execs, so that Firecracker finds
+    // the TAP device the snapshot expects
+    fn join_clone_namespace(&self, command: &mut Command) -> Result<(), SandboxError> {
+        let path = netdev::netns_path(&self.get_netns());
+        let namespace = File::open(&path).map_err(SandboxError::io(&path))?;
+        // SAFETY: setns(2) is async-signal-safe, and `namespace` stays open until the closure is
+        // dropped with `command`, after the spawn
+        unsafe {
+            command.pre_exec(move || {
+                if libc::setns(namespace.as_raw_fd(), libc::CLONE_NEWNET) < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        Ok(())
+    }
 
-    fn configure_using_unix_sockets(&self, socket_addr: &str) -> Result<()> {
-        let mut stream = std::os::unix::net::UnixStream::connect(socket_addr).expect("Failed to connect to unix socket");
+    fn create_log_file(output_dir: &str, id: &str, iteration: usize, suffix: &str) -> Result<File, SandboxError> {
+        let log_file = format!("{}/firecracker_snapshot{}-{}{}", output_dir, id, iteration, suffix);
+        File::create(&log_file).map_err(SandboxError::io(log_file))
+    }
 
-        // Send the load request
-        let load_snapshot = self.create_snapshot_load_http_string()?;
-        self.send_string_to_socket(&mut stream, &load_snapshot)?;
+    fn load_and_resume(&self, socket_addr: &str) -> Result<(), SandboxError> {
+        let api = FirecrackerApi::new(socket_addr);
 
+        // Has to come before the snapshot is loaded
+        if let Some(metrics) = &self.metrics {
+            api.put_metrics(&Metrics {
+                metrics_path: metrics.path().to_string(),
+            })?;
+        }
+        api.load_snapshot(&SnapshotLoadParams {
+            snapshot_path: self.config.snapshot_file.clone(),
+            mem_file_path: Some(self.config.mem_file.clone()),
+            ..SnapshotLoadParams::default()
+        })?;
         debug!("Snapshot loaded");
 
-        // Send the resume request
-        let resume = self.create_resume_http_string()?;
-        self.send_string_to_socket(&mut stream, &resume)?;
-
+        api.resume()?;
         debug!("VM resumed");
-
-        // Send the resume request
         Ok(())
     }
 
@@ -131,15 +253,11 @@ r#"{
 
 impl Sandbox for FirecrackerSnapshot {
     fn presetup(&mut self) -> Result<()> {
-        // Run the command in self.config.network_setup_file
-        let tap_device = "tap0";
-        let execution_setup= Command::new("sh")
-            .arg("-c")
-            .arg(format!("{} {} {} {}", &self.config.network_setup_file, tap_device, &self.get_tap_ip(), &self.get_target_ip()))
-            .output()
-            .expect("Failed to execute network setup script");
-        debug!("Network setup script executed with output: {} and error: {}", str::from_utf8(&execution_setup.stdout)?, str::from_utf8(&execution_setup.stderr)?);
-
+        self.cgroup = Cgroup::create_optional(self.config.cgroup.as_ref(), &self.id)?;
+        if let Some(metrics) = &self.config.metrics {
+            self.metrics = Some(MetricsSink::create(metrics, &self.id)?);
+        }
+        self.setup_network()?;
 
         let socket_addr = self.get_socket_addr(); 
 
@@ -153,31 +271,51 @@ impl Sandbox for FirecrackerSnapshot {
         debug!("Starting Firecracker VM with command: {:?}", firecracker_args);
 
         // Execute the program and send the output to /dev/null
-        let stdout_file = Self::create_log_file(&self.config.output_dir, &self.id, 0, ".out")?;
-        let stderr_file = Self::create_log_file(&self.config.output_dir, &self.id, 0, ".err")?;
+        let stdout_file = Self::create_log_file(&self.config.output_dir, &self.id, self.iteration, ".out")?;
+        let stderr_file = Self::create_log_file(&self.config.output_dir, &self.id, self.iteration, ".err")?;
 
-        let firecracker_process = Command::new(&firecracker_args[0])
+        let spawn_start = Instant::now();
+        let mut command = Command::new(&firecracker_args[0]);
+        command
             .args(&firecracker_args[1..])
             .current_dir(&self.config.firecracker_binary_dir)
             .stdout(stdout_file)
-            .stderr(stderr_file)
-            .spawn()?;
+            .stderr(stderr_file);
+        if let Some(cgroup) = &self.cgroup {
+            cgroup.attach(&mut command)?;
+        }
+        if self.clone_namespace {
+            self.join_clone_namespace(&mut command)?;
+        }
+        let firecracker_process = command.spawn().map_err(SandboxError::spawn(&firecracker_args[0]))?;
+        self.timings.record(Phase::ProcessSpawn, spawn_start, Instant::now());
 
         // Waiting for Firecracker to finish setup (in a real application, you'd likely handle this better)
         debug!("Started Firecracker VM with PID: {}", firecracker_process.id());
 
         self.child_process = Some(firecracker_process);
 
+        if wait_for_unix_socket(&socket_addr, Duration::from_secs(1)) {
+            self.timings.record(Phase::VmmApiReady, spawn_start, Instant::now());
+        }
+
         Ok(())
     }
 
     fn cleanup(&mut self) -> Result<()> {
-        // Run the command in self.config.network_setup_file
-        let execution_cleanup= Command::new(&self.config.network_cleanup_file)
-            .arg("tap0")
-            .arg(&self.get_target_ip())
-            .output().expect("Failed to execute network cleanup script");
-        debug!("Network cleanup script executed with output: {} and error {}", str::from_utf8(&execution_cleanup.stdout)?, str::from_utf8(&execution_cleanup.stderr)?); 
+        // Keep going on failure, so that the cgroup is still removed
+        if let Err(e) = self.cleanup_network() {
+            warn!("Failed to clean up network of {}: {}", self.id, e);
+        }
+        self.lease = None;
+        if let Some(metrics) = self.metrics.take()
+            && let Err(e) = metrics.close()
+        {
+            warn!("Failed to remove metrics sink of {}: {}", self.id, e);
+        }
+        if let Some(cgroup) = self.cgroup.take() {
+            cgroup.remove()?;
+        }
         Ok(())
     }
 
@@ -186,19 +324,36 @@ impl Sandbox for FirecrackerSnapshot {
 
         debug!("Using socket address {}", socket_addr);
 
-
-        self.configure_using_unix_sockets(&socket_addr)?;
+        // The VMM is spawned by presetup
+        if self.child_process.is_none() {
+            return Err(SandboxError::NotStarted.into());
+        }
+        self.load_and_resume(&socket_addr)?;
+        // Snapshot load and resume latencies
+        if let Err(e) = self.flush_vmm_metrics() {
+            debug!("Failed to flush VMM metrics of {}: {}", self.id, e);
+        }
 
         Ok(())
     }
 
     fn kill(&mut self) -> Result<()> {
-        self.child_process.as_mut().unwrap().kill().expect("Failed to kill Firecracker VM");
+        // Last chance to get the counters, Firecracker does not write them when killed
+        if self.child_process.is_some()
+            && let Err(e) = self.flush_vmm_metrics()
+        {
+            debug!("Failed to flush VMM metrics of {}: {}", self.id, e);
+        }
+        let child = self.child_process.as_mut().ok_or(SandboxError::NotStarted)?;
+        child.kill()?;
         Ok(())
     }
 
     fn get_target_ip(&self) -> String {
-        "172.16.0.2".to_string()
+        match &self.lease {
+            Some(lease) => lease.subnet.guest_ip.to_string(),
+            None => self.get_guest_ip(),
+        }
     }
 
     fn get_target_port(&self) -> u16 {
@@ -209,5 +364,106 @@ impl Sandbox for FirecrackerSnapshot {
         "Firecracker-Snapshot".to_string()
     }
 
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            needs_network_presetup: true,
+            supports_snapshot: true,
+            supports_pause: true,
+            readiness_kind: ProbeKind::echo_http(),
+            warmup_required: false,
+            // Without their own network, clones all claim the snapshotted guest's address
+            multi_instance_safe: self.config.clone_network.is_some(),
+        }
+    }
+
+    fn timings(&self) -> LifecycleTimings {
+        self.timings.clone()
+    }
+
+    fn root_pid(&self) -> Option<u32> {
+        self.child_process.as_ref().map(|child| child.id())
+    }
+
+    fn cgroup(&self) -> Option<&Cgroup> {
+        self.cgroup.as_ref()
+    }
+
+    fn readiness(&self) -> Result<Readiness> {
+        let target = ProbeTarget {
+            ip: self.get_target_ip(),
+            port: self.get_target_port(),
+            log_file: None,
+            api_socket: Some(self.get_socket_addr()),
+        };
+        Readiness::from_config(self.config.readiness.as_ref(), self.capabilities().readiness_kind, &target)
+    }
+
+    fn flush_vmm_metrics(&mut self) -> Result<()> {
+        let api = FirecrackerApi::new(&self.get_socket_addr());
+        let Some(metrics) = &mut self.metrics else {
+            return Ok(());
+        };
+        api.flush_metrics()?;
+        metrics.collect()?;
+        Ok(())
+    }
+
+    fn vmm_metrics(&self) -> Option<VmmMetrics> {
+        self.metrics.as_ref().map(|metrics| metrics.metrics().clone())
+    }
+
+    // With `regenerate`, creates the snapshot the sandboxes restore again from `source` and writes
+    // its manifest. Otherwise only reads the manifest, if there is one. Returns the manifest path and
+    // contents, so that results can be traced back to the snapshot they were measured on.
+    fn prepare_snapshot(&self, regenerate: bool) -> Result<Option<(String, SnapshotManifest)>> {
+        let manifest_file = self.config.manifest_file();
+
+        let source = match (&self.config.source, regenerate) {
+            (_, false) => {
+                if !Path::new(&manifest_file).exists() {
+                    return Ok(None);
+                }
+                let manifest = SnapshotManifest::load(&manifest_file)?;
+                if !manifest.matches_files() {
+                    warn!("Snapshot files do not match manifest {}, they may have been replaced", manifest_file);
+                }
+                return Ok(Some((manifest_file, manifest)));
+            },
+            (Some(source), true) => source,
+            (None, true) => {
+                return Err(SandboxError::ConfigInvalid {
+                    reason: "regenerating snapshots needs a 'source' in the firecracker-snapshot config".to_string(),
+                }
+                .into());
+            },
+        };
+
+        info!("Creating snapshot from {}", source.firecracker_config_location);
+        let manifest = create_snapshot(source, &self.config.snapshot_file, &self.config.mem_file)?;
+        manifest.save(&manifest_file)?;
+        Ok(Some((manifest_file, manifest)))
+    }
 
-}
\ No newline at end of file
+    fn as_pausable(&mut self) -> Option<&mut dyn Pausable> {
+        Some(self)
+    }
+}
+
+// Pauses and resumes the guest's vCPUs through the API, the VMM itself keeps running
+impl Pausable for FirecrackerSnapshot {
+    fn pause(&mut self) -> Result<()> {
+        if self.child_process.is_none() {
+            return Err(SandboxError::NotStarted.into());
+        }
+        FirecrackerApi::new(&self.get_socket_addr()).pause()?;
+        Ok(())
+    }
+
+    fn resume(&mut self) -> Result<()> {
+        if self.child_process.is_none() {
+            return Err(SandboxError::NotStarted.into());
+        }
+        FirecrackerApi::new(&self.get_socket_addr()).resume()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,59 @@
+use crate::{cgroup::Cgroup, error::SandboxError, memory::process_tree};
+use anyhow::Result;
+use std::{
+    fs, io, thread,
+    time::{Duration, Instant},
+};
+
+// How long the processes get to stop once signaled
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Pauses or resumes the processes of a sandbox in place: with the cgroup v2 freezer when the
+// sandbox runs in its own cgroup, or else with SIGSTOP and SIGCONT to every process of its tree
+pub fn set_frozen(cgroup: Option<&Cgroup>, root_pid: Option<u32>, frozen: bool) -> Result<()> {
+    if let Some(cgroup) = cgroup {
+        return Ok(cgroup.set_frozen(frozen)?);
+    }
+    let root = root_pid.ok_or(SandboxError::NotStarted)?;
+    let tree = process_tree(root)?;
+    let signal = if frozen { libc::SIGSTOP } else { libc::SIGCONT };
+    for &pid in &tree {
+        // SAFETY: only sends a signal
+        if unsafe { libc::kill(pid as libc::pid_t, signal) } < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+    }
+    if frozen {
+        wait_for_stop(&tree)?;
+    }
+    Ok(())
+}
+
+// Waits until every process of `pids` is stopped. Processes that are gone count as stopped.
+fn wait_for_stop(pids: &[u32]) -> Result<(), SandboxError> {
+    let start = Instant::now();
+    loop {
+        let running: Vec<u32> = pids.iter().copied().filter(|&pid| !is_stopped(pid)).collect();
+        if running.is_empty() {
+            return Ok(());
+        }
+        if start.elapsed() >= STOP_TIMEOUT {
+            return Err(SandboxError::Timeout {
+                what: format!("stop of processes {:?}", running),
+                after: STOP_TIMEOUT,
+            });
+        }
+        thread::sleep(Duration::from_micros(100));
+    }
+}
+
+fn is_stopped(pid: u32) -> bool {
+    // The state follows the command name, which is in parentheses and may contain anything
+    match fs::read_to_string(format!("/proc/{}/stat", pid)) {
+        Ok(stat) => {
+            let state = stat.rsplit_once(')').and_then(|(_, rest)| rest.trim_start().chars().next());
+            matches!(state, Some('T' | 'Z' | 'X'))
+        },
+        Err(_) => true,
+    }
+}
@@ -0,0 +1,89 @@
+use crate::{async_sandbox::AsyncSandbox, error::ignore_not_started};
+use anyhow::Result;
+use log::{debug, error};
+use std::{
+    future::Future,
+    ops::{Deref, DerefMut},
+    panic::{self, AssertUnwindSafe},
+};
+use tokio::signal::unix::{signal, SignalKind};
+
+// Owns a sandbox and makes sure it is killed and cleaned up exactly once. Drivers should call
+// `teardown` to do so on the runtime and see its errors. If the guard is dropped first, because of an
+// early return, a panic or a cancelled task, the sandbox is torn down synchronously instead, so that
+// no VMM process, TAP device, nft rule or API socket outlives the run.
+pub struct SandboxGuard {
+    sandbox: Box<dyn AsyncSandbox>,
+    torn_down: bool,
+}
+
+impl SandboxGuard {
+    pub fn new(sandbox: Box<dyn AsyncSandbox>) -> Self {
+        SandboxGuard {
+            sandbox,
+            torn_down: false,
+        }
+    }
+
+    // Kills and cleans up the sandbox. Cleanup is attempted even if kill fails, and the first error
+    // is returned. If this future is dropped before both complete, Drop tears down the sandbox.
+    pub async fn teardown(&mut self) -> Result<()> {
+        if self.torn_down {
+            return Ok(());
+        }
+        let killed = self.sandbox.kill().await;
+        let cleaned = self.sandbox.cleanup().await;
+        self.torn_down = true;
+        ignore_not_started(killed).and(cleaned)
+    }
+}
+
+impl Deref for SandboxGuard {
+    type Target = dyn AsyncSandbox;
+
+    fn deref(&self) -> &Self::Target {
+        self.sandbox.as_ref()
+    }
+}
+
+impl DerefMut for SandboxGuard {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.sandbox.as_mut()
+    }
+}
+
+impl Drop for SandboxGuard {
+    fn drop(&mut self) {
+        if self.torn_down {
+            return;
+        }
+        self.torn_down = true;
+        debug!("Tearing down {} on drop", self.sandbox.get_name());
+        // We may already be unwinding, and a second panic would abort before the host is clean
+        let sandbox = &mut self.sandbox;
+        match panic::catch_unwind(AssertUnwindSafe(|| sandbox.teardown_blocking())) {
+            Ok(Ok(())) => {},
+            Ok(Err(e)) => error!("Failed to tear down {}: {}", self.sandbox.get_name(), e),
+            Err(_) => error!("Panicked while tearing down {}", self.sandbox.get_name()),
+        }
+    }
+}
+
+// Resolves with the name of the signal once the process receives SIGINT or SIGTERM.
+pub async fn shutdown_signal() -> Result<&'static str> {
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        _ = interrupt.recv() => Ok("SIGINT"),
+        _ = terminate.recv() => Ok("SIGTERM"),
+    }
+}
+
+// Runs `work` until it completes or the process is asked to stop. On a signal, `work` is dropped
+// along with every `SandboxGuard` it owns, which tears the sandboxes down before returning.
+pub async fn until_shutdown<T>(work: impl Future<Output = Result<T>>) -> Result<T> {
+    tokio::select! {
+        result = work => result,
+        signal = shutdown_signal() => Err(anyhow::anyhow!("Interrupted by {}", signal?)),
+    }
+}
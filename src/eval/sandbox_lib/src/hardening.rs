@@ -0,0 +1,561 @@
+use crate::error::SandboxError;
+use log::debug;
+use serde::Deserialize;
+use std::{
+    ffi::CString,
+    io,
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd},
+        unix::process::CommandExt,
+    },
+    path::Path,
+    process::Command,
+};
+
+// Restrictions put on a process before it executes its binary: resource limits, an unprivileged
+// user, no_new_privs, Landlock file system rules and a seccomp-BPF syscall allowlist
+#[derive(Deserialize, Default)]
+pub struct HardeningConfig {
+    // Run as this user, and this group (defaulting to the user's id). Switching needs root.
+    uid: Option<u32>,
+    gid: Option<u32>,
+    // Keep exec from granting privileges (setuid binaries, file capabilities). Always set along
+    // with Landlock or seccomp, which need it without CAP_SYS_ADMIN.
+    #[serde(default)]
+    no_new_privs: bool,
+    #[serde(default)]
+    rlimits: RlimitConfig,
+    landlock: Option<LandlockConfig>,
+    seccomp: Option<SeccompConfig>,
+}
+
+// Both the soft and the hard limit are set to the value
+#[derive(Deserialize, Default)]
+struct RlimitConfig {
+    nofile: Option<u64>,
+    nproc: Option<u64>,
+    address_space: Option<u64>,
+    data: Option<u64>,
+    stack: Option<u64>,
+    core: Option<u64>,
+    fsize: Option<u64>,
+}
+
+// Everything outside these paths is out of reach, except for the binary itself, which is always
+// readable and executable. Paths missing on the host are skipped.
+#[derive(Deserialize)]
+struct LandlockConfig {
+    #[serde(default = "default_read_only")]
+    read_only: Vec<String>,
+    #[serde(default)]
+    read_write: Vec<String>,
+}
+
+fn default_read_only() -> Vec<String> {
+    // The dynamic loader needs the libraries and /etc/ld.so.cache
+    ["/lib", "/lib64", "/usr/lib", "/usr/lib64", "/etc"].map(String::from).to_vec()
+}
+
+#[derive(Deserialize)]
+struct SeccompConfig {
+    #[serde(default)]
+    action: SeccompAction,
+    // Allowed on top of the echo server's own syscalls, by name (x86_64)
+    #[serde(default)]
+    extra_syscalls: Vec<String>,
+}
+
+// What a syscall outside the allowlist does
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum SeccompAction {
+    #[default]
+    KillProcess,
+    // Fails with EPERM
+    Errno,
+    // Allowed, but logged by the kernel, to find out what a binary is missing
+    Log,
+}
+
+impl SeccompAction {
+    fn ret(&self) -> u32 {
+        match self {
+            SeccompAction::KillProcess => libc::SECCOMP_RET_KILL_PROCESS,
+            SeccompAction::Errno => libc::SECCOMP_RET_ERRNO | libc::EPERM as u32,
+            SeccompAction::Log => libc::SECCOMP_RET_LOG,
+        }
+    }
+}
+
+// What rust-http-echo does from the moment it is executed until it is killed: the dynamic loader,
+// std's and tokio's runtime, and hyper
+const ECHO_SYSCALLS: &[&str] = &[
+    // Traced while serving requests
+    "read", "write", "writev", "pread64", "close", "lseek", "ioctl", "fcntl", "access", "openat",
+    "newfstatat", "statx", "mmap", "mprotect", "munmap", "madvise", "brk", "rt_sigaction",
+    "rt_sigprocmask", "rt_sigreturn", "sigaltstack", "poll", "socket", "socketpair", "bind",
+    "listen", "accept4", "setsockopt", "sendto", "recvfrom", "shutdown", "epoll_create1",
+    "epoll_ctl", "epoll_wait", "eventfd2", "futex", "sched_getaffinity", "getrandom", "prctl",
+    "arch_prctl", "prlimit64", "set_tid_address", "set_robust_list", "rseq", "gettid", "execve",
+    "exit", "exit_group", "clone3",
+    // Not used in every run, only under Landlock, or only by other versions of glibc and tokio
+    "clone", "mremap", "fstat", "readlink", "readlinkat", "sched_yield", "nanosleep",
+    "clock_nanosleep", "clock_gettime", "getsockname", "getpeername", "getsockopt", "epoll_pwait",
+    "restart_syscall", "getpid", "tgkill",
+];
+
+// Syscalls an allowlist may name
+#[cfg(target_arch = "x86_64")]
+const SYSCALL_NUMBERS: &[(&str, libc::c_long)] = &[
+    ("read", libc::SYS_read),
+    ("write", libc::SYS_write),
+    ("readv", libc::SYS_readv),
+    ("writev", libc::SYS_writev),
+    ("pread64", libc::SYS_pread64),
+    ("pwrite64", libc::SYS_pwrite64),
+    ("open", libc::SYS_open),
+    ("openat", libc::SYS_openat),
+    ("close", libc::SYS_close),
+    ("lseek", libc::SYS_lseek),
+    ("ioctl", libc::SYS_ioctl),
+    ("fcntl", libc::SYS_fcntl),
+    ("access", libc::SYS_access),
+    ("faccessat2", libc::SYS_faccessat2),
+    ("stat", libc::SYS_stat),
+    ("fstat", libc::SYS_fstat),
+    ("lstat", libc::SYS_lstat),
+    ("newfstatat", libc::SYS_newfstatat),
+    ("statx", libc::SYS_statx),
+    ("readlink", libc::SYS_readlink),
+    ("readlinkat", libc::SYS_readlinkat),
+    ("getdents64", libc::SYS_getdents64),
+    ("getcwd", libc::SYS_getcwd),
+    ("chdir", libc::SYS_chdir),
+    ("mkdirat", libc::SYS_mkdirat),
+    ("unlinkat", libc::SYS_unlinkat),
+    ("renameat2", libc::SYS_renameat2),
+    ("ftruncate", libc::SYS_ftruncate),
+    ("fsync", libc::SYS_fsync),
+    ("fdatasync", libc::SYS_fdatasync),
+    ("dup", libc::SYS_dup),
+    ("dup2", libc::SYS_dup2),
+    ("dup3", libc::SYS_dup3),
+    ("pipe2", libc::SYS_pipe2),
+    ("memfd_create", libc::SYS_memfd_create),
+    ("mmap", libc::SYS_mmap),
+    ("mprotect", libc::SYS_mprotect),
+    ("munmap", libc::SYS_munmap),
+    ("mremap", libc::SYS_mremap),
+    ("madvise", libc::SYS_madvise),
+    ("brk", libc::SYS_brk),
+    ("rt_sigaction", libc::SYS_rt_sigaction),
+    ("rt_sigprocmask", libc::SYS_rt_sigprocmask),
+    ("rt_sigreturn", libc::SYS_rt_sigreturn),
+    ("sigaltstack", libc::SYS_sigaltstack),
+    ("poll", libc::SYS_poll),
+    ("ppoll", libc::SYS_ppoll),
+    ("select", libc::SYS_select),
+    ("pselect6", libc::SYS_pselect6),
+    ("socket", libc::SYS_socket),
+    ("socketpair", libc::SYS_socketpair),
+    ("bind", libc::SYS_bind),
+    ("listen", libc::SYS_listen),
+    ("accept", libc::SYS_accept),
+    ("accept4", libc::SYS_accept4),
+    ("connect", libc::SYS_connect),
+    ("getsockname", libc::SYS_getsockname),
+    ("getpeername", libc::SYS_getpeername),
+    ("setsockopt", libc::SYS_setsockopt),
+    ("getsockopt", libc::SYS_getsockopt),
+    ("sendto", libc::SYS_sendto),
+    ("recvfrom", libc::SYS_recvfrom),
+    ("sendmsg", libc::SYS_sendmsg),
+    ("recvmsg", libc::SYS_recvmsg),
+    ("shutdown", libc::SYS_shutdown),
+    ("epoll_create1", libc::SYS_epoll_create1),
+    ("epoll_ctl", libc::SYS_epoll_ctl),
+    ("epoll_wait", libc::SYS_epoll_wait),
+    ("epoll_pwait", libc::SYS_epoll_pwait),
+    ("epoll_pwait2", libc::SYS_epoll_pwait2),
+    ("eventfd2", libc::SYS_eventfd2),
+    ("timerfd_create", libc::SYS_timerfd_create),
+    ("timerfd_settime", libc::SYS_timerfd_settime),
+    ("futex", libc::SYS_futex),
+    ("sched_yield", libc::SYS_sched_yield),
+    ("sched_getaffinity", libc::SYS_sched_getaffinity),
+    ("nanosleep", libc::SYS_nanosleep),
+    ("clock_nanosleep", libc::SYS_clock_nanosleep),
+    ("clock_gettime", libc::SYS_clock_gettime),
+    ("getrandom", libc::SYS_getrandom),
+    ("uname", libc::SYS_uname),
+    ("sysinfo", libc::SYS_sysinfo),
+    ("prctl", libc::SYS_prctl),
+    ("arch_prctl", libc::SYS_arch_prctl),
+    ("prlimit64", libc::SYS_prlimit64),
+    ("getrlimit", libc::SYS_getrlimit),
+    ("set_tid_address", libc::SYS_set_tid_address),
+    ("set_robust_list", libc::SYS_set_robust_list),
+    ("rseq", libc::SYS_rseq),
+    ("getpid", libc::SYS_getpid),
+    ("getppid", libc::SYS_getppid),
+    ("gettid", libc::SYS_gettid),
+    ("getuid", libc::SYS_getuid),
+    ("geteuid", libc::SYS_geteuid),
+    ("getgid", libc::SYS_getgid),
+    ("getegid", libc::SYS_getegid),
+    ("kill", libc::SYS_kill),
+    ("tgkill", libc::SYS_tgkill),
+    ("wait4", libc::SYS_wait4),
+    ("clone", libc::SYS_clone),
+    ("clone3", libc::SYS_clone3),
+    ("vfork", libc::SYS_vfork),
+    ("execve", libc::SYS_execve),
+    ("execveat", libc::SYS_execveat),
+    ("exit", libc::SYS_exit),
+    ("exit_group", libc::SYS_exit_group),
+    ("restart_syscall", libc::SYS_restart_syscall),
+];
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+// Syscalls of the x32 ABI share the numbers above, with this bit set
+#[cfg(target_arch = "x86_64")]
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+// Offsets in `struct seccomp_data`
+const SECCOMP_DATA_NR: u32 = 0;
+const SECCOMP_DATA_ARCH: u32 = 4;
+
+// Landlock's file system access rights, from <linux/landlock.h>. Later ABI versions handle more.
+const LANDLOCK_CREATE_RULESET_VERSION: u32 = 1 << 0;
+const LANDLOCK_RULE_PATH_BENEATH: libc::c_int = 1;
+const ACCESS_FS_EXECUTE: u64 = 1 << 0;
+const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+const ACCESS_FS_READ_FILE: u64 = 1 << 2;
+const ACCESS_FS_READ_DIR: u64 = 1 << 3;
+const ACCESS_FS_V1: u64 = (1 << 13) - 1;
+const ACCESS_FS_REFER: u64 = 1 << 13;
+const ACCESS_FS_TRUNCATE: u64 = 1 << 14;
+const ACCESS_FS_IOCTL_DEV: u64 = 1 << 15;
+// The only rights a rule on a file, rather than a directory, may grant
+const ACCESS_FS_FILE: u64 =
+    ACCESS_FS_EXECUTE | ACCESS_FS_WRITE_FILE | ACCESS_FS_READ_FILE | ACCESS_FS_TRUNCATE | ACCESS_FS_IOCTL_DEV;
+const ACCESS_FS_READ_ONLY: u64 = ACCESS_FS_EXECUTE | ACCESS_FS_READ_FILE | ACCESS_FS_READ_DIR;
+
+#[repr(C)]
+struct LandlockRulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[repr(C, packed)]
+struct LandlockPathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: i32,
+}
+
+// The restrictions of a `HardeningConfig`, prepared by the parent so that the child only has to
+// issue the system calls that put them in place
+pub struct Hardening {
+    uid: Option<u32>,
+    gid: Option<u32>,
+    no_new_privs: bool,
+    rlimits: Vec<(libc::__rlimit_resource_t, libc::rlimit)>,
+    // Built with all its rules, only enforced by the child. Closed on exec.
+    landlock_ruleset: Option<OwnedFd>,
+    seccomp_filter: Option<Vec<libc::sock_filter>>,
+}
+
+impl Hardening {
+    pub fn prepare(config: &HardeningConfig, binary_path: &str) -> Result<Self, SandboxError> {
+        let limits = &config.rlimits;
+        let rlimits = [
+            (libc::RLIMIT_NOFILE, limits.nofile),
+            (libc::RLIMIT_NPROC, limits.nproc),
+            (libc::RLIMIT_AS, limits.address_space),
+            (libc::RLIMIT_DATA, limits.data),
+            (libc::RLIMIT_STACK, limits.stack),
+            (libc::RLIMIT_CORE, limits.core),
+            (libc::RLIMIT_FSIZE, limits.fsize),
+        ]
+        .into_iter()
+        .filter_map(|(resource, value)| value.map(|value| (resource, libc::rlimit { rlim_cur: value, rlim_max: value })))
+        .collect();
+
+        let landlock_ruleset = match &config.landlock {
+            Some(landlock) => Some(landlock_ruleset(landlock, binary_path)?),
+            None => None,
+        };
+        let seccomp_filter = match &config.seccomp {
+            Some(seccomp) => Some(seccomp_filter(seccomp)?),
+            None => None,
+        };
+
+        Ok(Hardening {
+            uid: config.uid,
+            gid: config.gid.or(config.uid),
+            no_new_privs: config.no_new_privs || landlock_ruleset.is_some() || seccomp_filter.is_some(),
+            rlimits,
+            landlock_ruleset,
+            seccomp_filter,
+        })
+    }
+
+    // Has `command` put the restrictions in place right before exec, after any earlier pre-exec
+    // hook, such as joining a cgroup
+    pub fn apply(self, command: &mut Command) {
+        // SAFETY: `enter` runs between fork and exec, where only async-signal-safe calls are allowed.
+        // It neither allocates nor takes locks, and only makes system calls on what `prepare` built.
+        unsafe {
+            command.pre_exec(move || self.enter());
+        }
+    }
+
+    // Puts the restrictions in place. Meant to run between fork and exec, so it only issues system
+    // calls on what `prepare` built.
+    pub fn enter(&self) -> io::Result<()> {
+        // SAFETY: every call below is a plain system call on data owned by `self`
+        unsafe {
+            // Still privileged enough to set any limit
+            for (resource, limit) in &self.rlimits {
+                check(libc::setrlimit(*resource, limit))?;
+            }
+            if let Some(gid) = self.gid {
+                check(libc::setgroups(0, std::ptr::null()))?;
+                check(libc::setgid(gid))?;
+            }
+            if let Some(uid) = self.uid {
+                check(libc::setuid(uid))?;
+            }
+            if self.no_new_privs {
+                check(libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0))?;
+            }
+            if let Some(ruleset) = &self.landlock_ruleset {
+                check(libc::syscall(libc::SYS_landlock_restrict_self, ruleset.as_raw_fd(), 0) as libc::c_int)?;
+            }
+            // Last, as from here on only the allowlist is left, which covers exec
+            if let Some(filter) = &self.seccomp_filter {
+                let program = libc::sock_fprog {
+                    len: filter.len() as libc::c_ushort,
+                    filter: filter.as_ptr() as *mut libc::sock_filter,
+                };
+                let program_ptr = &program as *const libc::sock_fprog;
+                check(libc::syscall(libc::SYS_seccomp, libc::SECCOMP_SET_MODE_FILTER, 0, program_ptr) as libc::c_int)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn landlock_ruleset(config: &LandlockConfig, binary_path: &str) -> Result<OwnedFd, SandboxError> {
+    let unsupported = || SandboxError::Unsupported {
+        operation: "restrict file system access with Landlock".to_string(),
+        backend: "Process".to_string(),
+    };
+    // SAFETY: asks for the ABI version, without any attribute
+    let abi = unsafe {
+        libc::syscall(libc::SYS_landlock_create_ruleset, std::ptr::null::<LandlockRulesetAttr>(), 0, LANDLOCK_CREATE_RULESET_VERSION)
+    };
+    let handled_access = handled_access(abi).ok_or_else(unsupported)?;
+    debug!("Landlock ABI version {}", abi);
+
+    let attr = LandlockRulesetAttr { handled_access_fs: handled_access };
+    // SAFETY: `attr` outlives the call and its size is passed along
+    let fd = unsafe {
+        libc::syscall(libc::SYS_landlock_create_ruleset, &attr as *const LandlockRulesetAttr, size_of::<LandlockRulesetAttr>(), 0)
+    };
+    if fd < 0 {
+        return Err(SandboxError::Io { path: "landlock ruleset".to_string(), source: io::Error::last_os_error() });
+    }
+    // SAFETY: the kernel just handed us this descriptor, which is close-on-exec
+    let ruleset = unsafe { OwnedFd::from_raw_fd(fd as i32) };
+
+    let rules = config
+        .read_only
+        .iter()
+        .map(|path| (path.as_str(), ACCESS_FS_READ_ONLY))
+        .chain(config.read_write.iter().map(|path| (path.as_str(), handled_access)))
+        .chain([(binary_path, ACCESS_FS_READ_ONLY)]);
+    for (path, access) in rules {
+        if !Path::new(path).exists() {
+            debug!("Not adding a Landlock rule for {}, it does not exist on the host", path);
+            continue;
+        }
+        add_landlock_rule(&ruleset, path, access & handled_access)?;
+    }
+    Ok(ruleset)
+}
+
+// File system rights that Landlock ABI version `abi` handles, or None without Landlock
+fn handled_access(abi: i64) -> Option<u64> {
+    match abi {
+        i64::MIN..=0 => None,
+        1 => Some(ACCESS_FS_V1),
+        2 => Some(ACCESS_FS_V1 | ACCESS_FS_REFER),
+        3 | 4 => Some(ACCESS_FS_V1 | ACCESS_FS_REFER | ACCESS_FS_TRUNCATE),
+        _ => Some(ACCESS_FS_V1 | ACCESS_FS_REFER | ACCESS_FS_TRUNCATE | ACCESS_FS_IOCTL_DEV),
+    }
+}
+
+fn add_landlock_rule(ruleset: &OwnedFd, path: &str, access: u64) -> Result<(), SandboxError> {
+    let c_path = CString::new(path)
+        .map_err(|_| SandboxError::ConfigInvalid { reason: format!("path '{}' contains a NUL byte", path) })?;
+    // SAFETY: `c_path` is a valid NUL terminated string
+    let fd = unsafe { libc::open(c_path.as_ptr(), libc::O_PATH | libc::O_CLOEXEC) };
+    if fd < 0 {
+        return Err(SandboxError::io(path)(io::Error::last_os_error()));
+    }
+    // SAFETY: the descriptor was just opened and nothing else owns it
+    let parent = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    let allowed_access = match Path::new(path).is_dir() {
+        true => access,
+        false => access & ACCESS_FS_FILE,
+    };
+    let rule = LandlockPathBeneathAttr { allowed_access, parent_fd: parent.as_raw_fd() };
+    // SAFETY: `rule` outlives the call and both descriptors are open
+    let added = unsafe {
+        libc::syscall(libc::SYS_landlock_add_rule, ruleset.as_raw_fd(), LANDLOCK_RULE_PATH_BENEATH, &rule as *const LandlockPathBeneathAttr, 0)
+    };
+    if added < 0 {
+        return Err(SandboxError::io(path)(io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+#[cfg(target_arch = "x86_64")]
+fn seccomp_filter(config: &SeccompConfig) -> Result<Vec<libc::sock_filter>, SandboxError> {
+    let mut syscalls = Vec::new();
+    for name in ECHO_SYSCALLS.iter().copied().chain(config.extra_syscalls.iter().map(String::as_str)) {
+        let number = SYSCALL_NUMBERS
+            .iter()
+            .find(|(known, _)| *known == name)
+            .map(|(_, number)| *number as u32)
+            .ok_or_else(|| SandboxError::ConfigInvalid { reason: format!("unknown syscall '{}'", name) })?;
+        if !syscalls.contains(&number) {
+            syscalls.push(number);
+        }
+    }
+
+    let denied = config.action.ret();
+    let mut filter = vec![
+        // Syscall numbers only mean something for the architecture they were taken from
+        stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, SECCOMP_DATA_ARCH),
+        jump(libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K, AUDIT_ARCH, 1, 0),
+        stmt(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_KILL_PROCESS),
+        stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, SECCOMP_DATA_NR),
+        jump(libc::BPF_JMP | libc::BPF_JGE | libc::BPF_K, X32_SYSCALL_BIT, 0, 1),
+        stmt(libc::BPF_RET | libc::BPF_K, denied),
+    ];
+    // Each match jumps over the rest of the comparisons and the final deny, onto the allow
+    for (index, number) in syscalls.iter().enumerate() {
+        let to_allow = u8::try_from(syscalls.len() - index)
+            .map_err(|_| SandboxError::ConfigInvalid { reason: "too many syscalls in the seccomp allowlist".to_string() })?;
+        filter.push(jump(libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K, *number, to_allow, 0));
+    }
+    filter.push(stmt(libc::BPF_RET | libc::BPF_K, denied));
+    filter.push(stmt(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_ALLOW));
+    Ok(filter)
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn seccomp_filter(_config: &SeccompConfig) -> Result<Vec<libc::sock_filter>, SandboxError> {
+    Err(SandboxError::Unsupported {
+        operation: "filter syscalls on this architecture".to_string(),
+        backend: "Process".to_string(),
+    })
+}
+
+fn stmt(code: u32, k: u32) -> libc::sock_filter {
+    jump(code, k, 0, 0)
+}
+
+fn jump(code: u32, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+    libc::sock_filter { code: code as u16, jt, jf, k }
+}
+
+fn check(result: libc::c_int) -> io::Result<libc::c_int> {
+    match result {
+        -1 => Err(io::Error::last_os_error()),
+        result => Ok(result),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn landlock_access_per_abi() {
+        assert_eq!(handled_access(-1), None);
+        assert_eq!(handled_access(0), None);
+        // ABI 1 covers execute through make_sym, and later versions add one right at a time
+        assert_eq!(handled_access(1), Some(0x1fff));
+        assert_eq!(handled_access(2), Some(0x3fff));
+        assert_eq!(handled_access(3), Some(0x7fff));
+        // ABI 4 only adds network rules, which are not used
+        assert_eq!(handled_access(4), Some(0x7fff));
+        assert_eq!(handled_access(5), Some(0xffff));
+        assert_eq!(handled_access(6), Some(0xffff));
+        // Rules on files never get directory rights
+        assert_eq!(ACCESS_FS_FILE & (ACCESS_FS_READ_DIR | ACCESS_FS_REFER), 0);
+    }
+
+    // Installs the filter of `config` in a forked child, and returns how `allowed` and `denied`
+    // failed there, as errno values or 0 on success
+    #[cfg(target_arch = "x86_64")]
+    fn syscalls_under_seccomp(config: &str, allowed: libc::c_long, denied: libc::c_long) -> (i32, i32) {
+        let config: HardeningConfig = serde_json::from_str(config).unwrap();
+        let hardening = Hardening::prepare(&config, "/bin/true").unwrap();
+        let mut pipe = [0; 2];
+        // SAFETY: `pipe` has room for both descriptors
+        assert_eq!(unsafe { libc::pipe(pipe.as_mut_ptr()) }, 0);
+
+        // SAFETY: the child only issues system calls and exits without returning to the harness
+        let pid = unsafe { libc::fork() };
+        assert!(pid >= 0);
+        if pid == 0 {
+            // SAFETY: plain system calls on our own descriptors and buffers
+            unsafe {
+                if hardening.enter().is_err() {
+                    libc::_exit(1);
+                }
+                let errno = |result: libc::c_long| match result {
+                    -1 => *libc::__errno_location(),
+                    _ => 0,
+                };
+                let results = [errno(libc::syscall(allowed)), errno(libc::syscall(denied))];
+                libc::write(pipe[1], results.as_ptr() as *const libc::c_void, size_of_val(&results));
+                libc::_exit(0);
+            }
+        }
+
+        let mut results = [-1i32; 2];
+        let mut status = 0;
+        // SAFETY: reads into `results`, then waits for our child
+        unsafe {
+            libc::close(pipe[1]);
+            libc::read(pipe[0], results.as_mut_ptr() as *mut libc::c_void, size_of_val(&results));
+            libc::close(pipe[0]);
+            assert_eq!(libc::waitpid(pid, &mut status, 0), pid);
+        }
+        assert!(libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0, "child failed: {}", status);
+        (results[0], results[1])
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn seccomp_denies_syscalls_outside_the_allowlist() {
+        let config = r#"{ "seccomp": { "action": "errno" } }"#;
+        assert_eq!(syscalls_under_seccomp(config, libc::SYS_getpid, libc::SYS_uname), (0, libc::EPERM));
+
+        let config = r#"{ "seccomp": { "action": "errno", "extra_syscalls": ["getppid"] } }"#;
+        assert_eq!(syscalls_under_seccomp(config, libc::SYS_getppid, libc::SYS_getuid), (0, libc::EPERM));
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn seccomp_rejects_unknown_syscalls() {
+        let config: HardeningConfig = serde_json::from_str(r#"{ "seccomp": { "extra_syscalls": ["nope"] } }"#).unwrap();
+        assert!(matches!(Hardening::prepare(&config, "/bin/true"), Err(SandboxError::ConfigInvalid { .. })));
+    }
+}
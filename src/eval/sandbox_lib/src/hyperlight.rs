@@ -1,10 +1,17 @@
 use client_lib::{build_empty_request, sync_send_request};
-use crate::net_lib::wait_for_port;
-use crate::sandbox::Sandbox;
+use crate::{
+    cgroup::{Cgroup, CgroupConfig},
+    error::SandboxError,
+    freezer,
+    readiness::{ProbeKind, ProbeTarget, Readiness, ReadinessConfig},
+    registry::{SandboxConfig, SandboxEntry},
+    sandbox::{Capabilities, Pausable, Sandbox},
+    timings::{LifecycleTimings, Phase},
+};
 use anyhow::Result; 
 use log::{debug, error};
 use serde::Deserialize;
-use std::{process::{Child, Command}, str, sync::Arc};
+use std::{process::{Child, Command}, str, sync::Arc, time::Instant};
 use uuid::Uuid;
 use std::fs::File;
 
@@ -15,7 +22,9 @@ struct HyperlightConfig {
     host_binary: String,
     listen_ip: String,
     listen_port: u16,
-    output_dir: String
+    output_dir: String,
+    readiness: Option<ReadinessConfig>,
+    cgroup: Option<CgroupConfig>,
 }
 
 pub struct Hyperlight {
@@ -23,32 +32,41 @@ pub struct Hyperlight {
     config: HyperlightConfig,
     child_process: Option<Child>,
     iteration: usize,
+    timings: LifecycleTimings,
+    cgroup: Option<Cgroup>,
 }
 
+pub const ENTRY: SandboxEntry = SandboxEntry {
+    name: "hyperlight",
+    build: |config, iteration| Ok(Box::new(Hyperlight::from_config(config, iteration)?)),
+};
+
 impl Hyperlight {
-    pub fn new(config_path: &str, iteration: usize) -> Self {
-        // Open file
-        let file = std::fs::File::open(config_path).expect("Failed to open config file");
-        let mut config: HyperlightConfig = serde_json::from_reader(file)
-            .expect("Failed to load config file");
+    pub fn new(config_path: &str, iteration: usize) -> Result<Self, SandboxError> {
+        Self::from_config(&SandboxConfig::Path(config_path.to_string()), iteration)
+    }
+
+    pub fn from_config(config: &SandboxConfig, iteration: usize) -> Result<Self, SandboxError> {
+        let mut config: HyperlightConfig = config.parse()?;
 
         // Update the port based on the iteration
         config.listen_port += iteration as u16;
 
         let id = Uuid::new_v4().to_string();
 
-        Hyperlight {
+        Ok(Hyperlight {
             id,
             config,
             child_process: None,
             iteration,
-        }
+            timings: LifecycleTimings::default(),
+            cgroup: None,
+        })
     }
 
-    fn create_log_file(output_dir: &str, id: &str, iteration: usize, suffix: &str) -> Result<File> {
+    fn create_log_file(output_dir: &str, id: &str, iteration: usize, suffix: &str) -> Result<File, SandboxError> {
         let log_file = format!("{}/hyperlight{}-{}{}", output_dir, id, iteration, suffix);
-        let log = File::create(log_file).expect("failed to open log");
-        Ok(log)
+        File::create(&log_file).map_err(SandboxError::io(log_file))
     }
 }
 
@@ -66,33 +84,45 @@ impl Sandbox for Hyperlight {
     }
 
     fn kill(&mut self) -> Result<()> {
-        self.child_process.as_mut().unwrap().kill().expect("Failed to kill Unikraft VM");
+        let child = self.child_process.as_mut().ok_or(SandboxError::NotStarted)?;
+        child.kill()?;
         Ok(())
     }
 
     fn cleanup(&mut self) -> Result<()> {
+        if let Some(cgroup) = self.cgroup.take() {
+            cgroup.remove()?;
+        }
         Ok(())
     }
 
     fn presetup(&mut self) -> Result<()> {
-        let log_file_out = Self::create_log_file(&self.config.output_dir, &self.id, self.iteration,".out").unwrap();
-        let log_file_err = Self::create_log_file(&self.config.output_dir, &self.id, self.iteration, ".err").unwrap();
-        let mut start_cmd = Command::new(&self.config.host_binary)
+        self.cgroup = Cgroup::create_optional(self.config.cgroup.as_ref(), &self.id)?;
+        let log_file_out = Self::create_log_file(&self.config.output_dir, &self.id, self.iteration,".out")?;
+        let log_file_err = Self::create_log_file(&self.config.output_dir, &self.id, self.iteration, ".err")?;
+        let spawn_start = Instant::now();
+        let mut command = Command::new(&self.config.host_binary);
+        command
             .arg("-listen")
             .arg(format!("{}:{}", &self.config.listen_ip, &self.config.listen_port))
             .arg("-guest")
             .arg(&self.config.guest_binary)
             .stdout(log_file_out)
-            .stderr(log_file_err)
-            .spawn()?;
-
-        let ready = wait_for_port(&self.config.listen_ip, self.config.listen_port);
-
-        if !ready {
-            error!("Failed to start Hyperlight VM: Port {} is not open", self.config.listen_port);
-            start_cmd.kill().expect("Failed to kill Hyperlight VM");
-            std::process::exit(1);
+            .stderr(log_file_err);
+        if let Some(cgroup) = &self.cgroup {
+            cgroup.attach(&mut command)?;
         }
+        let mut start_cmd = command.spawn().map_err(SandboxError::spawn(&self.config.host_binary))?;
+        self.timings.record(Phase::ProcessSpawn, spawn_start, Instant::now());
+
+        // The host must be listening before the guest can be started
+        let host_ready = Readiness::tcp(&self.config.listen_ip, self.config.listen_port);
+        if let Err(e) = host_ready.wait() {
+            start_cmd.kill()?;
+            return Err(e);
+        }
+        // The host is the VMM here, so its API is ready once it listens
+        self.timings.record(Phase::VmmApiReady, spawn_start, Instant::now());
         
         debug!("Started Hyperlight VM with PID: {}", start_cmd.id());
         self.child_process = Some(start_cmd);
@@ -103,6 +133,39 @@ impl Sandbox for Hyperlight {
         "Hyperlight".to_string()
     }
 
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            needs_network_presetup: false,
+            supports_snapshot: false,
+            supports_pause: true,
+            readiness_kind: ProbeKind::Tcp,
+            warmup_required: true,
+            multi_instance_safe: true,
+        }
+    }
+
+    fn timings(&self) -> LifecycleTimings {
+        self.timings.clone()
+    }
+
+    fn root_pid(&self) -> Option<u32> {
+        self.child_process.as_ref().map(|child| child.id())
+    }
+
+    fn cgroup(&self) -> Option<&Cgroup> {
+        self.cgroup.as_ref()
+    }
+
+    fn readiness(&self) -> Result<Readiness> {
+        let target = ProbeTarget {
+            ip: self.get_target_ip(),
+            port: self.get_target_port(),
+            log_file: Some(format!("{}/hyperlight{}-{}.out", self.config.output_dir, self.id, self.iteration)),
+            api_socket: None,
+        };
+        Readiness::from_config(self.config.readiness.as_ref(), self.capabilities().readiness_kind, &target)
+    }
+
     fn get_target_port(&self) -> u16 {
         self.config.listen_port
     }
@@ -110,4 +173,19 @@ impl Sandbox for Hyperlight {
     fn get_target_ip(&self) -> String {
         self.config.listen_ip.clone()
     }
-} 
+
+    fn as_pausable(&mut self) -> Option<&mut dyn Pausable> {
+        Some(self)
+    }
+}
+
+// Freezes the host along with its guest, see `freezer::set_frozen`
+impl Pausable for Hyperlight {
+    fn pause(&mut self) -> Result<()> {
+        freezer::set_frozen(self.cgroup.as_ref(), self.root_pid(), true)
+    }
+
+    fn resume(&mut self) -> Result<()> {
+        freezer::set_frozen(self.cgroup.as_ref(), self.root_pid(), false)
+    }
+}
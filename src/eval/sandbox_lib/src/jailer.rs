@@ -0,0 +1,328 @@
+use crate::{error::SandboxError, vm_config::VmConfig};
+use log::debug;
+use serde::Deserialize;
+use std::{
+    fs,
+    io,
+    os::{fd::AsRawFd, unix::fs::chown},
+    path::{Path, PathBuf},
+};
+
+// Runs Firecracker under `jailer`, like in production: in a chroot, as an unprivileged user, and
+// optionally in cgroups and a network namespace of its own. E.g.
+// `"jailer": { "jailer_binary": "./jailer", "uid": 123, "gid": 100, "cgroups": ["memory.max=256M"] }`
+#[derive(Clone, Debug, Deserialize)]
+pub struct JailerConfig {
+    pub jailer_binary: String,
+    #[serde(default = "JailerConfig::default_chroot_base_dir")]
+    pub chroot_base_dir: String,
+    pub uid: u32,
+    pub gid: u32,
+    // Passed as `--cgroup`, in the kernel's syntax. Better not combined with the backend's own
+    // `cgroup`, as the jailer moves Firecracker out of it.
+    #[serde(default)]
+    pub cgroups: Vec<String>,
+    #[serde(default = "JailerConfig::default_cgroup_version")]
+    pub cgroup_version: u8,
+    // Network namespace to join, e.g. /var/run/netns/fc0. Passed as `--netns`, but rejected by the
+    // Firecracker backend, which creates its TAP devices in the host namespace.
+    pub netns: Option<String>,
+}
+
+impl JailerConfig {
+    fn default_chroot_base_dir() -> String {
+        "/srv/jailer".to_string()
+    }
+
+    fn default_cgroup_version() -> u8 {
+        2
+    }
+}
+
+// Where the jailer puts one Firecracker instance, `<chroot_base_dir>/<exec file name>/<id>/root`.
+// Computing the layout and the command line touches nothing, so they can be checked without KVM or
+// root privileges.
+pub struct Jail {
+    config: JailerConfig,
+    exec_file: String,
+    id: String,
+    // `<chroot_base_dir>/<exec file name>/<id>`, which the jailer leaves behind
+    instance_dir: PathBuf,
+    root: PathBuf,
+}
+
+// Firecracker's default, relative to the chroot
+const API_SOCKET: &str = "/run/firecracker.socket";
+
+impl Jail {
+    pub fn new(config: &JailerConfig, exec_file: &str, id: &str) -> Result<Self, SandboxError> {
+        let exec_name = Path::new(exec_file)
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| SandboxError::ConfigInvalid {
+                reason: format!("'{}' does not name a Firecracker binary", exec_file),
+            })?;
+        // The jailer refuses anything else
+        if !exec_name.starts_with("firecracker") {
+            return Err(SandboxError::ConfigInvalid {
+                reason: format!("the jailer only runs binaries named firecracker*, not {}", exec_name),
+            });
+        }
+        if id.is_empty() || id.len() > 64 || !id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+            return Err(SandboxError::ConfigInvalid {
+                reason: format!("'{}' is not a valid jail id", id),
+            });
+        }
+
+        let instance_dir = Path::new(&config.chroot_base_dir).join(exec_name).join(id);
+        Ok(Jail {
+            config: config.clone(),
+            exec_file: exec_file.to_string(),
+            id: id.to_string(),
+            root: instance_dir.join("root"),
+            instance_dir,
+        })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    // Host path of a path as Firecracker sees it inside the jail
+    pub fn host_path(&self, jailed_path: &str) -> PathBuf {
+        self.root.join(jailed_path.trim_start_matches('/'))
+    }
+
+    // Path Firecracker sees for a file in the jail, or None for files outside of it
+    pub fn jailed_path(&self, host_path: &Path) -> Option<String> {
+        let relative = host_path.strip_prefix(&self.root).ok()?;
+        Some(format!("/{}", relative.to_str()?))
+    }
+
+    pub fn api_socket(&self) -> String {
+        self.host_path(API_SOCKET).to_string_lossy().into_owned()
+    }
+
+    // Jailer command line, with `firecracker_args` passed on to Firecracker. Paths in them are
+    // relative to the jail.
+    pub fn command_line(&self, firecracker_args: &[String]) -> Vec<String> {
+        let mut args = vec![
+            self.config.jailer_binary.clone(),
+            "--id".to_string(),
+            self.id.clone(),
+            "--exec-file".to_string(),
+            self.exec_file.clone(),
+            "--uid".to_string(),
+            self.config.uid.to_string(),
+            "--gid".to_string(),
+            self.config.gid.to_string(),
+            "--chroot-base-dir".to_string(),
+            self.config.chroot_base_dir.clone(),
+            "--cgroup-version".to_string(),
+            self.config.cgroup_version.to_string(),
+        ];
+        for cgroup in &self.config.cgroups {
+            args.push("--cgroup".to_string());
+            args.push(cgroup.clone());
+        }
+        if let Some(netns) = &self.config.netns {
+            args.push("--netns".to_string());
+            args.push(netns.clone());
+        }
+        args.push("--".to_string());
+        args.push("--api-sock".to_string());
+        args.push(API_SOCKET.to_string());
+        args.extend_from_slice(firecracker_args);
+        args
+    }
+
+    // Creates the chroot, with the directory the API socket goes in
+    pub fn prepare(&self) -> Result<(), SandboxError> {
+        let run_dir = self.host_path("/run");
+        fs::create_dir_all(&run_dir).map_err(SandboxError::io(run_dir.to_string_lossy()))?;
+        self.give_to_jailed_user(&run_dir)?;
+        debug!("Jail {} prepared", self.root.display());
+        Ok(())
+    }
+
+    // Creates an empty file Firecracker can write to, e.g. its log
+    pub fn create_file(&self, name: &str) -> Result<PathBuf, SandboxError> {
+        let path = self.host_path(name);
+        fs::File::create(&path).map_err(SandboxError::io(path.to_string_lossy()))?;
+        self.give_to_jailed_user(&path)?;
+        Ok(path)
+    }
+
+    // Makes a host file visible in the jail under its file name, and returns its path in the jail.
+    // The jail gets its own copy, owned by the jailed user: a hard link would hand the host file
+    // itself to that user, and share a writable rootfs with every other jail. Where the file system
+    // supports it, the copy is a reflink that shares blocks with the host file until written to.
+    pub fn copy_file(&self, host_file: &str) -> Result<String, SandboxError> {
+        let name = Path::new(host_file)
+            .file_name()
+            .ok_or_else(|| SandboxError::ConfigInvalid {
+                reason: format!("'{}' is not a file", host_file),
+            })?;
+        let target = self.root.join(name);
+        clone_or_copy(Path::new(host_file), &target).map_err(SandboxError::io(host_file))?;
+        self.give_to_jailed_user(&target)?;
+        Ok(format!("/{}", name.to_string_lossy()))
+    }
+
+    // Copies the kernel, initrd and drives of `vm_config` into the jail and points it at them
+    pub fn move_in(&self, vm_config: &mut VmConfig) -> Result<(), SandboxError> {
+        let boot_source = &mut vm_config.boot_source;
+        boot_source.kernel_image_path = self.copy_file(&boot_source.kernel_image_path)?;
+        if let Some(initrd_path) = &boot_source.initrd_path {
+            boot_source.initrd_path = Some(self.copy_file(initrd_path)?);
+        }
+        for drive in &mut vm_config.drives {
+            drive.path_on_host = self.copy_file(&drive.path_on_host)?;
+        }
+        Ok(())
+    }
+
+    pub fn give_to_jailed_user(&self, path: &Path) -> Result<(), SandboxError> {
+        chown(path, Some(self.config.uid), Some(self.config.gid)).map_err(SandboxError::io(path.to_string_lossy()))
+    }
+
+    // Removes the chroot, and the cgroups the jailer made for the instance if any. The jailer leaves
+    // both behind.
+    pub fn remove(&self) -> Result<(), SandboxError> {
+        if !self.config.cgroups.is_empty() {
+            let exec_name = self.instance_dir.parent().and_then(|dir| dir.file_name()).unwrap_or_default();
+            let cgroup = Path::new("/sys/fs/cgroup").join(exec_name).join(&self.id);
+            if let Err(e) = fs::remove_dir(&cgroup) {
+                debug!("Failed to remove jailer cgroup {}: {}", cgroup.display(), e);
+            }
+        }
+        match fs::remove_dir_all(&self.instance_dir) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                Err(SandboxError::Io { path: self.instance_dir.to_string_lossy().into_owned(), source: e })
+            },
+            _ => Ok(()),
+        }
+    }
+}
+
+// Copies `source` to the new file `target`, as a reflink if the file system can
+fn clone_or_copy(source: &Path, target: &Path) -> io::Result<()> {
+    let mut source = fs::File::open(source)?;
+    let mut target = fs::OpenOptions::new().write(true).create_new(true).open(target)?;
+    target.set_permissions(source.metadata()?.permissions())?;
+    // SAFETY: FICLONE takes the source descriptor, and both descriptors are open
+    if unsafe { libc::ioctl(target.as_raw_fd(), libc::FICLONE, source.as_raw_fd()) } == 0 {
+        return Ok(());
+    }
+    debug!("Cannot reflink into the jail ({}), copying", io::Error::last_os_error());
+    io::copy(&mut source, &mut target)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    // A jail under a fresh chroot base in the temporary directory, owned by the current user
+    fn config(chroot_base_dir: &Path) -> JailerConfig {
+        JailerConfig {
+            jailer_binary: "/usr/bin/jailer".to_string(),
+            chroot_base_dir: chroot_base_dir.to_string_lossy().into_owned(),
+            // SAFETY: getuid and getgid cannot fail
+            uid: unsafe { libc::getuid() },
+            gid: unsafe { libc::getgid() },
+            cgroups: vec!["memory.max=256M".to_string(), "cpu.max=50000".to_string()],
+            cgroup_version: 2,
+            netns: Some("/var/run/netns/fc0".to_string()),
+        }
+    }
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("jailer-test-{}", Uuid::new_v4()));
+        fs::create_dir(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn new_checks_exec_name_and_id() {
+        let config = config(Path::new("/srv/jailer"));
+        assert!(Jail::new(&config, "/opt/fc/firecracker-v1.11", "vm-1").is_ok());
+        assert!(Jail::new(&config, "/opt/fc/cloud-hypervisor", "vm-1").is_err());
+        assert!(Jail::new(&config, "/", "vm-1").is_err());
+        assert!(Jail::new(&config, "/opt/fc/firecracker", "").is_err());
+        assert!(Jail::new(&config, "/opt/fc/firecracker", "../escape").is_err());
+        assert!(Jail::new(&config, "/opt/fc/firecracker", &"a".repeat(65)).is_err());
+        assert!(Jail::new(&config, "/opt/fc/firecracker", &"a".repeat(64)).is_ok());
+    }
+
+    #[test]
+    fn command_line_passes_everything_to_the_jailer() {
+        let config = config(Path::new("/srv/jailer"));
+        let jail = Jail::new(&config, "/opt/fc/firecracker", "vm-1").unwrap();
+        let args = jail.command_line(&["--config-file".to_string(), "/vm_config.json".to_string()]);
+        let uid = config.uid.to_string();
+        let gid = config.gid.to_string();
+        let expected = [
+            "/usr/bin/jailer",
+            "--id", "vm-1",
+            "--exec-file", "/opt/fc/firecracker",
+            "--uid", &uid,
+            "--gid", &gid,
+            "--chroot-base-dir", "/srv/jailer",
+            "--cgroup-version", "2",
+            "--cgroup", "memory.max=256M",
+            "--cgroup", "cpu.max=50000",
+            "--netns", "/var/run/netns/fc0",
+            "--",
+            "--api-sock", "/run/firecracker.socket",
+            "--config-file", "/vm_config.json",
+        ];
+        assert_eq!(args, expected);
+    }
+
+    #[test]
+    fn paths_round_trip_through_the_jail() {
+        let jail = Jail::new(&config(Path::new("/srv/jailer")), "/opt/fc/firecracker", "vm-1").unwrap();
+        assert_eq!(jail.root(), Path::new("/srv/jailer/firecracker/vm-1/root"));
+
+        let host_path = jail.host_path("/run/firecracker.socket");
+        assert_eq!(host_path, Path::new("/srv/jailer/firecracker/vm-1/root/run/firecracker.socket"));
+        assert_eq!(jail.jailed_path(&host_path).as_deref(), Some("/run/firecracker.socket"));
+        assert_eq!(jail.api_socket(), host_path.to_string_lossy());
+
+        assert_eq!(jail.jailed_path(&jail.host_path("vmlinux")).as_deref(), Some("/vmlinux"));
+        assert_eq!(jail.jailed_path(Path::new("/srv/jailer/firecracker/vm-2/root/vmlinux")), None);
+    }
+
+    #[test]
+    fn prepare_copy_and_remove() {
+        let base = temp_dir();
+        let jail = Jail::new(&config(&base), "/opt/fc/firecracker", "vm-1").unwrap();
+
+        jail.prepare().unwrap();
+        assert!(jail.host_path("/run").is_dir());
+
+        let log = jail.create_file("firecracker.log").unwrap();
+        assert_eq!(log, jail.host_path("firecracker.log"));
+        assert!(log.is_file());
+
+        let kernel = base.join("vmlinux");
+        fs::write(&kernel, b"kernel").unwrap();
+        let jailed = jail.copy_file(&kernel.to_string_lossy()).unwrap();
+        assert_eq!(jailed, "/vmlinux");
+        assert_eq!(fs::read(jail.host_path(&jailed)).unwrap(), b"kernel");
+        assert!(jail.copy_file("/").is_err());
+        // The jail has a copy of its own, so writes in it never reach the host file
+        fs::write(jail.host_path(&jailed), b"changed").unwrap();
+        assert_eq!(fs::read(&kernel).unwrap(), b"kernel");
+
+        jail.remove().unwrap();
+        assert!(!base.join("firecracker/vm-1").exists());
+        // The host file outlives its link in the jail, and removing twice is fine
+        assert!(kernel.is_file());
+        jail.remove().unwrap();
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+}
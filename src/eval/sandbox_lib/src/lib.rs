@@ -1,7 +1,30 @@
 pub mod sandbox;
+pub mod cgroup;
+pub mod container;
+pub mod cpu;
+pub mod error;
+pub mod async_sandbox;
 pub mod firecracker_snapshot;
 pub mod firecracker;
+pub mod firecracker_api;
+pub mod freezer;
 pub mod process;
+pub mod qmp;
 pub mod unikraft;
 pub mod hyperlight;
-pub mod net_lib;
\ No newline at end of file
+pub mod guard;
+pub mod hardening;
+pub mod jailer;
+pub mod memory;
+pub mod net_alloc;
+pub mod net_lib;
+pub mod netdev;
+pub mod readiness;
+pub mod registry;
+pub mod snapshot;
+pub mod spawn;
+pub mod timings;
+pub mod vm_config;
+pub mod vmm_metrics;
+pub mod wasm;
+pub mod zygote;
\ No newline at end of file
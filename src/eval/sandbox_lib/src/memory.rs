@@ -0,0 +1,102 @@
+use anyhow::Result;
+use std::{collections::HashMap, fs, ops::AddAssign};
+
+// Memory used by a sandbox, in KiB, as reported by `/proc/<pid>/smaps_rollup`. RSS double counts
+// pages shared between processes of the same tree; PSS splits them, and USS only counts the pages
+// that are private to the tree's processes.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MemoryUsage {
+    pub rss_kb: u64,
+    pub pss_kb: u64,
+    pub uss_kb: u64,
+    pub swap_kb: u64,
+}
+
+impl AddAssign for MemoryUsage {
+    fn add_assign(&mut self, other: Self) {
+        self.rss_kb += other.rss_kb;
+        self.pss_kb += other.pss_kb;
+        self.uss_kb += other.uss_kb;
+        self.swap_kb += other.swap_kb;
+    }
+}
+
+pub fn read_smaps_rollup(pid: u32) -> Result<MemoryUsage> {
+    let rollup = fs::read_to_string(format!("/proc/{}/smaps_rollup", pid))?;
+    let mut usage = MemoryUsage::default();
+    for line in rollup.lines() {
+        let mut fields = line.split_whitespace();
+        let (Some(key), Some(value)) = (fields.next(), fields.next()) else {
+            continue;
+        };
+        let Ok(value) = value.parse::<u64>() else {
+            continue;
+        };
+        match key {
+            "Rss:" => usage.rss_kb = value,
+            "Pss:" => usage.pss_kb = value,
+            "Private_Clean:" | "Private_Dirty:" => usage.uss_kb += value,
+            "Swap:" => usage.swap_kb = value,
+            _ => {},
+        }
+    }
+    Ok(usage)
+}
+
+// Returns `root` followed by all of its descendants, e.g. the qemu process started by kraft.
+pub fn process_tree(root: u32) -> Result<Vec<u32>> {
+    let mut children: HashMap<u32, Vec<u32>> = HashMap::new();
+    for entry in fs::read_dir("/proc")? {
+        let Some(pid) = entry?.file_name().to_str().and_then(|name| name.parse::<u32>().ok())
+        else {
+            continue;
+        };
+        // Processes may exit while we scan
+        let Ok(stat) = fs::read_to_string(format!("/proc/{}/stat", pid)) else {
+            continue;
+        };
+        // The command name may contain spaces, so parse after its closing parenthesis
+        let Some(ppid) = stat
+            .rsplit_once(')')
+            .and_then(|(_, rest)| rest.split_whitespace().nth(1))
+            .and_then(|ppid| ppid.parse::<u32>().ok())
+        else {
+            continue;
+        };
+        children.entry(ppid).or_default().push(pid);
+    }
+
+    let mut tree = vec![root];
+    let mut next = 0;
+    while next < tree.len() {
+        if let Some(pids) = children.get(&tree[next]) {
+            tree.extend(pids);
+        }
+        next += 1;
+    }
+    Ok(tree)
+}
+
+// Memory used by the whole process tree of a sandbox.
+pub fn tree_memory_usage(root: u32) -> Result<MemoryUsage> {
+    let mut usage = MemoryUsage::default();
+    for pid in process_tree(root)? {
+        // Kernel threads and exited processes have no rollup
+        if let Ok(process_usage) = read_smaps_rollup(pid) {
+            usage += process_usage;
+        }
+    }
+    Ok(usage)
+}
+
+// System wide free memory, in MiB, as reported by `/proc/meminfo`.
+pub fn system_free_memory_mb() -> Result<u64> {
+    let meminfo = fs::read_to_string("/proc/meminfo")?;
+    let mem_free_kb = meminfo
+        .lines()
+        .find(|line| line.starts_with("MemFree:"))
+        .and_then(|line| line.split_whitespace().nth(1))
+        .ok_or_else(|| anyhow::anyhow!("Failed to get free memory"))?
+        .parse::<u64>()?;
+    Ok(mem_free_kb / 1024)
+}
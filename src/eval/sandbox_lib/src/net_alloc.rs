@@ -0,0 +1,282 @@
+use crate::error::SandboxError;
+use log::debug;
+use serde::Deserialize;
+use std::{
+    collections::{BTreeSet, HashMap},
+    io,
+    net::Ipv4Addr,
+    sync::{Arc, LazyLock, Mutex},
+};
+
+// Pool guest networks are carved from, e.g. `"address_pool": { "cidr": "10.0.0.0/8" }`. Every guest
+// gets its own subnet, with the host (TAP) address first and the guest address second.
+#[derive(Clone, Debug, Deserialize)]
+pub struct AddressPoolConfig {
+    #[serde(default = "AddressPoolConfig::default_cidr")]
+    pub cidr: String,
+    #[serde(default = "AddressPoolConfig::default_subnet_prefix_len")]
+    pub subnet_prefix_len: u8,
+}
+
+impl AddressPoolConfig {
+    fn default_cidr() -> String {
+        "172.16.0.0/16".to_string()
+    }
+
+    fn default_subnet_prefix_len() -> u8 {
+        30
+    }
+}
+
+impl Default for AddressPoolConfig {
+    fn default() -> Self {
+        AddressPoolConfig {
+            cidr: Self::default_cidr(),
+            subnet_prefix_len: Self::default_subnet_prefix_len(),
+        }
+    }
+}
+
+// Hands out non-overlapping subnets of a pool. Subnets holding an address already configured on the
+// host are skipped, so that runs do not clash with each other or with leftovers of a crashed run.
+pub struct NetworkAllocator {
+    network: u32,
+    prefix_len: u8,
+    subnet_prefix_len: u8,
+    // Indexes of the subnets in use
+    leased: Mutex<BTreeSet<u32>>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Subnet {
+    pub prefix_len: u8,
+    pub host_ip: Ipv4Addr,
+    pub guest_ip: Ipv4Addr,
+    // Locally administered MAC address that embeds the guest address
+    pub mac: String,
+}
+
+impl Subnet {
+    pub fn new(host_ip: Ipv4Addr, guest_ip: Ipv4Addr, prefix_len: u8) -> Self {
+        let [a, b, c, d] = guest_ip.octets();
+        Subnet {
+            prefix_len,
+            host_ip,
+            guest_ip,
+            mac: format!("06:00:{:02X}:{:02X}:{:02X}:{:02X}", a, b, c, d),
+        }
+    }
+}
+
+// A subnet taken from a `NetworkAllocator`, given back when dropped
+pub struct Lease {
+    allocator: Arc<NetworkAllocator>,
+    index: u32,
+    pub subnet: Subnet,
+}
+
+// Network, prefix length and subnet prefix length
+type PoolKey = (u32, u8, u8);
+
+// One allocator per pool for the whole process, so that every sandbox draws from the same leases
+static ALLOCATORS: LazyLock<Mutex<HashMap<PoolKey, Arc<NetworkAllocator>>>> = LazyLock::new(Default::default);
+
+impl NetworkAllocator {
+    pub fn new(config: &AddressPoolConfig) -> Result<Self, SandboxError> {
+        let invalid = |reason: String| SandboxError::ConfigInvalid { reason };
+        let (address, prefix_len) = config
+            .cidr
+            .split_once('/')
+            .ok_or_else(|| invalid(format!("address pool '{}' is not in CIDR notation", config.cidr)))?;
+        let address: Ipv4Addr = address
+            .parse()
+            .map_err(|e| invalid(format!("bad address pool '{}': {}", config.cidr, e)))?;
+        let prefix_len: u8 = prefix_len
+            .parse()
+            .map_err(|e| invalid(format!("bad address pool '{}': {}", config.cidr, e)))?;
+        // A subnet needs room for the host and the guest addresses
+        if prefix_len > config.subnet_prefix_len || config.subnet_prefix_len > 30 {
+            return Err(invalid(format!(
+                "cannot split {} into /{} subnets",
+                config.cidr, config.subnet_prefix_len
+            )));
+        }
+
+        Ok(NetworkAllocator {
+            network: u32::from(address) & mask(prefix_len),
+            prefix_len,
+            subnet_prefix_len: config.subnet_prefix_len,
+            leased: Mutex::new(BTreeSet::new()),
+        })
+    }
+
+    pub fn shared(config: &AddressPoolConfig) -> Result<Arc<Self>, SandboxError> {
+        let allocator = Self::new(config)?;
+        let key = (allocator.network, allocator.prefix_len, allocator.subnet_prefix_len);
+        let mut allocators = ALLOCATORS.lock().unwrap_or_else(|e| e.into_inner());
+        Ok(allocators.entry(key).or_insert_with(|| Arc::new(allocator)).clone())
+    }
+
+    // Takes the lowest free subnet
+    pub fn allocate(self: &Arc<Self>) -> Result<Lease, SandboxError> {
+        let host_addresses = host_addresses().map_err(|e| SandboxError::NetworkSetupFailed {
+            reason: format!("cannot list host addresses: {}", e),
+        })?;
+        self.allocate_avoiding(&host_addresses)
+    }
+
+    // Takes the lowest free subnet that holds none of `host_addresses`
+    fn allocate_avoiding(self: &Arc<Self>, host_addresses: &[u32]) -> Result<Lease, SandboxError> {
+        let mut leased = self.leased.lock().unwrap_or_else(|e| e.into_inner());
+        let subnets = 1u64 << (self.subnet_prefix_len - self.prefix_len);
+        for index in 0..subnets {
+            let index = index as u32;
+            if leased.contains(&index) {
+                continue;
+            }
+            let subnet = self.subnet(index);
+            let size = 1u32 << (32 - self.subnet_prefix_len);
+            if let Some(address) = host_addresses.iter().find(|address| address.wrapping_sub(subnet) < size) {
+                debug!("Skipping {}/{}, {} is in use on the host", Ipv4Addr::from(subnet), self.subnet_prefix_len, Ipv4Addr::from(*address));
+                continue;
+            }
+
+            leased.insert(index);
+            return Ok(Lease {
+                allocator: self.clone(),
+                index,
+                subnet: Subnet::new(Ipv4Addr::from(subnet + 1), Ipv4Addr::from(subnet + 2), self.subnet_prefix_len),
+            });
+        }
+
+        Err(SandboxError::NetworkSetupFailed {
+            reason: format!(
+                "address pool {}/{} has no free /{} subnet left",
+                Ipv4Addr::from(self.network),
+                self.prefix_len,
+                self.subnet_prefix_len
+            ),
+        })
+    }
+
+    fn subnet(&self, index: u32) -> u32 {
+        self.network + (index << (32 - self.subnet_prefix_len))
+    }
+
+    fn release(&self, index: u32) {
+        let mut leased = self.leased.lock().unwrap_or_else(|e| e.into_inner());
+        leased.remove(&index);
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        debug!("Releasing {}/{}", self.subnet.guest_ip, self.subnet.prefix_len);
+        self.allocator.release(self.index);
+    }
+}
+
+fn mask(prefix_len: u8) -> u32 {
+    u32::MAX.checked_shl(32 - prefix_len as u32).unwrap_or(0)
+}
+
+// IPv4 addresses configured on any host interface
+fn host_addresses() -> io::Result<Vec<u32>> {
+    let mut addresses: *mut libc::ifaddrs = std::ptr::null_mut();
+    // SAFETY: getifaddrs fills `addresses` with a list we free below
+    if unsafe { libc::getifaddrs(&mut addresses) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut result = Vec::new();
+    let mut current = addresses;
+    while !current.is_null() {
+        // SAFETY: `current` is an element of the list returned by getifaddrs
+        let entry = unsafe { &*current };
+        if !entry.ifa_addr.is_null() && unsafe { (*entry.ifa_addr).sa_family } as i32 == libc::AF_INET {
+            // SAFETY: AF_INET addresses are sockaddr_in
+            let address = unsafe { &*(entry.ifa_addr as *const libc::sockaddr_in) };
+            result.push(u32::from_be(address.sin_addr.s_addr));
+        }
+        current = entry.ifa_next;
+    }
+    // SAFETY: `addresses` was returned by getifaddrs and is not used afterwards
+    unsafe { libc::freeifaddrs(addresses) };
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allocator(cidr: &str, subnet_prefix_len: u8) -> Arc<NetworkAllocator> {
+        let config = AddressPoolConfig { cidr: cidr.to_string(), subnet_prefix_len };
+        Arc::new(NetworkAllocator::new(&config).unwrap())
+    }
+
+    fn address(address: &str) -> u32 {
+        u32::from(address.parse::<Ipv4Addr>().unwrap())
+    }
+
+    #[test]
+    fn subnets_follow_each_other() {
+        let pool = allocator("10.200.0.7/24", 30);
+        assert_eq!(pool.network, address("10.200.0.0"));
+        assert_eq!(pool.subnet(0), address("10.200.0.0"));
+        assert_eq!(pool.subnet(1), address("10.200.0.4"));
+        // The last subnet of the pool ends with its broadcast address
+        assert_eq!(pool.subnet(63), address("10.200.0.252"));
+
+        let leases: Vec<Lease> = (0..64).map(|_| pool.allocate_avoiding(&[]).unwrap()).collect();
+        let last = &leases[63].subnet;
+        assert_eq!((last.host_ip, last.guest_ip), ("10.200.0.253".parse().unwrap(), "10.200.0.254".parse().unwrap()));
+        assert_eq!(last.prefix_len, 30);
+
+        let wide = allocator("10.0.0.0/8", 24);
+        assert_eq!(wide.subnet(257), address("10.1.1.0"));
+    }
+
+    #[test]
+    fn skips_leased_and_host_subnets() {
+        let pool = allocator("172.16.0.0/28", 30);
+        let first = pool.allocate_avoiding(&[]).unwrap();
+        assert_eq!(first.subnet.guest_ip, Ipv4Addr::new(172, 16, 0, 2));
+        // The second subnet holds a host address, e.g. left over from a crashed run
+        let second = pool.allocate_avoiding(&[address("172.16.0.6"), address("10.0.0.1")]).unwrap();
+        assert_eq!(second.subnet.guest_ip, Ipv4Addr::new(172, 16, 0, 10));
+        let third = pool.allocate_avoiding(&[]).unwrap();
+        assert_eq!(third.subnet.guest_ip, Ipv4Addr::new(172, 16, 0, 6));
+    }
+
+    #[test]
+    fn exhaustion_and_release() {
+        let pool = allocator("172.16.0.0/29", 30);
+        let first = pool.allocate_avoiding(&[]).unwrap();
+        let second = pool.allocate_avoiding(&[]).unwrap();
+        assert!(matches!(pool.allocate_avoiding(&[]), Err(SandboxError::NetworkSetupFailed { .. })));
+
+        // Dropping a lease gives its subnet back
+        let guest_ip = first.subnet.guest_ip;
+        drop(first);
+        assert_eq!(pool.allocate_avoiding(&[]).unwrap().subnet.guest_ip, guest_ip);
+        drop(second);
+        assert!(pool.leased.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn mac_embeds_the_guest_address() {
+        let subnet = Subnet::new(Ipv4Addr::new(10, 200, 0, 1), Ipv4Addr::new(10, 200, 0, 254), 30);
+        assert_eq!(subnet.mac, "06:00:0A:C8:00:FE");
+    }
+
+    #[test]
+    fn rejects_pools_that_cannot_be_split() {
+        let new = |cidr: &str, subnet_prefix_len| {
+            NetworkAllocator::new(&AddressPoolConfig { cidr: cidr.to_string(), subnet_prefix_len })
+        };
+        assert!(new("10.0.0.0/24", 24).is_ok());
+        assert!(matches!(new("10.0.0.0/24", 16), Err(SandboxError::ConfigInvalid { .. })));
+        assert!(matches!(new("10.0.0.0/24", 31), Err(SandboxError::ConfigInvalid { .. })));
+        assert!(matches!(new("10.0.0.0", 30), Err(SandboxError::ConfigInvalid { .. })));
+        assert!(matches!(new("10.0.0.300/24", 30), Err(SandboxError::ConfigInvalid { .. })));
+    }
+}
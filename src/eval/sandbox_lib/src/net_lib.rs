@@ -1,25 +1,36 @@
-use std::{net::TcpStream, thread::sleep, time::Duration};
+use crate::error::SandboxError;
 use log::debug;
+use std::{net::TcpStream, path::Path, process::Command, thread::sleep, time::{Duration, Instant}};
 
 pub fn check_port(ip: &str, port: u16) -> bool {
     let address = format!("{}:{}", ip, port);
     TcpStream::connect_timeout(&address.parse().unwrap(), Duration::from_millis(1)).is_ok() 
 }
 
-pub fn wait_for_port(ip: &str, port: u16) -> bool {
-    let max_retries = 10000;
-    let mut retries = 0;
-    debug!("Waiting for port {} in ip {} to open", port, ip);
-    while !check_port(ip, port) {
-        sleep(Duration::from_millis(1));
-        retries += 1;
-        if retries > max_retries {
-            debug!("Port {} is not open after {} retries", port, retries);
+// Waits for a Unix socket (e.g. a VMM API socket) to be created
+pub fn wait_for_unix_socket(path: &str, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    while !Path::new(path).exists() {
+        if Instant::now() >= deadline {
             return false;
         }
+        sleep(Duration::from_micros(100));
     }
-    debug!("Port {} is open after {} retries", port, retries);
-
     true
 }
 
+// Runs a network setup or cleanup script, failing if it cannot run or exits with an error
+pub fn run_network_script(command: &mut Command) -> Result<(), SandboxError> {
+    let output = command.output().map_err(|e| SandboxError::NetworkSetupFailed {
+        reason: format!("cannot run {:?}: {}", command, e),
+    })?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    debug!("Network script {:?} executed with output: {} and error: {}", command, stdout, stderr);
+    if !output.status.success() {
+        return Err(SandboxError::NetworkSetupFailed {
+            reason: format!("{:?} exited with {}: {}", command, output.status, stderr.trim()),
+        });
+    }
+    Ok(())
+}
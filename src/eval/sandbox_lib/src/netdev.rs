@@ -0,0 +1,1211 @@
+use crate::{error::SandboxError, net_alloc::Subnet};
+use log::debug;
+use serde::Deserialize;
+use std::{
+    collections::HashSet,
+    ffi::CString,
+    fs::{self, OpenOptions},
+    io,
+    mem,
+    net::Ipv4Addr,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    time::Duration,
+};
+
+// Manages the host side of a sandbox's network in-process: TAP and veth devices and their addresses
+// through rtnetlink, and the nft rules that let guests reach the outside through nf_tables netlink.
+// In dry-run mode nothing is changed, and every change is printed instead.
+pub struct NetDev {
+    dry_run: bool,
+}
+
+// How a backend sets up the host side of its guest network, e.g. `"netdev": { "host_iface": "eth0" }`
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct NetDevConfig {
+    // Interface guests reach the outside through, defaults to the one of the default route
+    pub host_iface: Option<String>,
+    // Only print the changes, e.g. to check a config or on a host that is already set up
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+// The nft table and chains the rules go to, created if needed
+const NFT_TABLE: &str = "firecracker";
+const NFT_NAT_CHAIN: &str = "postrouting";
+const NFT_FILTER_CHAIN: &str = "filter";
+// Table of the NAT rules inside a clone's namespace, which goes away with the namespace
+const NFT_CLONE_TABLE: &str = "clone";
+
+// Where named network namespaces are bind mounted, like `ip netns add` does
+const NETNS_DIR: &str = "/var/run/netns";
+// The namespace end of a clone's veth pair
+const CLONE_VETH: &str = "veth0";
+
+// Handles of the rules added by `add_nat_rules`, `None` in dry-run mode
+#[derive(Clone, Debug, Default)]
+pub struct NatRules {
+    masquerade: Option<u64>,
+    forward: Option<u64>,
+}
+
+// A TAP device with its own address and the address of the guest behind it
+#[derive(Clone, Debug)]
+pub struct GuestTap<'a> {
+    pub name: &'a str,
+    pub tap_ip: Ipv4Addr,
+    pub prefix_len: u8,
+    pub guest_ip: Ipv4Addr,
+}
+
+// What `setup_tap` changed, so that `cleanup_tap` can undo it
+#[derive(Clone, Debug)]
+pub struct TapNetwork {
+    pub tap: String,
+    pub nat: NatRules,
+}
+
+impl NetDev {
+    pub fn new(dry_run: bool) -> Self {
+        NetDev { dry_run }
+    }
+
+    pub fn from_config(config: Option<&NetDevConfig>) -> Self {
+        Self::new(config.is_some_and(|config| config.dry_run))
+    }
+
+    // `setup_tap` with the configured host interface, as the Firecracker backends use it
+    pub fn setup_guest_tap(
+        config: Option<&NetDevConfig>,
+        tap: &str,
+        tap_ip: &str,
+        prefix_len: u8,
+        guest_ip: &str,
+    ) -> Result<TapNetwork, SandboxError> {
+        let host_iface = match config.and_then(|config| config.host_iface.clone()) {
+            Some(host_iface) => host_iface,
+            None => default_route_interface()?,
+        };
+        let parse = |ip: &str| {
+            ip.parse::<Ipv4Addr>().map_err(|e| SandboxError::ConfigInvalid {
+                reason: format!("bad address '{}': {}", ip, e),
+            })
+        };
+        Self::from_config(config).setup_tap(tap, parse(tap_ip)?, prefix_len, parse(guest_ip)?, &host_iface)
+    }
+
+    // Creates `tap` with `tap_ip/prefix_len`, brings it up and lets `guest_ip` out through
+    // `host_iface`, like scripts/firecracker/setup_network.sh
+    pub fn setup_tap(
+        &self,
+        tap: &str,
+        tap_ip: Ipv4Addr,
+        prefix_len: u8,
+        guest_ip: Ipv4Addr,
+        host_iface: &str,
+    ) -> Result<TapNetwork, SandboxError> {
+        self.create_tap(tap)?;
+        let nat = self
+            .add_address(tap, tap_ip, prefix_len)
+            .and_then(|_| self.set_up(tap))
+            .and_then(|_| self.add_nat_rules(tap, guest_ip, host_iface));
+        match nat {
+            Ok(nat) => Ok(TapNetwork { tap: tap.to_string(), nat }),
+            Err(e) => {
+                // Do not leave a half configured device behind
+                if let Err(delete_error) = self.delete_link(tap) {
+                    debug!("Failed to delete {} after failed setup: {}", tap, delete_error);
+                }
+                Err(e)
+            },
+        }
+    }
+
+    // Removes the rules and the device. Every step is attempted, and the first error is returned.
+    pub fn cleanup_tap(&self, network: &TapNetwork) -> Result<(), SandboxError> {
+        let rules = self.delete_nat_rules(&network.nat);
+        let link = self.delete_link(&network.tap);
+        rules.and(link)
+    }
+
+    pub fn create_tap(&self, name: &str) -> Result<(), SandboxError> {
+        self.apply(&format!("create TAP device {}", name), || create_persistent_tap(name))
+            .map(|_| ())
+    }
+
+    // Creates the veth pair `name` and `peer`, with `peer` right in the network namespace of process
+    // `pid`, e.g. a container's
+    pub fn create_veth(&self, name: &str, peer: &str, pid: u32) -> Result<(), SandboxError> {
+        let change = format!("create veth pair {} and {} in the namespace of {}", name, peer, pid);
+        self.apply(&change, || create_veth_pair(name, peer, IFLA_NET_NS_PID, pid))
+            .map(|_| ())
+    }
+
+    // Same as `create_veth`, with `peer` in the named network namespace `netns`
+    pub fn create_veth_in_netns(&self, name: &str, peer: &str, netns: &str) -> Result<(), SandboxError> {
+        let change = format!("create veth pair {} and {} in namespace {}", name, peer, netns);
+        self.apply(&change, || {
+            let namespace = fs::File::open(netns_path(netns))?;
+            create_veth_pair(name, peer, IFLA_NET_NS_FD, namespace.as_raw_fd() as u32)
+        })
+        .map(|_| ())
+    }
+
+    pub fn delete_link(&self, name: &str) -> Result<(), SandboxError> {
+        self.apply(&format!("delete device {}", name), || {
+            let index = link_index(name)?;
+            let mut socket = NetlinkSocket::open(libc::NETLINK_ROUTE)?;
+            let mut message = Message::new(RTM_DELLINK, NLM_F_REQUEST | NLM_F_ACK, socket.next_seq());
+            message.header(&ifinfomsg(index, 0, 0));
+            socket.transact(&[message])?;
+            Ok(())
+        })
+        .map(|_| ())
+    }
+
+    pub fn add_address(&self, name: &str, address: Ipv4Addr, prefix_len: u8) -> Result<(), SandboxError> {
+        self.apply(&format!("add {}/{} to {}", address, prefix_len, name), || {
+            let index = link_index(name)?;
+            let mut socket = NetlinkSocket::open(libc::NETLINK_ROUTE)?;
+            let flags = NLM_F_REQUEST | NLM_F_ACK | NLM_F_CREATE | NLM_F_EXCL;
+            let mut message = Message::new(RTM_NEWADDR, flags, socket.next_seq());
+            // struct ifaddrmsg
+            let mut ifaddrmsg = vec![libc::AF_INET as u8, prefix_len, 0, 0];
+            ifaddrmsg.extend_from_slice(&index.to_ne_bytes());
+            message.header(&ifaddrmsg);
+            message.attr(IFA_LOCAL, &address.octets());
+            message.attr(IFA_ADDRESS, &address.octets());
+            socket.transact(&[message])?;
+            Ok(())
+        })
+        .map(|_| ())
+    }
+
+    pub fn set_up(&self, name: &str) -> Result<(), SandboxError> {
+        self.apply(&format!("bring {} up", name), || {
+            let index = link_index(name)?;
+            let mut socket = NetlinkSocket::open(libc::NETLINK_ROUTE)?;
+            let mut message = Message::new(RTM_NEWLINK, NLM_F_REQUEST | NLM_F_ACK, socket.next_seq());
+            let up = libc::IFF_UP as u32;
+            message.header(&ifinfomsg(index, up, up));
+            socket.transact(&[message])?;
+            Ok(())
+        })
+        .map(|_| ())
+    }
+
+    // Adds `ip saddr <guest_ip> oifname <host_iface> counter masquerade` to the nat chain and
+    // `iifname <tap> oifname <host_iface> accept` to the filter chain
+    pub fn add_nat_rules(&self, tap: &str, guest_ip: Ipv4Addr, host_iface: &str) -> Result<NatRules, SandboxError> {
+        let change = format!("let {} out through {} from {}", guest_ip, host_iface, tap);
+        let rules = self.apply(&change, || {
+            let mut socket = NetlinkSocket::open(libc::NETLINK_NETFILTER)?;
+            let mut batch = vec![Message::batch(NFNL_MSG_BATCH_BEGIN, socket.next_seq())];
+
+            let create = NLM_F_REQUEST | NLM_F_ACK | NLM_F_CREATE;
+            let mut table = Message::nft(NFT_MSG_NEWTABLE, create, socket.next_seq());
+            table.attr_str(NFTA_TABLE_NAME, NFT_TABLE);
+            batch.push(table);
+            batch.push(base_chain(socket.next_seq(), NFT_TABLE, NFT_NAT_CHAIN, "nat", NF_INET_POST_ROUTING, NF_IP_PRI_NAT_SRC));
+            batch.push(base_chain(socket.next_seq(), NFT_TABLE, NFT_FILTER_CHAIN, "filter", NF_INET_FORWARD, NF_IP_PRI_FILTER));
+
+            let append = create | NLM_F_APPEND | NLM_F_ECHO;
+            let mut masquerade = Message::nft(NFT_MSG_NEWRULE, append, socket.next_seq());
+            masquerade.attr_str(NFTA_RULE_TABLE, NFT_TABLE);
+            masquerade.attr_str(NFTA_RULE_CHAIN, NFT_NAT_CHAIN);
+            masquerade.nested(NFTA_RULE_EXPRESSIONS, |expressions| {
+                expr_payload_ipv4(expressions, IPV4_SADDR_OFFSET);
+                expr_cmp_eq(expressions, &guest_ip.octets());
+                expr_meta(expressions, NFT_META_OIFNAME);
+                expr_cmp_eq(expressions, &ifname_bytes(host_iface));
+                expr_counter(expressions);
+                expressions.nested(NFTA_LIST_ELEM, |expression| {
+                    expression.attr_str(NFTA_EXPR_NAME, "masq");
+                });
+            });
+            batch.push(masquerade);
+
+            let mut forward = Message::nft(NFT_MSG_NEWRULE, append, socket.next_seq());
+            forward.attr_str(NFTA_RULE_TABLE, NFT_TABLE);
+            forward.attr_str(NFTA_RULE_CHAIN, NFT_FILTER_CHAIN);
+            forward.nested(NFTA_RULE_EXPRESSIONS, |expressions| {
+                expr_meta(expressions, NFT_META_IIFNAME);
+                expr_cmp_eq(expressions, &ifname_bytes(tap));
+                expr_meta(expressions, NFT_META_OIFNAME);
+                expr_cmp_eq(expressions, &ifname_bytes(host_iface));
+                expr_accept(expressions);
+            });
+            batch.push(forward);
+
+            batch.push(Message::batch(NFNL_MSG_BATCH_END, socket.next_seq()));
+            let echoed = socket.transact(&batch)?;
+
+            // The kernel echoes the rules back with the handles it gave them
+            let mut rules = NatRules::default();
+            for (kind, payload) in echoed {
+                if kind != nft_type(NFT_MSG_NEWRULE) {
+                    continue;
+                }
+                let attributes = parse_attributes(payload.get(NFGENMSG_LEN..).unwrap_or_default());
+                let chain = attributes.iter().find(|(kind, _)| *kind == NFTA_RULE_CHAIN);
+                let handle = attributes
+                    .iter()
+                    .find(|(kind, _)| *kind == NFTA_RULE_HANDLE)
+                    .and_then(|(_, value)| value.as_slice().try_into().ok())
+                    .map(u64::from_be_bytes);
+                match chain.map(|(_, name)| name.as_slice()) {
+                    Some(name) if name.starts_with(NFT_NAT_CHAIN.as_bytes()) => rules.masquerade = handle,
+                    Some(name) if name.starts_with(NFT_FILTER_CHAIN.as_bytes()) => rules.forward = handle,
+                    _ => {},
+                }
+            }
+            if rules.masquerade.is_none() || rules.forward.is_none() {
+                return Err(io::Error::other("kernel did not report the handles of the new rules"));
+            }
+            Ok(rules)
+        })?;
+        Ok(rules.unwrap_or_default())
+    }
+
+    pub fn delete_nat_rules(&self, rules: &NatRules) -> Result<(), SandboxError> {
+        let handles = [(NFT_NAT_CHAIN, rules.masquerade), (NFT_FILTER_CHAIN, rules.forward)];
+        for (chain, handle) in handles {
+            let Some(handle) = handle else {
+                continue;
+            };
+            self.apply(&format!("delete rule {} from {} {}", handle, NFT_TABLE, chain), || {
+                let mut socket = NetlinkSocket::open(libc::NETLINK_NETFILTER)?;
+                let mut rule = Message::nft(NFT_MSG_DELRULE, NLM_F_REQUEST | NLM_F_ACK, socket.next_seq());
+                rule.attr_str(NFTA_RULE_TABLE, NFT_TABLE);
+                rule.attr_str(NFTA_RULE_CHAIN, chain);
+                rule.attr(NFTA_RULE_HANDLE, &handle.to_be_bytes());
+                let batch = [
+                    Message::batch(NFNL_MSG_BATCH_BEGIN, socket.next_seq()),
+                    rule,
+                    Message::batch(NFNL_MSG_BATCH_END, socket.next_seq()),
+                ];
+                socket.transact(&batch)?;
+                Ok(())
+            })?;
+        }
+        Ok(())
+    }
+
+    // Creates the network namespace `name` and keeps it alive with a bind mount under
+    // /var/run/netns, like `ip netns add`
+    pub fn create_netns(&self, name: &str) -> Result<(), SandboxError> {
+        self.apply(&format!("create network namespace {}", name), || {
+            fs::create_dir_all(NETNS_DIR)?;
+            let path = netns_path(name);
+            OpenOptions::new().write(true).create_new(true).open(&path)?;
+            let mounted = std::thread::scope(|scope| {
+                scope
+                    .spawn(|| unshare_and_mount_netns(&path))
+                    .join()
+                    .unwrap_or_else(|_| Err(io::Error::other("namespace creation panicked")))
+            });
+            if mounted.is_err() {
+                let _ = fs::remove_file(&path);
+            }
+            mounted
+        })
+        .map(|_| ())
+    }
+
+    // Removes the network namespace `name`. The kernel deletes its devices and rules once nothing
+    // runs in it anymore.
+    pub fn delete_netns(&self, name: &str) -> Result<(), SandboxError> {
+        self.apply(&format!("delete network namespace {}", name), || {
+            let path = netns_path(name);
+            let c_path = CString::new(path.clone()).map_err(io::Error::other)?;
+            // SAFETY: `c_path` is a valid NUL terminated string
+            if unsafe { libc::umount2(c_path.as_ptr(), libc::MNT_DETACH) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            fs::remove_file(&path)
+        })
+        .map(|_| ())
+    }
+
+    // Gives a restored snapshot clone the network namespace `netns`, where it finds the TAP device it
+    // was snapshotted with. A veth pair from `host_veth` takes the addresses of `subnet`, and NAT maps
+    // the guest to the namespace end's address, the one the host reaches the clone at.
+    pub fn setup_clone_namespace(
+        &self,
+        netns: &str,
+        host_veth: &str,
+        subnet: &Subnet,
+        tap: &GuestTap,
+    ) -> Result<(), SandboxError> {
+        self.create_netns(netns)?;
+        let result = self
+            .create_veth_in_netns(host_veth, CLONE_VETH, netns)
+            .and_then(|_| self.add_address(host_veth, subnet.host_ip, subnet.prefix_len))
+            .and_then(|_| self.set_up(host_veth))
+            .and_then(|_| {
+                self.in_netns(netns, || {
+                    self.set_up("lo")?;
+                    self.create_tap(tap.name)?;
+                    self.add_address(tap.name, tap.tap_ip, tap.prefix_len)?;
+                    self.set_up(tap.name)?;
+                    self.add_address(CLONE_VETH, subnet.guest_ip, subnet.prefix_len)?;
+                    self.set_up(CLONE_VETH)?;
+                    self.enable_forwarding()?;
+                    self.add_clone_nat_rules(subnet.guest_ip, tap.guest_ip)
+                })
+            });
+        if let Err(e) = result {
+            // Do not leave a half configured namespace behind
+            if let Err(cleanup_error) = self.cleanup_clone_namespace(netns, host_veth) {
+                debug!("Failed to clean up {} after failed setup: {}", netns, cleanup_error);
+            }
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    // Deleting the namespace deletes the TAP device, the NAT rules and the namespace end of the veth
+    // pair. The host end goes with its peer, unless something keeps the namespace alive.
+    pub fn cleanup_clone_namespace(&self, netns: &str, host_veth: &str) -> Result<(), SandboxError> {
+        let namespace = self.delete_netns(netns);
+        if !self.dry_run && link_index(host_veth).is_ok() {
+            return namespace.and(self.delete_link(host_veth));
+        }
+        namespace
+    }
+
+    // Lets the current network namespace route between its devices
+    pub fn enable_forwarding(&self) -> Result<(), SandboxError> {
+        self.apply("enable IPv4 forwarding", || fs::write("/proc/sys/net/ipv4/ip_forward", "1"))
+            .map(|_| ())
+    }
+
+    // Adds `iifname veth0 ip daddr <clone_ip> counter dnat to <guest_ip>` to a prerouting chain and
+    // `oifname veth0 ip saddr <guest_ip> counter snat to <clone_ip>` to a postrouting chain, in a
+    // table of the current network namespace
+    pub fn add_clone_nat_rules(&self, clone_ip: Ipv4Addr, guest_ip: Ipv4Addr) -> Result<(), SandboxError> {
+        let change = format!("map {} to {} through {}", clone_ip, guest_ip, CLONE_VETH);
+        self.apply(&change, || {
+            let mut socket = NetlinkSocket::open(libc::NETLINK_NETFILTER)?;
+            let batch = clone_nat_batch(&mut socket, clone_ip, guest_ip);
+            socket.transact(&batch)?;
+            Ok(())
+        })
+        .map(|_| ())
+    }
+
+    // Runs `f` in the named network namespace `netns`. In dry-run mode the namespace does not exist,
+    // and `f` only prints its changes anyway.
+    fn in_netns<T: Send>(&self, netns: &str, f: impl FnOnce() -> Result<T, SandboxError> + Send) -> Result<T, SandboxError> {
+        if self.dry_run {
+            return f();
+        }
+        in_namespace_file(&netns_path(netns), f)
+    }
+
+    // Runs `change`, or only prints it in dry-run mode
+    fn apply<T>(&self, change: &str, f: impl FnOnce() -> io::Result<T>) -> Result<Option<T>, SandboxError> {
+        if self.dry_run {
+            eprintln!("[dry-run] {}", change);
+            return Ok(None);
+        }
+        debug!("Going to {}", change);
+        f().map(Some).map_err(|e| SandboxError::NetworkSetupFailed {
+            reason: format!("cannot {}: {}", change, e),
+        })
+    }
+}
+
+// Name of the interface the default route goes through, read from /proc/net/route
+pub fn default_route_interface() -> Result<String, SandboxError> {
+    let path = "/proc/net/route";
+    let routes = fs::read_to_string(path).map_err(SandboxError::io(path))?;
+    routes
+        .lines()
+        .skip(1)
+        .map(|line| line.split_whitespace().collect::<Vec<_>>())
+        // Iface Destination Gateway Flags RefCnt Use Metric Mask ...
+        .find(|fields| fields.len() > 7 && fields[1] == "00000000" && fields[7] == "00000000")
+        .map(|fields| fields[0].to_string())
+        .ok_or_else(|| SandboxError::NetworkSetupFailed {
+            reason: "no default route to find the host interface from".to_string(),
+        })
+}
+
+// Runs `f` on a thread of its own that joined the network namespace of process `pid`, so that
+// `NetDev` changes apply to the devices in there. The calling thread stays where it is.
+pub fn in_network_namespace<T: Send>(
+    pid: u32,
+    f: impl FnOnce() -> Result<T, SandboxError> + Send,
+) -> Result<T, SandboxError> {
+    in_namespace_file(&format!("/proc/{}/ns/net", pid), f)
+}
+
+// Same as `in_network_namespace`, with the namespace file at `path`
+fn in_namespace_file<T: Send>(
+    path: &str,
+    f: impl FnOnce() -> Result<T, SandboxError> + Send,
+) -> Result<T, SandboxError> {
+    let namespace = fs::File::open(path).map_err(SandboxError::io(path))?;
+    std::thread::scope(|scope| {
+        scope
+            .spawn(|| {
+                // SAFETY: `namespace` is an open namespace file, and setns(2) only moves this thread
+                if unsafe { libc::setns(namespace.as_raw_fd(), libc::CLONE_NEWNET) } < 0 {
+                    return Err(SandboxError::NetworkSetupFailed {
+                        reason: format!("cannot join {}: {}", path, io::Error::last_os_error()),
+                    });
+                }
+                f()
+            })
+            .join()
+            .unwrap_or_else(|_| {
+                Err(SandboxError::NetworkSetupFailed {
+                    reason: format!("network setup in {} panicked", path),
+                })
+            })
+    })
+}
+
+// File that keeps the named network namespace `name` alive, for setns(2) to join it by
+pub fn netns_path(name: &str) -> String {
+    format!("{}/{}", NETNS_DIR, name)
+}
+
+// Moves the calling thread to a new network namespace and bind mounts it onto `path`, so that it
+// outlives the thread
+fn unshare_and_mount_netns(path: &str) -> io::Result<()> {
+    // SAFETY: unshare(2) only moves this thread
+    if unsafe { libc::unshare(libc::CLONE_NEWNET) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let source = c"/proc/thread-self/ns/net";
+    let target = CString::new(path).map_err(io::Error::other)?;
+    // SAFETY: both paths are valid NUL terminated strings, and a bind mount takes no data
+    let mounted = unsafe {
+        libc::mount(source.as_ptr(), target.as_ptr(), std::ptr::null(), libc::MS_BIND, std::ptr::null())
+    };
+    if mounted < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+//
+// TAP devices
+//
+
+const TUNSETIFF: u64 = 0x400454ca;
+const TUNSETPERSIST: u64 = 0x400454cb;
+const IFF_TAP: i16 = 0x0002;
+const IFF_NO_PI: i16 = 0x1000;
+
+// struct ifreq, as used by the tun ioctls
+#[repr(C)]
+struct IfReq {
+    name: [u8; libc::IFNAMSIZ],
+    flags: i16,
+    _pad: [u8; 22],
+}
+
+// Creates a TAP device that outlives the file descriptor, for the VMM to attach to later
+fn create_persistent_tap(name: &str) -> io::Result<()> {
+    let tun = OpenOptions::new().read(true).write(true).open("/dev/net/tun")?;
+    let mut request = IfReq {
+        name: ifname_bytes(name),
+        flags: IFF_TAP | IFF_NO_PI,
+        _pad: [0; 22],
+    };
+    // SAFETY: `request` is a valid ifreq that outlives the call
+    if unsafe { libc::ioctl(tun.as_raw_fd(), TUNSETIFF as _, &mut request) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: TUNSETPERSIST takes its argument by value
+    if unsafe { libc::ioctl(tun.as_raw_fd(), TUNSETPERSIST as _, 1 as libc::c_ulong) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn ifname_bytes(name: &str) -> [u8; libc::IFNAMSIZ] {
+    let mut bytes = [0u8; libc::IFNAMSIZ];
+    let length = name.len().min(libc::IFNAMSIZ - 1);
+    bytes[..length].copy_from_slice(&name.as_bytes()[..length]);
+    bytes
+}
+
+fn link_index(name: &str) -> io::Result<i32> {
+    let c_name = CString::new(name).map_err(io::Error::other)?;
+    // SAFETY: `c_name` is a valid NUL terminated string
+    match unsafe { libc::if_nametoindex(c_name.as_ptr()) } {
+        0 => Err(io::Error::last_os_error()),
+        index => Ok(index as i32),
+    }
+}
+
+// Creates the veth pair `name` and `peer`, with `peer` in the network namespace given by `ns_attr`,
+// either a PID or a namespace file descriptor
+fn create_veth_pair(name: &str, peer: &str, ns_attr: u16, namespace: u32) -> io::Result<()> {
+    let mut socket = NetlinkSocket::open(libc::NETLINK_ROUTE)?;
+    let flags = NLM_F_REQUEST | NLM_F_ACK | NLM_F_CREATE | NLM_F_EXCL;
+    let mut message = Message::new(RTM_NEWLINK, flags, socket.next_seq());
+    message.header(&ifinfomsg(0, 0, 0));
+    message.attr_str(IFLA_IFNAME, name);
+    message.nested(IFLA_LINKINFO, |link_info| {
+        link_info.attr_str(IFLA_INFO_KIND, "veth");
+        link_info.nested(IFLA_INFO_DATA, |data| {
+            // The peer is described like a link of its own
+            data.nested(VETH_INFO_PEER, |peer_link| {
+                peer_link.header(&ifinfomsg(0, 0, 0));
+                peer_link.attr_str(IFLA_IFNAME, peer);
+                peer_link.attr(ns_attr, &namespace.to_ne_bytes());
+            });
+        });
+    });
+    socket.transact(&[message])?;
+    Ok(())
+}
+
+//
+// Netlink
+//
+
+const NLMSG_HDRLEN: usize = 16;
+const NLMSG_ERROR: u16 = 2;
+const NLMSG_DONE: u16 = 3;
+const NLM_F_REQUEST: u16 = 0x1;
+const NLM_F_ACK: u16 = 0x4;
+const NLM_F_ECHO: u16 = 0x8;
+const NLM_F_EXCL: u16 = 0x200;
+const NLM_F_CREATE: u16 = 0x400;
+const NLM_F_APPEND: u16 = 0x800;
+const NLA_F_NESTED: u16 = 0x8000;
+const NLA_TYPE_MASK: u16 = 0x3fff;
+
+const RTM_NEWLINK: u16 = 16;
+const RTM_DELLINK: u16 = 17;
+const RTM_NEWADDR: u16 = 20;
+const IFA_ADDRESS: u16 = 1;
+const IFLA_IFNAME: u16 = 3;
+const IFLA_LINKINFO: u16 = 18;
+const IFLA_NET_NS_PID: u16 = 19;
+const IFLA_NET_NS_FD: u16 = 28;
+const IFLA_INFO_KIND: u16 = 1;
+const IFLA_INFO_DATA: u16 = 2;
+const VETH_INFO_PEER: u16 = 1;
+const IFA_LOCAL: u16 = 2;
+
+// struct ifinfomsg
+fn ifinfomsg(index: i32, flags: u32, change: u32) -> Vec<u8> {
+    let mut header = vec![libc::AF_UNSPEC as u8, 0, 0, 0];
+    header.extend_from_slice(&index.to_ne_bytes());
+    header.extend_from_slice(&flags.to_ne_bytes());
+    header.extend_from_slice(&change.to_ne_bytes());
+    header
+}
+
+struct Message {
+    buffer: Vec<u8>,
+}
+
+impl Message {
+    fn new(kind: u16, flags: u16, seq: u32) -> Self {
+        let mut buffer = Vec::with_capacity(256);
+        buffer.extend_from_slice(&0u32.to_ne_bytes());
+        buffer.extend_from_slice(&kind.to_ne_bytes());
+        buffer.extend_from_slice(&flags.to_ne_bytes());
+        buffer.extend_from_slice(&seq.to_ne_bytes());
+        buffer.extend_from_slice(&0u32.to_ne_bytes());
+        Message { buffer }
+    }
+
+    // Fixed size header that follows the netlink one
+    fn header(&mut self, bytes: &[u8]) -> &mut Self {
+        self.buffer.extend_from_slice(bytes);
+        self.align();
+        self
+    }
+
+    fn attr(&mut self, kind: u16, value: &[u8]) -> &mut Self {
+        self.buffer.extend_from_slice(&(4 + value.len() as u16).to_ne_bytes());
+        self.buffer.extend_from_slice(&kind.to_ne_bytes());
+        self.buffer.extend_from_slice(value);
+        self.align();
+        self
+    }
+
+    fn attr_str(&mut self, kind: u16, value: &str) -> &mut Self {
+        let mut bytes = value.as_bytes().to_vec();
+        bytes.push(0);
+        self.attr(kind, &bytes)
+    }
+
+    fn attr_u32_be(&mut self, kind: u16, value: u32) -> &mut Self {
+        self.attr(kind, &value.to_be_bytes())
+    }
+
+    fn nested(&mut self, kind: u16, f: impl FnOnce(&mut Self)) -> &mut Self {
+        let start = self.buffer.len();
+        self.buffer.extend_from_slice(&0u16.to_ne_bytes());
+        self.buffer.extend_from_slice(&(kind | NLA_F_NESTED).to_ne_bytes());
+        f(self);
+        let length = (self.buffer.len() - start) as u16;
+        self.buffer[start..start + 2].copy_from_slice(&length.to_ne_bytes());
+        self
+    }
+
+    fn align(&mut self) {
+        while !self.buffer.len().is_multiple_of(4) {
+            self.buffer.push(0);
+        }
+    }
+
+    fn seq(&self) -> u32 {
+        u32::from_ne_bytes(self.buffer[8..12].try_into().unwrap())
+    }
+
+    fn wants_ack(&self) -> bool {
+        u16::from_ne_bytes(self.buffer[6..8].try_into().unwrap()) & NLM_F_ACK != 0
+    }
+}
+
+// Splits attributes into their type, without flags, and value
+fn parse_attributes(mut bytes: &[u8]) -> Vec<(u16, Vec<u8>)> {
+    let mut attributes = Vec::new();
+    while bytes.len() >= 4 {
+        let length = u16::from_ne_bytes([bytes[0], bytes[1]]) as usize;
+        let kind = u16::from_ne_bytes([bytes[2], bytes[3]]) & NLA_TYPE_MASK;
+        if length < 4 || length > bytes.len() {
+            break;
+        }
+        attributes.push((kind, bytes[4..length].to_vec()));
+        bytes = bytes.get((length + 3) & !3..).unwrap_or_default();
+    }
+    attributes
+}
+
+struct NetlinkSocket {
+    fd: OwnedFd,
+    seq: u32,
+}
+
+impl NetlinkSocket {
+    fn open(protocol: libc::c_int) -> io::Result<Self> {
+        // SAFETY: plain socket(2) call, the result is checked below
+        let fd = unsafe { libc::socket(libc::AF_NETLINK, libc::SOCK_RAW | libc::SOCK_CLOEXEC, protocol) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: `fd` was just returned by socket(2) and nothing else owns it
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        // SAFETY: sockaddr_nl is plain old data, all zeroes lets the kernel pick our port id
+        let mut address: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        address.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        // SAFETY: `address` is a valid sockaddr_nl of the given length
+        let bound = unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                &address as *const libc::sockaddr_nl as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            )
+        };
+        if bound < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // Never wait forever for an answer
+        let timeout = Duration::from_secs(5);
+        let timeval = libc::timeval {
+            tv_sec: timeout.as_secs() as libc::time_t,
+            tv_usec: 0,
+        };
+        // SAFETY: `timeval` is a valid timeval of the given length
+        let set = unsafe {
+            libc::setsockopt(
+                fd.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_RCVTIMEO,
+                &timeval as *const libc::timeval as *const libc::c_void,
+                mem::size_of::<libc::timeval>() as libc::socklen_t,
+            )
+        };
+        if set < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(NetlinkSocket { fd, seq: 0 })
+    }
+
+    fn next_seq(&mut self) -> u32 {
+        self.seq += 1;
+        self.seq
+    }
+
+    // Sends `messages` at once and waits until every one asking for it is acked. Returns the type
+    // and payload of everything else the kernel sent back.
+    fn transact(&mut self, messages: &[Message]) -> io::Result<Vec<(u16, Vec<u8>)>> {
+        let mut pending: HashSet<u32> = messages.iter().filter(|m| m.wants_ack()).map(Message::seq).collect();
+        let request = encode_messages(messages);
+        // SAFETY: `request` is a valid buffer of the given length
+        let sent = unsafe { libc::send(self.fd.as_raw_fd(), request.as_ptr() as *const libc::c_void, request.len(), 0) };
+        if sent < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut replies = Vec::new();
+        let mut buffer = vec![0u8; 64 * 1024];
+        while !pending.is_empty() {
+            // SAFETY: `buffer` is a valid buffer of the given length
+            let received = unsafe { libc::recv(self.fd.as_raw_fd(), buffer.as_mut_ptr() as *mut libc::c_void, buffer.len(), 0) };
+            if received < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            parse_replies(&buffer[..received as usize], &mut pending, &mut replies)?;
+        }
+        Ok(replies)
+    }
+}
+
+// Concatenates `messages` into one request, with their lengths filled in
+fn encode_messages(messages: &[Message]) -> Vec<u8> {
+    let mut request = Vec::new();
+    for message in messages {
+        request.extend_from_slice(&message.buffer);
+        // nlmsg_len, now that the message is complete
+        let start = request.len() - message.buffer.len();
+        request[start..start + 4].copy_from_slice(&(message.buffer.len() as u32).to_ne_bytes());
+    }
+    request
+}
+
+// Parses one datagram of replies. Acks are taken off `pending`, errors fail, and the type and
+// payload of anything else go to `replies`.
+fn parse_replies(mut bytes: &[u8], pending: &mut HashSet<u32>, replies: &mut Vec<(u16, Vec<u8>)>) -> io::Result<()> {
+    while bytes.len() >= NLMSG_HDRLEN {
+        let length = u32::from_ne_bytes(bytes[0..4].try_into().unwrap()) as usize;
+        if length < NLMSG_HDRLEN || length > bytes.len() {
+            break;
+        }
+        let kind = u16::from_ne_bytes(bytes[4..6].try_into().unwrap());
+        let seq = u32::from_ne_bytes(bytes[8..12].try_into().unwrap());
+        let payload = &bytes[NLMSG_HDRLEN..length];
+        match kind {
+            NLMSG_ERROR => {
+                let error = payload
+                    .get(0..4)
+                    .map(|error| i32::from_ne_bytes(error.try_into().unwrap()))
+                    .ok_or_else(|| io::Error::other("truncated netlink error"))?;
+                if error != 0 {
+                    return Err(io::Error::from_raw_os_error(-error));
+                }
+                pending.remove(&seq);
+            },
+            NLMSG_DONE => {},
+            _ => replies.push((kind, payload.to_vec())),
+        }
+        bytes = bytes.get((length + 3) & !3..).unwrap_or_default();
+    }
+    Ok(())
+}
+
+//
+// nf_tables
+//
+
+const NFNL_SUBSYS_NFTABLES: u16 = 10;
+const NFNL_MSG_BATCH_BEGIN: u16 = 0x10;
+const NFNL_MSG_BATCH_END: u16 = 0x11;
+const NFGENMSG_LEN: usize = 4;
+
+const NFT_MSG_NEWTABLE: u16 = 0;
+const NFT_MSG_NEWCHAIN: u16 = 3;
+const NFT_MSG_NEWRULE: u16 = 6;
+const NFT_MSG_DELRULE: u16 = 8;
+
+const NFTA_TABLE_NAME: u16 = 1;
+const NFTA_CHAIN_TABLE: u16 = 1;
+const NFTA_CHAIN_NAME: u16 = 3;
+const NFTA_CHAIN_HOOK: u16 = 4;
+const NFTA_CHAIN_POLICY: u16 = 5;
+const NFTA_CHAIN_TYPE: u16 = 7;
+const NFTA_HOOK_HOOKNUM: u16 = 1;
+const NFTA_HOOK_PRIORITY: u16 = 2;
+const NFTA_RULE_TABLE: u16 = 1;
+const NFTA_RULE_CHAIN: u16 = 2;
+const NFTA_RULE_HANDLE: u16 = 3;
+const NFTA_RULE_EXPRESSIONS: u16 = 4;
+const NFTA_LIST_ELEM: u16 = 1;
+const NFTA_EXPR_NAME: u16 = 1;
+const NFTA_EXPR_DATA: u16 = 2;
+const NFTA_META_DREG: u16 = 1;
+const NFTA_META_KEY: u16 = 2;
+const NFTA_PAYLOAD_DREG: u16 = 1;
+const NFTA_PAYLOAD_BASE: u16 = 2;
+const NFTA_PAYLOAD_OFFSET: u16 = 3;
+const NFTA_PAYLOAD_LEN: u16 = 4;
+const NFTA_CMP_SREG: u16 = 1;
+const NFTA_CMP_OP: u16 = 2;
+const NFTA_CMP_DATA: u16 = 3;
+const NFTA_DATA_VALUE: u16 = 1;
+const NFTA_DATA_VERDICT: u16 = 2;
+const NFTA_VERDICT_CODE: u16 = 1;
+const NFTA_IMMEDIATE_DREG: u16 = 1;
+const NFTA_IMMEDIATE_DATA: u16 = 2;
+const NFTA_COUNTER_BYTES: u16 = 1;
+const NFTA_COUNTER_PACKETS: u16 = 2;
+const NFTA_NAT_TYPE: u16 = 1;
+const NFTA_NAT_FAMILY: u16 = 2;
+const NFTA_NAT_REG_ADDR_MIN: u16 = 3;
+
+const NFT_REG_VERDICT: u32 = 0;
+const NFT_REG_1: u32 = 1;
+const NFT_CMP_EQ: u32 = 0;
+const NFT_META_IIFNAME: u32 = 6;
+const NFT_META_OIFNAME: u32 = 7;
+const NFT_PAYLOAD_NETWORK_HEADER: u32 = 1;
+const NFT_NAT_SNAT: u32 = 0;
+const NFT_NAT_DNAT: u32 = 1;
+const NFPROTO_IPV4: u32 = 2;
+// Offsets of the source and destination addresses in the IPv4 header
+const IPV4_SADDR_OFFSET: u32 = 12;
+const IPV4_DADDR_OFFSET: u32 = 16;
+const NF_ACCEPT: u32 = 1;
+const NF_INET_PRE_ROUTING: u32 = 0;
+const NF_INET_FORWARD: u32 = 2;
+const NF_INET_POST_ROUTING: u32 = 4;
+const NF_IP_PRI_NAT_DST: i32 = -100;
+const NF_IP_PRI_FILTER: i32 = 0;
+const NF_IP_PRI_NAT_SRC: i32 = 100;
+
+fn nft_type(message: u16) -> u16 {
+    (NFNL_SUBSYS_NFTABLES << 8) | message
+}
+
+impl Message {
+    fn nft(message: u16, flags: u16, seq: u32) -> Self {
+        let mut nft = Message::new(nft_type(message), flags, seq);
+        // struct nfgenmsg, for the ip family
+        nft.header(&[libc::AF_INET as u8, 0, 0, 0]);
+        nft
+    }
+
+    fn batch(kind: u16, seq: u32) -> Self {
+        let mut batch = Message::new(kind, NLM_F_REQUEST, seq);
+        let mut nfgenmsg = vec![libc::AF_UNSPEC as u8, 0];
+        nfgenmsg.extend_from_slice(&NFNL_SUBSYS_NFTABLES.to_be_bytes());
+        batch.header(&nfgenmsg);
+        batch
+    }
+}
+
+fn base_chain(seq: u32, table: &str, name: &str, kind: &str, hook: u32, priority: i32) -> Message {
+    let mut chain = Message::nft(NFT_MSG_NEWCHAIN, NLM_F_REQUEST | NLM_F_ACK | NLM_F_CREATE, seq);
+    chain.attr_str(NFTA_CHAIN_TABLE, table);
+    chain.attr_str(NFTA_CHAIN_NAME, name);
+    chain.nested(NFTA_CHAIN_HOOK, |hook_attributes| {
+        hook_attributes.attr_u32_be(NFTA_HOOK_HOOKNUM, hook);
+        hook_attributes.attr_u32_be(NFTA_HOOK_PRIORITY, priority as u32);
+    });
+    chain.attr_u32_be(NFTA_CHAIN_POLICY, NF_ACCEPT);
+    chain.attr_str(NFTA_CHAIN_TYPE, kind);
+    chain
+}
+
+fn expr_meta(expressions: &mut Message, key: u32) {
+    expressions.nested(NFTA_LIST_ELEM, |expression| {
+        expression.attr_str(NFTA_EXPR_NAME, "meta");
+        expression.nested(NFTA_EXPR_DATA, |data| {
+            data.attr_u32_be(NFTA_META_KEY, key);
+            data.attr_u32_be(NFTA_META_DREG, NFT_REG_1);
+        });
+    });
+}
+
+// Loads the IPv4 address at `offset` of the network header
+fn expr_payload_ipv4(expressions: &mut Message, offset: u32) {
+    expressions.nested(NFTA_LIST_ELEM, |expression| {
+        expression.attr_str(NFTA_EXPR_NAME, "payload");
+        expression.nested(NFTA_EXPR_DATA, |data| {
+            data.attr_u32_be(NFTA_PAYLOAD_DREG, NFT_REG_1);
+            data.attr_u32_be(NFTA_PAYLOAD_BASE, NFT_PAYLOAD_NETWORK_HEADER);
+            data.attr_u32_be(NFTA_PAYLOAD_OFFSET, offset);
+            data.attr_u32_be(NFTA_PAYLOAD_LEN, 4);
+        });
+    });
+}
+
+fn expr_cmp_eq(expressions: &mut Message, value: &[u8]) {
+    expressions.nested(NFTA_LIST_ELEM, |expression| {
+        expression.attr_str(NFTA_EXPR_NAME, "cmp");
+        expression.nested(NFTA_EXPR_DATA, |data| {
+            data.attr_u32_be(NFTA_CMP_SREG, NFT_REG_1);
+            data.attr_u32_be(NFTA_CMP_OP, NFT_CMP_EQ);
+            data.nested(NFTA_CMP_DATA, |cmp_data| {
+                cmp_data.attr(NFTA_DATA_VALUE, value);
+            });
+        });
+    });
+}
+
+fn expr_counter(expressions: &mut Message) {
+    expressions.nested(NFTA_LIST_ELEM, |expression| {
+        expression.attr_str(NFTA_EXPR_NAME, "counter");
+        expression.nested(NFTA_EXPR_DATA, |data| {
+            data.attr(NFTA_COUNTER_BYTES, &0u64.to_be_bytes());
+            data.attr(NFTA_COUNTER_PACKETS, &0u64.to_be_bytes());
+        });
+    });
+}
+
+fn expr_accept(expressions: &mut Message) {
+    expressions.nested(NFTA_LIST_ELEM, |expression| {
+        expression.attr_str(NFTA_EXPR_NAME, "immediate");
+        expression.nested(NFTA_EXPR_DATA, |data| {
+            data.attr_u32_be(NFTA_IMMEDIATE_DREG, NFT_REG_VERDICT);
+            data.nested(NFTA_IMMEDIATE_DATA, |immediate| {
+                immediate.nested(NFTA_DATA_VERDICT, |verdict| {
+                    verdict.attr_u32_be(NFTA_VERDICT_CODE, NF_ACCEPT);
+                });
+            });
+        });
+    });
+}
+
+// Loads `address` into register 1 and translates the source or destination address to it
+fn expr_nat(expressions: &mut Message, nat_type: u32, address: Ipv4Addr) {
+    expressions.nested(NFTA_LIST_ELEM, |expression| {
+        expression.attr_str(NFTA_EXPR_NAME, "immediate");
+        expression.nested(NFTA_EXPR_DATA, |data| {
+            data.attr_u32_be(NFTA_IMMEDIATE_DREG, NFT_REG_1);
+            data.nested(NFTA_IMMEDIATE_DATA, |immediate| {
+                immediate.attr(NFTA_DATA_VALUE, &address.octets());
+            });
+        });
+    });
+    expressions.nested(NFTA_LIST_ELEM, |expression| {
+        expression.attr_str(NFTA_EXPR_NAME, "nat");
+        expression.nested(NFTA_EXPR_DATA, |data| {
+            data.attr_u32_be(NFTA_NAT_TYPE, nat_type);
+            data.attr_u32_be(NFTA_NAT_FAMILY, NFPROTO_IPV4);
+            data.attr_u32_be(NFTA_NAT_REG_ADDR_MIN, NFT_REG_1);
+        });
+    });
+}
+
+// The table, chains and rules of `NetDev::add_clone_nat_rules`, as one batch
+fn clone_nat_batch(socket: &mut NetlinkSocket, clone_ip: Ipv4Addr, guest_ip: Ipv4Addr) -> Vec<Message> {
+    let mut batch = vec![Message::batch(NFNL_MSG_BATCH_BEGIN, socket.next_seq())];
+    let create = NLM_F_REQUEST | NLM_F_ACK | NLM_F_CREATE;
+    let mut table = Message::nft(NFT_MSG_NEWTABLE, create, socket.next_seq());
+    table.attr_str(NFTA_TABLE_NAME, NFT_CLONE_TABLE);
+    batch.push(table);
+    batch.push(base_chain(socket.next_seq(), NFT_CLONE_TABLE, "prerouting", "nat", NF_INET_PRE_ROUTING, NF_IP_PRI_NAT_DST));
+    batch.push(base_chain(socket.next_seq(), NFT_CLONE_TABLE, "postrouting", "nat", NF_INET_POST_ROUTING, NF_IP_PRI_NAT_SRC));
+
+    let append = create | NLM_F_APPEND;
+    let mut dnat = Message::nft(NFT_MSG_NEWRULE, append, socket.next_seq());
+    dnat.attr_str(NFTA_RULE_TABLE, NFT_CLONE_TABLE);
+    dnat.attr_str(NFTA_RULE_CHAIN, "prerouting");
+    dnat.nested(NFTA_RULE_EXPRESSIONS, |expressions| {
+        expr_meta(expressions, NFT_META_IIFNAME);
+        expr_cmp_eq(expressions, &ifname_bytes(CLONE_VETH));
+        expr_payload_ipv4(expressions, IPV4_DADDR_OFFSET);
+        expr_cmp_eq(expressions, &clone_ip.octets());
+        expr_counter(expressions);
+        expr_nat(expressions, NFT_NAT_DNAT, guest_ip);
+    });
+    batch.push(dnat);
+
+    let mut snat = Message::nft(NFT_MSG_NEWRULE, append, socket.next_seq());
+    snat.attr_str(NFTA_RULE_TABLE, NFT_CLONE_TABLE);
+    snat.attr_str(NFTA_RULE_CHAIN, "postrouting");
+    snat.nested(NFTA_RULE_EXPRESSIONS, |expressions| {
+        expr_meta(expressions, NFT_META_OIFNAME);
+        expr_cmp_eq(expressions, &ifname_bytes(CLONE_VETH));
+        expr_payload_ipv4(expressions, IPV4_SADDR_OFFSET);
+        expr_cmp_eq(expressions, &guest_ip.octets());
+        expr_counter(expressions);
+        expr_nat(expressions, NFT_NAT_SNAT, clone_ip);
+    });
+    batch.push(snat);
+
+    batch.push(Message::batch(NFNL_MSG_BATCH_END, socket.next_seq()));
+    batch
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(bytes: &[u8]) -> (u32, u16, u16, u32) {
+        (
+            u32::from_ne_bytes(bytes[0..4].try_into().unwrap()),
+            u16::from_ne_bytes(bytes[4..6].try_into().unwrap()),
+            u16::from_ne_bytes(bytes[6..8].try_into().unwrap()),
+            u32::from_ne_bytes(bytes[8..12].try_into().unwrap()),
+        )
+    }
+
+    // A reply as the kernel would send it
+    fn reply(kind: u16, seq: u32, payload: &[u8]) -> Vec<u8> {
+        let mut message = Message::new(kind, 0, seq);
+        message.header(payload);
+        encode_messages(&[message])
+    }
+
+    fn error_reply(seq: u32, errno: i32) -> Vec<u8> {
+        // struct nlmsgerr: the error, then the header of the failed request
+        let mut payload = (-errno).to_ne_bytes().to_vec();
+        payload.extend_from_slice(&[0; NLMSG_HDRLEN]);
+        reply(NLMSG_ERROR, seq, &payload)
+    }
+
+    #[test]
+    fn attributes_are_padded_and_nested() {
+        let mut message = Message::new(RTM_NEWLINK, NLM_F_REQUEST | NLM_F_ACK, 7);
+        message.header(&ifinfomsg(0, 0, 0));
+        message.attr_str(IFLA_IFNAME, "tap0");
+        message.nested(IFLA_LINKINFO, |info| {
+            info.attr_str(IFLA_INFO_KIND, "veth");
+            info.attr_u32_be(IFLA_INFO_DATA, 0x0a000001);
+        });
+        assert_eq!(message.seq(), 7);
+        assert!(message.wants_ack());
+        assert!(!Message::new(RTM_NEWLINK, NLM_F_REQUEST, 1).wants_ack());
+
+        let attributes = &message.buffer[NLMSG_HDRLEN + 16..];
+        // "tap0\0" pads to 8 bytes, and the nested attribute counts its children
+        assert_eq!(&attributes[..12], &[9, 0, 3, 0, b't', b'a', b'p', b'0', 0, 0, 0, 0]);
+        assert_eq!(u16::from_ne_bytes([attributes[12], attributes[13]]), 4 + 12 + 8);
+        assert_eq!(u16::from_ne_bytes([attributes[14], attributes[15]]), IFLA_LINKINFO | NLA_F_NESTED);
+
+        let parsed = parse_attributes(attributes);
+        assert_eq!(parsed.len(), 2);
+        assert_eq!(parsed[0], (IFLA_IFNAME, b"tap0\0".to_vec()));
+        assert_eq!(parsed[1].0, IFLA_LINKINFO);
+        let info = parse_attributes(&parsed[1].1);
+        assert_eq!(info, [(IFLA_INFO_KIND, b"veth\0".to_vec()), (IFLA_INFO_DATA, vec![10, 0, 0, 1])]);
+    }
+
+    #[test]
+    fn parse_attributes_stops_at_truncated_attribute() {
+        let mut message = Message::new(RTM_NEWADDR, NLM_F_REQUEST, 1);
+        message.attr(IFA_LOCAL, &[10, 0, 0, 1]);
+        message.attr(IFA_ADDRESS, &[10, 0, 0, 2]);
+        let attributes = &message.buffer[NLMSG_HDRLEN..message.buffer.len() - 2];
+        assert_eq!(parse_attributes(attributes), [(IFA_LOCAL, vec![10, 0, 0, 1])]);
+    }
+
+    #[test]
+    fn encoded_messages_carry_their_length() {
+        let mut table = Message::nft(NFT_MSG_NEWTABLE, NLM_F_REQUEST | NLM_F_ACK | NLM_F_CREATE, 2);
+        table.attr_str(NFTA_TABLE_NAME, NFT_TABLE);
+        let batch = [Message::batch(NFNL_MSG_BATCH_BEGIN, 1), table, Message::batch(NFNL_MSG_BATCH_END, 3)];
+        let request = encode_messages(&batch);
+        assert_eq!(request.len(), batch.iter().map(|m| m.buffer.len()).sum::<usize>());
+
+        let mut bytes = request.as_slice();
+        let mut seen = Vec::new();
+        while !bytes.is_empty() {
+            let (length, kind, flags, seq) = header(bytes);
+            assert!(length as usize >= NLMSG_HDRLEN && length % 4 == 0);
+            seen.push((kind, flags, seq));
+            bytes = &bytes[length as usize..];
+        }
+        assert_eq!(
+            seen,
+            [
+                (NFNL_MSG_BATCH_BEGIN, NLM_F_REQUEST, 1),
+                (nft_type(NFT_MSG_NEWTABLE), NLM_F_REQUEST | NLM_F_ACK | NLM_F_CREATE, 2),
+                (NFNL_MSG_BATCH_END, NLM_F_REQUEST, 3),
+            ]
+        );
+        // The batch messages name the nf_tables subsystem, big endian
+        assert_eq!(&request[NLMSG_HDRLEN + 2..NLMSG_HDRLEN + 4], &NFNL_SUBSYS_NFTABLES.to_be_bytes());
+    }
+
+    #[test]
+    fn acks_clear_pending_and_other_replies_are_kept() {
+        let mut pending = HashSet::from([2, 3]);
+        let mut replies = Vec::new();
+        // A multipart datagram: an echoed rule, an ack, and the end of the dump
+        let mut datagram = reply(nft_type(NFT_MSG_NEWRULE), 2, &[libc::AF_INET as u8, 0, 0, 0, 0xaa]);
+        datagram.extend(error_reply(2, 0));
+        datagram.extend(reply(NLMSG_DONE, 2, &[0; 4]));
+        parse_replies(&datagram, &mut pending, &mut replies).unwrap();
+        assert_eq!(pending, HashSet::from([3]));
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].0, nft_type(NFT_MSG_NEWRULE));
+        assert_eq!(&replies[0].1[..5], &[libc::AF_INET as u8, 0, 0, 0, 0xaa]);
+
+        parse_replies(&error_reply(3, 0), &mut pending, &mut replies).unwrap();
+        assert!(pending.is_empty());
+    }
+
+    #[test]
+    fn errors_map_to_their_errno() {
+        let mut pending = HashSet::from([5]);
+        let mut replies = Vec::new();
+        let error = parse_replies(&error_reply(5, libc::EEXIST), &mut pending, &mut replies).unwrap_err();
+        assert_eq!(error.raw_os_error(), Some(libc::EEXIST));
+        assert_eq!(pending, HashSet::from([5]));
+
+        // A reply cut short is ignored rather than read past its end
+        let truncated = error_reply(5, libc::EPERM);
+        parse_replies(&truncated[..NLMSG_HDRLEN + 2], &mut pending, &mut replies).unwrap();
+        assert!(replies.is_empty());
+    }
+
+    // Creates and deletes a TAP device and the nft rules for it, in a user and network namespace
+    // of a forked child so that nothing on the host changes. Skipped where unprivileged user
+    // namespaces are not allowed.
+    #[test]
+    fn tap_and_nat_rules_in_a_namespace() {
+        fn run() -> Result<(), SandboxError> {
+            let netdev = NetDev::new(false);
+            netdev.create_tap("tap-test")?;
+            netdev.add_address("tap-test", Ipv4Addr::new(172, 16, 0, 1), 30)?;
+            netdev.set_up("tap-test")?;
+            let rules = netdev.add_nat_rules("tap-test", Ipv4Addr::new(172, 16, 0, 2), "lo")?;
+            assert!(rules.masquerade.is_some() && rules.forward.is_some());
+            netdev.delete_nat_rules(&rules)?;
+            // The rules are gone, so deleting them again fails
+            assert!(netdev.delete_nat_rules(&rules).is_err());
+            netdev.delete_link("tap-test")?;
+            assert!(link_index("tap-test").is_err());
+            Ok(())
+        }
+
+        const SKIPPED: i32 = 77;
+        // SAFETY: the child only runs `run` and exits without returning to the test harness
+        let pid = unsafe { libc::fork() };
+        assert!(pid >= 0, "fork failed: {}", io::Error::last_os_error());
+        if pid == 0 {
+            // SAFETY: unshare(2) and _exit(2) only affect the child
+            unsafe {
+                if libc::unshare(libc::CLONE_NEWUSER | libc::CLONE_NEWNET) < 0 {
+                    libc::_exit(SKIPPED);
+                }
+                let code = match std::panic::catch_unwind(run) {
+                    Ok(Ok(())) => 0,
+                    Ok(Err(e)) => {
+                        eprintln!("{}", e);
+                        1
+                    },
+                    Err(_) => 1,
+                };
+                libc::_exit(code);
+            }
+        }
+
+        let mut status = 0;
+        // SAFETY: `pid` is our child and `status` a valid pointer
+        assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+        assert!(libc::WIFEXITED(status));
+        match libc::WEXITSTATUS(status) {
+            0 => {},
+            SKIPPED => eprintln!("skipped, cannot create a user and network namespace"),
+            code => panic!("network setup in the namespace failed with {}", code),
+        }
+    }
+}
@@ -1,8 +1,19 @@
-use crate::sandbox::Sandbox;
+use crate::{
+    cgroup::{Cgroup, CgroupConfig},
+    error::SandboxError,
+    freezer,
+    hardening::{Hardening, HardeningConfig},
+    readiness::{ProbeKind, ProbeTarget, Readiness, ReadinessConfig},
+    registry::{SandboxConfig, SandboxEntry},
+    sandbox::{Capabilities, Pausable, Sandbox},
+    spawn::{Exec, Server, SpawnStrategy},
+    timings::{LifecycleTimings, Phase},
+    zygote::Zygote,
+};
 use anyhow::Result; 
 use log::debug;
 use serde::Deserialize;
-use std::{process::{Child, Command}, str};
+use std::{net::TcpListener, str, sync::Arc, time::Instant};
 use uuid::Uuid;
 use std::fs::File;
 
@@ -13,54 +24,103 @@ struct ProcessConfig {
     port: u16,
     binary_path: String,
     output_dir: String,
+    readiness: Option<ReadinessConfig>,
+    cgroup: Option<CgroupConfig>,
+    // Restrictions put on the server before it runs, reported as "Process-Hardened"
+    hardening: Option<HardeningConfig>,
+    // How the server is started, reported as e.g. "Process-ForkExec"
+    #[serde(default)]
+    spawn: SpawnStrategy,
 }
 
 pub struct Process {
     id: String,
     config: ProcessConfig,
-    child_process: Option<Child>,
+    server: Option<Server>,
     iteration: usize,
+    timings: LifecycleTimings,
+    cgroup: Option<Cgroup>,
+    // Only with the zygote, which gets its servers a bound socket instead of an address
+    zygote: Option<Arc<Zygote>>,
+    listener: Option<TcpListener>,
 }
 
+pub const ENTRY: SandboxEntry = SandboxEntry {
+    name: "process",
+    build: |config, iteration| Ok(Box::new(Process::from_config(config, iteration)?)),
+};
+
 impl Process {
-    pub fn new(config_path: &str, iteration: usize) -> Self {
-        // Open file
-        let file = std::fs::File::open(config_path).expect("Failed to open config file");
-        let mut config: ProcessConfig = serde_json::from_reader(file)
-            .expect("Failed to load config file");
+    pub fn new(config_path: &str, iteration: usize) -> Result<Self, SandboxError> {
+        Self::from_config(&SandboxConfig::Path(config_path.to_string()), iteration)
+    }
+
+    pub fn from_config(config: &SandboxConfig, iteration: usize) -> Result<Self, SandboxError> {
+        let mut config: ProcessConfig = config.parse()?;
 
         // Update the port based on the iteration
         config.port += iteration as u16;
 
+        let unsupported = |operation: &str| SandboxError::Unsupported {
+            operation: operation.to_string(),
+            backend: "Process".to_string(),
+        };
+        match config.spawn {
+            SpawnStrategy::PosixSpawn if config.cgroup.is_some() || config.hardening.is_some() => {
+                return Err(unsupported("join a cgroup or harden the server with posix_spawn"));
+            },
+            // The zygote's servers are never executed, so there is nothing to restrict before exec
+            SpawnStrategy::Zygote if config.hardening.is_some() => {
+                return Err(unsupported("harden the servers of a zygote"));
+            },
+            _ => {},
+        }
+
         let id = Uuid::new_v4().to_string();
 
-        Process {
+        Ok(Process {
             id,
             config,
-            child_process: None,
+            server: None,
             iteration,
-        }
+            timings: LifecycleTimings::default(),
+            cgroup: None,
+            zygote: None,
+            listener: None,
+        })
     }
 
-    fn create_log_file(process_output_dir: &str, id: &str, iteration: usize, suffix: &str) -> Result<File> {
+    fn create_log_file(process_output_dir: &str, id: &str, iteration: usize, suffix: &str) -> Result<File, SandboxError> {
         let log_file = format!("{}/process{}-{}{}", process_output_dir, id, iteration, suffix);
-        let log = File::create(log_file).expect("failed to open log");
-        Ok(log)
+        File::create(&log_file).map_err(SandboxError::io(log_file))
     }
 }
 
 impl Sandbox for Process {
     fn presetup(&mut self) -> Result<()> {
+        self.cgroup = Cgroup::create_optional(self.config.cgroup.as_ref(), &self.id)?;
+        if let SpawnStrategy::Zygote = self.config.spawn {
+            self.zygote = Some(Zygote::get_or_start(&self.config.binary_path, &self.config.output_dir)?);
+            let address = format!("{}:{}", self.config.ip, self.config.port);
+            let listener = TcpListener::bind(&address).map_err(|e| SandboxError::NetworkSetupFailed {
+                reason: format!("cannot listen on {}: {}", address, e),
+            })?;
+            self.listener = Some(listener);
+        }
         Ok(())
     }
 
     fn cleanup(&mut self) -> Result<()> {
+        self.listener = None;
+        if let Some(cgroup) = self.cgroup.take() {
+            cgroup.remove()?;
+        }
         Ok(())
     }
 
     fn start(&mut self) -> Result<()> {
-        let log_file_out = Self::create_log_file(&self.config.output_dir, &self.id, self.iteration,".out").unwrap();
-        let log_file_err = Self::create_log_file(&self.config.output_dir, &self.id, self.iteration, ".err").unwrap();
+        let log_file_out = Self::create_log_file(&self.config.output_dir, &self.id, self.iteration,".out")?;
+        let log_file_err = Self::create_log_file(&self.config.output_dir, &self.id, self.iteration, ".err")?;
 
         let socket_addr = format!("{}:{}",&self.config.ip, self.config.port);
 
@@ -75,22 +135,43 @@ impl Sandbox for Process {
         // Print the command we're going to run
         debug!("Starting Process sandbox with command: {:?}", firecracker_args);
 
-        // Execute the program and send the output to /dev/null
-        let process = Command::new(&firecracker_args[0])
-            .args(&firecracker_args[1..])
-            .stdout(log_file_out)
-            .stderr(log_file_err)
-            .spawn()?;
-
-        // Waiting for Firecracker to finish setup (in a real application, you'd likely handle this better)
-        debug!("Started Process sandbox with PID: {}", process.id());
-        self.child_process = Some(process);
+        let spawn_start = Instant::now();
+        let server = match &self.zygote {
+            Some(zygote) => {
+                let listener = self.listener.as_ref().ok_or(SandboxError::NotStarted)?;
+                let cgroup_procs = self.cgroup.as_ref().map(Cgroup::open_procs).transpose()?;
+                let pid = zygote.fork(listener, &log_file_out, &log_file_err, cgroup_procs.as_ref())?;
+                Server::Zygote(pid)
+            },
+            None => {
+                // Put in place last, right before exec
+                let hardening = match &self.config.hardening {
+                    Some(hardening) => Some(Hardening::prepare(hardening, &self.config.binary_path)?),
+                    None => None,
+                };
+                let exec = Exec {
+                    program: &firecracker_args[0],
+                    args: firecracker_args[1..].to_vec(),
+                    stdout: log_file_out,
+                    stderr: log_file_err,
+                    cgroup: self.cgroup.as_ref(),
+                    hardening,
+                };
+                exec.spawn(self.config.spawn)?
+            },
+        };
+        self.timings.record(Phase::ProcessSpawn, spawn_start, Instant::now());
+
+        debug!("Started Process sandbox with PID: {}", server.id());
+        self.server = Some(server);
+        // The server has its own copy now
+        self.listener = None;
         Ok(())
     }
 
     fn kill(&mut self) -> Result<()> {
-        self.child_process.as_mut().unwrap().kill().expect("Failed to kill Process Sandbox");
-        Ok(())
+        let server = self.server.as_mut().ok_or(SandboxError::NotStarted)?;
+        server.kill()
     }
 
     fn get_target_ip(&self) -> String {
@@ -102,6 +183,63 @@ impl Sandbox for Process {
     }
 
     fn get_name(&self) -> String {
-        "Process".to_string()
+        let hardened = match self.config.hardening {
+            Some(_) => "-Hardened",
+            None => "",
+        };
+        format!("Process{}{}", self.config.spawn.name_suffix(), hardened)
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            needs_network_presetup: false,
+            supports_snapshot: false,
+            supports_pause: true,
+            // The zygote's listener accepts connections before the server is forked, so only a
+            // reply tells that it serves
+            readiness_kind: match self.config.spawn {
+                SpawnStrategy::Zygote => ProbeKind::echo_http(),
+                _ => ProbeKind::Tcp,
+            },
+            warmup_required: true,
+            multi_instance_safe: true,
+        }
+    }
+
+    fn timings(&self) -> LifecycleTimings {
+        self.timings.clone()
     }
-}
\ No newline at end of file
+
+    fn root_pid(&self) -> Option<u32> {
+        self.server.as_ref().map(Server::id)
+    }
+
+    fn cgroup(&self) -> Option<&Cgroup> {
+        self.cgroup.as_ref()
+    }
+
+    fn readiness(&self) -> Result<Readiness> {
+        let target = ProbeTarget {
+            ip: self.get_target_ip(),
+            port: self.get_target_port(),
+            log_file: Some(format!("{}/process{}-{}.out", self.config.output_dir, self.id, self.iteration)),
+            api_socket: None,
+        };
+        Readiness::from_config(self.config.readiness.as_ref(), self.capabilities().readiness_kind, &target)
+    }
+
+    fn as_pausable(&mut self) -> Option<&mut dyn Pausable> {
+        Some(self)
+    }
+}
+
+// Freezes the server, see `freezer::set_frozen`
+impl Pausable for Process {
+    fn pause(&mut self) -> Result<()> {
+        freezer::set_frozen(self.cgroup.as_ref(), self.root_pid(), true)
+    }
+
+    fn resume(&mut self) -> Result<()> {
+        freezer::set_frozen(self.cgroup.as_ref(), self.root_pid(), false)
+    }
+}
@@ -0,0 +1,175 @@
+use crate::error::SandboxError;
+use log::debug;
+use serde::Deserialize;
+use serde_json::Value;
+use std::{
+    io::{BufRead, BufReader, Write},
+    os::unix::net::UnixStream,
+    time::Duration,
+};
+
+// Client of the QEMU Machine Protocol, on the socket QEMU opens with `-qmp unix:<path>,server=on`.
+// Only runs commands without arguments, like `stop` and `cont`.
+pub struct QmpClient {
+    socket_path: String,
+    reader: BufReader<UnixStream>,
+}
+
+// Anything QEMU sends: a greeting, a command's result, an error or an asynchronous event
+#[derive(Deserialize)]
+struct Message {
+    #[serde(rename = "QMP")]
+    greeting: Option<Value>,
+    #[serde(rename = "return")]
+    result: Option<Value>,
+    error: Option<QmpError>,
+    event: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct QmpError {
+    class: String,
+    desc: String,
+}
+
+impl QmpClient {
+    // Connects and negotiates capabilities, after which QEMU accepts commands
+    pub fn connect(socket_path: &str, timeout: Duration) -> Result<Self, SandboxError> {
+        let io_error = |e: std::io::Error| SandboxError::VmmApi {
+            reason: format!("QMP on {}: {}", socket_path, e),
+        };
+        let stream = UnixStream::connect(socket_path).map_err(io_error)?;
+        stream.set_read_timeout(Some(timeout)).map_err(io_error)?;
+        stream.set_write_timeout(Some(timeout)).map_err(io_error)?;
+        let mut client = QmpClient {
+            socket_path: socket_path.to_string(),
+            reader: BufReader::new(stream),
+        };
+
+        let greeting = client.read_message()?;
+        if greeting.greeting.is_none() {
+            return Err(client.error("connect", "no greeting".to_string()));
+        }
+        client.execute("qmp_capabilities")?;
+        Ok(client)
+    }
+
+    // Runs `command` and waits for its result, skipping the events sent meanwhile
+    pub fn execute(&mut self, command: &str) -> Result<(), SandboxError> {
+        let request = format!("{{\"execute\":\"{}\"}}\n", command);
+        self.reader
+            .get_mut()
+            .write_all(request.as_bytes())
+            .map_err(|e| self.error(command, e.to_string()))?;
+        loop {
+            let message = self.read_message()?;
+            if let Some(error) = message.error {
+                return Err(self.error(command, format!("{}: {}", error.class, error.desc)));
+            }
+            if message.result.is_some() {
+                debug!("QMP {} on {}: done", command, self.socket_path);
+                return Ok(());
+            }
+            if let Some(event) = message.event {
+                debug!("QMP event on {}: {}", self.socket_path, event);
+            }
+        }
+    }
+
+    // Reads one message, QEMU sends each on its own line
+    fn read_message(&mut self) -> Result<Message, SandboxError> {
+        let mut line = String::new();
+        match self.reader.read_line(&mut line) {
+            Ok(0) => return Err(self.error("read", "connection closed".to_string())),
+            Ok(_) => {},
+            Err(e) => return Err(self.error("read", e.to_string())),
+        }
+        serde_json::from_str(&line).map_err(|e| self.error("read", format!("invalid message: {}", e)))
+    }
+
+    fn error(&self, command: &str, reason: String) -> SandboxError {
+        SandboxError::VmmApi {
+            reason: format!("QMP {} on {}: {}", command, self.socket_path, reason),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, os::unix::net::UnixListener, path::PathBuf, thread::JoinHandle};
+    use uuid::Uuid;
+
+    const GREETING: &str = r#"{"QMP": {"version": {"qemu": {"micro": 0, "minor": 2, "major": 8}}, "capabilities": []}}"#;
+    const DONE: &str = r#"{"return": {}}"#;
+    const TIMEOUT: Duration = Duration::from_secs(1);
+
+    // Sends `greeting` on the first connection to a socket in a fresh temporary directory, then
+    // answers each command line with the next batch of messages. Returns the commands it got once
+    // every batch is sent.
+    fn mock_qemu(greeting: &'static str, replies: Vec<Vec<&'static str>>) -> (PathBuf, String, JoinHandle<Vec<String>>) {
+        let dir = std::env::temp_dir().join(format!("qmp-test-{}", Uuid::new_v4()));
+        fs::create_dir(&dir).unwrap();
+        let socket_path = dir.join("qmp.socket").to_string_lossy().into_owned();
+        let listener = UnixListener::bind(&socket_path).unwrap();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            writeln!(stream, "{}", greeting).unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut received = Vec::new();
+            for messages in replies {
+                let mut command = String::new();
+                reader.read_line(&mut command).unwrap();
+                received.push(command.trim_end().to_string());
+                for message in messages {
+                    writeln!(stream, "{}", message).unwrap();
+                }
+            }
+            received
+        });
+        (dir, socket_path, server)
+    }
+
+    #[test]
+    fn negotiates_capabilities_and_skips_events() {
+        let stop_event = r#"{"event": "STOP", "timestamp": {"seconds": 1700000000, "microseconds": 1}}"#;
+        let (dir, socket_path, server) = mock_qemu(GREETING, vec![vec![DONE], vec![stop_event, DONE]]);
+        let mut client = QmpClient::connect(&socket_path, TIMEOUT).unwrap();
+        client.execute("stop").unwrap();
+
+        assert_eq!(server.join().unwrap(), [r#"{"execute":"qmp_capabilities"}"#, r#"{"execute":"stop"}"#]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn errors_become_vmm_api_errors() {
+        let error = r#"{"error": {"class": "GenericError", "desc": "Guest is not running"}}"#;
+        let (dir, socket_path, server) = mock_qemu(GREETING, vec![vec![DONE], vec![error]]);
+        let mut client = QmpClient::connect(&socket_path, TIMEOUT).unwrap();
+
+        match client.execute("cont") {
+            Err(SandboxError::VmmApi { reason }) => {
+                assert!(reason.starts_with("QMP cont on "), "{}", reason);
+                assert!(reason.ends_with(": GenericError: Guest is not running"), "{}", reason);
+            },
+            other => panic!("expected a VMM API error, got {:?}", other),
+        }
+
+        server.join().unwrap();
+        // QEMU went away
+        assert!(matches!(client.execute("cont"), Err(SandboxError::VmmApi { .. })));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_a_missing_greeting() {
+        let (dir, socket_path, server) = mock_qemu(DONE, vec![]);
+        match QmpClient::connect(&socket_path, TIMEOUT) {
+            Err(SandboxError::VmmApi { reason }) => assert!(reason.ends_with(": no greeting"), "{}", reason),
+            other => panic!("expected a VMM API error, got {:?}", other.err()),
+        }
+
+        server.join().unwrap();
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
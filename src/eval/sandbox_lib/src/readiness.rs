@@ -0,0 +1,466 @@
+use crate::{error::SandboxError, firecracker_api::FirecrackerApi};
+use anyhow::Result;
+use log::debug;
+use serde::Deserialize;
+use std::{
+    fs,
+    io::{Read, Write},
+    net::{SocketAddr, TcpStream},
+    sync::Arc,
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+// Body accepted by the echo server, used by HTTP probes that do not set their own
+const ECHO_PROBE_BODY: &str = r#"{"data":[]}"#;
+
+// A single, bounded check of whether a sandbox is ready to serve requests.
+pub trait ReadinessProbe: Send + Sync {
+    fn check(&self) -> Result<bool>;
+    fn describe(&self) -> String;
+}
+
+// How long to keep probing and how long to wait between attempts. The wait starts at
+// `initial_backoff_us` and is multiplied by `backoff_multiplier` after every failed attempt, up to
+// `max_backoff_us`.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct ProbePolicy {
+    pub timeout_ms: u64,
+    pub initial_backoff_us: u64,
+    pub max_backoff_us: u64,
+    pub backoff_multiplier: f64,
+    pub connect_timeout_ms: u64,
+}
+
+impl Default for ProbePolicy {
+    fn default() -> Self {
+        ProbePolicy {
+            timeout_ms: 10_000,
+            initial_backoff_us: 1_000,
+            max_backoff_us: 10_000,
+            backoff_multiplier: 1.0,
+            connect_timeout_ms: 100,
+        }
+    }
+}
+
+impl ProbePolicy {
+    fn connect_timeout(&self) -> Duration {
+        Duration::from_millis(self.connect_timeout_ms)
+    }
+
+    // The backoff only ever grows, up to its maximum
+    fn validate(&self) -> Result<(), SandboxError> {
+        if !self.backoff_multiplier.is_finite() || self.backoff_multiplier < 1.0 {
+            return Err(SandboxError::ConfigInvalid {
+                reason: format!("backoff_multiplier must be a number of at least 1, not {}", self.backoff_multiplier),
+            });
+        }
+        if self.initial_backoff_us > self.max_backoff_us {
+            return Err(SandboxError::ConfigInvalid {
+                reason: format!(
+                    "initial_backoff_us ({}) is larger than max_backoff_us ({})",
+                    self.initial_backoff_us, self.max_backoff_us
+                ),
+            });
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ProbeKind {
+    // The target address accepts TCP connections
+    Tcp,
+    // The target answers `path` with 200 OK. Without a body the probe sends a GET, otherwise a POST.
+    Http {
+        #[serde(default = "ProbeKind::default_http_path")]
+        path: String,
+        body: Option<String>,
+    },
+    // A line containing `pattern` shows up in the sandbox log
+    LogLine { pattern: String },
+    // The Firecracker API reports the microVM as running
+    FirecrackerApi,
+}
+
+impl ProbeKind {
+    fn default_http_path() -> String {
+        "/".to_string()
+    }
+
+    // HTTP probe understood by the echo server used across all backends
+    pub fn echo_http() -> Self {
+        ProbeKind::Http {
+            path: Self::default_http_path(),
+            body: Some(ECHO_PROBE_BODY.to_string()),
+        }
+    }
+}
+
+// Optional `readiness` section of a backend config, overriding the backend's default probe and
+// policy.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct ReadinessConfig {
+    pub probe: Option<ProbeKind>,
+    #[serde(flatten)]
+    pub policy: ProbePolicy,
+}
+
+// What a probe may look at. Backends fill in the fields they know about.
+#[derive(Clone, Debug, Default)]
+pub struct ProbeTarget {
+    pub ip: String,
+    pub port: u16,
+    pub log_file: Option<String>,
+    pub api_socket: Option<String>,
+}
+
+// A probe together with the policy used to drive it.
+#[derive(Clone)]
+pub struct Readiness {
+    pub probe: Arc<dyn ReadinessProbe>,
+    pub policy: ProbePolicy,
+}
+
+impl Readiness {
+    // Builds the readiness of a sandbox from its default probe and an optional override from the
+    // backend config.
+    pub fn from_config(
+        config: Option<&ReadinessConfig>,
+        default_kind: ProbeKind,
+        target: &ProbeTarget,
+    ) -> Result<Self> {
+        let (kind, policy) = match config {
+            Some(config) => (
+                config.probe.clone().unwrap_or(default_kind),
+                config.policy.clone(),
+            ),
+            None => (default_kind, ProbePolicy::default()),
+        };
+        policy.validate()?;
+        let probe = build_probe(&kind, target, &policy)?;
+        Ok(Readiness { probe, policy })
+    }
+
+    pub fn tcp(ip: &str, port: u16) -> Self {
+        let policy = ProbePolicy::default();
+        Readiness {
+            probe: Arc::new(TcpProbe::new(ip, port, policy.connect_timeout())),
+            policy,
+        }
+    }
+
+    // Blocks until the probe succeeds or the policy times out, returning the number of attempts.
+    pub fn wait(&self) -> Result<u32> {
+        let timeout = Duration::from_millis(self.policy.timeout_ms);
+        let deadline = Instant::now() + timeout;
+        let mut backoff = Duration::from_micros(self.policy.initial_backoff_us);
+        let max_backoff = Duration::from_micros(self.policy.max_backoff_us);
+        let mut attempts = 0;
+
+        debug!("Waiting for {}", self.probe.describe());
+        loop {
+            attempts += 1;
+            match self.probe.check() {
+                Ok(true) => {
+                    debug!("{} ready after {} attempts", self.probe.describe(), attempts);
+                    return Ok(attempts);
+                },
+                Ok(false) => {},
+                Err(e) => debug!("{} failed: {}", self.probe.describe(), e),
+            }
+
+            if Instant::now() >= deadline {
+                return Err(SandboxError::Timeout {
+                    what: format!("{} ({} attempts)", self.probe.describe(), attempts),
+                    after: timeout,
+                }
+                .into());
+            }
+
+            // Never sleep past the deadline, so that the last attempt comes right before it
+            sleep(backoff.min(deadline.saturating_duration_since(Instant::now())));
+            backoff = backoff.mul_f64(self.policy.backoff_multiplier).min(max_backoff);
+        }
+    }
+}
+
+pub fn build_probe(
+    kind: &ProbeKind,
+    target: &ProbeTarget,
+    policy: &ProbePolicy,
+) -> Result<Arc<dyn ReadinessProbe>> {
+    let probe: Arc<dyn ReadinessProbe> = match kind {
+        ProbeKind::Tcp => Arc::new(TcpProbe::new(&target.ip, target.port, policy.connect_timeout())),
+        ProbeKind::Http { path, body } => Arc::new(HttpProbe {
+            tcp: TcpProbe::new(&target.ip, target.port, policy.connect_timeout()),
+            path: path.clone(),
+            body: body.clone(),
+        }),
+        ProbeKind::LogLine { pattern } => {
+            let log_file = target
+                .log_file
+                .clone()
+                .ok_or_else(|| anyhow::anyhow!("log line probe needs a sandbox log file"))?;
+            Arc::new(LogLineProbe {
+                log_file,
+                pattern: pattern.clone(),
+            })
+        },
+        ProbeKind::FirecrackerApi => {
+            let api_socket = target
+                .api_socket
+                .clone()
+                .ok_or_else(|| anyhow::anyhow!("Firecracker API probe needs an API socket"))?;
+            Arc::new(FirecrackerApiProbe {
+                api_socket,
+                timeout: policy.connect_timeout(),
+            })
+        },
+    };
+    Ok(probe)
+}
+
+pub struct TcpProbe {
+    address: String,
+    connect_timeout: Duration,
+}
+
+impl TcpProbe {
+    pub fn new(ip: &str, port: u16, connect_timeout: Duration) -> Self {
+        TcpProbe {
+            address: format!("{}:{}", ip, port),
+            connect_timeout,
+        }
+    }
+
+    fn connect(&self) -> Result<TcpStream> {
+        let address: SocketAddr = self.address.parse()?;
+        let stream = TcpStream::connect_timeout(&address, self.connect_timeout)?;
+        stream.set_read_timeout(Some(self.connect_timeout))?;
+        stream.set_write_timeout(Some(self.connect_timeout))?;
+        Ok(stream)
+    }
+}
+
+impl ReadinessProbe for TcpProbe {
+    fn check(&self) -> Result<bool> {
+        Ok(self.connect().is_ok())
+    }
+
+    fn describe(&self) -> String {
+        format!("TCP probe on {}", self.address)
+    }
+}
+
+pub struct HttpProbe {
+    tcp: TcpProbe,
+    path: String,
+    body: Option<String>,
+}
+
+impl ReadinessProbe for HttpProbe {
+    fn check(&self) -> Result<bool> {
+        let mut stream = match self.tcp.connect() {
+            Ok(stream) => stream,
+            Err(_) => return Ok(false),
+        };
+        let request = match &self.body {
+            Some(body) => format!(
+                "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                self.path,
+                self.tcp.address,
+                body.len(),
+                body
+            ),
+            None => format!(
+                "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+                self.path, self.tcp.address
+            ),
+        };
+        stream.write_all(request.as_bytes())?;
+
+        let mut buffer = [0u8; 64];
+        let bytes_read = stream.read(&mut buffer)?;
+        let status_line = String::from_utf8_lossy(&buffer[..bytes_read]);
+        Ok(status_line.split_whitespace().nth(1) == Some("200"))
+    }
+
+    fn describe(&self) -> String {
+        format!("HTTP probe on {}{}", self.tcp.address, self.path)
+    }
+}
+
+pub struct LogLineProbe {
+    log_file: String,
+    pattern: String,
+}
+
+impl ReadinessProbe for LogLineProbe {
+    fn check(&self) -> Result<bool> {
+        let log = fs::read_to_string(&self.log_file)?;
+        Ok(log.lines().any(|line| line.contains(&self.pattern)))
+    }
+
+    fn describe(&self) -> String {
+        format!("log probe for '{}' in {}", self.pattern, self.log_file)
+    }
+}
+
+pub struct FirecrackerApiProbe {
+    api_socket: String,
+    timeout: Duration,
+}
+
+impl ReadinessProbe for FirecrackerApiProbe {
+    fn check(&self) -> Result<bool> {
+        let api = FirecrackerApi::new(&self.api_socket).with_timeout(self.timeout);
+        match api.instance_info() {
+            Ok(info) => Ok(info.state == "Running"),
+            // Not listening yet
+            Err(_) => Ok(false),
+        }
+    }
+
+    fn describe(&self) -> String {
+        format!("Firecracker API probe on {}", self.api_socket)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{io::BufRead, io::BufReader, net::TcpListener, thread};
+    use uuid::Uuid;
+
+    fn target(listener: &TcpListener) -> ProbeTarget {
+        ProbeTarget {
+            ip: "127.0.0.1".to_string(),
+            port: listener.local_addr().unwrap().port(),
+            ..Default::default()
+        }
+    }
+
+    // Answers one HTTP request with `status_line`, and returns the request it got
+    fn serve_once(listener: TcpListener, status_line: &'static str) -> thread::JoinHandle<String> {
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut request = String::new();
+            let mut content_length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if let Some(length) = line.strip_prefix("Content-Length: ") {
+                    content_length = length.trim().parse().unwrap();
+                }
+                request.push_str(&line);
+                if line == "\r\n" {
+                    break;
+                }
+            }
+            let mut body = vec![0u8; content_length];
+            reader.read_exact(&mut body).unwrap();
+            request.push_str(&String::from_utf8(body).unwrap());
+            stream.write_all(format!("{}\r\nContent-Length: 0\r\n\r\n", status_line).as_bytes()).unwrap();
+            request
+        })
+    }
+
+    #[test]
+    fn tcp_probe_needs_a_listener() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let target = target(&listener);
+        let probe = build_probe(&ProbeKind::Tcp, &target, &ProbePolicy::default()).unwrap();
+        assert!(probe.check().unwrap());
+        drop(listener);
+        assert!(!probe.check().unwrap());
+    }
+
+    #[test]
+    fn http_probe_posts_its_body_and_wants_200() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let target = target(&listener);
+        let probe = build_probe(&ProbeKind::echo_http(), &target, &ProbePolicy::default()).unwrap();
+        let server = serve_once(listener.try_clone().unwrap(), "HTTP/1.1 200 OK");
+        assert!(probe.check().unwrap());
+        let request = server.join().unwrap();
+        assert!(request.starts_with("POST / HTTP/1.1\r\n"), "{}", request);
+        assert!(request.ends_with(ECHO_PROBE_BODY), "{}", request);
+
+        let kind = ProbeKind::Http { path: "/health".to_string(), body: None };
+        let probe = build_probe(&kind, &target, &ProbePolicy::default()).unwrap();
+        let server = serve_once(listener, "HTTP/1.1 503 Service Unavailable");
+        assert!(!probe.check().unwrap());
+        assert!(server.join().unwrap().starts_with("GET /health HTTP/1.1\r\n"));
+        // Nothing listens any more
+        assert!(!probe.check().unwrap());
+    }
+
+    #[test]
+    fn log_line_probe_waits_for_the_pattern() {
+        let log_file = std::env::temp_dir().join(format!("readiness-test-{}.log", Uuid::new_v4()));
+        let target = ProbeTarget {
+            log_file: Some(log_file.to_string_lossy().into_owned()),
+            ..Default::default()
+        };
+        let kind = ProbeKind::LogLine { pattern: "listening on".to_string() };
+        let probe = build_probe(&kind, &target, &ProbePolicy::default()).unwrap();
+        // Not written yet
+        assert!(probe.check().is_err());
+        fs::write(&log_file, "starting\n").unwrap();
+        assert!(!probe.check().unwrap());
+        fs::write(&log_file, "starting\nserver listening on 0.0.0.0:8080\n").unwrap();
+        assert!(probe.check().unwrap());
+        fs::remove_file(&log_file).unwrap();
+
+        assert!(build_probe(&kind, &ProbeTarget::default(), &ProbePolicy::default()).is_err());
+    }
+
+    #[test]
+    fn from_config_rejects_a_shrinking_backoff() {
+        let target = ProbeTarget::default();
+        let from_json = |json: &str| {
+            let config: ReadinessConfig = serde_json::from_str(json).unwrap();
+            Readiness::from_config(Some(&config), ProbeKind::Tcp, &target)
+        };
+        assert!(from_json(r#"{ "backoff_multiplier": 2.0 }"#).is_ok());
+        for json in [
+            r#"{ "backoff_multiplier": 0.5 }"#,
+            r#"{ "backoff_multiplier": -1.0 }"#,
+            r#"{ "initial_backoff_us": 20000, "max_backoff_us": 10000 }"#,
+        ] {
+            let error = from_json(json).err().unwrap();
+            assert!(matches!(error.downcast_ref(), Some(SandboxError::ConfigInvalid { .. })), "{}", json);
+        }
+        let policy = ProbePolicy { backoff_multiplier: f64::NAN, ..Default::default() };
+        assert!(matches!(policy.validate(), Err(SandboxError::ConfigInvalid { .. })));
+    }
+
+    #[test]
+    fn wait_stops_at_the_deadline() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+        let readiness = Readiness {
+            probe: Arc::new(TcpProbe::new("127.0.0.1", port, Duration::from_millis(10))),
+            policy: ProbePolicy {
+                timeout_ms: 50,
+                initial_backoff_us: 5_000_000,
+                max_backoff_us: 5_000_000,
+                ..Default::default()
+            },
+        };
+        let start = Instant::now();
+        let error = readiness.wait().unwrap_err();
+        // One attempt, a sleep cut short at the deadline, and a last attempt
+        assert!(start.elapsed() < Duration::from_secs(1), "{:?}", start.elapsed());
+        match error.downcast_ref::<SandboxError>() {
+            Some(SandboxError::Timeout { what, .. }) => assert!(what.ends_with("(2 attempts)"), "{}", what),
+            _ => panic!("expected a timeout, got {}", error),
+        }
+    }
+}
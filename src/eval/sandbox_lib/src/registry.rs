@@ -0,0 +1,142 @@
+use crate::{
+    async_sandbox::{AsyncSandbox, BlockingSandbox},
+    error::SandboxError,
+    container, firecracker, firecracker_snapshot, hyperlight, process, sandbox::Sandbox, unikraft, wasm,
+};
+use anyhow::Result;
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::Value;
+use std::fmt;
+
+// Where the backend specific configuration of a sandbox comes from. Evals either point to a JSON
+// file on disk or embed the configuration directly in the eval config.
+#[derive(Clone, Debug)]
+pub enum SandboxConfig {
+    Path(String),
+    Inline(Value),
+}
+
+impl SandboxConfig {
+    pub fn parse<T: DeserializeOwned>(&self) -> Result<T, SandboxError> {
+        let invalid = |e: serde_json::Error| SandboxError::ConfigInvalid { reason: e.to_string() };
+        match self {
+            SandboxConfig::Path(path) => {
+                let file = std::fs::File::open(path).map_err(|source| {
+                    SandboxError::ConfigMissing { path: path.clone(), source }
+                })?;
+                serde_json::from_reader(file).map_err(invalid)
+            },
+            SandboxConfig::Inline(value) => T::deserialize(value).map_err(invalid),
+        }
+    }
+}
+
+// A single evaluation, as described in the eval config files consumed by the drivers.
+#[derive(Deserialize)]
+pub struct EvalConfig {
+    pub type_of_eval: String,
+    config_location: Option<String>,
+    config: Option<Value>,
+}
+
+impl EvalConfig {
+    pub fn sandbox_config(&self) -> Result<SandboxConfig> {
+        match (&self.config_location, &self.config) {
+            (Some(path), None) => Ok(SandboxConfig::Path(path.clone())),
+            (None, Some(value)) => Ok(SandboxConfig::Inline(value.clone())),
+            _ => Err(anyhow::anyhow!(
+                "eval '{}' must set exactly one of 'config_location' or 'config'",
+                self.type_of_eval
+            )),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct EvalsConfig {
+    pub evals: Vec<EvalConfig>,
+}
+
+impl EvalsConfig {
+    pub fn load(config_path: &str) -> Result<Self> {
+        let file = std::fs::File::open(config_path)?;
+        Ok(serde_json::from_reader(file)?)
+    }
+}
+
+// Builds a sandbox from its backend config and instance index
+pub type BuildSandbox = fn(&SandboxConfig, usize) -> Result<Box<dyn Sandbox>, SandboxError>;
+
+// Each backend exposes one of these under the name used in `type_of_eval`.
+pub struct SandboxEntry {
+    pub name: &'static str,
+    pub build: BuildSandbox,
+}
+
+const BACKENDS: &[SandboxEntry] = &[
+    firecracker::ENTRY,
+    firecracker_snapshot::ENTRY,
+    process::ENTRY,
+    unikraft::ENTRY,
+    container::ENTRY,
+    hyperlight::ENTRY,
+    wasm::ENTRY,
+];
+
+#[derive(Debug)]
+pub enum RegistryError {
+    UnknownSandbox { name: String, valid: Vec<&'static str> },
+    InvalidSandbox { name: String, error: SandboxError },
+}
+
+impl fmt::Display for RegistryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RegistryError::UnknownSandbox { name, valid } => write!(
+                f,
+                "unknown sandbox type '{}' (valid types: {})",
+                name,
+                valid.join(", ")
+            ),
+            RegistryError::InvalidSandbox { name, error } => {
+                write!(f, "cannot create sandbox '{}': {}", name, error)
+            },
+        }
+    }
+}
+
+impl std::error::Error for RegistryError {}
+
+pub fn names() -> Vec<&'static str> {
+    BACKENDS.iter().map(|entry| entry.name).collect()
+}
+
+pub fn lookup(name: &str) -> Result<&'static SandboxEntry, RegistryError> {
+    BACKENDS.iter().find(|entry| entry.name == name).ok_or_else(|| {
+        RegistryError::UnknownSandbox {
+            name: name.to_string(),
+            valid: names(),
+        }
+    })
+}
+
+pub fn create(
+    name: &str,
+    config: &SandboxConfig,
+    iteration: usize,
+) -> Result<Box<dyn Sandbox>, RegistryError> {
+    let entry = lookup(name)?;
+    (entry.build)(config, iteration).map_err(|error| RegistryError::InvalidSandbox {
+        name: name.to_string(),
+        error,
+    })
+}
+
+pub fn create_async(
+    name: &str,
+    config: &SandboxConfig,
+    iteration: usize,
+) -> Result<Box<dyn AsyncSandbox>, RegistryError> {
+    let sandbox = create(name, config, iteration)?;
+    Ok(Box::new(BlockingSandbox::new(sandbox)))
+}
@@ -1,6 +1,32 @@
+use crate::{
+    cgroup::Cgroup,
+    error::SandboxError,
+    firecracker_api::BalloonStats,
+    readiness::{ProbeKind, Readiness},
+    snapshot::SnapshotManifest,
+    timings::LifecycleTimings,
+    vmm_metrics::VmmMetrics,
+};
 use anyhow::Result;
 
-pub trait Sandbox {
+// What a backend needs and can do, so that drivers branch on it rather than on its name
+#[derive(Clone, Debug)]
+pub struct Capabilities {
+    // Creates host network devices or rules (TAP devices, veth pairs, nft rules, ...), which needs root
+    pub needs_network_presetup: bool,
+    // Restores from a snapshot that drivers prepare ahead of the eval, see `prepare_snapshot`
+    pub supports_snapshot: bool,
+    // `as_pausable` returns the sandbox
+    pub supports_pause: bool,
+    // Probe telling that the sandbox is ready, unless its config overrides it
+    pub readiness_kind: ProbeKind,
+    // Gets a first request, and a moment to settle after it, before its memory is measured
+    pub warmup_required: bool,
+    // Several instances of the same config can run at once
+    pub multi_instance_safe: bool,
+}
+
+pub trait Sandbox: Send {
     // This function is used to setup network or any other operation that needs to be performed 
     // ahead of the creation of the sandbox
     fn presetup(&mut self) -> Result<()>;
@@ -15,4 +41,75 @@ pub trait Sandbox {
     fn get_target_ip(&self) -> String;
     fn get_target_port(&self) -> u16;
     fn get_name(&self) -> String;
+    fn capabilities(&self) -> Capabilities;
+
+    // How to tell that the sandbox is ready to serve requests once started
+    fn readiness(&self) -> Result<Readiness> {
+        Ok(Readiness::tcp(&self.get_target_ip(), self.get_target_port()))
+    }
+
+    // Lifecycle phases observed by the sandbox itself
+    fn timings(&self) -> LifecycleTimings {
+        LifecycleTimings::default()
+    }
+
+    // Process hosting the sandbox, the root of its process tree for memory accounting
+    fn root_pid(&self) -> Option<u32> {
+        None
+    }
+
+    // cgroup the sandbox runs in, when the backend config asks for one
+    fn cgroup(&self) -> Option<&Cgroup> {
+        None
+    }
+
+    // Sets the guest's memory balloon to `amount_mib`, handing that much guest memory back to the
+    // host, or taking it back when shrinking
+    fn set_balloon(&mut self, _amount_mib: u32) -> Result<()> {
+        Err(SandboxError::Unsupported {
+            operation: "resize a memory balloon".to_string(),
+            backend: self.get_name(),
+        }
+        .into())
+    }
+
+    // Balloon statistics reported by the guest, if it has a balloon that reports them
+    fn balloon_stats(&self) -> Result<Option<BalloonStats>> {
+        Ok(None)
+    }
+
+    // Asks the VMM to write out its own counters, at the phase boundaries the backend cannot see
+    // itself, like readiness
+    fn flush_vmm_metrics(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    // Counters the VMM reported about itself so far, including after the sandbox was killed
+    fn vmm_metrics(&self) -> Option<VmmMetrics> {
+        None
+    }
+
+    // With `regenerate`, creates the snapshot the sandbox restores from again. Returns the path and
+    // contents of the snapshot's manifest, if there is one.
+    fn prepare_snapshot(&self, _regenerate: bool) -> Result<Option<(String, SnapshotManifest)>> {
+        Err(SandboxError::Unsupported {
+            operation: "prepare a snapshot".to_string(),
+            backend: self.get_name(),
+        }
+        .into())
+    }
+
+    // The sandbox as one that can be paused in place, if its backend can do that
+    fn as_pausable(&mut self) -> Option<&mut dyn Pausable> {
+        None
+    }
+}
+
+// Optional extension of `Sandbox`, for sandboxes that can be paused while idle and resumed later
+// instead of being killed and started again
+pub trait Pausable {
+    // Returns once the sandbox runs no more guest or server code
+    fn pause(&mut self) -> Result<()>;
+    // Returns once the sandbox runs again, it may not have served anything yet
+    fn resume(&mut self) -> Result<()>;
 }
\ No newline at end of file
@@ -0,0 +1,179 @@
+use crate::{
+    error::SandboxError,
+    firecracker::Firecracker,
+    firecracker_api::{FirecrackerApi, SnapshotCreateParams, SnapshotType},
+    net_alloc::Subnet,
+    registry::SandboxConfig,
+    sandbox::Sandbox,
+};
+use anyhow::Result;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::{
+    fs::{self, File},
+    io::Read,
+    net::Ipv4Addr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+// Network the snapshotted guest is configured with, which every clone restores into
+pub const SNAPSHOT_TAP: &str = "tap0";
+pub const SNAPSHOT_TAP_IP: Ipv4Addr = Ipv4Addr::new(172, 16, 0, 1);
+pub const SNAPSHOT_GUEST_IP: Ipv4Addr = Ipv4Addr::new(172, 16, 0, 2);
+pub const SNAPSHOT_PREFIX_LEN: u8 = 16;
+
+// How to (re)create the snapshot a `firecracker-snapshot` sandbox restores. The VM is booted from a
+// regular `firecracker` sandbox config, on the fixed network below rather than one from its address
+// pool, so that the restored guest expects what `FirecrackerSnapshot` sets up.
+#[derive(Clone, Debug, Deserialize)]
+pub struct SnapshotSource {
+    pub firecracker_config_location: String,
+    #[serde(default = "SnapshotSource::default_snapshot_type")]
+    pub snapshot_type: SnapshotType,
+}
+
+impl SnapshotSource {
+    fn default_snapshot_type() -> SnapshotType {
+        SnapshotType::Full
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SnapshotFile {
+    pub path: String,
+    pub size_bytes: u64,
+    pub sha256: String,
+}
+
+impl SnapshotFile {
+    fn record(path: &str) -> Result<Self, SandboxError> {
+        let mut file = File::open(path).map_err(SandboxError::io(path))?;
+        let mut hasher = Sha256::new();
+        let mut buffer = vec![0u8; 1 << 20];
+        let mut size_bytes = 0;
+        loop {
+            let bytes_read = file.read(&mut buffer).map_err(SandboxError::io(path))?;
+            if bytes_read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..bytes_read]);
+            size_bytes += bytes_read as u64;
+        }
+        let sha256 = hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect();
+        Ok(SnapshotFile {
+            path: path.to_string(),
+            size_bytes,
+            sha256,
+        })
+    }
+}
+
+// Where a snapshot comes from, written next to it when it is created.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SnapshotManifest {
+    pub created_at_unix_s: u64,
+    pub snapshot_type: SnapshotType,
+    pub vmm_version: String,
+    pub firecracker_config_location: String,
+    // Firecracker configuration the snapshotted VM booted with
+    pub vm_config: Value,
+    pub snapshot: SnapshotFile,
+    pub mem: SnapshotFile,
+}
+
+impl SnapshotManifest {
+    pub fn load(path: &str) -> Result<Self> {
+        let file = File::open(path).map_err(SandboxError::io(path))?;
+        Ok(serde_json::from_reader(file)?)
+    }
+
+    pub fn save(&self, path: &str) -> Result<()> {
+        fs::write(path, serde_json::to_vec_pretty(self)?).map_err(SandboxError::io(path))?;
+        Ok(())
+    }
+
+    // Cheap check that the files on disk are still the ones described here
+    pub fn matches_files(&self) -> bool {
+        [&self.snapshot, &self.mem].iter().all(|file| {
+            fs::metadata(&file.path).map(|metadata| metadata.len() == file.size_bytes).unwrap_or(false)
+        })
+    }
+}
+
+// Boots a Firecracker VM, waits for the echo server to answer, pauses the VM and snapshots it to
+// `snapshot_path` and `mem_file_path`. The VM is torn down whatever happens.
+pub fn create_snapshot(source: &SnapshotSource, snapshot_path: &str, mem_file_path: &str) -> Result<SnapshotManifest> {
+    let config = SandboxConfig::Path(source.firecracker_config_location.clone());
+    let subnet = Subnet::new(SNAPSHOT_TAP_IP, SNAPSHOT_GUEST_IP, SNAPSHOT_PREFIX_LEN);
+    let mut vm = Firecracker::with_network(&config, SNAPSHOT_TAP, subnet)?;
+
+    let result = boot_and_snapshot(&mut vm, source.snapshot_type, snapshot_path, mem_file_path);
+    if let Err(e) = vm.kill() {
+        warn!("Failed to kill snapshot VM: {}", e);
+    }
+    if let Err(e) = vm.cleanup() {
+        warn!("Failed to clean up snapshot VM: {}", e);
+    }
+    let (vmm_version, vm_config) = result?;
+
+    let manifest = SnapshotManifest {
+        created_at_unix_s: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+        snapshot_type: source.snapshot_type,
+        vmm_version,
+        firecracker_config_location: source.firecracker_config_location.clone(),
+        vm_config,
+        snapshot: SnapshotFile::record(snapshot_path)?,
+        mem: SnapshotFile::record(mem_file_path)?,
+    };
+    info!(
+        "Created {:?} snapshot {} ({} bytes) and memory file {} ({} bytes)",
+        manifest.snapshot_type,
+        manifest.snapshot.path,
+        manifest.snapshot.size_bytes,
+        manifest.mem.path,
+        manifest.mem.size_bytes
+    );
+    Ok(manifest)
+}
+
+// Returns the VMM version and the VM configuration of the snapshotted VM
+fn boot_and_snapshot(
+    vm: &mut Firecracker,
+    snapshot_type: SnapshotType,
+    snapshot_path: &str,
+    mem_file_path: &str,
+) -> Result<(String, Value)> {
+    // Firecracker would write the snapshot inside the jail, which goes away with the VM
+    if vm.is_jailed() {
+        return Err(SandboxError::ConfigInvalid {
+            reason: "snapshots are created without the jailer".to_string(),
+        }
+        .into());
+    }
+    vm.presetup()?;
+    let vm_config_file = File::open(vm.vm_config_location()).map_err(SandboxError::io(vm.vm_config_location()))?;
+    let vm_config: Value = serde_json::from_reader(vm_config_file)?;
+    // Firecracker only keeps the dirty page bitmap a Diff snapshot is made of if asked at boot
+    if matches!(snapshot_type, SnapshotType::Diff) && vm_config["machine-config"]["track_dirty_pages"] != true {
+        return Err(SandboxError::ConfigInvalid {
+            reason: "Diff snapshots need track_dirty_pages in the machine-config".to_string(),
+        }
+        .into());
+    }
+
+    vm.start()?;
+    vm.readiness()?.wait()?;
+    debug!("Snapshot VM is serving requests");
+
+    let api = FirecrackerApi::new(&vm.get_socket_addr());
+    let vmm_version = api.instance_info()?.vmm_version;
+    api.pause()?;
+    api.create_snapshot(&SnapshotCreateParams {
+        snapshot_path: snapshot_path.to_string(),
+        mem_file_path: mem_file_path.to_string(),
+        snapshot_type: Some(snapshot_type),
+    })?;
+    Ok((vmm_version, vm_config))
+}
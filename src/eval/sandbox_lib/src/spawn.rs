@@ -0,0 +1,349 @@
+use crate::{cgroup::Cgroup, error::SandboxError, hardening::Hardening};
+use anyhow::Result;
+use serde::Deserialize;
+use std::{
+    ffi::{CString, OsString},
+    fs::File,
+    io,
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        unix::ffi::OsStringExt,
+    },
+    process::{Child, Command},
+    ptr,
+};
+
+// How the Process backend starts its server
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SpawnStrategy {
+    // std's `Command::spawn`, which uses posix_spawn(3) unless a hook has to run before exec
+    #[default]
+    Command,
+    // fork(2), then execve(2) in the child
+    ForkExec,
+    // posix_spawn(3), which glibc implements with a vfork-like clone(2) sharing our memory. It runs
+    // nothing of ours in the child, so it can neither join a cgroup nor harden the server.
+    PosixSpawn,
+    // clone3(2), straight into the sandbox's cgroup with CLONE_INTO_CGROUP, then execve(2)
+    Clone3,
+    // Forked from a rust-http-echo that is already initialized, without exec (see `Zygote`)
+    Zygote,
+}
+
+impl SpawnStrategy {
+    // Added to the sandbox's name, so that each strategy's runs can be told apart
+    pub fn name_suffix(&self) -> &'static str {
+        match self {
+            SpawnStrategy::Command => "",
+            SpawnStrategy::ForkExec => "-ForkExec",
+            SpawnStrategy::PosixSpawn => "-PosixSpawn",
+            SpawnStrategy::Clone3 => "-Clone3",
+            SpawnStrategy::Zygote => "-Zygote",
+        }
+    }
+}
+
+// A server started with one of the strategies
+pub enum Server {
+    Child(Child),
+    // Our child, started without std
+    Forked(libc::pid_t),
+    // Forked by the zygote, but as our child (see `Zygote`)
+    Zygote(libc::pid_t),
+}
+
+impl Server {
+    pub fn id(&self) -> u32 {
+        match self {
+            Server::Child(child) => child.id(),
+            Server::Forked(pid) | Server::Zygote(pid) => *pid as u32,
+        }
+    }
+
+    pub fn kill(&mut self) -> Result<()> {
+        match self {
+            Server::Child(child) => {
+                child.kill()?;
+                child.wait()?;
+            },
+            Server::Forked(pid) | Server::Zygote(pid) => {
+                signal_kill(*pid)?;
+                // SAFETY: waits for a child of ours, without reading its status
+                while unsafe { libc::waitpid(*pid, ptr::null_mut(), 0) } < 0 {
+                    let error = io::Error::last_os_error();
+                    if error.kind() != io::ErrorKind::Interrupted {
+                        return Err(error.into());
+                    }
+                }
+            },
+        }
+        Ok(())
+    }
+}
+
+fn signal_kill(pid: libc::pid_t) -> io::Result<()> {
+    // SAFETY: only sends a signal
+    match unsafe { libc::kill(pid, libc::SIGKILL) } {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+// struct clone_args of <linux/sched.h>, up to the `cgroup` field added by Linux 5.7
+#[repr(C)]
+#[derive(Default)]
+struct CloneArgs {
+    flags: u64,
+    pidfd: u64,
+    child_tid: u64,
+    parent_tid: u64,
+    exit_signal: u64,
+    stack: u64,
+    stack_size: u64,
+    tls: u64,
+    set_tid: u64,
+    set_tid_size: u64,
+    cgroup: u64,
+}
+
+const CLONE_INTO_CGROUP: u64 = 0x2_0000_0000;
+
+// A program to start with any strategy but `Zygote`
+pub struct Exec<'a> {
+    pub program: &'a str,
+    pub args: Vec<String>,
+    pub stdout: File,
+    pub stderr: File,
+    pub cgroup: Option<&'a Cgroup>,
+    pub hardening: Option<Hardening>,
+}
+
+// What the child of fork(2) or clone3(2) needs to get to exec. It may not allocate, so everything
+// is prepared beforehand.
+struct ChildExec<'a> {
+    path: &'a CString,
+    argv: Vec<*const libc::c_char>,
+    envp: Vec<*const libc::c_char>,
+    stdout: RawFd,
+    stderr: RawFd,
+    // `cgroup.procs` of the cgroup to join, unless the child starts in it
+    cgroup_procs: Option<RawFd>,
+    hardening: Option<&'a Hardening>,
+    // Where the child reports why it could not exec. Closed on exec.
+    error_pipe: RawFd,
+}
+
+impl Exec<'_> {
+    pub fn spawn(self, strategy: SpawnStrategy) -> Result<Server> {
+        match strategy {
+            SpawnStrategy::Command => self.spawn_command(),
+            SpawnStrategy::ForkExec | SpawnStrategy::Clone3 => self.spawn_forked(strategy),
+            SpawnStrategy::PosixSpawn => self.spawn_posix(),
+            SpawnStrategy::Zygote => Err(SandboxError::Unsupported {
+                operation: "exec a zygote's server".to_string(),
+                backend: "Process".to_string(),
+            }
+            .into()),
+        }
+    }
+
+    fn spawn_command(self) -> Result<Server> {
+        let mut command = Command::new(self.program);
+        command.args(&self.args).stdout(self.stdout).stderr(self.stderr);
+        if let Some(cgroup) = self.cgroup {
+            cgroup.attach(&mut command)?;
+        }
+        // Put in place last, right before exec
+        if let Some(hardening) = self.hardening {
+            hardening.apply(&mut command);
+        }
+        let child = command.spawn().map_err(SandboxError::spawn(self.program))?;
+        Ok(Server::Child(child))
+    }
+
+    fn spawn_forked(self, strategy: SpawnStrategy) -> Result<Server> {
+        let (path, argv, envp) = self.c_strings()?;
+        let (error_read, error_write) = pipe()?;
+
+        // clone3(2) starts the child in the cgroup, fork(2) has it join the cgroup itself
+        let cgroup = match (self.cgroup, strategy) {
+            (Some(cgroup), SpawnStrategy::Clone3) => Some(cgroup.open_dir()?),
+            (Some(cgroup), _) => Some(cgroup.open_procs()?),
+            (None, _) => None,
+        };
+        let child = ChildExec {
+            path: &path,
+            argv: null_terminated(&argv),
+            envp: null_terminated(&envp),
+            stdout: self.stdout.as_raw_fd(),
+            stderr: self.stderr.as_raw_fd(),
+            cgroup_procs: match strategy {
+                SpawnStrategy::Clone3 => None,
+                _ => cgroup.as_ref().map(|procs| procs.as_raw_fd()),
+            },
+            hardening: self.hardening.as_ref(),
+            error_pipe: error_write.as_raw_fd(),
+        };
+
+        let pid = match strategy {
+            SpawnStrategy::Clone3 => {
+                let mut args = CloneArgs { exit_signal: libc::SIGCHLD as u64, ..Default::default() };
+                if let Some(dir) = &cgroup {
+                    args.flags |= CLONE_INTO_CGROUP;
+                    args.cgroup = dir.as_raw_fd() as u64;
+                }
+                // SAFETY: without CLONE_VM, the child gets a copy of our memory, as with fork(2)
+                unsafe { libc::syscall(libc::SYS_clone3, &mut args as *mut CloneArgs, size_of::<CloneArgs>()) as libc::pid_t }
+            },
+            // SAFETY: the child only issues system calls until it execs
+            _ => unsafe { libc::fork() },
+        };
+        match pid {
+            -1 => Err(SandboxError::spawn(self.program)(io::Error::last_os_error()).into()),
+            // SAFETY: this is the child, which never returns
+            0 => unsafe { child.exec() },
+            pid => {
+                drop(error_write);
+                match read_errno(&error_read)? {
+                    None => Ok(Server::Forked(pid)),
+                    Some(errno) => {
+                        Server::Forked(pid).kill()?;
+                        Err(SandboxError::spawn(self.program)(io::Error::from_raw_os_error(errno)).into())
+                    },
+                }
+            },
+        }
+    }
+
+    fn spawn_posix(self) -> Result<Server> {
+        if self.cgroup.is_some() || self.hardening.is_some() {
+            return Err(SandboxError::Unsupported {
+                operation: "join a cgroup or harden the server with posix_spawn".to_string(),
+                backend: "Process".to_string(),
+            }
+            .into());
+        }
+        let (path, argv, envp) = self.c_strings()?;
+        let argv = null_terminated(&argv);
+        let envp = null_terminated(&envp);
+
+        let mut pid: libc::pid_t = 0;
+        // SAFETY: the file actions are initialized before use and destroyed after, and every
+        // string is a valid NUL terminated string in a NULL terminated array
+        let result = unsafe {
+            let mut actions: libc::posix_spawn_file_actions_t = std::mem::zeroed();
+            libc::posix_spawn_file_actions_init(&mut actions);
+            libc::posix_spawn_file_actions_adddup2(&mut actions, self.stdout.as_raw_fd(), libc::STDOUT_FILENO);
+            libc::posix_spawn_file_actions_adddup2(&mut actions, self.stderr.as_raw_fd(), libc::STDERR_FILENO);
+            let result = libc::posix_spawn(
+                &mut pid,
+                path.as_ptr(),
+                &actions,
+                ptr::null(),
+                argv.as_ptr() as *const *mut libc::c_char,
+                envp.as_ptr() as *const *mut libc::c_char,
+            );
+            libc::posix_spawn_file_actions_destroy(&mut actions);
+            result
+        };
+        match result {
+            0 => Ok(Server::Forked(pid)),
+            errno => Err(SandboxError::spawn(self.program)(io::Error::from_raw_os_error(errno)).into()),
+        }
+    }
+
+    // The program, its arguments and our environment, as exec wants them
+    fn c_strings(&self) -> Result<(CString, Vec<CString>, Vec<CString>), SandboxError> {
+        let invalid = |_| SandboxError::ConfigInvalid {
+            reason: format!("the command line of '{}' contains a NUL byte", self.program),
+        };
+        let path = CString::new(self.program).map_err(invalid)?;
+        let argv = std::iter::once(self.program)
+            .chain(self.args.iter().map(String::as_str))
+            .map(|arg| CString::new(arg).map_err(invalid))
+            .collect::<Result<_, _>>()?;
+        let envp = std::env::vars_os()
+            .map(|(key, value)| {
+                let mut variable = OsString::with_capacity(key.len() + value.len() + 1);
+                variable.push(key);
+                variable.push("=");
+                variable.push(value);
+                CString::new(variable.into_vec()).map_err(invalid)
+            })
+            .collect::<Result<_, _>>()?;
+        Ok((path, argv, envp))
+    }
+}
+
+impl ChildExec<'_> {
+    // Gets the child to exec, or reports why it could not to the parent
+    unsafe fn exec(&self) -> ! {
+        let errno = match self.enter() {
+            Ok(()) => libc::EINVAL,
+            Err(e) => e.raw_os_error().unwrap_or(libc::EINVAL),
+        };
+        // SAFETY: plain system calls. The parent waits for the error or for the pipe to close.
+        unsafe {
+            libc::write(self.error_pipe, &errno as *const libc::c_int as *const libc::c_void, size_of::<libc::c_int>());
+            libc::_exit(127)
+        }
+    }
+
+    // Only returns if exec failed
+    fn enter(&self) -> io::Result<()> {
+        // SAFETY: every call below is a plain system call on data prepared by the parent
+        unsafe {
+            check(libc::dup2(self.stdout, libc::STDOUT_FILENO))?;
+            check(libc::dup2(self.stderr, libc::STDERR_FILENO))?;
+            if let Some(procs) = self.cgroup_procs
+                && libc::write(procs, b"0".as_ptr() as *const libc::c_void, 1) < 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+            if let Some(hardening) = self.hardening {
+                hardening.enter()?;
+            }
+            libc::execve(self.path.as_ptr(), self.argv.as_ptr(), self.envp.as_ptr());
+        }
+        Err(io::Error::last_os_error())
+    }
+}
+
+fn null_terminated(strings: &[CString]) -> Vec<*const libc::c_char> {
+    strings.iter().map(|string| string.as_ptr()).chain([ptr::null()]).collect()
+}
+
+fn pipe() -> Result<(OwnedFd, OwnedFd), SandboxError> {
+    let mut fds = [0; 2];
+    // SAFETY: `fds` has room for both ends
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } < 0 {
+        return Err(SandboxError::io("pipe")(io::Error::last_os_error()));
+    }
+    // SAFETY: both descriptors were just created and nothing else owns them
+    Ok(unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) })
+}
+
+// Reads the error number a child sent before exiting, or nothing if the pipe closed on exec
+fn read_errno(pipe: &OwnedFd) -> Result<Option<i32>, SandboxError> {
+    let mut errno: libc::c_int = 0;
+    loop {
+        // SAFETY: reads at most the size of `errno` into it
+        let read = unsafe {
+            libc::read(pipe.as_raw_fd(), &mut errno as *mut libc::c_int as *mut libc::c_void, size_of::<libc::c_int>())
+        };
+        match read {
+            -1 if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted => continue,
+            -1 => return Err(SandboxError::io("pipe")(io::Error::last_os_error())),
+            0 => return Ok(None),
+            _ => return Ok(Some(errno)),
+        }
+    }
+}
+
+fn check(result: libc::c_int) -> io::Result<libc::c_int> {
+    match result {
+        -1 => Err(io::Error::last_os_error()),
+        result => Ok(result),
+    }
+}
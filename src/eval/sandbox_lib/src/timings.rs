@@ -0,0 +1,91 @@
+use std::{
+    collections::BTreeMap,
+    time::{Duration, Instant},
+};
+
+// Phases of a sandbox's lifecycle. Not every backend can observe every phase.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Phase {
+    // Work done ahead of starting the sandbox (network, config files, ...)
+    Presetup,
+    // Spawning the process that hosts the sandbox (VMM, kraft, host binary, ...)
+    ProcessSpawn,
+    // From spawning the VMM until its API accepts connections
+    VmmApiReady,
+    // From starting the sandbox until its readiness probe succeeds
+    GuestListening,
+    // First request served by the sandbox
+    FirstResponse,
+    // Pausing and resuming an idle sandbox, see `Pausable`
+    Pause,
+    Resume,
+    Kill,
+    Cleanup,
+}
+
+impl Phase {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Phase::Presetup => "PRESETUP",
+            Phase::ProcessSpawn => "PROCESS_SPAWN",
+            Phase::VmmApiReady => "VMM_API_READY",
+            Phase::GuestListening => "GUEST_LISTENING",
+            Phase::FirstResponse => "FIRST_RESPONSE",
+            Phase::Pause => "PAUSE",
+            Phase::Resume => "RESUME",
+            Phase::Kill => "KILL",
+            Phase::Cleanup => "CLEANUP",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct PhaseTiming {
+    pub start: Instant,
+    pub end: Instant,
+}
+
+impl PhaseTiming {
+    pub fn duration(&self) -> Duration {
+        self.end.saturating_duration_since(self.start)
+    }
+}
+
+// Monotonic start and end timestamps of the lifecycle phases of a sandbox.
+#[derive(Clone, Debug, Default)]
+pub struct LifecycleTimings {
+    phases: BTreeMap<Phase, PhaseTiming>,
+}
+
+impl LifecycleTimings {
+    pub fn record(&mut self, phase: Phase, start: Instant, end: Instant) {
+        self.phases.insert(phase, PhaseTiming { start, end });
+    }
+
+    pub fn measure<T>(&mut self, phase: Phase, op: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = op();
+        self.record(phase, start, Instant::now());
+        result
+    }
+
+    pub fn get(&self, phase: Phase) -> Option<PhaseTiming> {
+        self.phases.get(&phase).copied()
+    }
+
+    // Adds the phases of `other` that are not recorded here yet
+    pub fn merge(&mut self, other: &LifecycleTimings) {
+        for (phase, timing) in &other.phases {
+            self.phases.entry(*phase).or_insert(*timing);
+        }
+    }
+
+    // Earliest timestamp recorded, used as the reference point when reporting offsets
+    pub fn origin(&self) -> Option<Instant> {
+        self.phases.values().map(|timing| timing.start).min()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (Phase, PhaseTiming)> + '_ {
+        self.phases.iter().map(|(phase, timing)| (*phase, *timing))
+    }
+}
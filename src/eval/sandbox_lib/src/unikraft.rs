@@ -1,8 +1,25 @@
-use crate::sandbox::Sandbox;
+use crate::{
+    cgroup::{Cgroup, CgroupConfig},
+    error::SandboxError,
+    memory::process_tree,
+    qmp::QmpClient,
+    readiness::{ProbeKind, ProbeTarget, Readiness, ReadinessConfig},
+    registry::{SandboxConfig, SandboxEntry},
+    sandbox::{Capabilities, Pausable, Sandbox},
+    timings::{LifecycleTimings, Phase},
+};
 use anyhow::Result; 
-use log::debug;
+use log::{debug, warn};
 use serde::Deserialize;
-use std::{process::{Child, Command, Stdio}, str};
+use std::{
+    fs,
+    io,
+    os::unix::process::CommandExt,
+    process::{Child, Command, Stdio},
+    str,
+    thread,
+    time::{Duration, Instant},
+};
 use uuid::Uuid;
 use std::fs::File;
 
@@ -11,9 +28,52 @@ use std::fs::File;
 struct UnikraftConfig {
     guest_port: u16,
     host_port: u16,
-    run_dir: String,
+    // Where `kraft run` builds and starts the image from
+    run_dir: Option<String>,
+    // Starts a prebuilt image with QEMU instead of kraft
+    qemu: Option<QemuConfig>,
+    // As kraft takes it, e.g. 128Mi
     memory: String,
-    output_dir: String
+    output_dir: String,
+    readiness: Option<ReadinessConfig>,
+    cgroup: Option<CgroupConfig>,
+}
+
+// Boots the image kraft built, e.g. `.unikraft/build/unikraft-rust-http-echo_qemu-x86_64`, with
+// QEMU directly, so that kraft's own startup is not measured and kraftkit is only needed to build.
+// The guest reaches the echo server through QEMU's user network, and gets its address over DHCP.
+#[derive(Deserialize)]
+struct QemuConfig {
+    kernel: String,
+    #[serde(default = "QemuConfig::default_binary")]
+    binary: String,
+    // "pc" or "microvm", which needs an image with virtio-mmio support
+    #[serde(default = "QemuConfig::default_machine")]
+    machine: String,
+    // "kvm" or "tcg", which works without KVM but emulates the CPU
+    #[serde(default = "QemuConfig::default_accel")]
+    accel: String,
+    // Kernel command line, e.g. Unikraft library parameters like `netdev.ip=...`
+    cmdline: Option<String>,
+}
+
+impl QemuConfig {
+    fn default_binary() -> String {
+        "qemu-system-x86_64".to_string()
+    }
+
+    fn default_machine() -> String {
+        "pc".to_string()
+    }
+
+    fn default_accel() -> String {
+        "kvm".to_string()
+    }
+
+    // Added to the sandbox's name, so that runs on each machine and accelerator can be told apart
+    fn name_suffix(&self) -> String {
+        format!("-QEMU-{}-{}", self.machine, self.accel)
+    }
 }
 
 pub struct Unikraft {
@@ -21,55 +81,168 @@ pub struct Unikraft {
     config: UnikraftConfig,
     child_process: Option<Child>,
     iteration: usize,
+    timings: LifecycleTimings,
+    cgroup: Option<Cgroup>,
 }
 
+// How long the killed processes get to go away
+const EXIT_TIMEOUT: Duration = Duration::from_secs(5);
+// How long QEMU gets to answer on its QMP socket
+const QMP_TIMEOUT: Duration = Duration::from_secs(1);
+
+pub const ENTRY: SandboxEntry = SandboxEntry {
+    name: "unikraft",
+    build: |config, iteration| Ok(Box::new(Unikraft::from_config(config, iteration)?)),
+};
+
 impl Unikraft {
-    pub fn new(config_path: &str, iteration: usize) -> Self {
-        // Open file
-        let file = std::fs::File::open(config_path).expect("Failed to open config file");
-        let mut config: UnikraftConfig = serde_json::from_reader(file)
-            .expect("Failed to load config file");
+    pub fn new(config_path: &str, iteration: usize) -> Result<Self, SandboxError> {
+        Self::from_config(&SandboxConfig::Path(config_path.to_string()), iteration)
+    }
+
+    pub fn from_config(config: &SandboxConfig, iteration: usize) -> Result<Self, SandboxError> {
+        let mut config: UnikraftConfig = config.parse()?;
+        match &config.qemu {
+            Some(qemu) if !["pc", "microvm"].contains(&qemu.machine.as_str()) => {
+                return Err(SandboxError::ConfigInvalid {
+                    reason: format!("QEMU machine must be pc or microvm, not {}", qemu.machine),
+                });
+            },
+            Some(qemu) if !["kvm", "tcg"].contains(&qemu.accel.as_str()) => {
+                return Err(SandboxError::ConfigInvalid {
+                    reason: format!("QEMU accelerator must be kvm or tcg, not {}", qemu.accel),
+                });
+            },
+            None if config.run_dir.is_none() => {
+                return Err(SandboxError::ConfigInvalid {
+                    reason: "unikraft needs either a run_dir for kraft or a qemu section".to_string(),
+                });
+            },
+            _ => {},
+        }
 
         // Update the host ports based on the iteration
         config.host_port += iteration as u16;
 
         let id = Uuid::new_v4().to_string();
 
-        Unikraft {
+        Ok(Unikraft {
             id,
             config,
             child_process: None,
             iteration,
+            timings: LifecycleTimings::default(),
+            cgroup: None,
+        })
+    }
+
+    // Name of the kraft machine, so that only this instance's is removed
+    fn machine_name(&self) -> String {
+        format!("sandbox-bench-{}", self.id)
+    }
+
+    // QMP socket of QEMU, when started without kraft
+    fn qmp_socket(&self) -> String {
+        format!("{}/unikraft{}-{}.qmp", self.config.output_dir, self.id, self.iteration)
+    }
+
+    fn kraft_command(&self, run_dir: &str) -> Command {
+        let mut command = Command::new("kraft");
+        command
+            .arg("run")
+            .arg("--rm")
+            .arg("--plat")
+            .arg("qemu")
+            .arg("--arch")
+            .arg("x86_64")
+            .arg("--name")
+            .arg(self.machine_name())
+            .arg("-p")
+            .arg(format!("{}:{}", self.config.host_port, self.config.guest_port))
+            .arg("--memory")
+            .arg(self.config.memory.clone())
+            .arg(".")
+            .current_dir(run_dir);
+        command
+    }
+
+    fn qemu_command(&self, qemu: &QemuConfig) -> Command {
+        // TCG can emulate every CPU feature, KVM passes the host's through
+        let cpu = if qemu.accel == "kvm" { "host" } else { "max" };
+        // microvm has no PCI bus
+        let net_device = if qemu.machine == "microvm" { "virtio-net-device" } else { "virtio-net-pci" };
+        // QEMU does not take kraft's binary suffixes
+        let memory = self.config.memory.strip_suffix('i').unwrap_or(&self.config.memory);
+
+        let mut command = Command::new(&qemu.binary);
+        command
+            .arg("-machine")
+            .arg(format!("{},accel={}", qemu.machine, qemu.accel))
+            .arg("-cpu")
+            .arg(cpu)
+            .arg("-m")
+            .arg(memory)
+            .arg("-kernel")
+            .arg(&qemu.kernel)
+            .arg("-netdev")
+            .arg(format!("user,id=net0,hostfwd=tcp:127.0.0.1:{}-:{}", self.config.host_port, self.config.guest_port))
+            .arg("-device")
+            .arg(format!("{},netdev=net0", net_device))
+            .arg("-nodefaults")
+            .arg("-no-reboot")
+            .arg("-display")
+            .arg("none")
+            // The guest console goes to the .out log, where the log_line probe looks
+            .arg("-serial")
+            .arg("stdio")
+            // For pausing and resuming the guest
+            .arg("-qmp")
+            .arg(format!("unix:{},server=on,wait=off", self.qmp_socket()));
+        if let Some(cmdline) = &qemu.cmdline {
+            command.arg("-append").arg(cmdline);
         }
+        command
     }
 
-    fn create_log_file(output_dir: &str, id: &str, iteration: usize, suffix: &str) -> Result<File> {
+    fn create_log_file(output_dir: &str, id: &str, iteration: usize, suffix: &str) -> Result<File, SandboxError> {
         let log_file = format!("{}/unikraft{}-{}{}", output_dir, id, iteration, suffix);
-        let log = File::create(log_file).expect("failed to open log");
-        Ok(log)
+        File::create(&log_file).map_err(SandboxError::io(log_file))
+    }
+
+    // Runs a QMP command on the QEMU started without kraft
+    fn qmp_execute(&self, command: &str) -> Result<()> {
+        if self.config.qemu.is_none() {
+            return Err(SandboxError::Unsupported {
+                operation: "pause a kraft machine, only one started with a qemu section".to_string(),
+                backend: self.get_name(),
+            }
+            .into());
+        }
+        if self.child_process.is_none() {
+            return Err(SandboxError::NotStarted.into());
+        }
+        QmpClient::connect(&self.qmp_socket(), QMP_TIMEOUT)?.execute(command)?;
+        Ok(())
     }
 }
 
 impl Sandbox for Unikraft {
     fn start(&mut self) -> Result<()> {
-        let log_file_out = Self::create_log_file(&self.config.output_dir, &self.id, self.iteration,".out").unwrap();
-        let log_file_err = Self::create_log_file(&self.config.output_dir, &self.id, self.iteration, ".err").unwrap();
-        let start_cmd = Command::new("kraft" )
-        .arg("run")
-        .arg("--rm")
-        .arg("--plat")
-        .arg("qemu")
-        .arg("--arch")
-        .arg("x86_64")
-        .arg("-p")
-        .arg(format!("{}:{}", self.config.host_port, self.config.guest_port))
-        .arg("--memory")
-        .arg(self.config.memory.clone())
-        .arg(".")
-        .current_dir(&self.config.run_dir)
-        .stdout(log_file_out)
-        .stderr(log_file_err)
-        .spawn()?;
+        let log_file_out = Self::create_log_file(&self.config.output_dir, &self.id, self.iteration,".out")?;
+        let log_file_err = Self::create_log_file(&self.config.output_dir, &self.id, self.iteration, ".err")?;
+        let spawn_start = Instant::now();
+        let (mut command, program) = match (&self.config.qemu, &self.config.run_dir) {
+            (Some(qemu), _) => (self.qemu_command(qemu), qemu.binary.clone()),
+            (None, Some(run_dir)) => (self.kraft_command(run_dir), "kraft".to_string()),
+            (None, None) => return Err(SandboxError::ConfigInvalid { reason: "nothing to run".to_string() }.into()),
+        };
+        // In its own process group, which takes the qemu started by kraft along when killed
+        command.stdout(log_file_out).stderr(log_file_err).process_group(0);
+        if let Some(cgroup) = &self.cgroup {
+            cgroup.attach(&mut command)?;
+        }
+        let start_cmd = command.spawn().map_err(SandboxError::spawn(program))?;
+        self.timings.record(Phase::ProcessSpawn, spawn_start, Instant::now());
 
         debug!("Started Unikraft VM with PID: {}", start_cmd.id());
         self.child_process = Some(start_cmd);
@@ -77,27 +250,96 @@ impl Sandbox for Unikraft {
     }
 
     fn kill(&mut self) -> Result<()> {
-        self.child_process.as_mut().unwrap().kill().expect("Failed to kill Unikraft VM");
-        let _clean_cmd = Command::new("kraft" )
-        .arg("rm")
-        .arg("--all")
-        .stdout(Stdio::null()) 
-        .stderr(Stdio::null())
-        .output()?;
+        let child = self.child_process.as_mut().ok_or(SandboxError::NotStarted)?;
+        let pid = child.id();
+        // Processes that left the group are still in the tree, as long as kraft is alive
+        let tree = process_tree(pid).unwrap_or_else(|_| vec![pid]);
+        // SAFETY: `pid` leads the process group set up in `start`, and `tree` was just read
+        unsafe {
+            libc::kill(-(pid as i32), libc::SIGKILL);
+            for &pid in &tree {
+                libc::kill(pid as i32, libc::SIGKILL);
+            }
+        }
+        child.wait()?;
+        wait_for_exit(&tree, EXIT_TIMEOUT)?;
+        debug!("Unikraft processes {:?} exited", tree);
+
+        // QEMU leaves nothing behind, kraft keeps a record of the machine
+        if self.config.qemu.is_some() {
+            return Ok(());
+        }
+        let removed = Command::new("kraft")
+            .arg("rm")
+            .arg(self.machine_name())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()?;
+        if !removed.success() {
+            warn!("Failed to remove kraft machine {}: {}", self.machine_name(), removed);
+        }
 
         Ok(())
     }
 
     fn cleanup(&mut self) -> Result<()> {
+        if self.config.qemu.is_some() {
+            match fs::remove_file(self.qmp_socket()) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                    return Err(SandboxError::io(self.qmp_socket())(e).into());
+                },
+                _ => {},
+            }
+        }
+        if let Some(cgroup) = self.cgroup.take() {
+            cgroup.remove()?;
+        }
         Ok(())
     }
 
     fn presetup(&mut self) -> Result<()> {
-       Ok(()) 
+        self.cgroup = Cgroup::create_optional(self.config.cgroup.as_ref(), &self.id)?;
+        Ok(())
     }
 
     fn get_name(&self) -> String {
-        "Unikraft".to_string()
+        // Started with kraft, the name is left as is
+        let suffix = self.config.qemu.as_ref().map(QemuConfig::name_suffix).unwrap_or_default();
+        format!("Unikraft{}", suffix)
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            needs_network_presetup: false,
+            supports_snapshot: false,
+            // Only QEMU started without kraft has a QMP socket
+            supports_pause: self.config.qemu.is_some(),
+            readiness_kind: ProbeKind::echo_http(),
+            warmup_required: false,
+            multi_instance_safe: true,
+        }
+    }
+
+    fn timings(&self) -> LifecycleTimings {
+        self.timings.clone()
+    }
+
+    fn root_pid(&self) -> Option<u32> {
+        self.child_process.as_ref().map(|child| child.id())
+    }
+
+    fn cgroup(&self) -> Option<&Cgroup> {
+        self.cgroup.as_ref()
+    }
+
+    fn readiness(&self) -> Result<Readiness> {
+        let target = ProbeTarget {
+            ip: self.get_target_ip(),
+            port: self.get_target_port(),
+            log_file: Some(format!("{}/unikraft{}-{}.out", self.config.output_dir, self.id, self.iteration)),
+            api_socket: None,
+        };
+        Readiness::from_config(self.config.readiness.as_ref(), self.capabilities().readiness_kind, &target)
     }
 
     fn get_target_port(&self) -> u16 {
@@ -108,5 +350,47 @@ impl Sandbox for Unikraft {
         "127.0.0.1".to_string()
     }
 
+    fn as_pausable(&mut self) -> Option<&mut dyn Pausable> {
+        Some(self)
+    }
+}
+
+// Stops and restarts the guest's vCPUs, QEMU itself keeps running
+impl Pausable for Unikraft {
+    fn pause(&mut self) -> Result<()> {
+        self.qmp_execute("stop")
+    }
+
+    fn resume(&mut self) -> Result<()> {
+        self.qmp_execute("cont")
+    }
+}
+
+// Waits until none of `pids` runs anymore. Zombies count as gone, as they hold no memory.
+fn wait_for_exit(pids: &[u32], timeout: Duration) -> Result<(), SandboxError> {
+    let start = Instant::now();
+    loop {
+        let running: Vec<u32> = pids.iter().copied().filter(|&pid| is_running(pid)).collect();
+        if running.is_empty() {
+            return Ok(());
+        }
+        if start.elapsed() >= timeout {
+            return Err(SandboxError::Timeout {
+                what: format!("exit of processes {:?}", running),
+                after: timeout,
+            });
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
 
-} 
+fn is_running(pid: u32) -> bool {
+    // The state follows the command name, which is in parentheses and may contain anything
+    match fs::read_to_string(format!("/proc/{}/stat", pid)) {
+        Ok(stat) => {
+            let state = stat.rsplit_once(')').and_then(|(_, rest)| rest.trim_start().chars().next());
+            !matches!(state, Some('Z' | 'X'))
+        },
+        Err(_) => false,
+    }
+}
@@ -0,0 +1,363 @@
+use crate::{
+    error::SandboxError,
+    firecracker_api::{Balloon, BootSource, Drive, MachineConfig, Metrics, NetworkInterface},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::{fs, net::Ipv4Addr};
+
+// The file Firecracker boots from with `--config-file`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct VmConfig {
+    #[serde(rename = "boot-source")]
+    pub boot_source: BootSource,
+    #[serde(default)]
+    pub drives: Vec<Drive>,
+    #[serde(rename = "machine-config")]
+    pub machine_config: MachineConfig,
+    #[serde(rename = "network-interfaces", default)]
+    pub network_interfaces: Vec<NetworkInterface>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logger: Option<Logger>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub balloon: Option<Balloon>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metrics: Option<Metrics>,
+    // Sections not modelled above, e.g. "vsock", kept as they are in the template
+    #[serde(flatten)]
+    pub other: Map<String, Value>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Logger {
+    pub log_path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub level: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub show_level: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub show_log_origin: Option<bool>,
+}
+
+// Settings an eval config can change on top of the template, e.g. for instance size sweeps:
+// `"vm": { "vcpu_count": 2, "mem_size_mib": 256, "huge_pages": "2M" }`
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct VmOverrides {
+    pub vcpu_count: Option<u8>,
+    pub mem_size_mib: Option<u32>,
+    // Replaces the template's kernel command line, except for the guest network settings
+    pub boot_args: Option<String>,
+    pub huge_pages: Option<String>,
+    // Applied to every drive
+    pub io_engine: Option<String>,
+    pub cache_type: Option<String>,
+}
+
+// Kernel command line keys the guest reads its network settings from, see
+// scripts/firecracker/guest_scripts/configure_vm_ip.sh
+const NETWORK_BOOT_ARGS: [&str; 2] = ["ip_with_mask=", "route="];
+
+// Firecracker's limit
+const MAX_VCPUS: u8 = 32;
+
+pub struct VmConfigBuilder {
+    config: VmConfig,
+}
+
+impl VmConfigBuilder {
+    pub fn from_template(path: &str) -> Result<Self, SandboxError> {
+        let template = fs::read_to_string(path).map_err(SandboxError::io(path))?;
+        let config = serde_json::from_str(&template).map_err(|e| SandboxError::ConfigInvalid {
+            reason: format!("bad Firecracker config template '{}': {}", path, e),
+        })?;
+        Ok(VmConfigBuilder { config })
+    }
+
+    pub fn overrides(mut self, overrides: &VmOverrides) -> Self {
+        let machine_config = &mut self.config.machine_config;
+        if let Some(vcpu_count) = overrides.vcpu_count {
+            machine_config.vcpu_count = vcpu_count;
+        }
+        if let Some(mem_size_mib) = overrides.mem_size_mib {
+            machine_config.mem_size_mib = mem_size_mib;
+        }
+        if let Some(huge_pages) = &overrides.huge_pages {
+            machine_config.huge_pages = Some(huge_pages.clone());
+        }
+        if let Some(boot_args) = &overrides.boot_args {
+            // Keep the network settings, which `network` may already have set
+            let (_, network_args) = split_boot_args(self.config.boot_source.boot_args.as_deref());
+            self.config.boot_source.boot_args = Some(join_args(&[boot_args, &network_args]));
+        }
+        for drive in &mut self.config.drives {
+            if let Some(io_engine) = &overrides.io_engine {
+                drive.io_engine = Some(io_engine.clone());
+            }
+            if let Some(cache_type) = &overrides.cache_type {
+                drive.cache_type = Some(cache_type.clone());
+            }
+        }
+        self
+    }
+
+    // Attaches the guest's only interface to `tap` and tells the guest its address and gateway
+    pub fn network(mut self, tap: &str, mac: &str, guest_ip: Ipv4Addr, prefix_len: u8, gateway: Ipv4Addr) -> Self {
+        self.config.network_interfaces = vec![NetworkInterface {
+            iface_id: "eth0".to_string(),
+            host_dev_name: tap.to_string(),
+            guest_mac: Some(mac.to_string()),
+        }];
+        let (boot_args, _) = split_boot_args(self.config.boot_source.boot_args.as_deref());
+        let network_args = format!("ip_with_mask={}/{} route={}", guest_ip, prefix_len, gateway);
+        self.config.boot_source.boot_args = Some(join_args(&[&boot_args, &network_args]));
+        self
+    }
+
+    pub fn log_path(mut self, log_path: &str) -> Self {
+        match &mut self.config.logger {
+            Some(logger) => logger.log_path = log_path.to_string(),
+            None => {
+                self.config.logger = Some(Logger {
+                    log_path: log_path.to_string(),
+                    level: None,
+                    show_level: None,
+                    show_log_origin: None,
+                })
+            },
+        }
+        self
+    }
+
+    pub fn metrics_path(mut self, metrics_path: &str) -> Self {
+        self.config.metrics = Some(Metrics {
+            metrics_path: metrics_path.to_string(),
+        });
+        self
+    }
+
+    pub fn balloon(mut self, balloon: &Balloon) -> Self {
+        self.config.balloon = Some(balloon.clone());
+        self
+    }
+
+    // Rejects what Firecracker would refuse to boot, with a message saying why
+    pub fn build(self) -> Result<VmConfig, SandboxError> {
+        let config = self.config;
+        let invalid = |reason: String| Err(SandboxError::ConfigInvalid { reason });
+        let machine_config = &config.machine_config;
+
+        if config.boot_source.kernel_image_path.is_empty() {
+            return invalid("no kernel image in boot-source".to_string());
+        }
+        if machine_config.vcpu_count == 0 || machine_config.vcpu_count > MAX_VCPUS {
+            return invalid(format!("vcpu_count must be between 1 and {}, not {}", MAX_VCPUS, machine_config.vcpu_count));
+        }
+        if machine_config.smt == Some(true) && machine_config.vcpu_count > 1 && !machine_config.vcpu_count.is_multiple_of(2) {
+            return invalid(format!("vcpu_count must be 1 or even with smt, not {}", machine_config.vcpu_count));
+        }
+        if machine_config.mem_size_mib == 0 {
+            return invalid("mem_size_mib must not be 0".to_string());
+        }
+        match machine_config.huge_pages.as_deref() {
+            None | Some("None") => {},
+            Some("2M") if machine_config.mem_size_mib.is_multiple_of(2) => {},
+            Some("2M") => {
+                return invalid(format!("mem_size_mib must be a multiple of 2 with 2M huge pages, not {}", machine_config.mem_size_mib));
+            },
+            Some(huge_pages) => return invalid(format!("huge_pages must be None or 2M, not {}", huge_pages)),
+        }
+
+        if let Some(balloon) = config.balloon.as_ref().filter(|balloon| balloon.amount_mib > machine_config.mem_size_mib) {
+            return invalid(format!("balloon of {} MiB is larger than the guest's {} MiB", balloon.amount_mib, machine_config.mem_size_mib));
+        }
+
+        if config.drives.iter().filter(|drive| drive.is_root_device).count() > 1 {
+            return invalid("more than one root drive".to_string());
+        }
+        for drive in &config.drives {
+            if drive.path_on_host.is_empty() {
+                return invalid(format!("drive {} has no path_on_host", drive.drive_id));
+            }
+            if let Some(io_engine) = drive.io_engine.as_deref().filter(|engine| !["Sync", "Async"].contains(engine)) {
+                return invalid(format!("io_engine of drive {} must be Sync or Async, not {}", drive.drive_id, io_engine));
+            }
+            if let Some(cache_type) = drive.cache_type.as_deref().filter(|cache| !["Unsafe", "Writeback"].contains(cache)) {
+                return invalid(format!("cache_type of drive {} must be Unsafe or Writeback, not {}", drive.drive_id, cache_type));
+            }
+        }
+
+        Ok(config)
+    }
+}
+
+impl VmConfig {
+    pub fn write(&self, path: &str) -> Result<(), SandboxError> {
+        let json = serde_json::to_vec_pretty(self).map_err(|e| SandboxError::ConfigInvalid {
+            reason: format!("cannot serialize Firecracker config: {}", e),
+        })?;
+        fs::write(path, json).map_err(SandboxError::io(path))
+    }
+}
+
+// Splits a kernel command line into the guest network settings and everything else
+fn split_boot_args(boot_args: Option<&str>) -> (String, String) {
+    let (network, other): (Vec<&str>, Vec<&str>) = boot_args
+        .unwrap_or_default()
+        .split_whitespace()
+        .partition(|arg| NETWORK_BOOT_ARGS.iter().any(|key| arg.starts_with(key)));
+    (other.join(" "), network.join(" "))
+}
+
+fn join_args(args: &[&str]) -> String {
+    args.iter().filter(|arg| !arg.is_empty()).copied().collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEMPLATE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../../../config/firecracker/vm_config_template.json");
+
+    fn template() -> VmConfigBuilder {
+        VmConfigBuilder::from_template(TEMPLATE).unwrap()
+    }
+
+    fn rejected(builder: VmConfigBuilder, reason: &str) {
+        match builder.build() {
+            Err(SandboxError::ConfigInvalid { reason: actual }) => assert!(actual.contains(reason), "{}", actual),
+            other => panic!("expected '{}', got {:?}", reason, other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn template_builds_as_is() {
+        let config = template().build().unwrap();
+        assert_eq!(config.boot_source.kernel_image_path, "vmlinux.bin");
+        assert_eq!((config.machine_config.vcpu_count, config.machine_config.mem_size_mib), (1, 128));
+        assert_eq!(config.drives.len(), 1);
+        // Sections that are not modelled survive a round trip
+        assert!(config.other.contains_key("vsock"));
+        let json = serde_json::to_value(&config).unwrap();
+        assert!(json.get("mmds-config").is_some());
+    }
+
+    #[test]
+    fn overrides_replace_template_values() {
+        let overrides = VmOverrides {
+            vcpu_count: Some(2),
+            mem_size_mib: Some(256),
+            boot_args: None,
+            huge_pages: Some("2M".to_string()),
+            io_engine: Some("Async".to_string()),
+            cache_type: Some("Writeback".to_string()),
+        };
+        let config = template().overrides(&overrides).build().unwrap();
+        let machine_config = &config.machine_config;
+        assert_eq!((machine_config.vcpu_count, machine_config.mem_size_mib), (2, 256));
+        assert_eq!(machine_config.huge_pages.as_deref(), Some("2M"));
+        assert_eq!(config.drives[0].io_engine.as_deref(), Some("Async"));
+        assert_eq!(config.drives[0].cache_type.as_deref(), Some("Writeback"));
+        // Untouched values stay as in the template
+        assert_eq!(config.boot_source.boot_args.as_deref(), Some("quiet loglevel=1 console=ttyS0 reboot=k panic=1 pci=off"));
+
+        let config = template().overrides(&VmOverrides::default()).build().unwrap();
+        assert_eq!(config.machine_config.vcpu_count, 1);
+        assert_eq!(config.drives[0].io_engine.as_deref(), Some("Sync"));
+    }
+
+    #[test]
+    fn network_sets_interface_and_boot_args() {
+        let config = template()
+            .network("tap7", "06:00:0A:C8:00:1E", Ipv4Addr::new(10, 200, 0, 30), 30, Ipv4Addr::new(10, 200, 0, 29))
+            .build()
+            .unwrap();
+        assert_eq!(config.network_interfaces.len(), 1);
+        let interface = &config.network_interfaces[0];
+        assert_eq!((interface.iface_id.as_str(), interface.host_dev_name.as_str()), ("eth0", "tap7"));
+        assert_eq!(interface.guest_mac.as_deref(), Some("06:00:0A:C8:00:1E"));
+        assert_eq!(
+            config.boot_source.boot_args.as_deref(),
+            Some("quiet loglevel=1 console=ttyS0 reboot=k panic=1 pci=off ip_with_mask=10.200.0.30/30 route=10.200.0.29")
+        );
+
+        // Setting the network again replaces the earlier settings rather than adding to them
+        let config = template()
+            .network("tap0", "06:00:AC:10:00:02", Ipv4Addr::new(172, 16, 0, 2), 16, Ipv4Addr::new(172, 16, 0, 1))
+            .network("tap1", "06:00:AC:10:00:06", Ipv4Addr::new(172, 16, 0, 6), 30, Ipv4Addr::new(172, 16, 0, 5))
+            .build()
+            .unwrap();
+        let boot_args = config.boot_source.boot_args.unwrap();
+        assert_eq!(boot_args.matches("ip_with_mask=").count(), 1);
+        assert!(boot_args.ends_with("ip_with_mask=172.16.0.6/30 route=172.16.0.5"), "{}", boot_args);
+    }
+
+    #[test]
+    fn boot_args_override_keeps_the_network() {
+        let overrides = VmOverrides { boot_args: Some("console=ttyS0 mitigations=off".to_string()), ..Default::default() };
+        let network = |builder: VmConfigBuilder| {
+            builder.network("tap1", "06:00:0A:00:00:02", Ipv4Addr::new(10, 0, 0, 2), 30, Ipv4Addr::new(10, 0, 0, 1))
+        };
+        let expected = "console=ttyS0 mitigations=off ip_with_mask=10.0.0.2/30 route=10.0.0.1";
+
+        // Whichever comes first
+        let config = network(template()).overrides(&overrides).build().unwrap();
+        assert_eq!(config.boot_source.boot_args.as_deref(), Some(expected));
+        let config = network(template().overrides(&overrides)).build().unwrap();
+        assert_eq!(config.boot_source.boot_args.as_deref(), Some(expected));
+    }
+
+    #[test]
+    fn paths_and_balloon() {
+        let balloon = Balloon { amount_mib: 64, deflate_on_oom: true, stats_polling_interval_s: Some(1) };
+        let config = template()
+            .log_path("/run/firecracker.log")
+            .metrics_path("/run/metrics.fifo")
+            .balloon(&balloon)
+            .build()
+            .unwrap();
+        let logger = config.logger.unwrap();
+        assert_eq!(logger.log_path, "/run/firecracker.log");
+        // The rest of the template's logger is kept
+        assert_eq!(logger.level.as_deref(), Some("Info"));
+        assert_eq!(config.metrics.unwrap().metrics_path, "/run/metrics.fifo");
+        assert_eq!(config.balloon.unwrap().amount_mib, 64);
+    }
+
+    #[test]
+    fn build_rejects_what_firecracker_refuses() {
+        let with = |overrides: VmOverrides| template().overrides(&overrides);
+        rejected(with(VmOverrides { vcpu_count: Some(0), ..Default::default() }), "vcpu_count must be between 1 and 32");
+        rejected(with(VmOverrides { vcpu_count: Some(33), ..Default::default() }), "vcpu_count must be between 1 and 32");
+        rejected(with(VmOverrides { mem_size_mib: Some(0), ..Default::default() }), "mem_size_mib must not be 0");
+        rejected(
+            with(VmOverrides { mem_size_mib: Some(129), huge_pages: Some("2M".to_string()), ..Default::default() }),
+            "multiple of 2 with 2M huge pages",
+        );
+        rejected(with(VmOverrides { huge_pages: Some("1G".to_string()), ..Default::default() }), "huge_pages must be None or 2M");
+        rejected(with(VmOverrides { io_engine: Some("Uring".to_string()), ..Default::default() }), "io_engine of drive rootfs");
+        rejected(with(VmOverrides { cache_type: Some("None".to_string()), ..Default::default() }), "cache_type of drive rootfs");
+
+        let mut smt = template();
+        smt.config.machine_config.smt = Some(true);
+        smt.config.machine_config.vcpu_count = 3;
+        rejected(smt, "vcpu_count must be 1 or even with smt");
+
+        let balloon = Balloon { amount_mib: 256, ..Default::default() };
+        rejected(template().balloon(&balloon), "balloon of 256 MiB is larger than the guest's 128 MiB");
+
+        let mut no_kernel = template();
+        no_kernel.config.boot_source.kernel_image_path.clear();
+        rejected(no_kernel, "no kernel image");
+
+        let mut two_roots = template();
+        let root = two_roots.config.drives[0].clone();
+        two_roots.config.drives.push(Drive { drive_id: "second".to_string(), ..root });
+        rejected(two_roots, "more than one root drive");
+
+        let mut no_path = template();
+        no_path.config.drives[0].path_on_host.clear();
+        rejected(no_path, "drive rootfs has no path_on_host");
+
+        assert!(matches!(VmConfigBuilder::from_template("/nonexistent.json"), Err(SandboxError::Io { .. })));
+    }
+}
@@ -0,0 +1,369 @@
+use crate::error::SandboxError;
+use log::debug;
+use serde::Deserialize;
+use std::{
+    ffi::CString,
+    fs::{self, File, OpenOptions},
+    io::{ErrorKind, Read},
+    os::unix::fs::OpenOptionsExt,
+};
+
+// Where Firecracker writes its own counters, e.g. `"metrics": { "output_dir": "/tmp", "fifo": true }`.
+// Each instance gets its own file, or a named pipe with `fifo`, which is removed on cleanup.
+#[derive(Clone, Debug, Deserialize)]
+pub struct MetricsConfig {
+    pub output_dir: String,
+    #[serde(default)]
+    pub fifo: bool,
+}
+
+// The counters we report, out of everything Firecracker writes on each flush. Firecracker resets
+// most counters on every flush, see `VmmMetrics::add`.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct VmmMetrics {
+    pub api_server: ApiServerMetrics,
+    pub vcpu: VcpuMetrics,
+    pub block: BlockMetrics,
+    pub net: NetMetrics,
+    pub latencies_us: LatencyMetrics,
+    pub seccomp: SeccompMetrics,
+    pub signals: SignalMetrics,
+    pub vmm: VmmCounters,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct ApiServerMetrics {
+    pub process_startup_time_us: u64,
+    pub process_startup_time_cpu_us: u64,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct VcpuMetrics {
+    pub exit_io_in: u64,
+    pub exit_io_out: u64,
+    pub exit_mmio_read: u64,
+    pub exit_mmio_write: u64,
+    pub failures: u64,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct BlockMetrics {
+    pub read_bytes: u64,
+    pub write_bytes: u64,
+    pub read_count: u64,
+    pub write_count: u64,
+    pub flush_count: u64,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct NetMetrics {
+    pub rx_bytes_count: u64,
+    pub tx_bytes_count: u64,
+    pub rx_packets_count: u64,
+    pub tx_packets_count: u64,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct LatencyMetrics {
+    pub full_create_snapshot: u64,
+    pub diff_create_snapshot: u64,
+    pub load_snapshot: u64,
+    pub pause_vm: u64,
+    pub resume_vm: u64,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct SeccompMetrics {
+    pub num_faults: u64,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct SignalMetrics {
+    pub sigbus: u64,
+    pub sigsegv: u64,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct VmmCounters {
+    pub panic_count: u64,
+}
+
+impl VmmMetrics {
+    // Folds in the next line Firecracker wrote. Event counters only hold what happened since the
+    // previous flush, so they add up, while startup times and operation latencies are kept as they
+    // are, unless the line does not have them.
+    pub fn add(&mut self, sample: &VmmMetrics) {
+        let keep = |total: &mut u64, value: u64| {
+            if value != 0 {
+                *total = value;
+            }
+        };
+        keep(&mut self.api_server.process_startup_time_us, sample.api_server.process_startup_time_us);
+        keep(&mut self.api_server.process_startup_time_cpu_us, sample.api_server.process_startup_time_cpu_us);
+        keep(&mut self.latencies_us.full_create_snapshot, sample.latencies_us.full_create_snapshot);
+        keep(&mut self.latencies_us.diff_create_snapshot, sample.latencies_us.diff_create_snapshot);
+        keep(&mut self.latencies_us.load_snapshot, sample.latencies_us.load_snapshot);
+        keep(&mut self.latencies_us.pause_vm, sample.latencies_us.pause_vm);
+        keep(&mut self.latencies_us.resume_vm, sample.latencies_us.resume_vm);
+
+        self.vcpu.exit_io_in += sample.vcpu.exit_io_in;
+        self.vcpu.exit_io_out += sample.vcpu.exit_io_out;
+        self.vcpu.exit_mmio_read += sample.vcpu.exit_mmio_read;
+        self.vcpu.exit_mmio_write += sample.vcpu.exit_mmio_write;
+        self.vcpu.failures += sample.vcpu.failures;
+        self.block.read_bytes += sample.block.read_bytes;
+        self.block.write_bytes += sample.block.write_bytes;
+        self.block.read_count += sample.block.read_count;
+        self.block.write_count += sample.block.write_count;
+        self.block.flush_count += sample.block.flush_count;
+        self.net.rx_bytes_count += sample.net.rx_bytes_count;
+        self.net.tx_bytes_count += sample.net.tx_bytes_count;
+        self.net.rx_packets_count += sample.net.rx_packets_count;
+        self.net.tx_packets_count += sample.net.tx_packets_count;
+        self.seccomp.num_faults += sample.seccomp.num_faults;
+        self.signals.sigbus += sample.signals.sigbus;
+        self.signals.sigsegv += sample.signals.sigsegv;
+        self.vmm.panic_count += sample.vmm.panic_count;
+    }
+
+    // Every counter with the name drivers report it under
+    pub fn rows(&self) -> Vec<(&'static str, u64)> {
+        vec![
+            ("STARTUP_TIME_US", self.api_server.process_startup_time_us),
+            ("STARTUP_TIME_CPU_US", self.api_server.process_startup_time_cpu_us),
+            ("VCPU_EXIT_IO_IN", self.vcpu.exit_io_in),
+            ("VCPU_EXIT_IO_OUT", self.vcpu.exit_io_out),
+            ("VCPU_EXIT_MMIO_READ", self.vcpu.exit_mmio_read),
+            ("VCPU_EXIT_MMIO_WRITE", self.vcpu.exit_mmio_write),
+            ("VCPU_FAILURES", self.vcpu.failures),
+            ("BLOCK_READ_BYTES", self.block.read_bytes),
+            ("BLOCK_WRITE_BYTES", self.block.write_bytes),
+            ("BLOCK_READ_COUNT", self.block.read_count),
+            ("BLOCK_WRITE_COUNT", self.block.write_count),
+            ("BLOCK_FLUSH_COUNT", self.block.flush_count),
+            ("NET_RX_BYTES", self.net.rx_bytes_count),
+            ("NET_TX_BYTES", self.net.tx_bytes_count),
+            ("NET_RX_PACKETS", self.net.rx_packets_count),
+            ("NET_TX_PACKETS", self.net.tx_packets_count),
+            ("LATENCY_FULL_CREATE_SNAPSHOT_US", self.latencies_us.full_create_snapshot),
+            ("LATENCY_DIFF_CREATE_SNAPSHOT_US", self.latencies_us.diff_create_snapshot),
+            ("LATENCY_LOAD_SNAPSHOT_US", self.latencies_us.load_snapshot),
+            ("LATENCY_PAUSE_VM_US", self.latencies_us.pause_vm),
+            ("LATENCY_RESUME_VM_US", self.latencies_us.resume_vm),
+            ("SECCOMP_FAULTS", self.seccomp.num_faults),
+            ("SIGBUS", self.signals.sigbus),
+            ("SIGSEGV", self.signals.sigsegv),
+            ("PANICS", self.vmm.panic_count),
+        ]
+    }
+}
+
+// Reading end of the file or named pipe a Firecracker instance writes its metrics to. Reads never
+// block: Firecracker writes a line on every flush request before answering it, so whatever is
+// there after the answer is all there is.
+pub struct MetricsSink {
+    path: String,
+    fifo: bool,
+    reader: File,
+    // Start of a line that was not completely written yet
+    pending: Vec<u8>,
+    metrics: VmmMetrics,
+}
+
+impl MetricsSink {
+    pub fn create(config: &MetricsConfig, id: &str) -> Result<Self, SandboxError> {
+        let path = match config.fifo {
+            true => format!("{}/firecracker_metrics_{}.fifo", config.output_dir, id),
+            false => format!("{}/firecracker_metrics_{}.json", config.output_dir, id),
+        };
+        // Firecracker expects the file to exist
+        match config.fifo {
+            true => {
+                let c_path = CString::new(path.as_str()).map_err(|e| SandboxError::ConfigInvalid {
+                    reason: format!("bad metrics path '{}': {}", path, e),
+                })?;
+                // SAFETY: `c_path` is a valid NUL terminated string
+                if unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) } < 0 {
+                    return Err(SandboxError::Io {
+                        path,
+                        source: std::io::Error::last_os_error(),
+                    });
+                }
+            },
+            false => {
+                File::create(&path).map_err(SandboxError::io(&path))?;
+            },
+        }
+        // Opening a named pipe for reading only does not wait for a writer when non blocking
+        let reader = OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(&path)
+            .map_err(SandboxError::io(&path))?;
+        debug!("Metrics sink {} created", path);
+
+        Ok(MetricsSink {
+            path,
+            fifo: config.fifo,
+            reader,
+            pending: Vec::new(),
+            metrics: VmmMetrics::default(),
+        })
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    // Reads the lines written since the last call and adds them to the totals
+    pub fn collect(&mut self) -> Result<(), SandboxError> {
+        let mut buffer = [0u8; 64 * 1024];
+        loop {
+            match self.reader.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(bytes_read) => self.pending.extend_from_slice(&buffer[..bytes_read]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(source) => {
+                    return Err(SandboxError::Io {
+                        path: self.path.clone(),
+                        source,
+                    })
+                },
+            }
+        }
+
+        while let Some(end) = self.pending.iter().position(|&byte| byte == b'\n') {
+            let line: Vec<u8> = self.pending.drain(..=end).collect();
+            match serde_json::from_slice::<VmmMetrics>(&line) {
+                Ok(sample) => self.metrics.add(&sample),
+                Err(e) => debug!("Skipping bad metrics line in {}: {}", self.path, e),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn metrics(&self) -> &VmmMetrics {
+        &self.metrics
+    }
+
+    // Named pipes go away, files are kept to look into outliers later
+    pub fn close(self) -> Result<(), SandboxError> {
+        if !self.fifo {
+            return Ok(());
+        }
+        fs::remove_file(&self.path).map_err(SandboxError::io(&self.path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use uuid::Uuid;
+
+    // Laid out like the first flush of a Firecracker v1.x instance that booted and served a
+    // request, with only a few of the sections and fields we do not read
+    const RECORDED: &str = concat!(
+        r#"{"utc_timestamp_ms":1718000000123,"#,
+        r#""api_server":{"process_startup_time_us":7393,"process_startup_time_cpu_us":1321,"sync_response_fails":0,"sync_vmm_send_timeout_count":0},"#,
+        r#""balloon":{"activate_fails":0,"inflate_count":0,"stats_updates_count":0},"#,
+        r#""block":{"activate_fails":0,"cfg_fails":0,"flush_count":1,"queue_event_count":12,"read_bytes":1052672,"write_bytes":4096,"read_count":257,"write_count":1,"read_agg":{"min_us":3,"max_us":41,"sum_us":900}},"#,
+        r#""block_rootfs":{"read_bytes":1052672},"#,
+        r#""latencies_us":{"full_create_snapshot":0,"diff_create_snapshot":0,"load_snapshot":0,"pause_vm":0,"resume_vm":0,"vmm_load_snapshot":0},"#,
+        r#""net":{"rx_bytes_count":1180,"tx_bytes_count":642,"rx_packets_count":9,"tx_packets_count":6,"tap_read_fails":0},"#,
+        r#""seccomp":{"num_faults":0},"#,
+        r#""signals":{"sigbus":0,"sigsegv":0,"sigxfsz":0,"sigxcpu":0,"sigpipe":0},"#,
+        r#""vcpu":{"exit_io_in":4,"exit_io_out":1023,"exit_mmio_read":11,"exit_mmio_write":34,"failures":0,"exit_io_in_agg":{"min_us":1}},"#,
+        r#""vmm":{"device_events":0,"panic_count":0},"#,
+        r#""uart":{"read_count":0,"write_count":1320}}"#,
+    );
+
+    fn sample(json: &str) -> VmmMetrics {
+        serde_json::from_str(json).unwrap()
+    }
+
+    fn row(metrics: &VmmMetrics, name: &str) -> u64 {
+        metrics.rows().into_iter().find(|(row, _)| *row == name).unwrap().1
+    }
+
+    #[test]
+    fn parses_a_recorded_line() {
+        let metrics = sample(RECORDED);
+        assert_eq!(metrics.api_server.process_startup_time_us, 7393);
+        assert_eq!(metrics.api_server.process_startup_time_cpu_us, 1321);
+        assert_eq!((metrics.block.read_bytes, metrics.block.read_count, metrics.block.flush_count), (1052672, 257, 1));
+        assert_eq!((metrics.net.rx_bytes_count, metrics.net.tx_packets_count), (1180, 6));
+        assert_eq!((metrics.vcpu.exit_io_out, metrics.vcpu.exit_mmio_write), (1023, 34));
+        assert_eq!(row(&metrics, "STARTUP_TIME_US"), 7393);
+        assert_eq!(row(&metrics, "NET_RX_BYTES"), 1180);
+    }
+
+    #[test]
+    fn missing_sections_and_unknown_fields_are_fine() {
+        let metrics = sample(r#"{"vcpu":{"exit_io_in":2,"exit_new_kind":5},"future_section":{"a":1}}"#);
+        assert_eq!(metrics.vcpu.exit_io_in, 2);
+        assert_eq!(metrics.block.read_bytes, 0);
+        assert_eq!(metrics.api_server.process_startup_time_us, 0);
+    }
+
+    #[test]
+    fn counters_add_up_and_latencies_are_kept() {
+        let mut total = VmmMetrics::default();
+        total.add(&sample(RECORDED));
+        total.add(&sample(r#"{"vcpu":{"exit_io_out":7},"net":{"rx_bytes_count":20},"latencies_us":{"pause_vm":310}}"#));
+        total.add(&sample(r#"{"vcpu":{"exit_io_out":3},"signals":{"sigsegv":1},"latencies_us":{"resume_vm":95}}"#));
+
+        assert_eq!(total.vcpu.exit_io_out, 1023 + 7 + 3);
+        assert_eq!(total.net.rx_bytes_count, 1180 + 20);
+        assert_eq!(total.signals.sigsegv, 1);
+        // Written once, and not reset by later flushes that do not have them
+        assert_eq!(total.api_server.process_startup_time_us, 7393);
+        assert_eq!((total.latencies_us.pause_vm, total.latencies_us.resume_vm), (310, 95));
+
+        // A later operation replaces the latency of the earlier one rather than adding to it
+        total.add(&sample(r#"{"latencies_us":{"pause_vm":290}}"#));
+        assert_eq!(total.latencies_us.pause_vm, 290);
+    }
+
+    #[test]
+    fn fifo_lines_may_arrive_in_pieces() {
+        let dir = std::env::temp_dir().join(format!("vmm-metrics-test-{}", Uuid::new_v4()));
+        fs::create_dir(&dir).unwrap();
+        let config = MetricsConfig { output_dir: dir.to_string_lossy().into_owned(), fifo: true };
+        let mut sink = MetricsSink::create(&config, "vm-1").unwrap();
+        let mut writer = OpenOptions::new().write(true).open(sink.path()).unwrap();
+
+        // Nothing written yet
+        sink.collect().unwrap();
+        assert_eq!(sink.metrics().vcpu.exit_io_out, 0);
+
+        let (start, end) = RECORDED.split_at(RECORDED.len() / 2);
+        writer.write_all(start.as_bytes()).unwrap();
+        sink.collect().unwrap();
+        assert_eq!(sink.metrics().vcpu.exit_io_out, 0);
+
+        // The rest of the line, a bad line, and a whole second line in one write
+        let rest = format!("{}\nnot json\n{}\n", end, r#"{"vcpu":{"exit_io_out":7}}"#);
+        writer.write_all(rest.as_bytes()).unwrap();
+        sink.collect().unwrap();
+        assert_eq!(sink.metrics().vcpu.exit_io_out, 1030);
+        assert_eq!(sink.metrics().api_server.process_startup_time_us, 7393);
+
+        // The named pipe goes away on close
+        let path = sink.path().to_string();
+        drop(writer);
+        sink.close().unwrap();
+        assert!(!std::path::Path::new(&path).exists());
+        fs::remove_dir(&dir).unwrap();
+    }
+}
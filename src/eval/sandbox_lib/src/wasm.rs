@@ -0,0 +1,207 @@
+use crate::{
+    cgroup::{Cgroup, CgroupConfig},
+    error::SandboxError,
+    readiness::{ProbeKind, ProbeTarget, Readiness, ReadinessConfig},
+    registry::{SandboxConfig, SandboxEntry},
+    sandbox::{Capabilities, Sandbox},
+    timings::{LifecycleTimings, Phase},
+};
+use anyhow::Result;
+use log::debug;
+use serde::Deserialize;
+use std::{
+    fs::File,
+    process::{Child, Command},
+    time::Instant,
+};
+use uuid::Uuid;
+
+// The echo logic compiled to wasm32-wasip1, run by the in-tree wasmtime host (src/wasm/host).
+// `module` is either the .wasm file or a .cwasm file precompiled with `wasm-host -compile`, which
+// skips compilation on start.
+#[derive(Deserialize)]
+struct WasmConfig {
+    host_binary: String,
+    module: String,
+    listen_ip: String,
+    listen_port: u16,
+    output_dir: String,
+    #[serde(default)]
+    instantiation: Instantiation,
+    // Preallocate instance slots instead of mapping memory on every instantiation
+    #[serde(default)]
+    pooling: bool,
+    readiness: Option<ReadinessConfig>,
+    cgroup: Option<CgroupConfig>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Instantiation {
+    // One Wasm instance for every request
+    PerRequest,
+    // One Wasm instance, created when the host starts and reused by every request
+    #[default]
+    PerInstance,
+}
+
+impl Instantiation {
+    fn host_arg(&self) -> &'static str {
+        match self {
+            Instantiation::PerRequest => "per-request",
+            Instantiation::PerInstance => "per-instance",
+        }
+    }
+
+    // Added to the sandbox's name, so that each way of instantiating can be told apart
+    fn name_suffix(&self) -> &'static str {
+        match self {
+            Instantiation::PerRequest => "-PerRequest",
+            Instantiation::PerInstance => "",
+        }
+    }
+}
+
+pub struct Wasm {
+    id: String,
+    config: WasmConfig,
+    child_process: Option<Child>,
+    iteration: usize,
+    timings: LifecycleTimings,
+    cgroup: Option<Cgroup>,
+}
+
+pub const ENTRY: SandboxEntry = SandboxEntry {
+    name: "wasm",
+    build: |config, iteration| Ok(Box::new(Wasm::from_config(config, iteration)?)),
+};
+
+impl Wasm {
+    pub fn new(config_path: &str, iteration: usize) -> Result<Self, SandboxError> {
+        Self::from_config(&SandboxConfig::Path(config_path.to_string()), iteration)
+    }
+
+    pub fn from_config(config: &SandboxConfig, iteration: usize) -> Result<Self, SandboxError> {
+        let mut config: WasmConfig = config.parse()?;
+
+        // Update the port based on the iteration
+        config.listen_port += iteration as u16;
+
+        Ok(Wasm {
+            id: Uuid::new_v4().to_string(),
+            config,
+            child_process: None,
+            iteration,
+            timings: LifecycleTimings::default(),
+            cgroup: None,
+        })
+    }
+
+    fn log_file(&self, suffix: &str) -> String {
+        format!("{}/wasm{}-{}{}", self.config.output_dir, self.id, self.iteration, suffix)
+    }
+}
+
+impl Sandbox for Wasm {
+    fn presetup(&mut self) -> Result<()> {
+        self.cgroup = Cgroup::create_optional(self.config.cgroup.as_ref(), &self.id)?;
+        Ok(())
+    }
+
+    fn cleanup(&mut self) -> Result<()> {
+        if let Some(cgroup) = self.cgroup.take() {
+            cgroup.remove()?;
+        }
+        Ok(())
+    }
+
+    fn start(&mut self) -> Result<()> {
+        let log_file_out = File::create(self.log_file(".out")).map_err(SandboxError::io(self.log_file(".out")))?;
+        let log_file_err = File::create(self.log_file(".err")).map_err(SandboxError::io(self.log_file(".err")))?;
+
+        // The host loads the module, and instantiates it unless per request, before it listens
+        let mut command = Command::new(&self.config.host_binary);
+        command
+            .arg("-listen")
+            .arg(format!("{}:{}", self.config.listen_ip, self.config.listen_port))
+            .arg("-module")
+            .arg(&self.config.module)
+            .arg("-instantiation")
+            .arg(self.config.instantiation.host_arg());
+        if self.config.pooling {
+            command.arg("-pooling");
+        }
+        command.stdout(log_file_out).stderr(log_file_err);
+        debug!("Starting Wasm sandbox with command: {:?}", command);
+
+        let spawn_start = Instant::now();
+        if let Some(cgroup) = &self.cgroup {
+            cgroup.attach(&mut command)?;
+        }
+        let process = command.spawn().map_err(SandboxError::spawn(&self.config.host_binary))?;
+        self.timings.record(Phase::ProcessSpawn, spawn_start, Instant::now());
+
+        debug!("Started Wasm sandbox with PID: {}", process.id());
+        self.child_process = Some(process);
+        Ok(())
+    }
+
+    fn kill(&mut self) -> Result<()> {
+        let child = self.child_process.as_mut().ok_or(SandboxError::NotStarted)?;
+        child.kill()?;
+        child.wait()?;
+        Ok(())
+    }
+
+    fn get_target_ip(&self) -> String {
+        self.config.listen_ip.clone()
+    }
+
+    fn get_target_port(&self) -> u16 {
+        self.config.listen_port
+    }
+
+    fn get_name(&self) -> String {
+        let mut name = format!("Wasm{}", self.config.instantiation.name_suffix());
+        if self.config.pooling {
+            name.push_str("-Pooling");
+        }
+        if self.config.module.ends_with(".cwasm") {
+            name.push_str("-Precompiled");
+        }
+        name
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            needs_network_presetup: false,
+            supports_snapshot: false,
+            supports_pause: false,
+            readiness_kind: ProbeKind::Tcp,
+            warmup_required: true,
+            multi_instance_safe: true,
+        }
+    }
+
+    fn timings(&self) -> LifecycleTimings {
+        self.timings.clone()
+    }
+
+    fn root_pid(&self) -> Option<u32> {
+        self.child_process.as_ref().map(|child| child.id())
+    }
+
+    fn cgroup(&self) -> Option<&Cgroup> {
+        self.cgroup.as_ref()
+    }
+
+    fn readiness(&self) -> Result<Readiness> {
+        let target = ProbeTarget {
+            ip: self.get_target_ip(),
+            port: self.get_target_port(),
+            log_file: Some(self.log_file(".out")),
+            api_socket: None,
+        };
+        Readiness::from_config(self.config.readiness.as_ref(), self.capabilities().readiness_kind, &target)
+    }
+}
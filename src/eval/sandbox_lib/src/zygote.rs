@@ -0,0 +1,145 @@
+use crate::error::SandboxError;
+use log::debug;
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, Read},
+    mem,
+    net::TcpListener,
+    os::{
+        fd::{AsRawFd, RawFd},
+        unix::{net::UnixStream, process::CommandExt},
+    },
+    process::{Child, Command},
+    ptr,
+    sync::{Arc, LazyLock, Mutex},
+};
+
+// Where the zygote finds its end of the control socket
+const CONTROL_FD: RawFd = 3;
+
+// A rust-http-echo started with `-zygote-fd`: it initializes once, then forks a server for each
+// request sent over the control socket, without exec. Servers are forked with CLONE_PARENT, so
+// they are our children and `Server::kill` reaps them. It stops once the control socket closes,
+// that is when the driver exits.
+pub struct Zygote {
+    control: Mutex<UnixStream>,
+    _process: Child,
+}
+
+// One zygote per binary for the whole process, shared by every sandbox
+static ZYGOTES: LazyLock<Mutex<HashMap<String, Arc<Zygote>>>> = LazyLock::new(Default::default);
+
+impl Zygote {
+    pub fn get_or_start(binary_path: &str, output_dir: &str) -> Result<Arc<Self>, SandboxError> {
+        let mut zygotes = ZYGOTES.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(zygote) = zygotes.get(binary_path) {
+            return Ok(zygote.clone());
+        }
+        let zygote = Arc::new(Self::start(binary_path, output_dir)?);
+        zygotes.insert(binary_path.to_string(), zygote.clone());
+        Ok(zygote)
+    }
+
+    fn start(binary_path: &str, output_dir: &str) -> Result<Self, SandboxError> {
+        let (mut control, zygote_end) = UnixStream::pair().map_err(SandboxError::io("socketpair"))?;
+        let log_file = |suffix: &str| {
+            let path = format!("{}/zygote{}{}", output_dir, std::process::id(), suffix);
+            File::create(&path).map_err(SandboxError::io(path))
+        };
+
+        let mut command = Command::new(binary_path);
+        command
+            .args(["-zygote-fd", &CONTROL_FD.to_string()])
+            .stdout(log_file(".out")?)
+            .stderr(log_file(".err")?);
+        let zygote_fd = zygote_end.as_raw_fd();
+        // SAFETY: only plain system calls run between fork and exec
+        unsafe {
+            command.pre_exec(move || {
+                let result = match zygote_fd {
+                    // Already in place, only has to survive exec
+                    CONTROL_FD => libc::fcntl(CONTROL_FD, libc::F_SETFD, 0),
+                    _ => libc::dup2(zygote_fd, CONTROL_FD),
+                };
+                match result {
+                    -1 => Err(io::Error::last_os_error()),
+                    _ => Ok(()),
+                }
+            });
+        }
+        let process = command.spawn().map_err(SandboxError::spawn(binary_path))?;
+        drop(zygote_end);
+
+        // Sent once the zygote is ready to fork
+        let mut ready = [0u8; 1];
+        control.read_exact(&mut ready).map_err(SandboxError::spawn(binary_path))?;
+        debug!("Started zygote {} with PID: {}", binary_path, process.id());
+
+        Ok(Zygote {
+            control: Mutex::new(control),
+            _process: process,
+        })
+    }
+
+    // Has the zygote fork a server that serves `listener`, and returns its PID. The server joins the
+    // cgroup of `cgroup_procs` before it serves.
+    pub fn fork(
+        &self,
+        listener: &TcpListener,
+        stdout: &File,
+        stderr: &File,
+        cgroup_procs: Option<&File>,
+    ) -> Result<libc::pid_t, SandboxError> {
+        let mut fds = vec![listener.as_raw_fd(), stdout.as_raw_fd(), stderr.as_raw_fd()];
+        fds.extend(cgroup_procs.map(|procs| procs.as_raw_fd()));
+
+        // One request at a time, so that replies match their requests
+        let mut control = self.control.lock().unwrap_or_else(|e| e.into_inner());
+        send_fds(&control, &fds).map_err(SandboxError::io("zygote control socket"))?;
+        let mut reply = [0u8; size_of::<i32>()];
+        control.read_exact(&mut reply).map_err(SandboxError::io("zygote control socket"))?;
+        match i32::from_ne_bytes(reply) {
+            pid if pid > 0 => Ok(pid),
+            errno => Err(SandboxError::spawn("zygote")(io::Error::from_raw_os_error(-errno))),
+        }
+    }
+}
+
+// Sends a one byte request carrying `fds`
+fn send_fds(control: &UnixStream, fds: &[RawFd]) -> io::Result<()> {
+    let mut byte = 0u8;
+    let mut iov = libc::iovec {
+        iov_base: &mut byte as *mut u8 as *mut libc::c_void,
+        iov_len: 1,
+    };
+    let fds_len = mem::size_of_val(fds) as u32;
+    // Aligned for `cmsghdr`, and large enough for the few descriptors of a request
+    let mut buffer = [0u64; 8];
+
+    // SAFETY: `msghdr` is plain old data, every pointer in it outlives the call, and the control
+    // buffer has room for the descriptors
+    unsafe {
+        let space = libc::CMSG_SPACE(fds_len) as usize;
+        assert!(space <= mem::size_of_val(&buffer));
+        let mut message: libc::msghdr = mem::zeroed();
+        message.msg_iov = &mut iov;
+        message.msg_iovlen = 1;
+        message.msg_control = buffer.as_mut_ptr() as *mut libc::c_void;
+        message.msg_controllen = space as _;
+
+        let header = libc::CMSG_FIRSTHDR(&message);
+        (*header).cmsg_level = libc::SOL_SOCKET;
+        (*header).cmsg_type = libc::SCM_RIGHTS;
+        (*header).cmsg_len = libc::CMSG_LEN(fds_len) as _;
+        ptr::copy_nonoverlapping(fds.as_ptr(), libc::CMSG_DATA(header) as *mut RawFd, fds.len());
+
+        loop {
+            match libc::sendmsg(control.as_raw_fd(), &message, 0) {
+                -1 if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted => continue,
+                -1 => return Err(io::Error::last_os_error()),
+                _ => return Ok(()),
+            }
+        }
+    }
+}
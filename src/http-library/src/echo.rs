@@ -0,0 +1,77 @@
+// Copyright(c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//==================================================================================================
+// Imports
+//==================================================================================================
+
+use ::log::error;
+use ::serde::Deserialize;
+use ::serde_json::Value;
+
+//==================================================================================================
+// Structures
+//==================================================================================================
+
+#[derive(Deserialize)]
+struct MessageJson {
+    data: Vec<u8>,
+}
+
+///
+/// # Description
+///
+/// Reasons why a request could not be echoed.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EchoError {
+    /// The request is not a valid JSON message.
+    BadRequest,
+    /// The response could not be built.
+    InternalError,
+}
+
+//==================================================================================================
+// Public Standalone Functions
+//==================================================================================================
+
+///
+/// # Description
+///
+/// Echoes the data of a JSON message back. This is the whole application logic of the echo
+/// server, kept free of any I/O so that it also builds for targets such as `wasm32-wasip1`.
+///
+/// # Parameters
+///
+/// - `body`: Request body.
+///
+/// # Returns
+///
+/// The body of the response on success, or the reason of the failure otherwise.
+///
+pub fn echo(body: &[u8]) -> Result<Vec<u8>, EchoError> {
+    // Deserialize the JSON directly into the struct
+    let request: MessageJson = match serde_json::from_slice(body) {
+        Ok(request) => request,
+        Err(_) => {
+            let reason: String = "failed to deserialize JSON".to_string();
+            error!("{}", reason);
+            return Err(EchoError::BadRequest);
+        },
+    };
+
+    // For now this is just copying the request data into the response.
+    let json: Value = serde_json::json!({
+        "response": String::from_utf8_lossy(&request.data).to_string(),
+    });
+
+    // Convert JSON to bytes.
+    match serde_json::to_vec(&json) {
+        Ok(bytes) => Ok(bytes),
+        Err(_) => {
+            let reason: String = "failed to convert JSON to bytes".to_string();
+            error!("{}", reason);
+            Err(EchoError::InternalError)
+        },
+    }
+}
@@ -0,0 +1,119 @@
+// Copyright(c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//==================================================================================================
+// Imports
+//==================================================================================================
+
+use crate::echo::{
+    echo,
+    EchoError,
+};
+use ::http_body_util::{
+    BodyExt,
+    Full,
+};
+use ::hyper::{
+    body::{
+        Bytes,
+        Incoming,
+    },
+    service::Service,
+    Request,
+    Response,
+    StatusCode,
+};
+use ::log::error;
+use ::std::{
+    future::Future,
+    pin::Pin,
+};
+
+//==================================================================================================
+// Structures
+//==================================================================================================
+
+pub struct HttpService {}
+
+impl HttpService {
+    pub fn new() -> Self {
+        Self {}
+    }
+
+    ///
+    /// # Description
+    ///
+    /// Helper function that creates a "bad request" response.
+    ///
+    /// # Returns
+    ///
+    /// A "bad request" response.
+    ///
+    fn bad_request() -> Response<Full<Bytes>> {
+        let mut bad_request: Response<Full<Bytes>> = Response::new(Full::new(Bytes::new()));
+        *bad_request.status_mut() = hyper::StatusCode::BAD_REQUEST;
+        bad_request
+    }
+
+    ///
+    /// # Description
+    ///
+    /// Helper function that creates an "internal server error" response.
+    ///
+    /// # Returns
+    ///
+    /// An "internal server error" response.
+    ///
+    fn internal_server_error() -> Response<Full<Bytes>> {
+        let mut internal_server_error: Response<Full<Bytes>> =
+            Response::new(Full::new(Bytes::new()));
+        *internal_server_error.status_mut() = hyper::StatusCode::INTERNAL_SERVER_ERROR;
+        internal_server_error
+    }
+}
+
+impl Default for HttpService {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Service<Request<Incoming>> for HttpService {
+    type Response = Response<Full<Bytes>>;
+    type Error = hyper::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn call(&self, request: Request<Incoming>) -> Self::Future {
+        let future = async move {
+            let body: Bytes = match request.collect().await {
+                Ok(body) => body.to_bytes(),
+                Err(_) => {
+                    let reason: String = "failed to read body".to_string();
+                    error!("{}", reason);
+                    return Ok(Self::internal_server_error());
+                },
+            };
+
+            let bytes: Bytes = match echo(body.as_ref()) {
+                Ok(bytes) => Bytes::from(bytes),
+                Err(EchoError::BadRequest) => return Ok(Self::bad_request()),
+                Err(EchoError::InternalError) => return Ok(Self::internal_server_error()),
+            };
+
+            match Response::builder()
+                .status(StatusCode::OK)
+                .header("Content-Type", "application/json")
+                .header("Content-Length", bytes.len())
+                .body(Full::new(bytes))
+            {
+                Ok(response) => Ok(response),
+                Err(_) => {
+                    let reason: String = "failed to build response".to_string();
+                    error!("{}", reason);
+                    Ok(Self::internal_server_error())
+                },
+            }
+        };
+        Box::pin(future)
+    }
+}
@@ -13,6 +13,8 @@ use ::anyhow::Result;
 
 pub struct Args {
     listen_sockaddr: String,
+    listen_fd: Option<i32>,
+    zygote_fd: Option<i32>,
 }
 
 //==================================================================================================
@@ -22,9 +24,13 @@ pub struct Args {
 impl Args {
     const OPT_HELP: &'static str = "-help";
     const OPT_LISTEN_SOCKADDR: &'static str = "-listen";
+    const OPT_LISTEN_FD: &'static str = "-listen-fd";
+    const OPT_ZYGOTE_FD: &'static str = "-zygote-fd";
 
     pub fn parse(args: Vec<String>) -> Result<Self> {
         let mut http_sockaddr: String = String::new();
+        let mut listen_fd: Option<i32> = None;
+        let mut zygote_fd: Option<i32> = None;
 
         let mut i: usize = 1;
         while i < args.len() {
@@ -37,6 +43,14 @@ impl Args {
                     i += 1;
                     http_sockaddr = args[i].clone();
                 },
+                Self::OPT_LISTEN_FD => {
+                    i += 1;
+                    listen_fd = Some(args[i].parse()?);
+                },
+                Self::OPT_ZYGOTE_FD => {
+                    i += 1;
+                    zygote_fd = Some(args[i].parse()?);
+                },
                 _ => {
                     return Err(anyhow::anyhow!("invalid argument"));
                 },
@@ -47,18 +61,30 @@ impl Args {
 
         Ok(Self {
             listen_sockaddr: http_sockaddr,
+            listen_fd,
+            zygote_fd,
         })
     }
 
     pub fn usage(program_name: &str) {
         println!(
-            "Usage: {} {} <sockaddr>",
+            "Usage: {} {} <sockaddr> | {} <fd> | {} <fd>",
             program_name,
             Self::OPT_LISTEN_SOCKADDR,
+            Self::OPT_LISTEN_FD,
+            Self::OPT_ZYGOTE_FD,
         );
     }
 
     pub fn listen_sockaddr(&self) -> &str {
         &self.listen_sockaddr
     }
+
+    pub fn listen_fd(&self) -> Option<i32> {
+        self.listen_fd
+    }
+
+    pub fn zygote_fd(&self) -> Option<i32> {
+        self.zygote_fd
+    }
 }
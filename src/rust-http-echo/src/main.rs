@@ -7,6 +7,7 @@
 
 mod args;
 mod logging;
+mod zygote;
 
 //==================================================================================================
 // Imports
@@ -21,11 +22,13 @@ use ::anyhow::Result;
 use ::http_library::HttpService;
 use ::hyper::server::conn::http1;
 use ::hyper_util::rt::TokioIo;
+use ::std::os::fd::FromRawFd;
 use ::tokio::{
     net::{
         TcpListener,
         TcpStream,
     },
+    runtime::Runtime,
     signal::unix::{
         signal,
         Signal,
@@ -37,14 +40,39 @@ use ::tokio::{
 // Standalone Functions
 //==================================================================================================
 
-#[tokio::main]
-pub async fn main() -> Result<()> {
+pub fn main() -> Result<()> {
     logging::initialize(false);
 
     let args: Args = Args::parse(std::env::args().collect())?;
 
+    // A zygote forks before the runtime starts any thread, and each fork serves what it was given.
+    let listen_fd: Option<i32> = match args.zygote_fd() {
+        Some(control_fd) => match zygote::run(control_fd)? {
+            Some(listen_fd) => Some(listen_fd),
+            None => return Ok(()),
+        },
+        None => args.listen_fd(),
+    };
+
+    Runtime::new()?.block_on(serve(args.listen_sockaddr(), listen_fd))
+}
+
+///
+/// # Description
+///
+/// Serves HTTP requests until interrupted.
+///
+/// # Parameters
+///
+/// - `listen_sockaddr`: Socket address to listen on, unless a listening socket is inherited.
+/// - `listen_fd`: Inherited listening socket.
+///
+async fn serve(listen_sockaddr: &str, listen_fd: Option<i32>) -> Result<()> {
     let mut signals: Signal = signal(SignalKind::interrupt())?;
-    let http_listener: TcpListener = TcpListener::bind(args.listen_sockaddr()).await?;
+    let http_listener: TcpListener = match listen_fd {
+        Some(fd) => inherited_listener(fd)?,
+        None => TcpListener::bind(listen_sockaddr).await?,
+    };
 
     loop {
         tokio::select! {
@@ -72,3 +100,20 @@ pub async fn main() -> Result<()> {
 
     Ok(())
 }
+
+///
+/// # Description
+///
+/// Takes over a socket that the parent process already bound and put in listening state, e.g. when
+/// the server runs in a network namespace of its own.
+///
+/// # Parameters
+///
+/// - `fd`: Inherited file descriptor.
+///
+fn inherited_listener(fd: i32) -> Result<TcpListener> {
+    // SAFETY: the parent handed this descriptor over to us, and nothing else in this process owns it.
+    let listener: std::net::TcpListener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+    listener.set_nonblocking(true)?;
+    Ok(TcpListener::from_std(listener)?)
+}
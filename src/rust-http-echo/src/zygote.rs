@@ -0,0 +1,265 @@
+// Copyright(c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//!
+//! # Zygote
+//!
+//! This module implements a fork server: a server that initializes once, and then forks copies of
+//! itself that start serving right away, without exec or loading anything.
+//!
+
+//==================================================================================================
+// Imports
+//==================================================================================================
+
+use ::anyhow::Result;
+use ::std::{
+    io,
+    mem,
+    os::fd::RawFd,
+    ptr,
+};
+
+//==================================================================================================
+// Constants
+//==================================================================================================
+
+/// Most descriptors that a request carries: listening socket, stdout, stderr and `cgroup.procs`.
+const MAX_FDS: usize = 4;
+
+/// Sent once the zygote is ready to fork.
+const READY: u8 = 1;
+
+//==================================================================================================
+// Public Standalone Functions
+//==================================================================================================
+
+///
+/// # Description
+///
+/// Serves fork requests sent over a control socket. Each request carries the listening socket,
+/// stdout and stderr of a new server, and optionally the `cgroup.procs` file of a cgroup that it
+/// has to join. The zygote answers each request with the PID of the new server, or a negated error
+/// number. New servers are children of the process that started the zygote, which reaps them.
+///
+/// # Parameters
+///
+/// - `control_fd`: Control socket.
+///
+/// # Returns
+///
+/// In each new server, the listening socket to serve. In the zygote, nothing once the control
+/// socket is closed.
+///
+/// # Note
+///
+/// Forking is only safe while the process runs a single thread, so this has to run before any
+/// runtime is built. Servers share what is initialized here: the parsed arguments, the logger, the
+/// code and data pages faulted in, and the heap. Each builds its own runtime once forked, as neither
+/// threads nor epoll instances can be shared with a fork.
+///
+pub fn run(control_fd: RawFd) -> Result<Option<RawFd>> {
+    warm_up();
+
+    write_all(control_fd, &[READY])?;
+    info!("zygote ready");
+
+    loop {
+        let fds: Vec<RawFd> = match receive_fds(control_fd)? {
+            Some(fds) => fds,
+            None => {
+                info!("control socket closed, stopping...");
+                return Ok(None);
+            },
+        };
+
+        let reply: i32 = if fds.len() < 3 {
+            -libc::EINVAL
+        } else {
+            match fork_sibling() {
+                Ok(0) => return enter_server(control_fd, &fds).map(Some),
+                Ok(pid) => pid,
+                Err(e) => -e.raw_os_error().unwrap_or(libc::EIO),
+            }
+        };
+
+        for fd in fds {
+            // SAFETY: the descriptor was received with the request, and the server has its own copy.
+            unsafe { libc::close(fd) };
+        }
+        write_all(control_fd, &reply.to_ne_bytes())?;
+    }
+}
+
+//==================================================================================================
+// Private Standalone Functions
+//==================================================================================================
+
+///
+/// # Description
+///
+/// Runs what every server would otherwise run first, so that forks start with it done: one request
+/// through the echo handler, and a heap grown to what serving a few connections takes.
+///
+fn warm_up() {
+    let _ = ::http_library::echo(br#"{"data":[0]}"#);
+
+    // Freed chunks below the trim threshold stay in the heap, ready for the first allocations of
+    // the runtime and its connections.
+    let chunks: Vec<Vec<u8>> = (0..64).map(|_| vec![0u8; 1024]).collect();
+    drop(chunks);
+}
+
+///
+/// # Description
+///
+/// Forks a copy of this process as a sibling rather than a child, i.e. a child of our parent,
+/// which can then wait for it like for any child of its own.
+///
+/// # Returns
+///
+/// Like fork(2): the PID of the new process, or 0 in the new process.
+///
+/// # Note
+///
+/// Unlike fork(3), this runs no `pthread_atfork` handlers, which a process that runs a single
+/// thread and has not started a runtime does not need.
+///
+fn fork_sibling() -> io::Result<libc::pid_t> {
+    // SAFETY: this process runs a single thread, so the copy is consistent, and with no stack given
+    // the new process continues on a copy of the current one, like after fork(2).
+    match unsafe {
+        libc::syscall(
+            libc::SYS_clone,
+            (libc::CLONE_PARENT | libc::SIGCHLD) as libc::c_ulong,
+            0 as libc::c_ulong,
+            0 as libc::c_ulong,
+            0 as libc::c_ulong,
+            0 as libc::c_ulong,
+        )
+    } {
+        -1 => Err(io::Error::last_os_error()),
+        pid => Ok(pid as libc::pid_t),
+    }
+}
+
+///
+/// # Description
+///
+/// Turns a freshly forked child into a server of its own.
+///
+/// # Parameters
+///
+/// - `control_fd`: Control socket of the zygote.
+/// - `fds`: Descriptors received with the request.
+///
+/// # Returns
+///
+/// The listening socket to serve.
+///
+fn enter_server(control_fd: RawFd, fds: &[RawFd]) -> Result<RawFd> {
+    // SAFETY: every call below is a plain system call on descriptors that this process owns.
+    unsafe {
+        libc::close(control_fd);
+
+        if let Some(&cgroup_procs) = fds.get(3) {
+            // Writing "0" moves the writing process.
+            let joined: isize = libc::write(cgroup_procs, b"0".as_ptr() as *const libc::c_void, 1);
+            libc::close(cgroup_procs);
+            if joined < 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+        }
+
+        for (fd, target) in [(fds[1], libc::STDOUT_FILENO), (fds[2], libc::STDERR_FILENO)] {
+            if libc::dup2(fd, target) < 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+            libc::close(fd);
+        }
+    }
+
+    Ok(fds[0])
+}
+
+///
+/// # Description
+///
+/// Receives a request and the descriptors that it carries.
+///
+/// # Parameters
+///
+/// - `control_fd`: Control socket.
+///
+/// # Returns
+///
+/// The descriptors, or nothing once the control socket is closed.
+///
+fn receive_fds(control_fd: RawFd) -> Result<Option<Vec<RawFd>>> {
+    let mut byte: u8 = 0;
+    let mut iov: libc::iovec = libc::iovec {
+        iov_base: &mut byte as *mut u8 as *mut libc::c_void,
+        iov_len: 1,
+    };
+    // Aligned for `cmsghdr`, and large enough for `CMSG_SPACE(MAX_FDS * sizeof(int))`.
+    let mut control: [u64; 8] = [0; 8];
+
+    // SAFETY: `msghdr` is plain old data, and every pointer in it outlives the call.
+    let mut message: libc::msghdr = unsafe { mem::zeroed() };
+    message.msg_iov = &mut iov;
+    message.msg_iovlen = 1;
+    message.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    message.msg_controllen = mem::size_of_val(&control) as _;
+
+    let received: isize = loop {
+        // SAFETY: `message` describes valid buffers.
+        match unsafe { libc::recvmsg(control_fd, &mut message, 0) } {
+            -1 if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted => continue,
+            -1 => return Err(io::Error::last_os_error().into()),
+            received => break received,
+        }
+    };
+    if received == 0 {
+        return Ok(None);
+    }
+
+    let mut fds: Vec<RawFd> = Vec::with_capacity(MAX_FDS);
+    // SAFETY: the kernel filled in the control buffer that `message` points to.
+    unsafe {
+        let mut header: *mut libc::cmsghdr = libc::CMSG_FIRSTHDR(&message);
+        while !header.is_null() {
+            if (*header).cmsg_level == libc::SOL_SOCKET && (*header).cmsg_type == libc::SCM_RIGHTS {
+                let data: *const RawFd = libc::CMSG_DATA(header) as *const RawFd;
+                let len: usize = (*header).cmsg_len as usize - libc::CMSG_LEN(0) as usize;
+                for i in 0..len / mem::size_of::<RawFd>() {
+                    fds.push(ptr::read_unaligned(data.add(i)));
+                }
+            }
+            header = libc::CMSG_NXTHDR(&message, header);
+        }
+    }
+
+    Ok(Some(fds))
+}
+
+///
+/// # Description
+///
+/// Writes a whole buffer to the control socket.
+///
+/// # Parameters
+///
+/// - `control_fd`: Control socket.
+/// - `buffer`: Buffer to write.
+///
+fn write_all(control_fd: RawFd, mut buffer: &[u8]) -> Result<()> {
+    while !buffer.is_empty() {
+        // SAFETY: `buffer` is a valid buffer of the given length.
+        match unsafe { libc::write(control_fd, buffer.as_ptr() as *const libc::c_void, buffer.len()) } {
+            -1 if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted => continue,
+            -1 => return Err(io::Error::last_os_error().into()),
+            written => buffer = &buffer[written as usize..],
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,99 @@
+// Copyright(c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//!
+//! # Wasm Echo Guest
+//!
+//! The echo logic of `http-library`, built as a `wasm32-wasip1` reactor module. The host copies a
+//! request into a buffer obtained from `echo_alloc()`, calls `echo_handle()`, and reads the response
+//! back from guest memory. Every buffer is released with `echo_free()`.
+//!
+
+//==================================================================================================
+// Imports
+//==================================================================================================
+
+use ::http_library::{
+    echo,
+    EchoError,
+};
+use ::std::{
+    ptr,
+    slice,
+};
+
+//==================================================================================================
+// Public Standalone Functions
+//==================================================================================================
+
+///
+/// # Description
+///
+/// Allocates a buffer in guest memory.
+///
+/// # Parameters
+///
+/// - `len`: Size of the buffer.
+///
+/// # Returns
+///
+/// The address of the buffer.
+///
+#[unsafe(no_mangle)]
+pub extern "C" fn echo_alloc(len: u32) -> *mut u8 {
+    Box::into_raw(vec![0u8; len as usize].into_boxed_slice()) as *mut u8
+}
+
+///
+/// # Description
+///
+/// Releases a buffer returned by `echo_alloc()` or `echo_handle()`.
+///
+/// # Parameters
+///
+/// - `ptr`: Address of the buffer.
+/// - `len`: Size of the buffer.
+///
+/// # Safety
+///
+/// The buffer must not be used afterwards.
+///
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn echo_free(ptr: *mut u8, len: u32) {
+    // SAFETY: the buffer was leaked from a boxed slice of the same length.
+    drop(unsafe { Box::from_raw(ptr::slice_from_raw_parts_mut(ptr, len as usize)) });
+}
+
+///
+/// # Description
+///
+/// Echoes a request.
+///
+/// # Parameters
+///
+/// - `ptr`: Address of the request, in a buffer returned by `echo_alloc()`.
+/// - `len`: Size of the request.
+///
+/// # Returns
+///
+/// The address of the response in the upper 32 bits and its size in the lower 32 bits. Zero if the
+/// request is malformed, and `u64::MAX` if the response could not be built.
+///
+/// # Safety
+///
+/// `ptr` and `len` must describe a valid buffer.
+///
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn echo_handle(ptr: *const u8, len: u32) -> u64 {
+    // SAFETY: the caller passes a valid buffer.
+    let request: &[u8] = unsafe { slice::from_raw_parts(ptr, len as usize) };
+    match echo(request) {
+        Ok(response) => {
+            let len: u64 = response.len() as u64;
+            let ptr: u64 = Box::into_raw(response.into_boxed_slice()) as *mut u8 as usize as u64;
+            (ptr << 32) | len
+        },
+        Err(EchoError::BadRequest) => 0,
+        Err(EchoError::InternalError) => u64::MAX,
+    }
+}
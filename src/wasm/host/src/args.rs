@@ -0,0 +1,121 @@
+// Copyright(c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//==================================================================================================
+// Imports
+//==================================================================================================
+
+use crate::runtime::Instantiation;
+use ::anyhow::Result;
+
+//==================================================================================================
+// Structures
+//==================================================================================================
+
+pub struct Args {
+    listen_sockaddr: String,
+    module: String,
+    instantiation: Instantiation,
+    pooling: bool,
+    compile: Option<String>,
+}
+
+//==================================================================================================
+// Implementations
+//==================================================================================================
+
+impl Args {
+    const OPT_HELP: &'static str = "-help";
+    const OPT_LISTEN_SOCKADDR: &'static str = "-listen";
+    const OPT_MODULE: &'static str = "-module";
+    const OPT_INSTANTIATION: &'static str = "-instantiation";
+    const OPT_POOLING: &'static str = "-pooling";
+    const OPT_COMPILE: &'static str = "-compile";
+
+    pub fn parse(args: Vec<String>) -> Result<Self> {
+        let mut http_sockaddr: String = String::new();
+        let mut module: String = String::new();
+        let mut instantiation: Instantiation = Instantiation::PerInstance;
+        let mut pooling: bool = false;
+        let mut compile: Option<String> = None;
+
+        let mut i: usize = 1;
+        while i < args.len() {
+            match args[i].as_str() {
+                Self::OPT_HELP => {
+                    Self::usage(args[0].as_str());
+                    return Err(anyhow::anyhow!("wrong usage"));
+                },
+                Self::OPT_LISTEN_SOCKADDR => {
+                    i += 1;
+                    http_sockaddr = args[i].clone();
+                },
+                Self::OPT_MODULE => {
+                    i += 1;
+                    module = args[i].clone();
+                },
+                Self::OPT_INSTANTIATION => {
+                    i += 1;
+                    instantiation = args[i].parse()?;
+                },
+                Self::OPT_POOLING => {
+                    pooling = true;
+                },
+                Self::OPT_COMPILE => {
+                    i += 1;
+                    compile = Some(args[i].clone());
+                },
+                _ => {
+                    return Err(anyhow::anyhow!("invalid argument"));
+                },
+            }
+
+            i += 1;
+        }
+
+        if module.is_empty() {
+            Self::usage(args[0].as_str());
+            return Err(anyhow::anyhow!("missing {}", Self::OPT_MODULE));
+        }
+
+        Ok(Self {
+            listen_sockaddr: http_sockaddr,
+            module,
+            instantiation,
+            pooling,
+            compile,
+        })
+    }
+
+    pub fn usage(program_name: &str) {
+        println!(
+            "Usage: {} {} <sockaddr> {} <filepath> [{} per-request|per-instance] [{}] [{} <filepath>]",
+            program_name,
+            Self::OPT_LISTEN_SOCKADDR,
+            Self::OPT_MODULE,
+            Self::OPT_INSTANTIATION,
+            Self::OPT_POOLING,
+            Self::OPT_COMPILE,
+        );
+    }
+
+    pub fn listen_sockaddr(&self) -> &str {
+        &self.listen_sockaddr
+    }
+
+    pub fn module(&self) -> &str {
+        &self.module
+    }
+
+    pub fn instantiation(&self) -> Instantiation {
+        self.instantiation
+    }
+
+    pub fn pooling(&self) -> bool {
+        self.pooling
+    }
+
+    pub fn compile(&self) -> Option<&str> {
+        self.compile.as_deref()
+    }
+}
@@ -0,0 +1,105 @@
+// Copyright(c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//==================================================================================================
+// Imports
+//==================================================================================================
+
+use crate::runtime::{
+    Reply,
+    Runtime,
+};
+use ::http_body_util::{
+    BodyExt,
+    Full,
+};
+use ::hyper::{
+    body::{
+        Bytes,
+        Incoming,
+    },
+    service::Service,
+    Request,
+    Response,
+    StatusCode,
+};
+use ::std::{
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+};
+
+//==================================================================================================
+// Structures
+//==================================================================================================
+
+pub struct HttpServer {
+    runtime: Arc<Runtime>,
+}
+
+impl HttpServer {
+    pub fn new(runtime: Arc<Runtime>) -> Self {
+        Self { runtime }
+    }
+
+    ///
+    /// # Description
+    ///
+    /// Helper function that creates a response with an empty body.
+    ///
+    /// # Parameters
+    ///
+    /// - `status`: Status of the response.
+    ///
+    /// # Returns
+    ///
+    /// The response.
+    ///
+    fn empty_response(status: StatusCode) -> Response<Full<Bytes>> {
+        let mut response: Response<Full<Bytes>> = Response::new(Full::new(Bytes::new()));
+        *response.status_mut() = status;
+        response
+    }
+}
+
+impl Service<Request<Incoming>> for HttpServer {
+    type Response = Response<Full<Bytes>>;
+    type Error = hyper::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn call(&self, request: Request<Incoming>) -> Self::Future {
+        let runtime: Arc<Runtime> = self.runtime.clone();
+        let future = async move {
+            let body: Bytes = match request.collect().await {
+                Ok(body) => body.to_bytes(),
+                Err(_) => {
+                    error!("failed to read body");
+                    return Ok(Self::empty_response(StatusCode::INTERNAL_SERVER_ERROR));
+                },
+            };
+
+            let bytes: Bytes = match runtime.echo(body.as_ref()) {
+                Ok(Reply::Echoed(bytes)) => Bytes::from(bytes),
+                Ok(Reply::BadRequest) => return Ok(Self::empty_response(StatusCode::BAD_REQUEST)),
+                Err(e) => {
+                    warn!("failed to serve request ({:?})", e);
+                    return Ok(Self::empty_response(StatusCode::INTERNAL_SERVER_ERROR));
+                },
+            };
+
+            match Response::builder()
+                .status(StatusCode::OK)
+                .header("Content-Type", "application/json")
+                .header("Content-Length", bytes.len())
+                .body(Full::new(bytes))
+            {
+                Ok(response) => Ok(response),
+                Err(_) => {
+                    error!("failed to build response");
+                    Ok(Self::empty_response(StatusCode::INTERNAL_SERVER_ERROR))
+                },
+            }
+        };
+        Box::pin(future)
+    }
+}
@@ -0,0 +1,91 @@
+// Copyright(c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//==================================================================================================
+// Modules
+//==================================================================================================
+
+mod args;
+mod http;
+mod logging;
+mod runtime;
+
+//==================================================================================================
+// Imports
+//==================================================================================================
+
+// Must come first.
+#[macro_use]
+extern crate log;
+
+use crate::{
+    args::Args,
+    http::HttpServer,
+    runtime::Runtime,
+};
+use ::anyhow::Result;
+use ::hyper::server::conn::http1;
+use ::hyper_util::rt::TokioIo;
+use ::std::sync::Arc;
+use ::tokio::{
+    net::{
+        TcpListener,
+        TcpStream,
+    },
+    signal::unix::{
+        signal,
+        Signal,
+        SignalKind,
+    },
+};
+
+//==================================================================================================
+// Standalone Functions
+//==================================================================================================
+
+#[tokio::main]
+pub async fn main() -> Result<()> {
+    logging::initialize(false);
+
+    let args: Args = Args::parse(std::env::args().collect())?;
+
+    // Only compile the module ahead of time.
+    if let Some(output_path) = args.compile() {
+        Runtime::compile(args.module(), output_path, args.pooling())?;
+        info!("compiled {} to {}", args.module(), output_path);
+        return Ok(());
+    }
+
+    // Load the module before listening, so that accepting connections means being ready.
+    let runtime: Arc<Runtime> =
+        Arc::new(Runtime::new(args.module(), args.instantiation(), args.pooling())?);
+
+    let mut signals: Signal = signal(SignalKind::interrupt())?;
+    let http_listener: TcpListener = TcpListener::bind(args.listen_sockaddr()).await?;
+
+    loop {
+        tokio::select! {
+           result = http_listener.accept() => {
+                match result {
+                    Ok((stream, sockaddr)) => {
+                        debug!("accepted connection from {:?}", sockaddr);
+                        let client = HttpServer::new(runtime.clone());
+                        let io: TokioIo<TcpStream> = TokioIo::new(stream);
+                        if let Err(e) = http1::Builder::new().serve_connection(io, client).await  {
+                            error!("failed to serve connection ({:?})", e);
+                        }
+                    },
+                    Err(e) => {
+                        error!("failed to accept connection ({:?})", e);
+                    },
+                }
+            },
+            _ = signals.recv() => {
+                info!("received exit signal, stopping...");
+                break;
+            },
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,284 @@
+// Copyright(c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//!
+//! # Runtime
+//!
+//! This module runs the echo guest in wasmtime.
+//!
+
+//==================================================================================================
+// Imports
+//==================================================================================================
+
+use ::anyhow::Result;
+use ::std::{
+    str::FromStr,
+    sync::Mutex,
+};
+use ::wasmtime::{
+    Config,
+    Engine,
+    InstanceAllocationStrategy,
+    InstancePre,
+    Linker,
+    Memory,
+    Module,
+    PoolingAllocationConfig,
+    Precompiled,
+    Store,
+    TypedFunc,
+};
+use ::wasmtime_wasi::{
+    preview1::{
+        self,
+        WasiP1Ctx,
+    },
+    WasiCtxBuilder,
+};
+
+//==================================================================================================
+// Constants
+//==================================================================================================
+
+/// Number of instances that may be alive at once with the pooling allocator.
+const POOL_SIZE: u32 = 128;
+
+//==================================================================================================
+// Structures
+//==================================================================================================
+
+///
+/// # Description
+///
+/// When guest instances are created.
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Instantiation {
+    /// A fresh instance serves every request.
+    PerRequest,
+    /// A single instance, created at startup, serves every request.
+    PerInstance,
+}
+
+///
+/// # Description
+///
+/// Outcome of a request that the guest handled.
+///
+pub enum Reply {
+    /// Body of the response.
+    Echoed(Vec<u8>),
+    /// The request is malformed.
+    BadRequest,
+}
+
+///
+/// # Description
+///
+/// An instance of the echo guest, along with its exports.
+///
+struct Guest {
+    store: Store<WasiP1Ctx>,
+    memory: Memory,
+    alloc: TypedFunc<u32, u32>,
+    free: TypedFunc<(u32, u32), ()>,
+    handle: TypedFunc<(u32, u32), u64>,
+}
+
+pub struct Runtime {
+    engine: Engine,
+    instance_pre: InstancePre<WasiP1Ctx>,
+    instantiation: Instantiation,
+    /// Instance reused across requests, with per-instance instantiation only.
+    instance: Mutex<Option<Guest>>,
+}
+
+//==================================================================================================
+// Implementations
+//==================================================================================================
+
+impl FromStr for Instantiation {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "per-request" => Ok(Self::PerRequest),
+            "per-instance" => Ok(Self::PerInstance),
+            _ => Err(anyhow::anyhow!("invalid instantiation '{}'", s)),
+        }
+    }
+}
+
+impl Guest {
+    ///
+    /// # Description
+    ///
+    /// Copies a request into the guest, has it echoed, and copies the response back.
+    ///
+    /// # Parameters
+    ///
+    /// - `request`: Request body.
+    ///
+    /// # Returns
+    ///
+    /// The reply of the guest.
+    ///
+    fn echo(&mut self, request: &[u8]) -> Result<Reply> {
+        let len: u32 = u32::try_from(request.len())?;
+        let ptr: u32 = self.alloc.call(&mut self.store, len)?;
+        self.memory.write(&mut self.store, ptr as usize, request)?;
+        let packed: u64 = self.handle.call(&mut self.store, (ptr, len))?;
+        self.free.call(&mut self.store, (ptr, len))?;
+
+        match packed {
+            0 => Ok(Reply::BadRequest),
+            u64::MAX => Err(anyhow::anyhow!("guest failed to build the response")),
+            _ => {
+                let (ptr, len): (u32, u32) = ((packed >> 32) as u32, packed as u32);
+                let mut response: Vec<u8> = vec![0u8; len as usize];
+                self.memory.read(&self.store, ptr as usize, &mut response)?;
+                self.free.call(&mut self.store, (ptr, len))?;
+                Ok(Reply::Echoed(response))
+            },
+        }
+    }
+}
+
+impl Runtime {
+    ///
+    /// # Description
+    ///
+    /// Loads a guest module and links it against WASI.
+    ///
+    /// # Parameters
+    ///
+    /// - `module_path`: Module, either a `.wasm`/`.wat` file or a `.cwasm` file made by `compile()`.
+    /// - `instantiation`: When guest instances are created.
+    /// - `pooling`: Use the pooling allocator?
+    ///
+    /// # Returns
+    ///
+    /// The runtime, with its instance already created for per-instance instantiation.
+    ///
+    pub fn new(module_path: &str, instantiation: Instantiation, pooling: bool) -> Result<Self> {
+        let engine: Engine = Self::engine(pooling)?;
+        // Anything that is neither a precompiled module nor binary Wasm, such as text, gets compiled.
+        let module: Module = match engine.detect_precompiled_file(module_path).ok().flatten() {
+            // SAFETY: precompiled modules are trusted to come from `compile()`.
+            Some(Precompiled::Module) => unsafe { Module::deserialize_file(&engine, module_path)? },
+            Some(Precompiled::Component) => {
+                return Err(anyhow::anyhow!("{} is a component, not a module", module_path));
+            },
+            None => Module::from_file(&engine, module_path)?,
+        };
+
+        let mut linker: Linker<WasiP1Ctx> = Linker::new(&engine);
+        preview1::add_to_linker_sync(&mut linker, |ctx| ctx)?;
+        let instance_pre: InstancePre<WasiP1Ctx> = linker.instantiate_pre(&module)?;
+
+        let runtime: Self = Self {
+            engine,
+            instance_pre,
+            instantiation,
+            instance: Mutex::new(None),
+        };
+        if instantiation == Instantiation::PerInstance {
+            let guest: Guest = runtime.instantiate()?;
+            *runtime.lock_instance() = Some(guest);
+        }
+        Ok(runtime)
+    }
+
+    ///
+    /// # Description
+    ///
+    /// Compiles a module ahead of time, so that loading it later skips compilation.
+    ///
+    /// # Parameters
+    ///
+    /// - `module_path`: Module to compile.
+    /// - `output_path`: Where to write the compiled module, usually a `.cwasm` file.
+    /// - `pooling`: Whether the module will be loaded with the pooling allocator.
+    ///
+    pub fn compile(module_path: &str, output_path: &str, pooling: bool) -> Result<()> {
+        let engine: Engine = Self::engine(pooling)?;
+        let compiled: Vec<u8> = engine.precompile_module(&std::fs::read(module_path)?)?;
+        std::fs::write(output_path, compiled)?;
+        Ok(())
+    }
+
+    ///
+    /// # Description
+    ///
+    /// Has a request echoed by the guest.
+    ///
+    /// # Parameters
+    ///
+    /// - `request`: Request body.
+    ///
+    /// # Returns
+    ///
+    /// The reply of the guest.
+    ///
+    pub fn echo(&self, request: &[u8]) -> Result<Reply> {
+        match self.instantiation {
+            Instantiation::PerRequest => self.instantiate()?.echo(request),
+            Instantiation::PerInstance => {
+                let mut instance = self.lock_instance();
+                let guest: &mut Guest = match instance.as_mut() {
+                    Some(guest) => guest,
+                    None => instance.insert(self.instantiate()?),
+                };
+                let reply: Result<Reply> = guest.echo(request);
+                // A trap may leave the instance in any state, start over with a new one.
+                if reply.is_err() {
+                    *instance = None;
+                }
+                reply
+            },
+        }
+    }
+
+    fn engine(pooling: bool) -> Result<Engine> {
+        let mut config: Config = Config::new();
+        if pooling {
+            let mut pool: PoolingAllocationConfig = PoolingAllocationConfig::default();
+            pool.total_core_instances(POOL_SIZE)
+                .total_memories(POOL_SIZE)
+                .total_tables(POOL_SIZE);
+            config.allocation_strategy(InstanceAllocationStrategy::Pooling(pool));
+        }
+        Engine::new(&config)
+    }
+
+    fn instantiate(&self) -> Result<Guest> {
+        let wasi: WasiP1Ctx = WasiCtxBuilder::new().inherit_stdout().inherit_stderr().build_p1();
+        let mut store: Store<WasiP1Ctx> = Store::new(&self.engine, wasi);
+        let instance = self.instance_pre.instantiate(&mut store)?;
+
+        // Reactors set up their runtime before any other export is called.
+        if let Some(initialize) = instance.get_func(&mut store, "_initialize") {
+            initialize.typed::<(), ()>(&store)?.call(&mut store, ())?;
+        }
+
+        let memory: Memory = instance
+            .get_memory(&mut store, "memory")
+            .ok_or_else(|| anyhow::anyhow!("guest does not export its memory"))?;
+        let alloc = instance.get_typed_func::<u32, u32>(&mut store, "echo_alloc")?;
+        let free = instance.get_typed_func::<(u32, u32), ()>(&mut store, "echo_free")?;
+        let handle = instance.get_typed_func::<(u32, u32), u64>(&mut store, "echo_handle")?;
+
+        Ok(Guest {
+            store,
+            memory,
+            alloc,
+            free,
+            handle,
+        })
+    }
+
+    fn lock_instance(&self) -> ::std::sync::MutexGuard<'_, Option<Guest>> {
+        self.instance.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}